@@ -1,7 +1,8 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 
-use crate::hyperware::process::fwd_ws::{
-    ConnectionType, Request as FwdWsRequest, Response as FwdWsResponse, State,
+use crate::hyperware::process::fwd_ws::{Request as FwdWsRequest, Response as FwdWsResponse};
+use crate::hyperware::process::kibitz::{
+    CheckToolsRequest, Request as KibitzRequest, Response as KibitzResponse,
 };
 use hyperware_process_lib::logging::{error, info, init_logging, Level};
 use hyperware_process_lib::{
@@ -16,9 +17,14 @@ use hyperware_process_lib::{
     },
     println, set_state,
     timer::set_timer,
+    vfs::{create_drive, create_file},
     Address, LazyLoadBlob, Message, Request, Response,
 };
 
+mod machine;
+mod persist;
+use machine::{Effect, Event, ProcessState, HTTP_API_PATH, WS_PATH};
+
 wit_bindgen::generate!({
     path: "target/wit",
     world: "kibitz-nick-dot-hypr-v0",
@@ -26,112 +32,56 @@ wit_bindgen::generate!({
     additional_derives: [serde::Deserialize, serde::Serialize, process_macros::SerdeJsonInto],
 });
 
-const INITIAL_RECONNECT_DELAY_MS: u64 = 5000;
-const MAX_RECONNECT_DELAY_MS: u64 = 30000;
-const RECONNECT_TIMER_ID: u32 = 123;
-
-const HTTP_API_PATH: &str = "/api";
-const WS_PATH: &str = "/";
-const DEFAULT_WS_URL: &str = "ws://localhost:10125";
-
 const RECONNECT_CONTEXT: &[u8] = b"reconnect";
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
-struct ProcessState {
-    partner: Option<String>,
-    connection: ConnectionType,
-    ws_url: Option<String>,
-    #[serde(skip)]
-    ws_channel: Option<u32>,
-    #[serde(skip)]
-    pending_message: Option<String>,
-    #[serde(skip)]
-    pending_partner_message: Option<String>,
-    #[serde(skip)]
-    current_reconnect_delay_ms: Option<u64>,
-}
+/// How long to wait for kibitz to check tool calls against a profile.
+const CHECK_TOOLS_TIMEOUT_S: u64 = 5;
 
-impl Default for ProcessState {
-    fn default() -> Self {
-        Self {
-            partner: None,
-            connection: ConnectionType::None,
-            ws_url: None,
-            ws_channel: None,
-            pending_message: None,
-            pending_partner_message: None,
-            current_reconnect_delay_ms: None,
-        }
-    }
-}
+const STATE_BACKUP_DRIVE: &str = "state-backup";
 
 impl ProcessState {
-    fn restore() -> anyhow::Result<Self> {
-        let restored = if let Some(state) = get_state() {
-            let mut state: Self = serde_json::from_slice(&state)?;
-
-            // If we have a WebSocket server connection and a channel
-            state.try_reconnect_to_server()?;
-
-            state
-        } else {
-            Self::default()
+    /// Restore and migrate persisted state. State that can't be migrated is
+    /// backed up to VFS and reported through `restore_error` instead of
+    /// being silently replaced.
+    fn restore(our: &Address) -> Self {
+        let Some(bytes) = get_state() else {
+            return Self::default();
         };
-        Ok(restored)
-    }
-
-    fn save(&self) -> anyhow::Result<()> {
-        set_state(&serde_json::to_vec(self)?);
-        Ok(())
-    }
-
-    fn to_public_state(&self) -> State {
-        State {
-            partner: self.partner.clone(),
-            connection: self.connection.clone(),
-            ws_url: self.ws_url.clone(),
-        }
-    }
-
-    fn try_reconnect_to_server(&mut self) -> anyhow::Result<()> {
-        if !matches!(self.connection, ConnectionType::ToWsServer) {
-            return Ok(());
-        }
-
-        // Only attempt reconnect if we don't have a valid channel
-        if self.ws_channel.is_some() {
-            return Ok(());
-        }
-
-        let url = self
-            .ws_url
-            .as_ref()
-            .map(|url| url.clone())
-            .unwrap_or_else(|| DEFAULT_WS_URL.to_string());
-
-        // Create new connection
-        let channel_id = rand::random();
-        match open_ws_connection(url.clone(), None, channel_id) {
-            Ok(_) => {
-                self.ws_url = Some(url);
-                self.ws_channel = Some(channel_id);
-                self.current_reconnect_delay_ms = None; // Reset delay on success
-                self.save()?;
-                info!("Successfully reconnected to WebSocket server");
-                return Ok(());
-            }
+        match persist::decode(&bytes) {
+            Ok(state) => state,
             Err(e) => {
-                info!("Failed to reconnect to WebSocket server: {}", e);
-                self.ws_channel = None;
-                self.save()?;
-                schedule_reconnect(self)?;
+                let backup = match backup_state(our, &bytes) {
+                    Ok(path) => format!("previous state saved to {path}"),
+                    Err(backup_error) => {
+                        format!("failed to back up previous state: {backup_error}")
+                    }
+                };
+                let restore_error = format!("could not restore state ({e:#}); {backup}");
+                error!("{restore_error}");
+                Self {
+                    restore_error: Some(restore_error),
+                    ..Self::default()
+                }
             }
         }
+    }
 
+    fn save(&self) -> anyhow::Result<()> {
+        set_state(&persist::encode(self)?);
         Ok(())
     }
 }
 
+fn backup_state(our: &Address, bytes: &[u8]) -> anyhow::Result<String> {
+    let drive = create_drive(our.package_id(), STATE_BACKUP_DRIVE, None)?;
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    let path = format!("{drive}/fwd-ws-state-{timestamp}.json");
+    create_file(&path, None)?.write(bytes)?;
+    Ok(path)
+}
+
 fn make_http_server_address(our: &Address) -> Address {
     Address::from((our.node(), "http-server", "distro", "sys"))
 }
@@ -143,19 +93,126 @@ fn make_timer_address(our: &Address) -> Address {
     Address::from((our.node(), "timer", "distro", "sys"))
 }
 
-fn schedule_reconnect(state: &mut ProcessState) -> anyhow::Result<()> {
-    let delay = state
-        .current_reconnect_delay_ms
-        .unwrap_or(INITIAL_RECONNECT_DELAY_MS);
-
-    // Start a timer for reconnection
-    set_timer(delay, Some(RECONNECT_CONTEXT.to_vec()));
+fn make_kibitz_address(our: &Address) -> Address {
+    Address::from((our.node(), "kibitz", "kibitz", "nick.hypr"))
+}
 
-    // Update the next delay (double it but cap at max)
-    state.current_reconnect_delay_ms = Some(std::cmp::min(delay * 2, MAX_RECONNECT_DELAY_MS));
-    state.save()?;
+/// Ask kibitz which of `tools` `profile` doesn't allow.
+fn check_tools(our: &Address, profile: String, tools: Vec<String>) -> Result<Vec<String>, String> {
+    let request = KibitzRequest::CheckTools(CheckToolsRequest {
+        profile_id: profile.clone(),
+        tools,
+    });
+    let response = Request::to(make_kibitz_address(our))
+        .body(request)
+        .send_and_await_response(CHECK_TOOLS_TIMEOUT_S)
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())?;
+    match KibitzResponse::try_from(response.body()) {
+        Ok(KibitzResponse::CheckTools(Some(denied))) => Ok(denied),
+        Ok(KibitzResponse::CheckTools(None)) => Err(format!("no agent profile {profile}")),
+        Ok(KibitzResponse::Err(e)) => Err(e),
+        _ => Err("unexpected response from kibitz".to_string()),
+    }
+}
 
-    info!("Scheduled reconnection attempt in {}ms", delay);
+/// Feed `event` to the state machine and perform the resulting effects,
+/// including any follow-up events they produce (e.g. connection results).
+fn run(
+    our: &Address,
+    event: Event,
+    server: &mut HttpServer,
+    state: &mut ProcessState,
+) -> anyhow::Result<()> {
+    let mut events = VecDeque::from([event]);
+    while let Some(event) = events.pop_front() {
+        for effect in state.handle(event) {
+            match effect {
+                Effect::Save => state.save()?,
+                Effect::Respond(response) => {
+                    Response::new().body(response.into_response(state)).send()?;
+                }
+                Effect::ForwardToPartner { partner, message } => {
+                    Request::new()
+                        .target((&partner, "fwd-ws", "kibitz", "nick.hypr"))
+                        .body(FwdWsRequest::Forward(message))
+                        .send()?;
+                }
+                Effect::PushToServer {
+                    channel_id,
+                    message,
+                } => {
+                    send_ws_client_push(
+                        channel_id,
+                        WsMessageType::Text,
+                        LazyLoadBlob {
+                            mime: Some("text/plain".to_string()),
+                            bytes: message.into_bytes(),
+                        },
+                    );
+                }
+                Effect::PushToClient {
+                    channel_id,
+                    message,
+                } => {
+                    Request::new()
+                        .target(make_http_server_address(our))
+                        .body(serde_json::to_vec(&HttpServerAction::WebSocketPush {
+                            channel_id,
+                            message_type: WsMessageType::Text,
+                        })?)
+                        .blob(LazyLoadBlob {
+                            mime: Some("text/plain".to_string()),
+                            bytes: message.into_bytes(),
+                        })
+                        .send()?;
+                }
+                Effect::OpenWsConnection { url, reason } => {
+                    let channel_id = rand::random();
+                    let result = match open_ws_connection(url.clone(), None, channel_id) {
+                        Ok(_) => {
+                            info!("Connected to WebSocket server {url}");
+                            Ok(channel_id)
+                        }
+                        Err(e) => {
+                            info!("Failed to connect to WebSocket server {url}: {e}");
+                            Err(e.to_string())
+                        }
+                    };
+                    events.push_back(Event::WsOpened {
+                        url,
+                        reason,
+                        result,
+                    });
+                }
+                Effect::ScheduleReconnect { delay_ms } => {
+                    set_timer(delay_ms, Some(RECONNECT_CONTEXT.to_vec()));
+                    info!("Scheduled reconnection attempt in {}ms", delay_ms);
+                }
+                Effect::AcceptClient { path, channel_id } => {
+                    info!("WebSocket client connected on channel {}", channel_id);
+                    server.handle_websocket_open(&path, channel_id);
+                }
+                Effect::ReleaseClient { channel_id } => {
+                    info!("WebSocket client disconnected");
+                    server.handle_websocket_close(channel_id);
+                }
+                Effect::CheckTools {
+                    profile,
+                    tools,
+                    channel_id,
+                    message,
+                } => {
+                    let denied = check_tools(our, profile, tools);
+                    events.push_back(Event::ToolsChecked {
+                        channel_id,
+                        message,
+                        denied,
+                    });
+                }
+            }
+        }
+    }
     Ok(())
 }
 
@@ -168,67 +225,26 @@ fn handle_http_server_request(
     let request = serde_json::from_slice::<HttpServerRequest>(body)?;
 
     match request {
-        HttpServerRequest::WebSocketOpen {
-            ref path,
-            channel_id,
-        } => {
-            if !(path == WS_PATH || path == HTTP_API_PATH)
-                || matches!(state.connection, ConnectionType::ToWsServer)
-            {
-                return Ok(());
-            }
-            info!("WebSocket client connected on channel {}", channel_id);
-            state.connection = ConnectionType::ToWsClient;
-            state.ws_channel = Some(channel_id);
-            state.save()?;
-            server.handle_websocket_open(path, channel_id);
-
-            // Send any pending partner messages
-            if let Some(message) = state.pending_partner_message.take() {
-                Request::new()
-                    .target(&make_http_server_address(our))
-                    .body(serde_json::to_vec(&HttpServerAction::WebSocketPush {
-                        channel_id,
-                        message_type: WsMessageType::Text,
-                    })?)
-                    .blob(LazyLoadBlob {
-                        mime: Some("text/plain".to_string()),
-                        bytes: message.into_bytes(),
-                    })
-                    .send()?;
-            }
-            state.save()?;
+        HttpServerRequest::WebSocketOpen { path, channel_id } => {
+            run(our, Event::ClientOpened { path, channel_id }, server, state)?;
         }
 
         HttpServerRequest::WebSocketClose(channel_id) => {
-            if state.ws_channel != Some(channel_id) {
-                return Ok(());
-            }
-            info!("WebSocket client disconnected");
-            state.connection = ConnectionType::None;
-            state.ws_channel = None;
-            state.save()?;
-            server.handle_websocket_close(channel_id);
+            run(our, Event::ClientClosed { channel_id }, server, state)?;
         }
 
         HttpServerRequest::WebSocketPush { channel_id, .. } => {
-            // request from client (kibitz fe):
-            //  forward to our partner over the Kinet
-            if state.ws_channel != Some(channel_id) {
-                return Ok(());
-            }
             if let Some(blob) = get_blob() {
-                let msg = String::from_utf8(blob.bytes)?;
-                if let Some(ref partner) = state.partner {
-                    Request::new()
-                        .target((partner, "fwd-ws", "kibitz", "nick.hypr"))
-                        .body(FwdWsRequest::Forward(msg))
-                        .send()?;
-                } else {
-                    // Store message if no partner set
-                    state.pending_message = Some(msg);
-                    state.save()?;
-                }
+                let message = String::from_utf8(blob.bytes)?;
+                run(
+                    our,
+                    Event::ClientMessage {
+                        channel_id,
+                        message,
+                    },
+                    server,
+                    state,
+                )?;
             }
         }
 
@@ -248,12 +264,23 @@ fn handle_http_server_request(
                     );
                 }
                 "PUT" => {
-                    if let Some(blob) = get_blob() {
-                        handle_request_message(our, our, &blob.bytes, false, state)?;
-                        send_response(StatusCode::OK, None, vec![]);
-                    } else {
+                    let Some(request) = get_blob()
+                        .and_then(|blob| FwdWsRequest::try_from(blob.bytes.as_slice()).ok())
+                    else {
                         send_response(StatusCode::BAD_REQUEST, None, vec![]);
-                    }
+                        return Ok(());
+                    };
+                    run(
+                        our,
+                        Event::Request {
+                            source_node: our.node().to_string(),
+                            request,
+                            respond: false,
+                        },
+                        server,
+                        state,
+                    )?;
+                    send_response(StatusCode::OK, None, vec![]);
                 }
                 _ => send_response(StatusCode::METHOD_NOT_ALLOWED, None, vec![]),
             }
@@ -263,177 +290,25 @@ fn handle_http_server_request(
     Ok(())
 }
 
-fn handle_request_message(
-    our: &Address,
-    source: &Address,
-    body: &[u8],
-    should_respond: bool,
-    state: &mut ProcessState,
-) -> anyhow::Result<()> {
-    let request: FwdWsRequest = body.try_into()?;
-    match request {
-        FwdWsRequest::SetPartner(partner) => {
-            state.partner = partner;
-            // If partner is being set, send any pending messages
-            if state.partner.is_some() {
-                if let Some(msg) = state.pending_message.take() {
-                    if let Some(ref partner) = state.partner {
-                        Request::new()
-                            .target((partner, "fwd-ws", "kibitz", "nick.hypr"))
-                            .body(FwdWsRequest::Forward(msg))
-                            .send()?;
-                    }
-                }
-            }
-            state.save()?;
-            if should_respond {
-                Response::new().body(FwdWsResponse::Ok).send()?;
-            }
-        }
-
-        FwdWsRequest::ConnectToServer(url) => {
-            if !matches!(state.connection, ConnectionType::None) {
-                if should_respond {
-                    Response::new()
-                        .body(FwdWsResponse::Err("Already connected".to_string()))
-                        .send()?;
-                }
-                return Ok(());
-            }
-
-            let channel_id = rand::random(); // Simple unique ID generation
-            if let Ok(_) = open_ws_connection(url.clone(), None, channel_id) {
-                state.connection = ConnectionType::ToWsServer;
-                state.ws_url = Some(url);
-                state.ws_channel = Some(channel_id);
-                state.save()?;
-                if should_respond {
-                    Response::new().body(FwdWsResponse::Ok).send()?;
-                }
-            } else {
-                if should_respond {
-                    Response::new()
-                        .body(FwdWsResponse::Err("Failed to connect".to_string()))
-                        .send()?;
-                }
-            }
-        }
-
-        FwdWsRequest::AcceptClients(endpoint) => {
-            if !matches!(state.connection, ConnectionType::None) {
-                Response::new()
-                    .body(FwdWsResponse::Err("Already connected".to_string()))
-                    .send()?;
-                return Ok(());
-            }
-
-            state.ws_url = Some(endpoint);
-            state.save()?;
-            if should_respond {
-                Response::new().body(FwdWsResponse::Ok).send()?;
-            }
-        }
-
-        FwdWsRequest::Disconnect => {
-            state.connection = ConnectionType::None;
-            state.ws_url = None;
-            state.ws_channel = None;
-            state.save()?;
-            if should_respond {
-                Response::new().body(FwdWsResponse::Ok).send()?;
-            }
-        }
-
-        FwdWsRequest::GetState => {
-            if should_respond {
-                Response::new()
-                    .body(FwdWsResponse::GetState(state.to_public_state()))
-                    .send()?;
-            }
-        }
-
-        FwdWsRequest::Forward(message) => {
-            if message.is_empty() {
-                return Ok(());
-            }
-            // Only handle if from partner
-            if !state.partner.as_ref().map_or(false, |p| p == &source.node) {
-                return Ok(());
-            }
-            let Some(channel_id) = state.ws_channel else {
-                // Store message if no WS channel
-                state.pending_partner_message = Some(message);
-                state.save()?;
-                if should_respond {
-                    Response::new().body(FwdWsResponse::Ok).send()?;
-                }
-                return Ok(());
-            };
-            match state.connection {
-                ConnectionType::ToWsServer => {
-                    // we're connected to a WS server: the ws-mcp
-                    //  send the message to the ws-mcp to be fulfilled
-                    send_ws_client_push(
-                        channel_id,
-                        WsMessageType::Text,
-                        LazyLoadBlob {
-                            mime: Some("text/plain".to_string()),
-                            bytes: message.clone().into_bytes(),
-                        },
-                    );
-                }
-                ConnectionType::ToWsClient => {
-                    // we're connected to kibitz:
-                    //  send the message to kibitz
-                    let http_server = make_http_server_address(our);
-                    Request::new()
-                        .target(&http_server)
-                        .body(serde_json::to_vec(&HttpServerAction::WebSocketPush {
-                            channel_id,
-                            message_type: WsMessageType::Text,
-                        })?)
-                        .blob(LazyLoadBlob {
-                            mime: Some("text/plain".to_string()),
-                            bytes: message.clone().into_bytes(),
-                        })
-                        .send()?;
-                }
-                ConnectionType::None => {
-                    // Store message if no WS connection
-                    state.pending_partner_message = Some(message);
-                    state.save()?;
-                }
-            }
-            if should_respond {
-                Response::new().body(FwdWsResponse::Ok).send()?;
-            }
-        }
-    }
-    Ok(())
-}
-
 fn handle_message(
     our: &Address,
     message: &Message,
     server: &mut HttpServer,
     state: &mut ProcessState,
 ) -> anyhow::Result<()> {
+    let source = message.source();
+
     if !message.is_request() {
+        // Timers pop as responses to our `set_timer` requests
+        if source == &make_timer_address(our)
+            && message.context().is_some_and(|c| c == RECONNECT_CONTEXT)
+        {
+            run(our, Event::ReconnectTimer, server, state)?;
+        }
         return Ok(());
     }
 
     let body = message.body();
-    let source = message.source();
-
-    // Handle timer messages for reconnection
-    if source.process == "timer.os" && state.ws_channel.is_none() {
-        if let Ok(ref timer_message) = serde_json::from_slice::<u32>(body) {
-            if *timer_message == RECONNECT_TIMER_ID {
-                state.try_reconnect_to_server()?;
-                return Ok(());
-            }
-        }
-    }
 
     if source == &make_http_server_address(our) {
         handle_http_server_request(our, body, server, state)?;
@@ -441,31 +316,37 @@ fn handle_message(
         let request = serde_json::from_slice::<HttpClientRequest>(body)?;
         match request {
             HttpClientRequest::WebSocketClose { .. } => {
-                if matches!(state.connection, ConnectionType::ToWsServer) {
-                    state.ws_channel = None;
-                    state.try_reconnect_to_server()?;
-                }
+                run(our, Event::ServerClosed, server, state)?;
             }
             _ => {
                 // Its a WebSocketPush:
                 //  Handle WebSocket client message
                 if let Some(blob) = get_blob() {
-                    if let Some(ref partner) = state.partner {
-                        Request::new()
-                            .target((partner, "fwd-ws", "kibitz", "nick.hypr"))
-                            .body(FwdWsRequest::Forward(String::from_utf8(blob.bytes)?))
-                            .send()?;
-                    }
+                    let message = String::from_utf8(blob.bytes)?;
+                    run(our, Event::ServerMessage { message }, server, state)?;
                 }
             }
         }
-    } else if source == &make_timer_address(our) {
-        if message.context().is_some_and(|c| c == RECONNECT_CONTEXT) {
-            state.try_reconnect_to_server()?;
-        }
     } else {
         // Handle request from another node
-        handle_request_message(our, source, body, true, state)?;
+        let request = FwdWsRequest::try_from(body)?;
+        // Only we may choose what our client's tool calls are held to
+        if matches!(request, FwdWsRequest::SetProfile(_)) && source.node() != our.node() {
+            Response::new()
+                .body(FwdWsResponse::Err("Not allowed".to_string()))
+                .send()?;
+            return Ok(());
+        }
+        run(
+            our,
+            Event::Request {
+                source_node: source.node().to_string(),
+                request,
+                respond: true,
+            },
+            server,
+            state,
+        )?;
     }
     server.ws_push_all_channels(
         HTTP_API_PATH,
@@ -486,7 +367,7 @@ fn init(our: Address) {
     info!("begin");
 
     let mut server = HttpServer::new(5);
-    let mut state = ProcessState::restore().unwrap_or_default();
+    let mut state = ProcessState::restore(&our);
 
     // Serve static UI files at root
     server
@@ -508,18 +389,8 @@ fn init(our: Address) {
 
     add_to_homepage("fwd-ws", None, Some("index.html"), None);
 
-    match open_ws_connection(DEFAULT_WS_URL.to_string(), None, rand::random()) {
-        Ok(_) => {
-            state.connection = ConnectionType::ToWsServer;
-            state.ws_url = Some(DEFAULT_WS_URL.to_string());
-            state.save().unwrap();
-        }
-        Err(e) => {
-            info!("couldn't connect to default WS url: {}, will retry...", e);
-            state.connection = ConnectionType::None;
-            state.ws_url = None;
-            state.try_reconnect_to_server().unwrap();
-        }
+    if let Err(e) = run(&our, Event::Startup, &mut server, &mut state) {
+        error!("failed to start WebSocket connection: {e:?}");
     }
 
     info!("initialized with state: {:?}", state);
@@ -0,0 +1,1017 @@
+//! Pure forwarding state machine.
+//!
+//! `ProcessState` consumes `Event`s (requests from partners or the local
+//! UI, WebSocket traffic, timers, the results of opening a connection) and
+//! returns the `Effect`s the process should perform. It never touches the
+//! runtime itself, so it can be exercised with `cargo test` off-node;
+//! `lib.rs` is responsible for turning messages into events and effects
+//! into actual sends.
+//!
+//! With a `profile` set, the client's MCP `tools/call` requests are held to
+//! that kibitz agent profile: kibitz is asked which of the tools they call
+//! the profile doesn't allow (as it is now, so narrowing a profile takes
+//! effect at once), and a message calling any such tool never reaches the
+//! partner or the tool server; each of its calls is answered with a
+//! JSON-RPC error instead. If kibitz can't be asked, the calls are refused.
+
+use serde_json::{json, Value};
+
+use crate::hyperware::process::fwd_ws::{
+    ConnectionType, Request as FwdWsRequest, Response as FwdWsResponse, State,
+};
+
+pub const INITIAL_RECONNECT_DELAY_MS: u64 = 5000;
+pub const MAX_RECONNECT_DELAY_MS: u64 = 30000;
+
+pub const HTTP_API_PATH: &str = "/api";
+pub const WS_PATH: &str = "/";
+pub const DEFAULT_WS_URL: &str = "ws://localhost:10125";
+
+/// JSON-RPC's "invalid params", for tool calls the profile refuses.
+const TOOL_NOT_ALLOWED_CODE: i64 = -32602;
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct ProcessState {
+    pub partner: Option<String>,
+    pub connection: ConnectionType,
+    pub ws_url: Option<String>,
+    /// The agent profile the client's tool calls are held to.
+    #[serde(default)]
+    pub profile: Option<String>,
+    #[serde(skip)]
+    pub ws_channel: Option<u32>,
+    #[serde(skip)]
+    pub pending_message: Option<String>,
+    #[serde(skip)]
+    pub pending_partner_message: Option<String>,
+    #[serde(skip)]
+    pub current_reconnect_delay_ms: Option<u64>,
+    /// Why persisted state could not be restored at startup, if it couldn't.
+    #[serde(skip)]
+    pub restore_error: Option<String>,
+}
+
+impl Default for ProcessState {
+    fn default() -> Self {
+        Self {
+            partner: None,
+            connection: ConnectionType::None,
+            ws_url: None,
+            profile: None,
+            ws_channel: None,
+            pending_message: None,
+            pending_partner_message: None,
+            current_reconnect_delay_ms: None,
+            restore_error: None,
+        }
+    }
+}
+
+/// Why a WebSocket connection to a server is being opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenReason {
+    /// Process start: reconnect to the configured server, or try the default.
+    Startup,
+    /// Explicit `ConnectToServer` request.
+    Connect { respond: bool },
+    /// Backoff timer or server-side close.
+    Reconnect,
+}
+
+#[derive(Debug)]
+pub enum Event {
+    Startup,
+    /// A `fwd-ws` request, from a partner node or from our own UI.
+    Request {
+        source_node: String,
+        request: FwdWsRequest,
+        respond: bool,
+    },
+    /// The result of performing an `Effect::OpenWsConnection`.
+    WsOpened {
+        url: String,
+        reason: OpenReason,
+        result: Result<u32, String>,
+    },
+    /// A WebSocket client (kibitz frontend) connected to us.
+    ClientOpened {
+        path: String,
+        channel_id: u32,
+    },
+    ClientClosed {
+        channel_id: u32,
+    },
+    ClientMessage {
+        channel_id: u32,
+        message: String,
+    },
+    /// The result of performing an `Effect::CheckTools`: the tools the
+    /// profile doesn't allow, or why that couldn't be found out.
+    ToolsChecked {
+        channel_id: u32,
+        message: String,
+        denied: Result<Vec<String>, String>,
+    },
+    /// The ws-mcp server we are connected to sent us a message.
+    ServerMessage {
+        message: String,
+    },
+    ServerClosed,
+    ReconnectTimer,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Effect {
+    /// Persist the current state.
+    Save,
+    Respond(ResponseEffect),
+    /// Send `Forward(message)` to our partner's `fwd-ws`.
+    ForwardToPartner {
+        partner: String,
+        message: String,
+    },
+    /// Push to the ws-mcp server we are a client of.
+    PushToServer {
+        channel_id: u32,
+        message: String,
+    },
+    /// Push to the kibitz frontend connected to us.
+    PushToClient {
+        channel_id: u32,
+        message: String,
+    },
+    /// Open a connection; the driver reports back with `Event::WsOpened`.
+    OpenWsConnection {
+        url: String,
+        reason: OpenReason,
+    },
+    ScheduleReconnect {
+        delay_ms: u64,
+    },
+    AcceptClient {
+        path: String,
+        channel_id: u32,
+    },
+    ReleaseClient {
+        channel_id: u32,
+    },
+    /// Ask kibitz which of `tools` `profile` doesn't allow; the driver
+    /// reports back with `Event::ToolsChecked`.
+    CheckTools {
+        profile: String,
+        tools: Vec<String>,
+        channel_id: u32,
+        message: String,
+    },
+}
+
+/// `FwdWsResponse` does not implement `PartialEq`, so effects carry this
+/// mirror of it instead.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ResponseEffect {
+    Ok,
+    GetState,
+    Err(String),
+}
+
+impl ResponseEffect {
+    pub fn into_response(self, state: &ProcessState) -> FwdWsResponse {
+        match self {
+            ResponseEffect::Ok => FwdWsResponse::Ok,
+            ResponseEffect::GetState => FwdWsResponse::GetState(state.to_public_state()),
+            ResponseEffect::Err(e) => FwdWsResponse::Err(e),
+        }
+    }
+}
+
+impl ProcessState {
+    pub fn to_public_state(&self) -> State {
+        State {
+            partner: self.partner.clone(),
+            connection: self.connection,
+            ws_url: self.ws_url.clone(),
+            profile: self.profile.clone(),
+            restore_error: self.restore_error.clone(),
+        }
+    }
+
+    pub fn handle(&mut self, event: Event) -> Vec<Effect> {
+        let mut effects = vec![];
+        match event {
+            Event::Startup => self.handle_startup(&mut effects),
+            Event::Request {
+                source_node,
+                request,
+                respond,
+            } => self.handle_request(&source_node, request, respond, &mut effects),
+            Event::WsOpened {
+                url,
+                reason,
+                result,
+            } => self.handle_ws_opened(url, reason, result, &mut effects),
+            Event::ClientOpened { path, channel_id } => {
+                if !(path == WS_PATH || path == HTTP_API_PATH)
+                    || matches!(self.connection, ConnectionType::ToWsServer)
+                {
+                    return effects;
+                }
+                self.connection = ConnectionType::ToWsClient;
+                self.ws_channel = Some(channel_id);
+                effects.push(Effect::Save);
+                effects.push(Effect::AcceptClient { path, channel_id });
+                // Send any pending partner messages
+                if let Some(message) = self.pending_partner_message.take() {
+                    effects.push(Effect::PushToClient {
+                        channel_id,
+                        message,
+                    });
+                }
+            }
+            Event::ClientClosed { channel_id } => {
+                if self.ws_channel != Some(channel_id) {
+                    return effects;
+                }
+                self.connection = ConnectionType::None;
+                self.ws_channel = None;
+                effects.push(Effect::Save);
+                effects.push(Effect::ReleaseClient { channel_id });
+            }
+            Event::ClientMessage {
+                channel_id,
+                message,
+            } => {
+                // request from client (kibitz fe):
+                //  forward to our partner over the network
+                if self.ws_channel != Some(channel_id) {
+                    return effects;
+                }
+                let calls = tool_calls(&message);
+                match self.profile {
+                    Some(ref profile) if !calls.is_empty() => {
+                        effects.push(Effect::CheckTools {
+                            profile: profile.clone(),
+                            tools: calls.into_iter().map(|(_, name)| name).collect(),
+                            channel_id,
+                            message,
+                        });
+                    }
+                    _ => self.forward_client_message(message, &mut effects),
+                }
+            }
+            Event::ToolsChecked {
+                channel_id,
+                message,
+                denied,
+            } => {
+                // The client may have gone meanwhile
+                if self.ws_channel != Some(channel_id) {
+                    return effects;
+                }
+                let reason = match denied {
+                    Ok(denied) if denied.is_empty() => {
+                        self.forward_client_message(message, &mut effects);
+                        return effects;
+                    }
+                    Ok(denied) => format!(
+                        "not allowed by agent profile {}: {}",
+                        self.profile.as_deref().unwrap_or_default(),
+                        denied.join(", ")
+                    ),
+                    Err(e) => format!("could not check tools against the agent profile: {e}"),
+                };
+                for (id, _) in tool_calls(&message) {
+                    let error = json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": { "code": TOOL_NOT_ALLOWED_CODE, "message": reason },
+                    });
+                    effects.push(Effect::PushToClient {
+                        channel_id,
+                        message: error.to_string(),
+                    });
+                }
+            }
+            Event::ServerMessage { message } => {
+                if let Some(ref partner) = self.partner {
+                    effects.push(Effect::ForwardToPartner {
+                        partner: partner.clone(),
+                        message,
+                    });
+                }
+            }
+            Event::ServerClosed => {
+                if matches!(self.connection, ConnectionType::ToWsServer) {
+                    self.ws_channel = None;
+                    self.try_reconnect(&mut effects);
+                }
+            }
+            Event::ReconnectTimer => self.try_reconnect(&mut effects),
+        }
+        effects
+    }
+
+    fn forward_client_message(&mut self, message: String, effects: &mut Vec<Effect>) {
+        if let Some(ref partner) = self.partner {
+            effects.push(Effect::ForwardToPartner {
+                partner: partner.clone(),
+                message,
+            });
+        } else {
+            // Store message if no partner set
+            self.pending_message = Some(message);
+        }
+    }
+
+    fn handle_startup(&mut self, effects: &mut Vec<Effect>) {
+        // Channels do not survive a restart
+        self.ws_channel = None;
+        let url = match self.connection {
+            ConnectionType::ToWsServer => self
+                .ws_url
+                .clone()
+                .unwrap_or_else(|| DEFAULT_WS_URL.to_string()),
+            ConnectionType::ToWsClient | ConnectionType::None => {
+                // Clients reconnect to us on their own
+                self.connection = ConnectionType::None;
+                DEFAULT_WS_URL.to_string()
+            }
+        };
+        effects.push(Effect::OpenWsConnection {
+            url,
+            reason: OpenReason::Startup,
+        });
+    }
+
+    fn handle_request(
+        &mut self,
+        source_node: &str,
+        request: FwdWsRequest,
+        respond: bool,
+        effects: &mut Vec<Effect>,
+    ) {
+        let respond_with = |effects: &mut Vec<Effect>, response: ResponseEffect| {
+            if respond {
+                effects.push(Effect::Respond(response));
+            }
+        };
+        match request {
+            FwdWsRequest::SetPartner(partner) => {
+                self.partner = partner;
+                // If partner is being set, send any pending messages
+                if let Some(ref partner) = self.partner {
+                    if let Some(message) = self.pending_message.take() {
+                        effects.push(Effect::ForwardToPartner {
+                            partner: partner.clone(),
+                            message,
+                        });
+                    }
+                }
+                effects.push(Effect::Save);
+                respond_with(effects, ResponseEffect::Ok);
+            }
+
+            FwdWsRequest::ConnectToServer(url) => {
+                if !matches!(self.connection, ConnectionType::None) {
+                    respond_with(
+                        effects,
+                        ResponseEffect::Err("Already connected".to_string()),
+                    );
+                    return;
+                }
+                effects.push(Effect::OpenWsConnection {
+                    url,
+                    reason: OpenReason::Connect { respond },
+                });
+            }
+
+            FwdWsRequest::AcceptClients(endpoint) => {
+                if !matches!(self.connection, ConnectionType::None) {
+                    respond_with(
+                        effects,
+                        ResponseEffect::Err("Already connected".to_string()),
+                    );
+                    return;
+                }
+                self.ws_url = Some(endpoint);
+                effects.push(Effect::Save);
+                respond_with(effects, ResponseEffect::Ok);
+            }
+
+            FwdWsRequest::Disconnect => {
+                self.connection = ConnectionType::None;
+                self.ws_url = None;
+                self.ws_channel = None;
+                self.current_reconnect_delay_ms = None;
+                effects.push(Effect::Save);
+                respond_with(effects, ResponseEffect::Ok);
+            }
+
+            FwdWsRequest::GetState => respond_with(effects, ResponseEffect::GetState),
+
+            FwdWsRequest::SetProfile(profile) => {
+                self.profile = profile;
+                effects.push(Effect::Save);
+                respond_with(effects, ResponseEffect::Ok);
+            }
+
+            FwdWsRequest::Forward(message) => {
+                if message.is_empty() {
+                    return;
+                }
+                // Only handle if from partner
+                if self.partner.as_deref() != Some(source_node) {
+                    return;
+                }
+                match (self.connection, self.ws_channel) {
+                    (ConnectionType::ToWsServer, Some(channel_id)) => {
+                        // we're connected to a WS server: the ws-mcp
+                        //  send the message to the ws-mcp to be fulfilled
+                        effects.push(Effect::PushToServer {
+                            channel_id,
+                            message,
+                        });
+                    }
+                    (ConnectionType::ToWsClient, Some(channel_id)) => {
+                        // we're connected to kibitz:
+                        //  send the message to kibitz
+                        effects.push(Effect::PushToClient {
+                            channel_id,
+                            message,
+                        });
+                    }
+                    _ => {
+                        // Store message if no WS connection
+                        self.pending_partner_message = Some(message);
+                    }
+                }
+                respond_with(effects, ResponseEffect::Ok);
+            }
+        }
+    }
+
+    fn handle_ws_opened(
+        &mut self,
+        url: String,
+        reason: OpenReason,
+        result: Result<u32, String>,
+        effects: &mut Vec<Effect>,
+    ) {
+        match (reason, result) {
+            (OpenReason::Reconnect, _)
+                if !matches!(self.connection, ConnectionType::ToWsServer) =>
+            {
+                // Disconnected while the attempt was in flight
+            }
+            (_, Ok(channel_id)) => {
+                self.connection = ConnectionType::ToWsServer;
+                self.ws_url = Some(url);
+                self.ws_channel = Some(channel_id);
+                self.current_reconnect_delay_ms = None; // Reset delay on success
+                effects.push(Effect::Save);
+                if let OpenReason::Connect { respond: true } = reason {
+                    effects.push(Effect::Respond(ResponseEffect::Ok));
+                }
+                if let Some(message) = self.pending_partner_message.take() {
+                    effects.push(Effect::PushToServer {
+                        channel_id,
+                        message,
+                    });
+                }
+            }
+            (OpenReason::Connect { respond }, Err(_)) => {
+                if respond {
+                    effects.push(Effect::Respond(ResponseEffect::Err(
+                        "Failed to connect".to_string(),
+                    )));
+                }
+            }
+            (OpenReason::Startup, Err(_)) => {
+                // Only keep retrying a server we were explicitly configured
+                //  for; the default URL is a best-effort guess
+                if matches!(self.connection, ConnectionType::ToWsServer) {
+                    self.schedule_reconnect(effects);
+                }
+            }
+            (OpenReason::Reconnect, Err(_)) => {
+                self.ws_channel = None;
+                self.schedule_reconnect(effects);
+            }
+        }
+    }
+
+    fn try_reconnect(&mut self, effects: &mut Vec<Effect>) {
+        if !matches!(self.connection, ConnectionType::ToWsServer) {
+            return;
+        }
+        // Only attempt reconnect if we don't have a valid channel
+        if self.ws_channel.is_some() {
+            return;
+        }
+        let url = self
+            .ws_url
+            .clone()
+            .unwrap_or_else(|| DEFAULT_WS_URL.to_string());
+        effects.push(Effect::OpenWsConnection {
+            url,
+            reason: OpenReason::Reconnect,
+        });
+    }
+
+    fn schedule_reconnect(&mut self, effects: &mut Vec<Effect>) {
+        let delay_ms = self
+            .current_reconnect_delay_ms
+            .unwrap_or(INITIAL_RECONNECT_DELAY_MS);
+        // Update the next delay (double it but cap at max)
+        self.current_reconnect_delay_ms = Some(std::cmp::min(delay_ms * 2, MAX_RECONNECT_DELAY_MS));
+        effects.push(Effect::Save);
+        effects.push(Effect::ScheduleReconnect { delay_ms });
+    }
+}
+
+/// The id and tool name of each MCP `tools/call` request in a JSON-RPC
+/// message or batch.
+pub fn tool_calls(message: &str) -> Vec<(Value, String)> {
+    let Ok(value) = serde_json::from_str::<Value>(message) else {
+        return vec![];
+    };
+    let requests = match value {
+        Value::Array(batch) => batch,
+        request => vec![request],
+    };
+    requests
+        .into_iter()
+        .filter(|request| request["method"] == "tools/call")
+        .map(|request| {
+            let name = request["params"]["name"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string();
+            (request["id"].clone(), name)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARTNER: &str = "partner.os";
+
+    fn request(state: &mut ProcessState, source_node: &str, request: FwdWsRequest) -> Vec<Effect> {
+        state.handle(Event::Request {
+            source_node: source_node.to_string(),
+            request,
+            respond: true,
+        })
+    }
+
+    fn opened(
+        state: &mut ProcessState,
+        url: &str,
+        reason: OpenReason,
+        result: Result<u32, String>,
+    ) -> Vec<Effect> {
+        state.handle(Event::WsOpened {
+            url: url.to_string(),
+            reason,
+            result,
+        })
+    }
+
+    fn server_state(channel_id: u32) -> ProcessState {
+        let mut state = ProcessState {
+            partner: Some(PARTNER.to_string()),
+            ..Default::default()
+        };
+        request(
+            &mut state,
+            "us.os",
+            FwdWsRequest::ConnectToServer("ws://mcp".to_string()),
+        );
+        opened(
+            &mut state,
+            "ws://mcp",
+            OpenReason::Connect { respond: true },
+            Ok(channel_id),
+        );
+        state
+    }
+
+    #[test]
+    fn set_partner_flushes_pending_client_message() {
+        let mut state = ProcessState::default();
+        state.handle(Event::ClientOpened {
+            path: WS_PATH.to_string(),
+            channel_id: 1,
+        });
+        let effects = state.handle(Event::ClientMessage {
+            channel_id: 1,
+            message: "hi".to_string(),
+        });
+        assert!(effects.is_empty());
+        assert_eq!(state.pending_message.as_deref(), Some("hi"));
+
+        let effects = request(
+            &mut state,
+            "us.os",
+            FwdWsRequest::SetPartner(Some(PARTNER.to_string())),
+        );
+        assert_eq!(
+            effects,
+            vec![
+                Effect::ForwardToPartner {
+                    partner: PARTNER.to_string(),
+                    message: "hi".to_string(),
+                },
+                Effect::Save,
+                Effect::Respond(ResponseEffect::Ok),
+            ]
+        );
+        assert!(state.pending_message.is_none());
+    }
+
+    #[test]
+    fn clearing_partner_stops_forwarding() {
+        let mut state = server_state(7);
+        request(&mut state, "us.os", FwdWsRequest::SetPartner(None));
+        let effects = state.handle(Event::ServerMessage {
+            message: "result".to_string(),
+        });
+        assert!(effects.is_empty());
+    }
+
+    #[test]
+    fn forward_only_accepted_from_partner() {
+        let mut state = server_state(7);
+        let effects = request(
+            &mut state,
+            "stranger.os",
+            FwdWsRequest::Forward("x".to_string()),
+        );
+        assert!(effects.is_empty());
+
+        let effects = request(&mut state, PARTNER, FwdWsRequest::Forward("x".to_string()));
+        assert_eq!(
+            effects,
+            vec![
+                Effect::PushToServer {
+                    channel_id: 7,
+                    message: "x".to_string(),
+                },
+                Effect::Respond(ResponseEffect::Ok),
+            ]
+        );
+    }
+
+    #[test]
+    fn partner_message_queued_until_client_connects() {
+        let mut state = ProcessState {
+            partner: Some(PARTNER.to_string()),
+            ..Default::default()
+        };
+        let effects = request(
+            &mut state,
+            PARTNER,
+            FwdWsRequest::Forward("queued".to_string()),
+        );
+        assert_eq!(effects, vec![Effect::Respond(ResponseEffect::Ok)]);
+
+        let effects = state.handle(Event::ClientOpened {
+            path: WS_PATH.to_string(),
+            channel_id: 3,
+        });
+        assert!(matches!(state.connection, ConnectionType::ToWsClient));
+        assert!(effects.contains(&Effect::PushToClient {
+            channel_id: 3,
+            message: "queued".to_string(),
+        }));
+        assert!(state.pending_partner_message.is_none());
+    }
+
+    #[test]
+    fn partner_message_queued_until_server_connects() {
+        let mut state = ProcessState {
+            partner: Some(PARTNER.to_string()),
+            ..Default::default()
+        };
+        request(
+            &mut state,
+            PARTNER,
+            FwdWsRequest::Forward("queued".to_string()),
+        );
+        let effects = request(
+            &mut state,
+            "us.os",
+            FwdWsRequest::ConnectToServer("ws://mcp".to_string()),
+        );
+        assert_eq!(
+            effects,
+            vec![Effect::OpenWsConnection {
+                url: "ws://mcp".to_string(),
+                reason: OpenReason::Connect { respond: true },
+            }]
+        );
+        let effects = opened(
+            &mut state,
+            "ws://mcp",
+            OpenReason::Connect { respond: true },
+            Ok(9),
+        );
+        assert!(effects.contains(&Effect::PushToServer {
+            channel_id: 9,
+            message: "queued".to_string(),
+        }));
+    }
+
+    #[test]
+    fn reconnect_backoff_doubles_and_caps() {
+        let mut state = server_state(7);
+        let effects = state.handle(Event::ServerClosed);
+        assert_eq!(
+            effects,
+            vec![Effect::OpenWsConnection {
+                url: "ws://mcp".to_string(),
+                reason: OpenReason::Reconnect,
+            }]
+        );
+
+        let mut delays = vec![];
+        for _ in 0..5 {
+            let effects = opened(
+                &mut state,
+                "ws://mcp",
+                OpenReason::Reconnect,
+                Err("refused".to_string()),
+            );
+            for effect in effects {
+                if let Effect::ScheduleReconnect { delay_ms } = effect {
+                    delays.push(delay_ms);
+                }
+            }
+            state.handle(Event::ReconnectTimer);
+        }
+        assert_eq!(delays, vec![5000, 10000, 20000, 30000, 30000]);
+
+        // Success resets the backoff
+        opened(&mut state, "ws://mcp", OpenReason::Reconnect, Ok(8));
+        assert_eq!(state.ws_channel, Some(8));
+        assert_eq!(state.current_reconnect_delay_ms, None);
+    }
+
+    #[test]
+    fn reconnect_abandoned_after_disconnect() {
+        let mut state = server_state(7);
+        state.handle(Event::ServerClosed);
+        request(&mut state, "us.os", FwdWsRequest::Disconnect);
+        let effects = opened(&mut state, "ws://mcp", OpenReason::Reconnect, Ok(8));
+        assert!(effects.is_empty());
+        assert!(matches!(state.connection, ConnectionType::None));
+        assert!(state.handle(Event::ReconnectTimer).is_empty());
+    }
+
+    #[test]
+    fn server_mode_rejects_clients_and_second_connect() {
+        let mut state = server_state(7);
+        let effects = state.handle(Event::ClientOpened {
+            path: WS_PATH.to_string(),
+            channel_id: 3,
+        });
+        assert!(effects.is_empty());
+        assert_eq!(state.ws_channel, Some(7));
+
+        let effects = request(
+            &mut state,
+            "us.os",
+            FwdWsRequest::ConnectToServer("ws://other".to_string()),
+        );
+        assert_eq!(
+            effects,
+            vec![Effect::Respond(ResponseEffect::Err(
+                "Already connected".to_string()
+            ))]
+        );
+    }
+
+    #[test]
+    fn switch_from_client_to_server_mode() {
+        let mut state = ProcessState {
+            partner: Some(PARTNER.to_string()),
+            ..Default::default()
+        };
+        state.handle(Event::ClientOpened {
+            path: WS_PATH.to_string(),
+            channel_id: 3,
+        });
+        let effects = request(
+            &mut state,
+            "us.os",
+            FwdWsRequest::AcceptClients("/".to_string()),
+        );
+        assert_eq!(
+            effects,
+            vec![Effect::Respond(ResponseEffect::Err(
+                "Already connected".to_string()
+            ))]
+        );
+
+        let effects = state.handle(Event::ClientClosed { channel_id: 3 });
+        assert_eq!(
+            effects,
+            vec![Effect::Save, Effect::ReleaseClient { channel_id: 3 }]
+        );
+        assert!(matches!(state.connection, ConnectionType::None));
+
+        request(
+            &mut state,
+            "us.os",
+            FwdWsRequest::ConnectToServer("ws://mcp".to_string()),
+        );
+        opened(
+            &mut state,
+            "ws://mcp",
+            OpenReason::Connect { respond: true },
+            Ok(9),
+        );
+        assert!(matches!(state.connection, ConnectionType::ToWsServer));
+
+        // A stale close for the old client channel is ignored
+        assert!(state
+            .handle(Event::ClientClosed { channel_id: 3 })
+            .is_empty());
+        assert_eq!(state.ws_channel, Some(9));
+    }
+
+    #[test]
+    fn startup_retries_only_configured_server() {
+        let mut state = ProcessState::default();
+        let effects = state.handle(Event::Startup);
+        assert_eq!(
+            effects,
+            vec![Effect::OpenWsConnection {
+                url: DEFAULT_WS_URL.to_string(),
+                reason: OpenReason::Startup,
+            }]
+        );
+        let effects = opened(
+            &mut state,
+            DEFAULT_WS_URL,
+            OpenReason::Startup,
+            Err("refused".to_string()),
+        );
+        assert!(effects.is_empty());
+
+        let mut state = ProcessState {
+            connection: ConnectionType::ToWsServer,
+            ws_url: Some("ws://mcp".to_string()),
+            ..Default::default()
+        };
+        state.handle(Event::Startup);
+        let effects = opened(
+            &mut state,
+            "ws://mcp",
+            OpenReason::Startup,
+            Err("refused".to_string()),
+        );
+        assert!(effects.contains(&Effect::ScheduleReconnect {
+            delay_ms: INITIAL_RECONNECT_DELAY_MS,
+        }));
+    }
+
+    fn client_state(channel_id: u32, profile: &str) -> ProcessState {
+        let mut state = ProcessState {
+            partner: Some(PARTNER.to_string()),
+            ..Default::default()
+        };
+        state.handle(Event::ClientOpened {
+            path: WS_PATH.to_string(),
+            channel_id,
+        });
+        let effects = request(
+            &mut state,
+            "us.os",
+            FwdWsRequest::SetProfile(Some(profile.to_string())),
+        );
+        assert_eq!(
+            effects,
+            vec![Effect::Save, Effect::Respond(ResponseEffect::Ok)]
+        );
+        state
+    }
+
+    fn call(id: u64, tool: &str) -> String {
+        json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": "tools/call",
+            "params": { "name": tool, "arguments": {} },
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn denied_tool_calls_never_leave_the_node() {
+        let mut state = client_state(3, "reader");
+        let message = call(1, "write_file");
+        let effects = state.handle(Event::ClientMessage {
+            channel_id: 3,
+            message: message.clone(),
+        });
+        assert_eq!(
+            effects,
+            vec![Effect::CheckTools {
+                profile: "reader".to_string(),
+                tools: vec!["write_file".to_string()],
+                channel_id: 3,
+                message: message.clone(),
+            }]
+        );
+
+        let effects = state.handle(Event::ToolsChecked {
+            channel_id: 3,
+            message,
+            denied: Ok(vec!["write_file".to_string()]),
+        });
+        let [Effect::PushToClient {
+            channel_id: 3,
+            message: answer,
+        }] = effects.as_slice()
+        else {
+            panic!("unexpected {effects:?}");
+        };
+        let answer: Value = serde_json::from_str(answer).unwrap();
+        assert_eq!(answer["id"], 1);
+        assert_eq!(answer["error"]["code"], TOOL_NOT_ALLOWED_CODE);
+        assert!(state.pending_message.is_none());
+
+        // Nor do any when kibitz can't say
+        let effects = state.handle(Event::ToolsChecked {
+            channel_id: 3,
+            message: call(2, "read_file"),
+            denied: Err("timeout".to_string()),
+        });
+        assert!(!effects
+            .iter()
+            .any(|effect| matches!(effect, Effect::ForwardToPartner { .. })));
+    }
+
+    #[test]
+    fn allowed_tool_calls_and_other_messages_are_forwarded() {
+        let mut state = client_state(3, "reader");
+        let forwarded = |message: &str| {
+            vec![Effect::ForwardToPartner {
+                partner: PARTNER.to_string(),
+                message: message.to_string(),
+            }]
+        };
+        let list = r#"{"jsonrpc":"2.0","id":1,"method":"tools/list"}"#;
+        let effects = state.handle(Event::ClientMessage {
+            channel_id: 3,
+            message: list.to_string(),
+        });
+        assert_eq!(effects, forwarded(list));
+
+        let message = call(2, "read_file");
+        let effects = state.handle(Event::ToolsChecked {
+            channel_id: 3,
+            message: message.clone(),
+            denied: Ok(vec![]),
+        });
+        assert_eq!(effects, forwarded(&message));
+
+        // Without a profile, calls aren't checked
+        request(&mut state, "us.os", FwdWsRequest::SetProfile(None));
+        let message = call(3, "write_file");
+        let effects = state.handle(Event::ClientMessage {
+            channel_id: 3,
+            message: message.clone(),
+        });
+        assert_eq!(effects, forwarded(&message));
+    }
+
+    #[test]
+    fn tool_calls_are_found_in_messages_and_batches() {
+        assert_eq!(
+            tool_calls(&call(4, "search")),
+            vec![(json!(4), "search".to_string())]
+        );
+        let batch = format!(
+            r#"[{}, {{"method": "ping"}}, {}]"#,
+            call(1, "a"),
+            call(2, "b")
+        );
+        assert_eq!(tool_calls(&batch).len(), 2);
+        assert!(tool_calls("not json").is_empty());
+        assert!(tool_calls(r#"{"method": "tools/list"}"#).is_empty());
+    }
+
+    #[test]
+    fn responses_suppressed_when_not_requested() {
+        let mut state = ProcessState::default();
+        let effects = state.handle(Event::Request {
+            source_node: "us.os".to_string(),
+            request: FwdWsRequest::AcceptClients("/".to_string()),
+            respond: false,
+        });
+        assert_eq!(effects, vec![Effect::Save]);
+    }
+}
@@ -0,0 +1,111 @@
+//! Versioned encoding of the persisted `ProcessState`.
+//!
+//! State is stored as `{"version": N, "state": {...}}`. Older layouts are
+//! brought up to date by running `MIGRATIONS` in order; anything that can't
+//! be migrated is reported as an error rather than replaced with defaults.
+
+use anyhow::Context;
+use serde_json::Value;
+
+use crate::machine::ProcessState;
+
+/// Current on-disk layout; bump it and append to `MIGRATIONS` whenever the
+/// persisted fields of `ProcessState` change.
+pub const STATE_VERSION: u32 = 2;
+
+/// `MIGRATIONS[n]` rewrites a version `n` state into version `n + 1`.
+const MIGRATIONS: &[fn(Value) -> anyhow::Result<Value>] = &[v0_to_v1, v1_to_v2];
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Persisted<T> {
+    version: u32,
+    state: T,
+}
+
+pub fn encode(state: &ProcessState) -> anyhow::Result<Vec<u8>> {
+    Ok(serde_json::to_vec(&Persisted {
+        version: STATE_VERSION,
+        state,
+    })?)
+}
+
+pub fn decode(bytes: &[u8]) -> anyhow::Result<ProcessState> {
+    let value: Value = serde_json::from_slice(bytes).context("state is not valid JSON")?;
+    let (mut version, mut state) = match value {
+        Value::Object(ref fields) if fields.contains_key("version") => {
+            let Persisted { version, state } = serde_json::from_value::<Persisted<Value>>(value)
+                .context("malformed versioned state")?;
+            (version, state)
+        }
+        // Layouts from before versioning stored the bare state
+        state => (0, state),
+    };
+
+    if version > STATE_VERSION {
+        anyhow::bail!("state version {version} is newer than supported version {STATE_VERSION}");
+    }
+    while version < STATE_VERSION {
+        state = MIGRATIONS[version as usize](state)
+            .with_context(|| format!("failed to migrate state from version {version}"))?;
+        version += 1;
+    }
+    serde_json::from_value(state)
+        .with_context(|| format!("state does not match version {STATE_VERSION} layout"))
+}
+
+/// v0 is the unversioned layout: the same fields, just not wrapped.
+fn v0_to_v1(state: Value) -> anyhow::Result<Value> {
+    anyhow::ensure!(state.is_object(), "expected an object, got {state}");
+    Ok(state)
+}
+
+/// v2 adds `profile`, which v1 states don't have: no profile.
+fn v1_to_v2(state: Value) -> anyhow::Result<Value> {
+    anyhow::ensure!(state.is_object(), "expected an object, got {state}");
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hyperware::process::fwd_ws::ConnectionType;
+
+    #[test]
+    fn round_trip_keeps_configuration() {
+        let state = ProcessState {
+            partner: Some("partner.os".to_string()),
+            connection: ConnectionType::ToWsServer,
+            ws_url: Some("ws://mcp".to_string()),
+            profile: Some("reader".to_string()),
+            ws_channel: Some(4),
+            ..Default::default()
+        };
+        let restored = decode(&encode(&state).unwrap()).unwrap();
+        assert_eq!(restored.partner, state.partner);
+        assert_eq!(restored.profile, state.profile);
+        assert!(matches!(restored.connection, ConnectionType::ToWsServer));
+        assert_eq!(restored.ws_url, state.ws_url);
+        assert_eq!(restored.ws_channel, None);
+    }
+
+    #[test]
+    fn migrates_unversioned_state() {
+        let v0 = br#"{"partner":"partner.os","connection":"ToWsClient","ws_url":"/"}"#;
+        let restored = decode(v0).unwrap();
+        assert_eq!(restored.partner.as_deref(), Some("partner.os"));
+        assert!(matches!(restored.connection, ConnectionType::ToWsClient));
+        assert_eq!(restored.ws_url.as_deref(), Some("/"));
+        assert_eq!(restored.profile, None);
+    }
+
+    #[test]
+    fn rejects_unmigratable_state() {
+        let newer = format!(r#"{{"version":{},"state":{{}}}}"#, STATE_VERSION + 1);
+        let error = decode(newer.as_bytes()).unwrap_err();
+        assert!(error.to_string().contains("newer"));
+
+        assert!(decode(b"not json").is_err());
+        assert!(decode(br#"["partner.os"]"#).is_err());
+        assert!(decode(br#"{"version":2,"state":{"connection":"Sideways"}}"#).is_err());
+    }
+}
@@ -0,0 +1,1142 @@
+//! Headless agent runs, driven by kibitz itself so they carry on after the
+//! browser tab that started them is gone.
+//!
+//! A run is a conversation and a loop: the conversation so far goes to the
+//! LLM through `http-client`; tool calls in the reply go to an MCP tool
+//! server (ws-mcp) over a WebSocket, and their results are appended; then
+//! round again, until the model answers without calling a tool, the run
+//! has made `max_steps` LLM calls, or it is cancelled. Each reply and each
+//! batch of tool results is appended to the conversation as it arrives and
+//! the run record under `run:{id}` kept up to date, so when kibitz restarts
+//! a run picks up again from its last stored step.
+//!
+//! Runs speak the Anthropic Messages API. They name a provider or a route:
+//! each LLM call goes to the first of the route's Anthropic targets with a
+//! key and budget left. Their tool server defaults to the ws-mcp server
+//! fwd-ws is connected to, if any.
+
+use std::collections::HashMap;
+
+use hyperware_process_lib::http::client::{
+    close_ws_connection, open_ws_connection, send_ws_client_push,
+};
+use hyperware_process_lib::http::server::{IncomingHttpRequest, WsMessageType};
+use hyperware_process_lib::http::Method;
+use hyperware_process_lib::logging::{error, info};
+use hyperware_process_lib::{Address, LazyLoadBlob};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::backup;
+use crate::conversations::{self, ChatMessage, NewConversation, NewMessage, Page, Paged, ServedBy};
+use crate::crypto::Cipher;
+use crate::http::{
+    parse_json_body, send_http_error, send_http_json, send_http_response, url_param,
+    HTTP_BAD_REQUEST, HTTP_CREATED, HTTP_METHOD_NOT_ALLOWED, HTTP_NOT_FOUND, HTTP_OK,
+};
+use crate::hyperware::process::fwd_ws::ConnectionType;
+use crate::llm;
+use crate::profiles;
+use crate::prompts::{self, PromptRef};
+use crate::routing::{self, Format, Target};
+use crate::store::{self, Store};
+use crate::sync::{ChangeEvent, SyncClients};
+use crate::usage::{self, Usage};
+
+pub const HTTP_RUNS_PATH: &str = "/api/runs";
+pub const HTTP_RUN_PATH: &str = "/api/runs/:id";
+pub const HTTP_RUN_CANCEL_PATH: &str = "/api/runs/:id/cancel";
+
+/// Ids of every run, newest first.
+pub const RUNS_KEY: &str = "runs";
+
+/// Prefixes the context of a run's LLM calls, to tell them apart from
+/// proxied calls.
+const CONTEXT_PREFIX: &[u8] = b"run:";
+
+const DEFAULT_PROVIDER: &str = "anthropic";
+const DEFAULT_MAX_STEPS: u32 = 50;
+const DEFAULT_MAX_TOKENS: u32 = 8192;
+const TITLE_LEN: usize = 80;
+const MCP_PROTOCOL_VERSION: &str = "2024-11-05";
+
+fn default_provider() -> String {
+    DEFAULT_PROVIDER.to_string()
+}
+
+/// The body of `POST /api/runs`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewRun {
+    pub prompt: String,
+    /// May be left to the profile.
+    #[serde(default)]
+    pub model: String,
+    /// A provider or a route.
+    #[serde(default = "default_provider")]
+    pub provider: String,
+    /// An agent profile's id: it supplies what the run leaves unset and
+    /// bounds its tools.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub project_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    /// In place of `system`: a template from the prompt library, filled in
+    /// when the run starts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<PromptRef>,
+    /// Names of the tools the model may call, or `prefix*` patterns; all of
+    /// them if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<String>>,
+    /// WebSocket URL of the MCP tool server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_server: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_steps: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Run {
+    pub id: String,
+    pub conversation_id: String,
+    /// The conversation's project.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub project_id: Option<String>,
+    /// A provider or a route.
+    pub provider: String,
+    pub model: String,
+    /// The agent profile it runs as, whose tool allow-list it is held to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_server: Option<String>,
+    pub max_steps: u32,
+    pub max_tokens: u32,
+    pub status: RunStatus,
+    /// LLM calls answered so far.
+    pub steps: u32,
+    /// Tokens used by those calls.
+    #[serde(default)]
+    pub usage: Usage,
+    /// The text of the final reply, once completed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RunIndex {
+    runs: Vec<String>,
+}
+
+fn run_key(id: &str) -> String {
+    format!("run:{id}")
+}
+
+pub fn get(store: &dyn Store, id: &str) -> anyhow::Result<Option<Run>> {
+    store::get_json(store, &run_key(id))
+}
+
+fn save(store: &dyn Store, run: &Run) -> anyhow::Result<()> {
+    store::set_json(store, &run_key(&run.id), run)
+}
+
+fn load_index(store: &dyn Store) -> anyhow::Result<RunIndex> {
+    Ok(store::get_json(store, RUNS_KEY)?.unwrap_or_default())
+}
+
+/// Runs, newest first.
+pub fn list(store: &dyn Store, page: Page) -> anyhow::Result<Paged<Run>> {
+    let ids = load_index(store)?.runs;
+    let total = ids.len();
+    let runs = ids[page.range(total)]
+        .iter()
+        .filter_map(|id| get(store, id).transpose())
+        .collect::<anyhow::Result<_>>()?;
+    Ok(page.paged(runs, total))
+}
+
+/// A tool the model asked to call.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolUse {
+    pub id: String,
+    pub name: String,
+    pub input: Value,
+}
+
+/// What a run does next, given its conversation so far.
+#[derive(Debug, PartialEq)]
+pub enum Next {
+    CallLlm,
+    CallTools(Vec<ToolUse>),
+    /// The model is done; its final text.
+    Finish(String),
+}
+
+fn blocks(content: &Value) -> &[Value] {
+    content.as_array().map_or(&[], Vec::as_slice)
+}
+
+pub fn tool_uses(content: &Value) -> Vec<ToolUse> {
+    blocks(content)
+        .iter()
+        .filter(|block| block["type"] == "tool_use")
+        .map(|block| ToolUse {
+            id: block["id"].as_str().unwrap_or_default().to_string(),
+            name: block["name"].as_str().unwrap_or_default().to_string(),
+            input: block["input"].clone(),
+        })
+        .collect()
+}
+
+/// The text of a message, without its tool calls.
+pub fn text(content: &Value) -> String {
+    if let Some(text) = content.as_str() {
+        return text.to_string();
+    }
+    blocks(content)
+        .iter()
+        .filter(|block| block["type"] == "text")
+        .filter_map(|block| block["text"].as_str())
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+pub fn next_step(messages: &[ChatMessage]) -> Next {
+    match messages.last() {
+        Some(message) if message.role == "assistant" => {
+            let uses = tool_uses(&message.content);
+            if uses.is_empty() {
+                Next::Finish(text(&message.content))
+            } else {
+                Next::CallTools(uses)
+            }
+        }
+        _ => Next::CallLlm,
+    }
+}
+
+/// The Messages API request for the next reply.
+pub fn llm_body(run: &Run, tools: &[Value], messages: &[ChatMessage]) -> Value {
+    let mut body = json!({
+        "model": run.model,
+        "max_tokens": run.max_tokens,
+        "messages": messages
+            .iter()
+            .map(|message| json!({ "role": message.role, "content": message.content }))
+            .collect::<Vec<_>>(),
+    });
+    if let Some(ref system) = run.system {
+        body["system"] = json!(system);
+    }
+    if !tools.is_empty() {
+        body["tools"] = json!(tools);
+    }
+    body
+}
+
+/// The content of a Messages API reply, or why it has none.
+pub fn reply_content(status: u16, body: &[u8]) -> Result<Value, String> {
+    if !(200..300).contains(&status) {
+        let body = String::from_utf8_lossy(body);
+        return Err(format!("LLM call failed with status {status}: {body}"));
+    }
+    let reply: Value =
+        serde_json::from_slice(body).map_err(|e| format!("LLM reply is not JSON: {e}"))?;
+    match reply.get("content") {
+        Some(content @ Value::Array(_)) => Ok(content.clone()),
+        _ => Err("LLM reply has no content".to_string()),
+    }
+}
+
+/// An MCP `tools/list` result as Messages API tool definitions, keeping
+/// only those `allowed` (names or `prefix*` patterns).
+pub fn tool_definitions(result: &Value, allowed: Option<&[String]>) -> Vec<Value> {
+    blocks(&result["tools"])
+        .iter()
+        .filter_map(|tool| {
+            let name = tool["name"].as_str()?;
+            if allowed.is_some_and(|allowed| !profiles::tool_allowed(allowed, name)) {
+                return None;
+            }
+            let mut definition = json!({
+                "name": name,
+                "input_schema": tool
+                    .get("inputSchema")
+                    .cloned()
+                    .unwrap_or_else(|| json!({ "type": "object" })),
+            });
+            if let Some(description) = tool.get("description") {
+                definition["description"] = description.clone();
+            }
+            Some(definition)
+        })
+        .collect()
+}
+
+/// A `tool_result` block for the outcome of an MCP `tools/call`. Content
+/// other than text is passed on as its JSON.
+pub fn tool_result(tool_use_id: &str, outcome: Result<Value, String>) -> Value {
+    match outcome {
+        Ok(result) => {
+            let content: Vec<Value> = blocks(&result["content"])
+                .iter()
+                .map(|block| match block["text"].as_str() {
+                    Some(text) if block["type"] == "text" => {
+                        json!({ "type": "text", "text": text })
+                    }
+                    _ => json!({ "type": "text", "text": block.to_string() }),
+                })
+                .collect();
+            json!({
+                "type": "tool_result",
+                "tool_use_id": tool_use_id,
+                "content": content,
+                "is_error": result["isError"] == true,
+            })
+        }
+        Err(message) => json!({
+            "type": "tool_result",
+            "tool_use_id": tool_use_id,
+            "content": message,
+            "is_error": true,
+        }),
+    }
+}
+
+/// The conversation title for a prompt: its first line, shortened.
+fn title(prompt: &str) -> String {
+    let line = prompt.lines().next().unwrap_or_default().trim();
+    match line.char_indices().nth(TITLE_LEN) {
+        Some((end, _)) => format!("{}…", &line[..end]),
+        None => line.to_string(),
+    }
+}
+
+/// The run a context sent with an LLM call belongs to, if it is a run's.
+pub fn run_context(context: &[u8]) -> Option<&str> {
+    std::str::from_utf8(context.strip_prefix(CONTEXT_PREFIX)?).ok()
+}
+
+enum Waiting {
+    /// For the tool server to answer `initialize`.
+    Initialize,
+    ToolList,
+    Llm,
+    Tools {
+        uses: Vec<ToolUse>,
+        /// Position in `uses` of each outstanding call, by JSON-RPC id.
+        calls: HashMap<u64, usize>,
+        results: Vec<Option<Value>>,
+    },
+}
+
+/// What a running run is waiting on; not persisted.
+struct Active {
+    channel_id: Option<u32>,
+    tools: Vec<Value>,
+    next_rpc_id: u64,
+    waiting: Waiting,
+    /// Where the LLM call being waited on went.
+    target: Option<Target>,
+}
+
+impl Active {
+    fn rpc(&mut self, method: &str, params: Value) -> anyhow::Result<u64> {
+        let id = self.next_rpc_id;
+        self.next_rpc_id += 1;
+        self.push(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }))?;
+        Ok(id)
+    }
+
+    fn push(&self, message: Value) -> anyhow::Result<()> {
+        let Some(channel_id) = self.channel_id else {
+            anyhow::bail!("not connected to a tool server");
+        };
+        send_ws_client_push(
+            channel_id,
+            WsMessageType::Text,
+            LazyLoadBlob::new(Some("application/json"), serde_json::to_vec(&message)?),
+        );
+        Ok(())
+    }
+}
+
+/// Runs in progress, and the driver for them.
+pub struct Runs {
+    our: Address,
+    active: HashMap<String, Active>,
+}
+
+impl Runs {
+    pub fn new(our: &Address) -> Self {
+        Self {
+            our: our.clone(),
+            active: HashMap::new(),
+        }
+    }
+
+    /// The ws-mcp server fwd-ws is connected to, if it is.
+    fn default_tool_server(&self) -> Option<String> {
+        match backup::get_fwd_ws_config(&self.our) {
+            Ok(config) if matches!(config.connection, ConnectionType::ToWsServer) => config.ws_url,
+            Ok(_) => None,
+            Err(e) => {
+                info!("runs: no tool server from fwd-ws: {e:?}");
+                None
+            }
+        }
+    }
+
+    /// Start a run in a new conversation.
+    pub fn start(
+        &mut self,
+        store: &dyn Store,
+        cipher: &Cipher,
+        sync: &mut SyncClients,
+        mut new: NewRun,
+        now: u64,
+    ) -> anyhow::Result<Result<Run, String>> {
+        if let Some(ref profile_id) = new.profile {
+            let Some(profile) = profiles::get(store, profile_id)? else {
+                return Ok(Err(format!("no profile {profile_id}")));
+            };
+            if let Err(message) = profiles::apply(&profile, &mut new) {
+                return Ok(Err(message));
+            }
+        }
+        if new.model.is_empty() {
+            return Ok(Err("model is empty".to_string()));
+        }
+        let project_id = new.project_id.as_deref();
+        if let Err(message) =
+            routing::check_format(store, &new.provider, project_id, Format::Anthropic)?
+        {
+            return Ok(Err(message));
+        }
+        if new.prompt.trim().is_empty() {
+            return Ok(Err("prompt is empty".to_string()));
+        }
+        let tool_server = match new.tools {
+            Some(ref tools) if tools.is_empty() => None,
+            _ => new.tool_server.or_else(|| self.default_tool_server()),
+        };
+        let mut conversation = NewConversation {
+            title: title(&new.prompt),
+            project_id: new.project_id,
+            settings: json!({ "provider": new.provider, "model": new.model }),
+        };
+        let mut system = new.system;
+        if let Some(ref prompt) = new.system_prompt {
+            if system.is_some() {
+                return Ok(Err("give system or system_prompt, not both".to_string()));
+            }
+            if let Err(message) = prompts::apply(store, &mut conversation, prompt)? {
+                return Ok(Err(message));
+            }
+            system = conversation.settings["system"].as_str().map(str::to_string);
+        }
+        let conversation = conversations::create(store, conversation, now)?.value;
+        sync.publish(
+            store,
+            ChangeEvent::ConversationCreated {
+                id: conversation.id.clone(),
+            },
+            now,
+        );
+        let run = Run {
+            id: conversations::new_id(),
+            conversation_id: conversation.id,
+            project_id: conversation.project_id,
+            provider: new.provider,
+            model: new.model,
+            profile_id: new.profile,
+            system,
+            tools: new.tools,
+            tool_server,
+            max_steps: new.max_steps.unwrap_or(DEFAULT_MAX_STEPS),
+            max_tokens: new.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            status: RunStatus::Running,
+            steps: 0,
+            usage: Usage::default(),
+            output: None,
+            error: None,
+            created_at: now,
+            updated_at: now,
+        };
+        save(store, &run)?;
+        let mut index = load_index(store)?;
+        index.runs.insert(0, run.id.clone());
+        store::set_json(store, RUNS_KEY, &index)?;
+        self.append(
+            store,
+            sync,
+            &run,
+            NewMessage {
+                role: "user".to_string(),
+                content: json!(new.prompt),
+                served_by: None,
+            },
+            now,
+        )?;
+        info!("run {}: started", run.id);
+        let id = run.id.clone();
+        self.activate(store, cipher, sync, run, now);
+        // It may already have failed, e.g. to reach its tool server
+        Ok(get(store, &id)?.ok_or_else(|| "run was lost".to_string()))
+    }
+
+    /// Pick up the runs that were going when kibitz stopped.
+    pub fn resume_all(
+        &mut self,
+        store: &dyn Store,
+        cipher: &Cipher,
+        sync: &mut SyncClients,
+        now: u64,
+    ) -> anyhow::Result<()> {
+        for id in load_index(store)?.runs {
+            match get(store, &id)? {
+                Some(run) if run.status == RunStatus::Running => {
+                    info!("run {id}: resuming");
+                    self.activate(store, cipher, sync, run, now);
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop a run. Returns it, or `None` if there is no such run.
+    pub fn cancel(
+        &mut self,
+        store: &dyn Store,
+        sync: &mut SyncClients,
+        id: &str,
+        now: u64,
+    ) -> anyhow::Result<Option<Run>> {
+        let Some(run) = get(store, id)? else {
+            return Ok(None);
+        };
+        if run.status != RunStatus::Running {
+            return Ok(Some(run));
+        }
+        self.finish(store, sync, run, RunStatus::Cancelled, None, now)
+            .map(Some)
+    }
+
+    /// Connect the run to its tool server, if it has one, and carry on.
+    fn activate(
+        &mut self,
+        store: &dyn Store,
+        cipher: &Cipher,
+        sync: &mut SyncClients,
+        run: Run,
+        now: u64,
+    ) {
+        let mut active = Active {
+            channel_id: None,
+            tools: vec![],
+            next_rpc_id: 0,
+            waiting: Waiting::Llm,
+            target: None,
+        };
+        let id = run.id.clone();
+        let result = match run.tool_server {
+            Some(ref url) => {
+                let channel_id = rand::random();
+                match open_ws_connection(url.clone(), None, channel_id) {
+                    Ok(()) => {
+                        active.channel_id = Some(channel_id);
+                        active.waiting = Waiting::Initialize;
+                        let params = json!({
+                            "protocolVersion": MCP_PROTOCOL_VERSION,
+                            "capabilities": {},
+                            "clientInfo": { "name": "kibitz", "version": env!("CARGO_PKG_VERSION") },
+                        });
+                        let sent = active.rpc("initialize", params).map(|_| ());
+                        self.active.insert(id.clone(), active);
+                        sent
+                    }
+                    Err(e) => Err(anyhow::anyhow!(
+                        "could not connect to tool server {url}: {e}"
+                    )),
+                }
+            }
+            None => {
+                self.active.insert(id.clone(), active);
+                self.advance(store, cipher, sync, &id, now)
+            }
+        };
+        self.fail_on_error(store, sync, &id, result, now);
+    }
+
+    /// Fail the run if driving it went wrong.
+    fn fail_on_error(
+        &mut self,
+        store: &dyn Store,
+        sync: &mut SyncClients,
+        id: &str,
+        result: anyhow::Result<()>,
+        now: u64,
+    ) {
+        let Err(e) = result else {
+            return;
+        };
+        let message = format!("{e:#}");
+        info!("run {id}: failed: {message}");
+        let failed = get(store, id).and_then(|run| match run {
+            Some(run) if run.status == RunStatus::Running => self
+                .finish(store, sync, run, RunStatus::Failed, Some(message), now)
+                .map(|_| ()),
+            _ => Ok(()),
+        });
+        if let Err(e) = failed {
+            error!("run {id}: could not record failure: {e:?}");
+        }
+        self.active.remove(id);
+    }
+
+    fn finish(
+        &mut self,
+        store: &dyn Store,
+        sync: &mut SyncClients,
+        mut run: Run,
+        status: RunStatus,
+        detail: Option<String>,
+        now: u64,
+    ) -> anyhow::Result<Run> {
+        if let Some(channel_id) = self
+            .active
+            .remove(&run.id)
+            .and_then(|active| active.channel_id)
+        {
+            if let Err(e) = close_ws_connection(channel_id) {
+                info!("run {}: closing tool server connection: {e}", run.id);
+            }
+        }
+        run.status = status;
+        match status {
+            RunStatus::Failed => run.error = detail,
+            _ => run.output = detail,
+        }
+        run.updated_at = now;
+        save(store, &run)?;
+        sync.publish(store, ChangeEvent::RunUpdated { id: run.id.clone() }, now);
+        info!("run {}: {status:?}", run.id);
+        Ok(run)
+    }
+
+    fn append(
+        &self,
+        store: &dyn Store,
+        sync: &mut SyncClients,
+        run: &Run,
+        new: NewMessage,
+        now: u64,
+    ) -> anyhow::Result<()> {
+        let Some((message, _)) = conversations::append(store, &run.conversation_id, new, now)?
+        else {
+            anyhow::bail!("its conversation was deleted");
+        };
+        let event = ChangeEvent::MessageAppended {
+            conversation_id: run.conversation_id.clone(),
+            seq: message.seq,
+        };
+        sync.publish(store, event, now);
+        Ok(())
+    }
+
+    /// Take the next step, as the conversation so far calls for.
+    fn advance(
+        &mut self,
+        store: &dyn Store,
+        cipher: &Cipher,
+        sync: &mut SyncClients,
+        id: &str,
+        now: u64,
+    ) -> anyhow::Result<()> {
+        let Some(run) = get(store, id)?.filter(|run| run.status == RunStatus::Running) else {
+            self.active.remove(id);
+            return Ok(());
+        };
+        let everything = Page {
+            offset: 0,
+            limit: usize::MAX,
+        };
+        let Some(messages) = conversations::messages(store, &run.conversation_id, everything)?
+        else {
+            anyhow::bail!("its conversation was deleted");
+        };
+        let Some(active) = self.active.get_mut(id) else {
+            return Ok(());
+        };
+        match next_step(&messages.items) {
+            Next::CallLlm => {
+                if run.steps >= run.max_steps {
+                    anyhow::bail!("stopped after {} steps", run.max_steps);
+                }
+                let mut body = llm_body(&run, &active.tools, &messages.items);
+                let client_headers = [("content-type", "application/json")];
+                let (target, outgoing) = routing::pick(
+                    store,
+                    cipher,
+                    &run.provider,
+                    run.project_id.as_deref(),
+                    Format::Anthropic,
+                    &client_headers,
+                )?
+                .map_err(|refusal| anyhow::Error::msg(refusal.message))?;
+                if let Some(ref model) = target.model {
+                    body["model"] = json!(model);
+                }
+                info!("run {id}: step {}: calling {}", run.steps + 1, outgoing.url);
+                let context = [CONTEXT_PREFIX, id.as_bytes()].concat();
+                llm::send(&Method::POST, outgoing, serde_json::to_vec(&body)?, context)?;
+                active.waiting = Waiting::Llm;
+                active.target = Some(target);
+            }
+            Next::CallTools(uses) => {
+                let mut calls = HashMap::new();
+                let mut results = vec![None; uses.len()];
+                for (at, tool_use) in uses.iter().enumerate() {
+                    let mut allowed = run
+                        .tools
+                        .as_ref()
+                        .is_none_or(|tools| profiles::tool_allowed(tools, &tool_use.name));
+                    // Held to the profile as it is now, in case it was
+                    //  narrowed since the run started
+                    if let Some(ref profile_id) = run.profile_id {
+                        allowed &= profiles::profile_allows(store, profile_id, &tool_use.name)?;
+                    }
+                    if !allowed || active.channel_id.is_none() {
+                        let message = format!("tool {} is not available", tool_use.name);
+                        results[at] = Some(tool_result(&tool_use.id, Err(message)));
+                        continue;
+                    }
+                    let params = json!({ "name": tool_use.name, "arguments": tool_use.input });
+                    calls.insert(active.rpc("tools/call", params)?, at);
+                }
+                active.waiting = Waiting::Tools {
+                    uses,
+                    calls,
+                    results,
+                };
+                self.finish_tools(store, cipher, sync, id, now)?;
+            }
+            Next::Finish(output) => {
+                self.finish(store, sync, run, RunStatus::Completed, Some(output), now)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Once every tool call is answered, append the results and go on.
+    fn finish_tools(
+        &mut self,
+        store: &dyn Store,
+        cipher: &Cipher,
+        sync: &mut SyncClients,
+        id: &str,
+        now: u64,
+    ) -> anyhow::Result<()> {
+        let Some(Active {
+            waiting: Waiting::Tools { calls, results, .. },
+            ..
+        }) = self.active.get(id)
+        else {
+            return Ok(());
+        };
+        if !calls.is_empty() {
+            return Ok(());
+        }
+        let content: Vec<Value> = results.iter().flatten().cloned().collect();
+        let Some(mut run) = get(store, id)? else {
+            return Ok(());
+        };
+        self.append(
+            store,
+            sync,
+            &run,
+            NewMessage {
+                role: "user".to_string(),
+                content: Value::Array(content),
+                served_by: None,
+            },
+            now,
+        )?;
+        run.updated_at = now;
+        save(store, &run)?;
+        self.advance(store, cipher, sync, id, now)
+    }
+
+    /// `http-client`'s answer to the LLM call of the run in `context`.
+    pub fn handle_llm_reply(
+        &mut self,
+        store: &dyn Store,
+        cipher: &Cipher,
+        sync: &mut SyncClients,
+        context: &[u8],
+        response: Result<(&[u8], Option<LazyLoadBlob>), String>,
+        now: u64,
+    ) {
+        let Some(id) = run_context(context) else {
+            return;
+        };
+        if !matches!(
+            self.active.get(id),
+            Some(Active {
+                waiting: Waiting::Llm,
+                ..
+            })
+        ) {
+            // Cancelled meanwhile
+            return;
+        }
+        let result = self.handle_reply(store, cipher, sync, id, response, now);
+        self.fail_on_error(store, sync, id, result, now);
+    }
+
+    fn handle_reply(
+        &mut self,
+        store: &dyn Store,
+        cipher: &Cipher,
+        sync: &mut SyncClients,
+        id: &str,
+        response: Result<(&[u8], Option<LazyLoadBlob>), String>,
+        now: u64,
+    ) -> anyhow::Result<()> {
+        let (response, body) = llm::upstream_response(response).map_err(anyhow::Error::msg)?;
+        let Some(mut run) = get(store, id)? else {
+            return Ok(());
+        };
+        let target = self
+            .active
+            .get_mut(id)
+            .and_then(|active| active.target.take())
+            .unwrap_or_else(|| Target {
+                provider: run.provider.clone(),
+                model: None,
+            });
+        let asked = target.model.as_deref().unwrap_or(&run.model);
+        if (200..300).contains(&response.status) {
+            let project_id = run.project_id.as_deref();
+            if let Some(used) = usage::note_reply(
+                store,
+                sync,
+                &target.provider,
+                Some(asked),
+                project_id,
+                &body,
+                now,
+            ) {
+                run.usage.add(&used);
+            }
+        }
+        let content = reply_content(response.status, &body).map_err(anyhow::Error::msg)?;
+        let model = usage::body_model(&body).unwrap_or_else(|| asked.to_string());
+        self.append(
+            store,
+            sync,
+            &run,
+            NewMessage {
+                role: "assistant".to_string(),
+                content,
+                served_by: Some(ServedBy {
+                    provider: target.provider,
+                    model,
+                }),
+            },
+            now,
+        )?;
+        run.steps += 1;
+        run.updated_at = now;
+        save(store, &run)?;
+        sync.publish(store, ChangeEvent::RunUpdated { id: run.id }, now);
+        self.advance(store, cipher, sync, id, now)
+    }
+
+    /// A message from the tool server on `channel_id`.
+    pub fn handle_tool_message(
+        &mut self,
+        store: &dyn Store,
+        cipher: &Cipher,
+        sync: &mut SyncClients,
+        channel_id: u32,
+        bytes: &[u8],
+        now: u64,
+    ) {
+        let Some(id) = self.run_on(channel_id) else {
+            return;
+        };
+        let result = self.handle_rpc(store, cipher, sync, &id, bytes, now);
+        self.fail_on_error(store, sync, &id, result, now);
+    }
+
+    fn run_on(&self, channel_id: u32) -> Option<String> {
+        self.active
+            .iter()
+            .find(|(_, active)| active.channel_id == Some(channel_id))
+            .map(|(id, _)| id.clone())
+    }
+
+    fn handle_rpc(
+        &mut self,
+        store: &dyn Store,
+        cipher: &Cipher,
+        sync: &mut SyncClients,
+        id: &str,
+        bytes: &[u8],
+        now: u64,
+    ) -> anyhow::Result<()> {
+        let message: Value = serde_json::from_slice(bytes)?;
+        // Only answers to our requests matter, not notifications
+        let Some(rpc_id) = message["id"].as_u64() else {
+            return Ok(());
+        };
+        if message.get("result").is_none() && message.get("error").is_none() {
+            return Ok(());
+        }
+        let outcome = match message.get("error") {
+            Some(error) => Err(error["message"]
+                .as_str()
+                .map_or_else(|| error.to_string(), str::to_string)),
+            None => Ok(message["result"].clone()),
+        };
+        let Some(run) = get(store, id)? else {
+            return Ok(());
+        };
+        let Some(active) = self.active.get_mut(id) else {
+            return Ok(());
+        };
+        match active.waiting {
+            Waiting::Initialize => {
+                outcome.map_err(|e| anyhow::anyhow!("tool server initialize failed: {e}"))?;
+                active.push(json!({ "jsonrpc": "2.0", "method": "notifications/initialized" }))?;
+                active.rpc("tools/list", json!({}))?;
+                active.waiting = Waiting::ToolList;
+                Ok(())
+            }
+            Waiting::ToolList => {
+                let result = outcome.map_err(|e| anyhow::anyhow!("listing tools failed: {e}"))?;
+                active.tools = tool_definitions(&result, run.tools.as_deref());
+                info!("run {id}: {} tools", active.tools.len());
+                self.advance(store, cipher, sync, id, now)
+            }
+            Waiting::Tools {
+                ref uses,
+                ref mut calls,
+                ref mut results,
+            } => {
+                let Some(at) = calls.remove(&rpc_id) else {
+                    return Ok(());
+                };
+                results[at] = Some(tool_result(&uses[at].id, outcome));
+                self.finish_tools(store, cipher, sync, id, now)
+            }
+            Waiting::Llm => Ok(()),
+        }
+    }
+
+    /// The tool server closed the connection on `channel_id`.
+    pub fn handle_tool_close(
+        &mut self,
+        store: &dyn Store,
+        sync: &mut SyncClients,
+        channel_id: u32,
+        now: u64,
+    ) {
+        let Some(id) = self.run_on(channel_id) else {
+            return;
+        };
+        if let Some(active) = self.active.get_mut(&id) {
+            active.channel_id = None;
+        }
+        let result = Err(anyhow::anyhow!("the tool server closed the connection"));
+        self.fail_on_error(store, sync, &id, result, now);
+    }
+
+    /// Serve `POST /api/runs`, whose `body` may have been read earlier.
+    pub fn handle_create(
+        &mut self,
+        store: &dyn Store,
+        cipher: &Cipher,
+        sync: &mut SyncClients,
+        body: Option<&[u8]>,
+        now: u64,
+    ) -> anyhow::Result<()> {
+        let Some(new) = parse_json_body::<NewRun>(body)? else {
+            return Ok(());
+        };
+        match self.start(store, cipher, sync, new, now)? {
+            Ok(run) => send_http_json(HTTP_CREATED, &run),
+            Err(message) => send_http_error(HTTP_BAD_REQUEST, &message),
+        }
+    }
+
+    /// Serve the other run endpoints.
+    pub fn handle_http(
+        &mut self,
+        store: &dyn Store,
+        sync: &mut SyncClients,
+        http_request: &IncomingHttpRequest,
+        bound_path: &str,
+        now: u64,
+    ) -> anyhow::Result<()> {
+        let method = http_request.method()?;
+        match (method.as_str(), bound_path) {
+            ("GET", HTTP_RUNS_PATH) => {
+                let page = match Page::from_query(http_request.query_params()) {
+                    Ok(page) => page,
+                    Err(message) => return send_http_error(HTTP_BAD_REQUEST, &message),
+                };
+                send_http_json(HTTP_OK, &list(store, page)?)
+            }
+            (_, HTTP_RUNS_PATH) => send_http_response(HTTP_METHOD_NOT_ALLOWED, None),
+            (method, HTTP_RUN_PATH | HTTP_RUN_CANCEL_PATH) => {
+                let Some(id) = url_param(http_request, "id") else {
+                    return send_http_error(HTTP_BAD_REQUEST, "missing run id");
+                };
+                let run = match (method, bound_path) {
+                    ("GET", HTTP_RUN_PATH) => get(store, id)?,
+                    ("POST", HTTP_RUN_CANCEL_PATH) => self.cancel(store, sync, id, now)?,
+                    _ => return send_http_response(HTTP_METHOD_NOT_ALLOWED, None),
+                };
+                match run {
+                    Some(run) => send_http_json(HTTP_OK, &run),
+                    None => send_http_error(HTTP_NOT_FOUND, "no such run"),
+                }
+            }
+            _ => send_http_response(HTTP_NOT_FOUND, None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(seq: u64, role: &str, content: Value) -> ChatMessage {
+        ChatMessage {
+            seq,
+            role: role.to_string(),
+            content,
+            served_by: None,
+            created_at: seq,
+        }
+    }
+
+    fn run() -> Run {
+        Run {
+            id: "r".to_string(),
+            conversation_id: "c".to_string(),
+            project_id: None,
+            provider: "anthropic".to_string(),
+            model: "m".to_string(),
+            profile_id: None,
+            system: Some("be brief".to_string()),
+            tools: None,
+            tool_server: None,
+            max_steps: 3,
+            max_tokens: 100,
+            status: RunStatus::Running,
+            steps: 0,
+            usage: Usage::default(),
+            output: None,
+            error: None,
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    #[test]
+    fn the_conversation_decides_the_next_step() {
+        let prompt = message(0, "user", json!("list the files"));
+        assert_eq!(next_step(std::slice::from_ref(&prompt)), Next::CallLlm);
+
+        let call = message(
+            1,
+            "assistant",
+            json!([
+                { "type": "text", "text": "Looking." },
+                { "type": "tool_use", "id": "t1", "name": "ls", "input": { "path": "." } },
+            ]),
+        );
+        let messages = [prompt, call];
+        assert_eq!(
+            next_step(&messages),
+            Next::CallTools(vec![ToolUse {
+                id: "t1".to_string(),
+                name: "ls".to_string(),
+                input: json!({ "path": "." }),
+            }])
+        );
+
+        let body = llm_body(&run(), &[json!({ "name": "ls" })], &messages);
+        assert_eq!(body["system"], "be brief");
+        assert_eq!(body["messages"][1]["content"][1]["id"], "t1");
+        assert_eq!(body["tools"][0]["name"], "ls");
+        assert!(llm_body(&run(), &[], &messages).get("tools").is_none());
+
+        let done = message(3, "assistant", json!([{ "type": "text", "text": "a, b" }]));
+        assert_eq!(next_step(&[done]), Next::Finish("a, b".to_string()));
+    }
+
+    #[test]
+    fn mcp_tools_and_results_become_messages_api_blocks() {
+        let listed = json!({ "tools": [
+            { "name": "ls", "description": "List", "inputSchema": { "type": "object" } },
+            { "name": "rm" },
+        ] });
+        let all = tool_definitions(&listed, None);
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0]["input_schema"]["type"], "object");
+        assert_eq!(all[0]["description"], "List");
+        let allowed = ["ls".to_string()];
+        let some = tool_definitions(&listed, Some(&allowed));
+        assert_eq!(some, vec![all[0].clone()]);
+
+        let result = json!({
+            "content": [{ "type": "text", "text": "a\nb" }, { "type": "image", "data": "" }],
+            "isError": false,
+        });
+        let block = tool_result("t1", Ok(result));
+        assert_eq!(block["tool_use_id"], "t1");
+        assert_eq!(block["content"][0]["text"], "a\nb");
+        assert_eq!(block["content"][1]["type"], "text");
+        assert_eq!(block["is_error"], false);
+        assert_eq!(tool_result("t1", Err("gone".to_string()))["is_error"], true);
+
+        assert!(reply_content(529, b"overloaded").is_err());
+        assert!(reply_content(200, b"{}").is_err());
+        let reply = br#"{"content":[{"type":"text","text":"hi"}],"stop_reason":"end_turn"}"#;
+        assert_eq!(text(&reply_content(200, reply).unwrap()), "hi");
+    }
+
+    #[test]
+    fn contexts_and_titles() {
+        assert_eq!(run_context(b"run:abc"), Some("abc"));
+        assert_eq!(run_context(b"7"), None);
+        assert_eq!(title("fix the build\nin detail"), "fix the build");
+        assert_eq!(title(&"é".repeat(100)).chars().count(), TITLE_LEN + 1);
+    }
+}
@@ -0,0 +1,735 @@
+//! Backup and restore of everything needed to set kibitz up again: API
+//! keys, provider settings, conversations and projects, scheduled tasks,
+//! the price table, budgets, the prompt library, agent profiles, and the
+//! fwd-ws configuration (partner and ws-mcp URL).
+//!
+//! A backup is a JSON archive, downloaded or saved to the `backups` VFS
+//! drive. With a passphrase its snapshot is sealed under a key derived from
+//! it, so the archive can be restored on another node. API keys are only
+//! included in sealed archives: the node-local key they are stored under
+//! doesn't travel, so they must be written out in the clear otherwise.
+//!
+//! Restoring validates the whole archive before applying any of it.
+//! Conversations are imported as by `export::import`, so restoring twice
+//! doesn't duplicate them; tasks, prices, budgets, prompts and profiles
+//! replace those here with the same id (model, provider) and leave the
+//! rest.
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use hyperware_process_lib::http::server::HttpResponse;
+use hyperware_process_lib::logging::info;
+use hyperware_process_lib::vfs::{create_drive, create_file, open_dir, open_file, FileType};
+use hyperware_process_lib::{Address, Request};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::budget::{self, Budget};
+use crate::crypto::{self, Cipher};
+use crate::export::{self, Bundle, ImportReport};
+use crate::http::{
+    read_json_body, send_http, send_http_error, send_http_json, send_http_response,
+    HTTP_BAD_GATEWAY, HTTP_BAD_REQUEST, HTTP_CREATED, HTTP_NOT_FOUND, HTTP_OK,
+};
+use crate::hyperware::process::fwd_ws::{
+    ConnectionType, Request as FwdWsRequest, Response as FwdWsResponse,
+};
+use crate::keys::{self, ApiKeys};
+use crate::llm::{self, ProviderSettings};
+use crate::profiles::{self, Profile};
+use crate::prompts::{self, Prompt};
+use crate::schedule::{self, Cron, Task};
+use crate::store::{self, Store};
+use crate::sync::{ChangeEvent, SyncClients};
+use crate::usage::{self, Price, Prices};
+
+pub const HTTP_BACKUP_PATH: &str = "/api/backup";
+pub const HTTP_BACKUPS_PATH: &str = "/api/backups";
+pub const HTTP_RESTORE_PATH: &str = "/api/restore";
+
+const BACKUP_DRIVE: &str = "backups";
+
+pub const ARCHIVE_FORMAT: &str = "kibitz-backup";
+/// Bump when the archive layout changes; restores refuse newer archives.
+pub const ARCHIVE_VERSION: u32 = 2;
+
+const KDF: &str = "pbkdf2-sha256";
+const KDF_ITERATIONS: u32 = 600_000;
+/// Refuse archives asking for more work than this to open.
+const MAX_KDF_ITERATIONS: u32 = 10_000_000;
+/// Binds the sealed snapshot to what it is.
+const SEAL_CONTEXT: &[u8] = b"kibitz-backup";
+
+const FWD_WS_TIMEOUT_S: u64 = 5;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Archive {
+    pub format: String,
+    pub version: u32,
+    pub created_at: u64,
+    /// How `sealed` was sealed; absent for a plain archive.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<Encryption>,
+    /// The snapshot, sealed with a passphrase.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sealed: Option<String>,
+    /// The snapshot of a plain archive.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<Snapshot>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Encryption {
+    pub kdf: String,
+    pub iterations: u32,
+    /// Base64.
+    pub salt: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Only in sealed archives.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_keys: Option<ApiKeys>,
+    pub providers: ProviderSettings,
+    pub conversations: Bundle,
+    /// Added in version 2, like the rest below.
+    #[serde(default)]
+    pub tasks: BTreeMap<String, Task>,
+    /// By model, as in `usage::Prices`.
+    #[serde(default)]
+    pub prices: BTreeMap<String, Price>,
+    /// By provider.
+    #[serde(default)]
+    pub budgets: BTreeMap<String, Budget>,
+    #[serde(default)]
+    pub prompts: BTreeMap<String, Prompt>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fwd_ws: Option<FwdWsConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FwdWsConfig {
+    pub partner: Option<String>,
+    pub connection: ConnectionType,
+    pub ws_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BackupRequest {
+    #[serde(default)]
+    pub passphrase: Option<String>,
+    /// Save to the `backups` drive rather than download.
+    #[serde(default)]
+    pub save: bool,
+    /// Include the fwd-ws configuration.
+    #[serde(default = "default_true")]
+    pub fwd_ws: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RestoreRequest {
+    /// An archive, as downloaded.
+    #[serde(default)]
+    pub archive: Option<Archive>,
+    /// Or the name of one saved in the `backups` drive.
+    #[serde(default)]
+    pub file: Option<String>,
+    #[serde(default)]
+    pub passphrase: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct RestoreReport {
+    /// Providers whose key was restored; absent if the archive had none.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_keys: Option<Vec<String>>,
+    pub conversations: ImportReport,
+    /// How many of each were restored.
+    pub tasks: usize,
+    pub prices: usize,
+    pub budgets: usize,
+    pub prompts: usize,
+    pub profiles: usize,
+    pub fwd_ws: Option<FwdWsRestore>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FwdWsRestore {
+    pub restored: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Everything to back up from the db. `with_keys` decrypts the API keys
+/// into it.
+pub fn snapshot(
+    store: &dyn Store,
+    cipher: &Cipher,
+    with_keys: bool,
+    fwd_ws: Option<FwdWsConfig>,
+    now: u64,
+) -> anyhow::Result<Snapshot> {
+    let api_keys = if with_keys {
+        let mut api_keys = ApiKeys::default();
+        for provider in keys::list(store)?.value.keys.into_keys() {
+            if let Some(key) = keys::get(store, cipher, &provider)? {
+                api_keys.keys.insert(provider, key);
+            }
+        }
+        Some(api_keys)
+    } else {
+        None
+    };
+    Ok(Snapshot {
+        api_keys,
+        providers: llm::get_settings(store)?.value,
+        conversations: export::export_all(store, now)?,
+        tasks: schedule::load(store)?.tasks,
+        prices: usage::get_prices(store)?.value.models,
+        budgets: budget::load(store)?.value.budgets,
+        prompts: prompts::load(store)?.prompts,
+        profiles: profiles::load(store)?.profiles,
+        fwd_ws,
+    })
+}
+
+/// An archive of `snapshot`, sealed if there is a passphrase.
+pub fn archive(snapshot: Snapshot, passphrase: Option<&str>, now: u64) -> anyhow::Result<Archive> {
+    archive_with(snapshot, passphrase.map(|p| (p, KDF_ITERATIONS)), now)
+}
+
+fn archive_with(
+    snapshot: Snapshot,
+    passphrase: Option<(&str, u32)>,
+    now: u64,
+) -> anyhow::Result<Archive> {
+    let mut archive = Archive {
+        format: ARCHIVE_FORMAT.to_string(),
+        version: ARCHIVE_VERSION,
+        created_at: now,
+        encryption: None,
+        sealed: None,
+        snapshot: None,
+    };
+    let Some((passphrase, iterations)) = passphrase else {
+        archive.snapshot = Some(snapshot);
+        return Ok(archive);
+    };
+    let salt = crypto::new_salt();
+    let key = crypto::passphrase_key(passphrase, &salt, iterations);
+    let sealed = Cipher::new(&key).seal(&serde_json::to_vec(&snapshot)?, SEAL_CONTEXT)?;
+    archive.encryption = Some(Encryption {
+        kdf: KDF.to_string(),
+        iterations,
+        salt: BASE64.encode(salt),
+    });
+    archive.sealed = Some(sealed);
+    Ok(archive)
+}
+
+/// The checked snapshot of `archive`, or why it can't be restored.
+pub fn open(archive: Archive, passphrase: Option<&str>) -> Result<Snapshot, String> {
+    if archive.format != ARCHIVE_FORMAT {
+        return Err(format!("not a kibitz backup: format is {}", archive.format));
+    }
+    if archive.version > ARCHIVE_VERSION {
+        return Err(format!(
+            "backup version {} is newer than supported version {ARCHIVE_VERSION}",
+            archive.version
+        ));
+    }
+    let snapshot = match (archive.encryption, archive.sealed, archive.snapshot) {
+        (None, None, Some(snapshot)) => snapshot,
+        (Some(encryption), Some(sealed), None) => {
+            let Some(passphrase) = passphrase else {
+                return Err("this backup is encrypted: a passphrase is required".to_string());
+            };
+            if encryption.kdf != KDF || encryption.iterations > MAX_KDF_ITERATIONS {
+                return Err(format!(
+                    "unsupported key derivation {} ({} iterations)",
+                    encryption.kdf, encryption.iterations
+                ));
+            }
+            let salt = BASE64
+                .decode(&encryption.salt)
+                .map_err(|e| format!("malformed salt: {e}"))?;
+            let key = crypto::passphrase_key(passphrase, &salt, encryption.iterations);
+            let plaintext = Cipher::new(&key)
+                .open(&sealed, SEAL_CONTEXT)
+                .map_err(|_| "wrong passphrase or corrupt backup".to_string())?;
+            serde_json::from_slice(&plaintext)
+                .map_err(|e| format!("malformed backup contents: {e}"))?
+        }
+        _ => return Err("backup has neither a snapshot nor a sealed one".to_string()),
+    };
+
+    if let Some(api_keys) = &snapshot.api_keys {
+        if let Some(provider) = api_keys.keys.keys().find(|p| llm::provider(p).is_none()) {
+            return Err(format!("backup has a key for unknown provider {provider}"));
+        }
+    }
+    llm::validate_settings(&snapshot.providers).map_err(|e| format!("{e:#}"))?;
+    export::check_bundle(&snapshot.conversations)?;
+    for task in snapshot.tasks.values() {
+        Cron::parse(&task.spec.schedule).map_err(|e| format!("task {}: {e}", task.spec.name))?;
+    }
+    let prices = Prices {
+        models: snapshot.prices.clone(),
+    };
+    usage::validate_prices(&prices)?;
+    for (provider, budget) in &snapshot.budgets {
+        budget::validate(provider, budget)?;
+    }
+    if let Some(prompt) = snapshot.prompts.values().find(|p| p.versions.is_empty()) {
+        return Err(format!("prompt {} has no versions", prompt.name));
+    }
+    for profile in snapshot.profiles.values() {
+        profiles::validate(&mut profile.spec.clone())?;
+    }
+    Ok(snapshot)
+}
+
+/// Apply the db part of a checked snapshot. The fwd-ws part is left to the
+/// caller, which can message it.
+pub fn apply(
+    store: &dyn Store,
+    cipher: &Cipher,
+    snapshot: Snapshot,
+    now: u64,
+) -> anyhow::Result<RestoreReport> {
+    let mut report = RestoreReport::default();
+    if let Some(api_keys) = &snapshot.api_keys {
+        keys::replace_all(store, cipher, api_keys, now, None)?;
+        let mut providers: Vec<_> = api_keys.keys.keys().cloned().collect();
+        providers.sort();
+        report.api_keys = Some(providers);
+    }
+    llm::set_settings(store, snapshot.providers, None)?;
+    report.conversations = export::import(store, snapshot.conversations)?
+        .map_err(|message| anyhow::anyhow!(message))?;
+
+    report.tasks = snapshot.tasks.len();
+    let mut tasks = schedule::load(store)?;
+    tasks.tasks.extend(snapshot.tasks);
+    schedule::save(store, &tasks)?;
+    report.prices = snapshot.prices.len();
+    let mut prices = usage::get_prices(store)?;
+    prices.value.models.extend(snapshot.prices);
+    store::put_revisioned(store, usage::PRICES_KEY, &mut prices, None)?;
+    report.budgets = snapshot.budgets.len();
+    let mut budgets = budget::load(store)?;
+    budgets.value.budgets.extend(snapshot.budgets);
+    store::put_revisioned(store, budget::BUDGETS_KEY, &mut budgets, None)?;
+    report.prompts = snapshot.prompts.len();
+    let mut prompts = prompts::load(store)?;
+    prompts.prompts.extend(snapshot.prompts);
+    prompts::save(store, &prompts)?;
+    report.profiles = snapshot.profiles.len();
+    let mut profiles = profiles::load(store)?;
+    profiles.profiles.extend(snapshot.profiles);
+    profiles::save(store, &profiles)?;
+    Ok(report)
+}
+
+fn fwd_ws_address(our: &Address) -> Address {
+    Address::from((our.node(), "fwd-ws", "kibitz", "nick.hypr"))
+}
+
+fn fwd_ws(our: &Address, request: FwdWsRequest) -> anyhow::Result<FwdWsResponse> {
+    let response = Request::to(fwd_ws_address(our))
+        .body(request)
+        .send_and_await_response(FWD_WS_TIMEOUT_S)??;
+    Ok(FwdWsResponse::try_from(response.body())?)
+}
+
+pub fn get_fwd_ws_config(our: &Address) -> anyhow::Result<FwdWsConfig> {
+    match fwd_ws(our, FwdWsRequest::GetState)? {
+        FwdWsResponse::GetState(state) => Ok(FwdWsConfig {
+            partner: state.partner,
+            connection: state.connection,
+            ws_url: state.ws_url,
+        }),
+        other => Err(anyhow::anyhow!("unexpected fwd-ws response {other:?}")),
+    }
+}
+
+/// Put fwd-ws back as `config` had it: same partner, and connected to (or
+/// accepting clients at) the same URL.
+fn restore_fwd_ws(our: &Address, config: FwdWsConfig) -> anyhow::Result<()> {
+    let mut requests = vec![
+        FwdWsRequest::Disconnect,
+        FwdWsRequest::SetPartner(config.partner),
+    ];
+    match (config.connection, config.ws_url) {
+        (ConnectionType::ToWsServer, Some(url)) => {
+            requests.push(FwdWsRequest::ConnectToServer(url))
+        }
+        (_, Some(url)) => requests.push(FwdWsRequest::AcceptClients(url)),
+        (_, None) => {}
+    }
+    for request in requests {
+        if let FwdWsResponse::Err(e) = fwd_ws(our, request)? {
+            anyhow::bail!("{e}");
+        }
+    }
+    Ok(())
+}
+
+fn backup_drive(our: &Address) -> anyhow::Result<String> {
+    Ok(create_drive(our.package_id(), BACKUP_DRIVE, None)?)
+}
+
+/// Names of the archives in the `backups` drive, oldest first.
+fn list_backups(our: &Address) -> anyhow::Result<Vec<String>> {
+    let drive = backup_drive(our)?;
+    let mut names: Vec<String> = open_dir(&drive, false, None)?
+        .read()?
+        .into_iter()
+        .filter(|entry| entry.file_type == FileType::File)
+        .filter_map(|entry| entry.path.rsplit('/').next().map(str::to_string))
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+/// Only plain names of files in the drive, so a restore can't be pointed
+/// elsewhere in VFS.
+fn is_backup_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains('/') && !name.starts_with('.')
+}
+
+/// Serve the backup and restore endpoints.
+pub fn handle_http(
+    our: &Address,
+    store: &dyn Store,
+    cipher: &Cipher,
+    sync: &mut SyncClients,
+    method: &str,
+    bound_path: &str,
+    now: u64,
+) -> anyhow::Result<()> {
+    match (method, bound_path) {
+        ("POST", HTTP_BACKUP_PATH) => {
+            let Some(request) = read_json_body::<BackupRequest>()? else {
+                return Ok(());
+            };
+            let passphrase = request.passphrase.as_deref().filter(|p| !p.is_empty());
+            let fwd_ws = if request.fwd_ws {
+                match get_fwd_ws_config(our) {
+                    Ok(config) => Some(config),
+                    Err(e) => {
+                        let message = format!("could not read the fwd-ws configuration: {e:#}");
+                        return send_http_error(HTTP_BAD_GATEWAY, &message);
+                    }
+                }
+            } else {
+                None
+            };
+            let snapshot = snapshot(store, cipher, passphrase.is_some(), fwd_ws, now)?;
+            let archive = serde_json::to_vec_pretty(&archive(snapshot, passphrase, now)?)?;
+            let name = format!("kibitz-backup-{now}.json");
+            if request.save {
+                let path = format!("{}/{name}", backup_drive(our)?);
+                create_file(&path, None)?.write(&archive)?;
+                info!("saved backup to {path}");
+                return send_http_json(
+                    HTTP_CREATED,
+                    &serde_json::json!({ "file": name, "path": path }),
+                );
+            }
+            send_http(
+                HttpResponse::new(HTTP_OK)
+                    .header("Content-Type", "application/json")
+                    .header(
+                        "Content-Disposition",
+                        format!("attachment; filename=\"{name}\""),
+                    ),
+                Some(archive),
+            )
+        }
+        ("GET", HTTP_BACKUPS_PATH) => send_http_json(
+            HTTP_OK,
+            &serde_json::json!({ "backups": list_backups(our)? }),
+        ),
+        ("POST", HTTP_RESTORE_PATH) => {
+            let Some(request) = read_json_body::<RestoreRequest>()? else {
+                return Ok(());
+            };
+            let archive = match (request.archive, request.file) {
+                (Some(archive), None) => archive,
+                (None, Some(file)) => {
+                    if !is_backup_name(&file) || !list_backups(our)?.contains(&file) {
+                        return send_http_error(HTTP_NOT_FOUND, "no such backup");
+                    }
+                    let path = format!("{}/{file}", backup_drive(our)?);
+                    let bytes = open_file(&path, false, None)?.read()?;
+                    match serde_json::from_slice(&bytes) {
+                        Ok(archive) => archive,
+                        Err(e) => {
+                            let message = format!("malformed backup {file}: {e}");
+                            return send_http_error(HTTP_BAD_REQUEST, &message);
+                        }
+                    }
+                }
+                _ => {
+                    let message = "send either an archive or the file name of a saved one";
+                    return send_http_error(HTTP_BAD_REQUEST, message);
+                }
+            };
+            let mut snapshot = match open(archive, request.passphrase.as_deref()) {
+                Ok(snapshot) => snapshot,
+                Err(message) => return send_http_error(HTTP_BAD_REQUEST, &message),
+            };
+            let fwd_ws_config = snapshot.fwd_ws.take();
+            let mut report = apply(store, cipher, snapshot, now)?;
+            if report.api_keys.is_some() {
+                sync.publish(store, ChangeEvent::KeysChanged, now);
+            }
+            sync.publish(store, ChangeEvent::ProvidersChanged, now);
+            export::publish_import(store, sync, &report.conversations, now);
+            let restored = [
+                (report.tasks, ChangeEvent::TasksChanged),
+                (report.prices, ChangeEvent::PricesChanged),
+                (report.budgets, ChangeEvent::BudgetsChanged),
+                (report.prompts, ChangeEvent::PromptsChanged),
+                (report.profiles, ChangeEvent::ProfilesChanged),
+            ];
+            for (_, event) in restored.into_iter().filter(|(count, _)| *count > 0) {
+                sync.publish(store, event, now);
+            }
+            report.fwd_ws = fwd_ws_config.map(|config| match restore_fwd_ws(our, config) {
+                Ok(()) => FwdWsRestore {
+                    restored: true,
+                    error: None,
+                },
+                Err(e) => FwdWsRestore {
+                    restored: false,
+                    error: Some(format!("{e:#}")),
+                },
+            });
+            info!("restored backup: {report:?}");
+            send_http_json(HTTP_OK, &report)
+        }
+        _ => send_http_response(HTTP_NOT_FOUND, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversations::{append, create, NewConversation, NewMessage};
+    use crate::store::MemoryStore;
+    use serde_json::json;
+
+    fn cipher() -> Cipher {
+        Cipher::new(&[3; 32])
+    }
+
+    fn populated() -> MemoryStore {
+        let store = MemoryStore::default();
+        let api_keys = ApiKeys {
+            keys: [("anthropic".to_string(), "sk-ant-1234".to_string())].into(),
+        };
+        keys::replace_all(&store, &cipher(), &api_keys, 1, None).unwrap();
+        let new = NewConversation {
+            title: "kept".to_string(),
+            project_id: None,
+            settings: serde_json::Value::Null,
+        };
+        let id = create(&store, new, 2).unwrap().value.id;
+        let new = NewMessage {
+            role: "user".to_string(),
+            content: serde_json::json!("hello"),
+            served_by: None,
+        };
+        append(&store, &id, new, 3).unwrap();
+        store
+    }
+
+    fn fwd_ws() -> FwdWsConfig {
+        FwdWsConfig {
+            partner: Some("partner.os".to_string()),
+            connection: ConnectionType::ToWsServer,
+            ws_url: Some("ws://localhost:10125".to_string()),
+        }
+    }
+
+    #[test]
+    fn sealed_backup_restores_keys_on_another_node() {
+        let here = populated();
+        let snapshot = snapshot(&here, &cipher(), true, Some(fwd_ws()), 9).unwrap();
+        // Few iterations: this is about the layout, not the KDF's strength
+        let archive = archive_with(snapshot, Some(("correct horse", 2)), 9).unwrap();
+        let bytes = serde_json::to_vec(&archive).unwrap();
+        assert!(!String::from_utf8_lossy(&bytes).contains("sk-ant"));
+        let parse = || serde_json::from_slice::<Archive>(&bytes).unwrap();
+
+        assert!(open(parse(), None).is_err());
+        assert!(open(parse(), Some("wrong")).is_err());
+        let snapshot = open(parse(), Some("correct horse")).unwrap();
+        assert_eq!(snapshot.fwd_ws.as_ref().unwrap().ws_url, fwd_ws().ws_url);
+
+        // A different node: its own cipher, nothing stored yet
+        let there = MemoryStore::default();
+        let their_cipher = Cipher::new(&[4; 32]);
+        let report = apply(&there, &their_cipher, snapshot, 10).unwrap();
+        assert_eq!(report.api_keys, Some(vec!["anthropic".to_string()]));
+        assert_eq!(report.conversations.imported.len(), 1);
+        assert_eq!(
+            keys::get(&there, &their_cipher, "anthropic").unwrap(),
+            Some("sk-ant-1234".to_string())
+        );
+    }
+
+    #[test]
+    fn plain_backup_leaves_keys_out_and_restores_idempotently() {
+        let store = populated();
+        let snapshot = snapshot(&store, &cipher(), false, None, 9).unwrap();
+        let archive = archive(snapshot, None, 9).unwrap();
+        let bytes = serde_json::to_vec(&archive).unwrap();
+        assert!(!String::from_utf8_lossy(&bytes).contains("sk-ant"));
+
+        let snapshot = open(serde_json::from_slice(&bytes).unwrap(), None).unwrap();
+        let report = apply(&store, &cipher(), snapshot, 10).unwrap();
+        assert_eq!(report.api_keys, None);
+        assert_eq!(report.conversations.imported.len(), 0);
+        assert_eq!(report.conversations.duplicates.len(), 1);
+        // Keys already here are untouched
+        assert!(keys::get(&store, &cipher(), "anthropic").unwrap().is_some());
+    }
+
+    #[test]
+    fn settings_round_trip_and_replace_only_their_own() {
+        let store = populated();
+        let task: Task = serde_json::from_value(json!({
+            "id": "t1", "name": "nightly", "schedule": "0 3 * * *",
+            "run": { "prompt": "tidy up", "model": "m" },
+            "created_at": 1, "updated_at": 1,
+        }))
+        .unwrap();
+        schedule::save(
+            &store,
+            &schedule::Tasks {
+                tasks: [("t1".to_string(), task)].into(),
+            },
+        )
+        .unwrap();
+        let price: Price = serde_json::from_value(json!({ "input": 3.0, "output": 15.0 })).unwrap();
+        let mut prices = usage::get_prices(&store).unwrap();
+        prices.value.models.insert("claude".to_string(), price);
+        store::put_revisioned(&store, usage::PRICES_KEY, &mut prices, None).unwrap();
+        let daily = json!({ "daily": { "usd": 5.0 } });
+        budget::set(
+            &store,
+            "anthropic",
+            Some(serde_json::from_value(daily).unwrap()),
+            None,
+        )
+        .unwrap();
+        let prompt: Prompt = serde_json::from_value(json!({
+            "id": "p1", "name": "reviewer",
+            "versions": [{ "version": 1, "text": "Review {{lang}}", "created_at": 1 }],
+            "created_at": 1, "updated_at": 1,
+        }))
+        .unwrap();
+        prompts::save(
+            &store,
+            &prompts::Prompts {
+                prompts: [("p1".to_string(), prompt)].into(),
+            },
+        )
+        .unwrap();
+        let profile: Profile = serde_json::from_value(json!({
+            "id": "a1", "name": "reviewer", "tools": ["read_*"], "created_at": 1, "updated_at": 1,
+        }))
+        .unwrap();
+        profiles::save(
+            &store,
+            &profiles::Profiles {
+                profiles: [("a1".to_string(), profile)].into(),
+            },
+        )
+        .unwrap();
+
+        let snapshot = snapshot(&store, &cipher(), false, None, 9).unwrap();
+        let bytes = serde_json::to_vec(&archive(snapshot, None, 9).unwrap()).unwrap();
+        let snapshot = open(serde_json::from_slice(&bytes).unwrap(), None).unwrap();
+
+        // Another node, with a profile of its own
+        let there = MemoryStore::default();
+        let theirs: Profile = serde_json::from_value(json!({
+            "id": "a2", "name": "refactorer", "tools": ["*"], "created_at": 2, "updated_at": 2,
+        }))
+        .unwrap();
+        profiles::save(
+            &there,
+            &profiles::Profiles {
+                profiles: [("a2".to_string(), theirs)].into(),
+            },
+        )
+        .unwrap();
+        let report = apply(&there, &cipher(), snapshot, 10).unwrap();
+        let counts = [
+            report.tasks,
+            report.prices,
+            report.budgets,
+            report.prompts,
+            report.profiles,
+        ];
+        assert_eq!(counts, [1; 5]);
+        assert_eq!(
+            schedule::load(&there).unwrap().tasks["t1"].spec.schedule,
+            "0 3 * * *"
+        );
+        let prices = usage::get_prices(&there).unwrap().value;
+        assert_eq!(prices.for_model("claude-x").unwrap().output, 15.0);
+        let budgets = budget::load(&there).unwrap().value.budgets;
+        assert_eq!(budgets["anthropic"].daily.as_ref().unwrap().usd, Some(5.0));
+        assert_eq!(
+            prompts::load(&there).unwrap().prompts["p1"].versions.len(),
+            1
+        );
+        let restored = profiles::load(&there).unwrap().profiles;
+        assert_eq!(restored.keys().collect::<Vec<_>>(), ["a1", "a2"]);
+
+        // Archives from before these were backed up still open
+        let v1 = serde_json::json!({
+            "format": ARCHIVE_FORMAT,
+            "version": 1,
+            "created_at": 0,
+            "snapshot": { "providers": {}, "conversations": export::export_all(&there, 0).unwrap() },
+        });
+        let snapshot = open(serde_json::from_value(v1).unwrap(), None).unwrap();
+        assert!(snapshot.tasks.is_empty() && snapshot.profiles.is_empty());
+    }
+
+    #[test]
+    fn invalid_archives_are_refused_whole() {
+        let store = populated();
+        let mut snapshot = snapshot(&store, &cipher(), true, None, 9).unwrap();
+        snapshot
+            .api_keys
+            .as_mut()
+            .unwrap()
+            .keys
+            .insert("nope".to_string(), "x".to_string());
+        let error = open(archive(snapshot, None, 9).unwrap(), None).unwrap_err();
+        assert!(error.contains("unknown provider nope"));
+
+        let newer = serde_json::json!({
+            "format": ARCHIVE_FORMAT,
+            "version": ARCHIVE_VERSION + 1,
+            "created_at": 0,
+        });
+        let error = open(serde_json::from_value(newer).unwrap(), None).unwrap_err();
+        assert!(error.contains("newer"));
+        assert!(is_backup_name("kibitz-backup-1.json"));
+        assert!(!is_backup_name("../secrets/kv-key"));
+    }
+}
@@ -0,0 +1,489 @@
+//! Branching conversations, to try another approach without losing the
+//! context so far.
+//!
+//! Forking a conversation at a message makes a branch: a new conversation
+//! holding copies of the messages up to and including it, which then goes
+//! its own way. Branches are conversations like any other, so everything
+//! else (appending, search, export, runs) works on them unchanged. Each
+//! records where it was forked from and the root of its tree. Which branch
+//! of a tree is active, i.e. being worked on, is kept under its own
+//! revisioned key, so switching doesn't disturb edits to the root: forking
+//! switches to the new branch. A root can't be deleted while it has
+//! branches.
+//!
+//! Checkpoints name messages in a conversation, to fork from later by name.
+
+use hyperware_process_lib::http::server::IncomingHttpRequest;
+use serde::{Deserialize, Serialize};
+
+use crate::conversations::{
+    self, ChatMessage, Checkpoint, Conversation, ConversationSummary, ForkPoint,
+};
+use crate::http::{
+    read_if_match, read_json_body, send_http_error, send_http_json, send_http_json_tagged,
+    send_http_response, send_write_result, url_param, IfMatch, HTTP_BAD_REQUEST, HTTP_CREATED,
+    HTTP_METHOD_NOT_ALLOWED, HTTP_NOT_FOUND, HTTP_OK,
+};
+use crate::store::{self, Revisioned, Store};
+use crate::sync::{ChangeEvent, SyncClients};
+
+pub const HTTP_FORK_PATH: &str = "/api/conversations/:id/fork";
+pub const HTTP_BRANCHES_PATH: &str = "/api/conversations/:id/branches";
+pub const HTTP_CHECKPOINTS_PATH: &str = "/api/conversations/:id/checkpoints";
+pub const HTTP_DIFF_PATH: &str = "/api/conversations/:id/diff";
+
+/// The body of `POST /api/conversations/{id}/fork`: where to fork, by
+/// message or checkpoint, defaulting to the last message.
+#[derive(Debug, Default, Deserialize)]
+pub struct Fork {
+    #[serde(default)]
+    pub seq: Option<u64>,
+    #[serde(default)]
+    pub checkpoint: Option<String>,
+    /// The branch's title; the conversation's if unset.
+    #[serde(default)]
+    pub title: Option<String>,
+}
+
+/// The body of `POST /api/conversations/{id}/checkpoints`.
+#[derive(Debug, Deserialize)]
+struct NewCheckpoint {
+    name: String,
+    /// The last message if unset.
+    #[serde(default)]
+    seq: Option<u64>,
+}
+
+/// The body of `PUT /api/conversations/{id}/branches`.
+#[derive(Debug, Deserialize)]
+struct Switch {
+    active: String,
+}
+
+/// Which branch of a tree is active; the root if none is.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Active {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    active: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Branch {
+    #[serde(flatten)]
+    pub summary: ConversationSummary,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forked_from: Option<ForkPoint>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Tree {
+    pub root_id: String,
+    pub active: String,
+    /// Most recently updated first.
+    pub branches: Vec<Branch>,
+}
+
+/// What two conversations have in common, and where they part.
+#[derive(Debug, Serialize)]
+pub struct Diff {
+    /// How many messages, from the first, they share.
+    pub common: u64,
+    /// Each one's messages after those.
+    pub base: Vec<ChatMessage>,
+    pub other: Vec<ChatMessage>,
+}
+
+fn all_messages(
+    store: &dyn Store,
+    conversation: &Conversation,
+) -> anyhow::Result<Vec<ChatMessage>> {
+    (0..conversation.message_count)
+        .filter_map(|seq| conversations::message(store, &conversation.id, seq).transpose())
+        .collect()
+}
+
+/// The message `fork` names in `conversation`, or why there is none.
+fn fork_point(conversation: &Conversation, fork: &Fork) -> Result<u64, String> {
+    let seq = match (fork.seq, &fork.checkpoint) {
+        (Some(_), Some(_)) => return Err("give seq or checkpoint, not both".to_string()),
+        (Some(seq), None) => seq,
+        (None, Some(name)) => match conversation.checkpoints.iter().find(|c| &c.name == name) {
+            Some(checkpoint) => checkpoint.seq,
+            None => return Err(format!("no checkpoint {name}")),
+        },
+        (None, None) => match conversation.message_count.checked_sub(1) {
+            Some(last) => last,
+            None => return Err("conversation has no messages".to_string()),
+        },
+    };
+    if seq >= conversation.message_count {
+        return Err(format!("no message {seq}"));
+    }
+    Ok(seq)
+}
+
+/// Fork conversation `id` as `fork` says, making the branch the active one.
+/// Returns the branch, or why there is none.
+pub fn fork(
+    store: &dyn Store,
+    id: &str,
+    fork: Fork,
+    now: u64,
+) -> anyhow::Result<Option<Result<Conversation, String>>> {
+    let Some(source) = conversations::get(store, id)? else {
+        return Ok(None);
+    };
+    let source = source.value;
+    let seq = match fork_point(&source, &fork) {
+        Ok(seq) => seq,
+        Err(message) => return Ok(Some(Err(message))),
+    };
+    let root_id = source.root_id().to_string();
+    let messages = all_messages(store, &source)?
+        .into_iter()
+        .take(seq as usize + 1)
+        .collect();
+    let branch = Conversation {
+        title: fork.title.unwrap_or_else(|| source.title.clone()),
+        project_id: source.project_id.clone(),
+        settings: source.settings.clone(),
+        created_at: now,
+        updated_at: now,
+        forked_from: Some(ForkPoint {
+            conversation_id: source.id.clone(),
+            seq,
+            root_id: root_id.clone(),
+        }),
+        checkpoints: source
+            .checkpoints
+            .iter()
+            .filter(|checkpoint| checkpoint.seq <= seq)
+            .cloned()
+            .collect(),
+        ..Default::default()
+    };
+    let branch = conversations::restore(store, branch, messages)?.value;
+    switch(store, &root_id, &branch.id, None)?;
+    Ok(Some(Ok(branch)))
+}
+
+fn active_key(root_id: &str) -> String {
+    format!("branches:{root_id}:active")
+}
+
+fn get_active(store: &dyn Store, root_id: &str) -> anyhow::Result<Revisioned<Active>> {
+    store::get_revisioned(store, &active_key(root_id))
+}
+
+/// Make `branch_id` the active branch of the tree rooted at `root_id`,
+/// returning the new revision of the choice.
+fn switch(
+    store: &dyn Store,
+    root_id: &str,
+    branch_id: &str,
+    expected: Option<u64>,
+) -> anyhow::Result<u64> {
+    let mut active = get_active(store, root_id)?;
+    active.value.active = Some(branch_id.to_string());
+    store::put_revisioned(store, &active_key(root_id), &mut active, expected)
+}
+
+/// Drop what is kept about the tree rooted at `root_id`, as it is deleted.
+pub fn forget(store: &dyn Store, root_id: &str) -> anyhow::Result<()> {
+    store.delete(&active_key(root_id))
+}
+
+/// The tree of branches `conversation` is in.
+pub fn tree(store: &dyn Store, conversation: &Conversation) -> anyhow::Result<Revisioned<Tree>> {
+    let root_id = conversation.root_id().to_string();
+    let chosen = get_active(store, &root_id)?;
+    let summaries = conversations::tree(store, &root_id)?;
+    let active = chosen
+        .value
+        .active
+        .filter(|active| summaries.iter().any(|summary| &summary.id == active))
+        .unwrap_or_else(|| root_id.clone());
+    let branches = summaries
+        .into_iter()
+        .map(|summary| {
+            let forked_from =
+                conversations::get(store, &summary.id)?.and_then(|branch| branch.value.forked_from);
+            Ok(Branch {
+                summary,
+                forked_from,
+            })
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok(Revisioned {
+        revision: chosen.revision,
+        value: Tree {
+            root_id,
+            active,
+            branches,
+        },
+    })
+}
+
+/// Compare two conversations' messages by role and content.
+pub fn diff(base: Vec<ChatMessage>, other: Vec<ChatMessage>) -> Diff {
+    let common = base
+        .iter()
+        .zip(&other)
+        .take_while(|(a, b)| a.role == b.role && a.content == b.content)
+        .count();
+    Diff {
+        common: common as u64,
+        base: base.into_iter().skip(common).collect(),
+        other: other.into_iter().skip(common).collect(),
+    }
+}
+
+/// Name message `seq` (the last if unset) of `conversation`, replacing any
+/// checkpoint of the same name.
+fn add_checkpoint(
+    conversation: &mut Conversation,
+    new: NewCheckpoint,
+    now: u64,
+) -> Result<Checkpoint, String> {
+    let name = new.name.trim().to_string();
+    if name.is_empty() {
+        return Err("a checkpoint needs a name".to_string());
+    }
+    let seq = match new.seq.or(conversation.message_count.checked_sub(1)) {
+        Some(seq) if seq < conversation.message_count => seq,
+        _ => return Err("no such message".to_string()),
+    };
+    let checkpoint = Checkpoint {
+        name,
+        seq,
+        created_at: now,
+    };
+    conversation
+        .checkpoints
+        .retain(|existing| existing.name != checkpoint.name);
+    conversation.checkpoints.push(checkpoint.clone());
+    Ok(checkpoint)
+}
+
+/// Serve the branch, checkpoint and diff endpoints.
+pub fn handle_http(
+    store: &dyn Store,
+    sync: &mut SyncClients,
+    http_request: &IncomingHttpRequest,
+    bound_path: &str,
+    now: u64,
+) -> anyhow::Result<()> {
+    let method = http_request.method()?;
+    let Some(id) = url_param(http_request, "id") else {
+        return send_http_error(HTTP_BAD_REQUEST, "missing conversation id");
+    };
+    let Some(mut conversation) = conversations::get(store, id)? else {
+        return send_http_error(HTTP_NOT_FOUND, "no such conversation");
+    };
+    match (method.as_str(), bound_path) {
+        ("POST", HTTP_FORK_PATH) => {
+            let Some(request) = read_json_body::<Fork>()? else {
+                return Ok(());
+            };
+            match fork(store, id, request, now)? {
+                Some(Ok(branch)) => {
+                    let event = ChangeEvent::ConversationCreated {
+                        id: branch.id.clone(),
+                    };
+                    sync.publish(store, event, now);
+                    let id = branch.root_id().to_string();
+                    sync.publish(store, ChangeEvent::ConversationUpdated { id }, now);
+                    send_http_json(HTTP_CREATED, &branch)
+                }
+                Some(Err(message)) => send_http_error(HTTP_BAD_REQUEST, &message),
+                None => send_http_error(HTTP_NOT_FOUND, "no such conversation"),
+            }
+        }
+        ("GET", HTTP_BRANCHES_PATH) => {
+            let tree = tree(store, &conversation.value)?;
+            send_http_json_tagged(HTTP_OK, tree.revision, Some(&tree.value))
+        }
+        ("PUT", HTTP_BRANCHES_PATH) => {
+            let Some(IfMatch(expected)) = read_if_match(http_request)? else {
+                return Ok(());
+            };
+            let Some(Switch { active }) = read_json_body()? else {
+                return Ok(());
+            };
+            let root_id = conversation.value.root_id().to_string();
+            let in_tree = conversations::get(store, &active)?
+                .is_some_and(|branch| branch.value.root_id() == root_id);
+            if !in_tree {
+                return send_http_error(HTTP_BAD_REQUEST, "not a branch of this conversation");
+            }
+            if let Err(e) = switch(store, &root_id, &active, expected) {
+                return send_write_result(HTTP_BRANCHES_PATH, Err(e));
+            }
+            let event = ChangeEvent::ConversationUpdated {
+                id: root_id.clone(),
+            };
+            sync.publish(store, event, now);
+            let tree = tree(store, &conversation.value)?;
+            send_http_json_tagged(HTTP_OK, tree.revision, Some(&tree.value))
+        }
+        ("GET", HTTP_CHECKPOINTS_PATH) => send_http_json(HTTP_OK, &conversation.value.checkpoints),
+        ("POST", HTTP_CHECKPOINTS_PATH) => {
+            let Some(new) = read_json_body::<NewCheckpoint>()? else {
+                return Ok(());
+            };
+            let checkpoint = match add_checkpoint(&mut conversation.value, new, now) {
+                Ok(checkpoint) => checkpoint,
+                Err(message) => return send_http_error(HTTP_BAD_REQUEST, &message),
+            };
+            conversations::save(store, &mut conversation, None)?;
+            let id = id.to_string();
+            sync.publish(store, ChangeEvent::ConversationUpdated { id }, now);
+            send_http_json(HTTP_CREATED, &checkpoint)
+        }
+        ("GET", HTTP_DIFF_PATH) => {
+            let Some(other_id) = http_request.query_params().get("with") else {
+                return send_http_error(HTTP_BAD_REQUEST, "missing with=");
+            };
+            let Some(other) = conversations::get(store, other_id)? else {
+                return send_http_error(HTTP_NOT_FOUND, "no such conversation to compare with");
+            };
+            let base = all_messages(store, &conversation.value)?;
+            let other = all_messages(store, &other.value)?;
+            send_http_json(HTTP_OK, &diff(base, other))
+        }
+        (_, HTTP_FORK_PATH | HTTP_BRANCHES_PATH | HTTP_CHECKPOINTS_PATH | HTTP_DIFF_PATH) => {
+            send_http_response(HTTP_METHOD_NOT_ALLOWED, None)
+        }
+        _ => send_http_response(HTTP_NOT_FOUND, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversations::{append, create, NewConversation, NewMessage};
+    use crate::store::MemoryStore;
+
+    fn conversation_with(store: &MemoryStore, texts: &[&str]) -> String {
+        let new = NewConversation {
+            title: "main".to_string(),
+            project_id: None,
+            settings: serde_json::Value::Null,
+        };
+        let id = create(store, new, 1).unwrap().value.id;
+        for (at, text) in texts.iter().enumerate() {
+            let role = if at % 2 == 0 { "user" } else { "assistant" };
+            let message = NewMessage {
+                role: role.to_string(),
+                content: serde_json::json!(text),
+                served_by: None,
+            };
+            append(store, &id, message, 2).unwrap();
+        }
+        id
+    }
+
+    fn forked(store: &MemoryStore, id: &str, fork: Fork) -> Conversation {
+        super::fork(store, id, fork, 5).unwrap().unwrap().unwrap()
+    }
+
+    #[test]
+    fn forks_share_history_up_to_the_fork_and_form_a_tree() {
+        let store = MemoryStore::default();
+        let root = conversation_with(&store, &["plan", "use a loop", "ok"]);
+        let branch = forked(
+            &store,
+            &root,
+            Fork {
+                seq: Some(0),
+                ..Default::default()
+            },
+        );
+        assert_eq!(branch.message_count, 1);
+        assert_eq!(branch.root_id(), root);
+        assert_eq!(branch.title, "main");
+        // A branch of a branch is still in the root's tree
+        let twig = forked(&store, &branch.id, Fork::default());
+        assert_eq!(twig.root_id(), root);
+        assert_eq!(
+            twig.forked_from.as_ref().unwrap().conversation_id,
+            branch.id
+        );
+
+        let root_conversation = conversations::get(&store, &root).unwrap().unwrap().value;
+        let tree = tree(&store, &root_conversation).unwrap().value;
+        assert_eq!(tree.root_id, root);
+        assert_eq!(tree.active, twig.id);
+        assert_eq!(tree.branches.len(), 3);
+        assert!(conversations::tree(&store, &branch.id).unwrap().len() == 1);
+
+        // Switching is checked against its own revision, not the root's
+        let root_revision = conversations::get(&store, &root).unwrap().unwrap().revision;
+        let chosen = get_active(&store, &root).unwrap().revision;
+        assert!(switch(&store, &root, &branch.id, Some(chosen + 1)).is_err());
+        switch(&store, &root, &branch.id, Some(chosen)).unwrap();
+        let root_now = conversations::get(&store, &root).unwrap().unwrap();
+        assert_eq!(root_now.revision, root_revision);
+        assert_eq!(
+            super::tree(&store, &root_now.value).unwrap().value.active,
+            branch.id
+        );
+
+        let bad = |fork: Fork| super::fork(&store, &root, fork, 5).unwrap().unwrap();
+        assert!(bad(Fork {
+            seq: Some(3),
+            ..Default::default()
+        })
+        .is_err());
+        assert!(bad(Fork {
+            checkpoint: Some("nope".to_string()),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn checkpoints_name_fork_points_and_branches_diff() {
+        let store = MemoryStore::default();
+        let root = conversation_with(&store, &["plan", "use a loop", "ok"]);
+        let mut conversation = conversations::get(&store, &root).unwrap().unwrap();
+        let named = |name: &str, seq: Option<u64>| NewCheckpoint {
+            name: name.to_string(),
+            seq,
+        };
+        assert!(add_checkpoint(&mut conversation.value, named(" ", None), 3).is_err());
+        assert!(add_checkpoint(&mut conversation.value, named("x", Some(9)), 3).is_err());
+        let last = add_checkpoint(&mut conversation.value, named("planned", None), 3).unwrap();
+        assert_eq!(last.seq, 2);
+        add_checkpoint(&mut conversation.value, named("planned", Some(0)), 4).unwrap();
+        assert_eq!(conversation.value.checkpoints.len(), 1);
+        conversations::save(&store, &mut conversation, None).unwrap();
+
+        let branch = forked(
+            &store,
+            &root,
+            Fork {
+                checkpoint: Some("planned".to_string()),
+                title: Some("recursion".to_string()),
+                ..Default::default()
+            },
+        );
+        assert_eq!(branch.message_count, 1);
+        assert_eq!(branch.checkpoints.len(), 1);
+        let message = NewMessage {
+            role: "assistant".to_string(),
+            content: serde_json::json!("use recursion"),
+            served_by: None,
+        };
+        append(&store, &branch.id, message, 6).unwrap();
+
+        let messages = |id: &str| {
+            let conversation = conversations::get(&store, id).unwrap().unwrap().value;
+            all_messages(&store, &conversation).unwrap()
+        };
+        let diff = diff(messages(&root), messages(&branch.id));
+        assert_eq!(diff.common, 1);
+        assert_eq!(diff.base.len(), 2);
+        assert_eq!(diff.other[0].content, "use recursion");
+    }
+}
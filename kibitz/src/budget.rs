@@ -0,0 +1,458 @@
+//! Spending budgets and hard limits per provider.
+//!
+//! A provider's budget (under `BUDGETS_KEY`, revisioned apart from the
+//! keys so editing one doesn't invalidate the other) caps what it may
+//! spend a day and a month (UTC), in USD as priced by `usage`, in tokens,
+//! or both. Once a cap is reached `llm::prepare` refuses further calls to
+//! that provider until the period is over, so runaway agent runs stop too.
+//! As spending crosses the budget's `warn_at` percentages a
+//! `budget_warning` is published to sync clients, once per percentage and
+//! period.
+
+use std::collections::BTreeMap;
+
+use hyperware_process_lib::http::server::IncomingHttpRequest;
+use hyperware_process_lib::logging::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::export::civil_from_days;
+use crate::http::{
+    read_if_match, read_json_body, send_http_error, send_http_json_tagged, send_http_response,
+    send_write_result, url_param, IfMatch, HTTP_BAD_REQUEST, HTTP_METHOD_NOT_ALLOWED,
+    HTTP_NOT_FOUND, HTTP_OK,
+};
+use crate::llm;
+use crate::store::{self, Revisioned, Store};
+use crate::sync::{ChangeEvent, SyncClients};
+use crate::usage::{self, Filter, GroupBy};
+
+pub const HTTP_BUDGETS_PATH: &str = "/api/budgets";
+pub const HTTP_BUDGET_PATH: &str = "/api/budgets/:provider";
+
+pub const BUDGETS_KEY: &str = "budgets";
+
+/// The highest percentage of each period's budget already warned about.
+pub const WARNINGS_KEY: &str = "budget_warnings";
+
+const SECS_PER_DAY: u64 = 86400;
+
+fn default_warn_at() -> Vec<u32> {
+    vec![80]
+}
+
+/// A cap on one period's spending. Either or both may be set.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Limit {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usd: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokens: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Budget {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub daily: Option<Limit>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub monthly: Option<Limit>,
+    /// Percentages of a limit at which to warn.
+    #[serde(default = "default_warn_at")]
+    pub warn_at: Vec<u32>,
+}
+
+/// By provider; a provider may have a budget before it has a key.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Budgets {
+    pub budgets: BTreeMap<String, Budget>,
+}
+
+pub fn load(store: &dyn Store) -> anyhow::Result<Revisioned<Budgets>> {
+    store::get_revisioned(store, BUDGETS_KEY)
+}
+
+/// Set or (with `None`) remove a provider's budget. Returns the new
+/// revision, or `None` if there was no budget to remove.
+pub fn set(
+    store: &dyn Store,
+    provider: &str,
+    budget: Option<Budget>,
+    expected: Option<u64>,
+) -> anyhow::Result<Option<u64>> {
+    let mut budgets = load(store)?;
+    store::check_revision(&budgets, expected)?;
+    let previous = match budget {
+        Some(budget) => budgets.value.budgets.insert(provider.to_string(), budget),
+        None => budgets.value.budgets.remove(provider),
+    };
+    if previous.is_none() && !budgets.value.budgets.contains_key(provider) {
+        return Ok(None);
+    }
+    store::put_revisioned(store, BUDGETS_KEY, &mut budgets, expected).map(Some)
+}
+
+pub fn validate(provider: &str, budget: &Budget) -> Result<(), String> {
+    if llm::provider(provider).is_none() {
+        return Err(format!("unknown provider {provider}"));
+    }
+    let usd = [&budget.daily, &budget.monthly]
+        .into_iter()
+        .flatten()
+        .filter_map(|limit| limit.usd);
+    for usd in usd {
+        if !usd.is_finite() || usd < 0.0 {
+            return Err("a USD limit must be zero or more".to_string());
+        }
+    }
+    if budget
+        .warn_at
+        .iter()
+        .any(|percent| !(1..=100).contains(percent))
+    {
+        return Err("warn_at percentages must be from 1 to 100".to_string());
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Period {
+    Daily,
+    Monthly,
+}
+
+impl Period {
+    /// The first and last day (since the epoch) of the period `now` is in.
+    pub fn days(self, now: u64) -> (u64, u64) {
+        let today = now / SECS_PER_DAY;
+        match self {
+            Period::Daily => (today, today),
+            Period::Monthly => {
+                let (_, _, day) = civil_from_days(today);
+                (today - u64::from(day - 1), today)
+            }
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Period::Daily => "daily",
+            Period::Monthly => "monthly",
+        }
+    }
+}
+
+/// What has been spent against one limit this period.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Spent {
+    pub period: Period,
+    pub limit: Limit,
+    pub usd: f64,
+    pub tokens: u64,
+    /// Of whichever of the limits is closest to being reached.
+    pub percent: f64,
+    /// The period's first day, as `YYYY-MM-DD`.
+    pub since: String,
+}
+
+impl Spent {
+    pub fn is_exhausted(&self) -> bool {
+        self.percent >= 100.0
+    }
+
+    fn describe(&self, provider: &str) -> String {
+        let used = match (self.limit.usd, self.limit.tokens) {
+            (Some(usd), _) if self.usd >= usd => format!("${:.2} of ${usd:.2}", self.usd),
+            (_, Some(tokens)) => format!("{} of {tokens} tokens", self.tokens),
+            (Some(usd), None) => format!("${:.2} of ${usd:.2}", self.usd),
+            (None, None) => String::new(),
+        };
+        format!(
+            "{provider}'s {} budget is {:.0}% spent ({used})",
+            self.period.name(),
+            self.percent
+        )
+    }
+}
+
+fn percent(spent: f64, limit: f64) -> f64 {
+    if limit <= 0.0 {
+        return 100.0;
+    }
+    spent / limit * 100.0
+}
+
+/// How each of `budget`'s limits stands at `now`.
+pub fn spent(
+    store: &dyn Store,
+    provider: &str,
+    budget: &Budget,
+    now: u64,
+) -> anyhow::Result<Vec<Spent>> {
+    let filter = Filter {
+        provider: Some(provider.to_string()),
+        ..Filter::default()
+    };
+    let limits = [
+        (Period::Daily, &budget.daily),
+        (Period::Monthly, &budget.monthly),
+    ];
+    let mut spent = vec![];
+    for (period, limit) in limits {
+        let Some(limit) = limit else {
+            continue;
+        };
+        let days = period.days(now);
+        let total = usage::summarise(store, days, &filter, GroupBy::default())?.total;
+        let tokens = total.usage.total();
+        let percent = [
+            limit.usd.map(|usd| percent(total.cost_usd, usd)),
+            limit.tokens.map(|max| percent(tokens as f64, max as f64)),
+        ]
+        .into_iter()
+        .flatten()
+        .fold(0.0, f64::max);
+        spent.push(Spent {
+            period,
+            limit: limit.clone(),
+            usd: total.cost_usd,
+            tokens,
+            percent,
+            since: usage::format_day(days.0),
+        });
+    }
+    Ok(spent)
+}
+
+/// Why `provider` may not be called now, if its budget is spent.
+pub fn check(store: &dyn Store, provider: &str, now: u64) -> anyhow::Result<Option<String>> {
+    let Some(budget) = load(store)?.value.budgets.remove(provider) else {
+        return Ok(None);
+    };
+    let exhausted = spent(store, provider, &budget, now)?
+        .into_iter()
+        .find(Spent::is_exhausted);
+    Ok(exhausted.map(|spent| {
+        let resets = match spent.period {
+            Period::Daily => "tomorrow",
+            Period::Monthly => "next month",
+        };
+        format!(
+            "{}; calls resume {resets} (UTC) or when the budget is raised",
+            spent.describe(provider)
+        )
+    }))
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Warnings {
+    /// `{provider}:{period}:{first day}` to the highest percentage warned.
+    warned: BTreeMap<String, u32>,
+}
+
+/// Publish a warning for each of `warn_at` that `provider`'s spending
+/// crossed since last time. Called after its usage is recorded.
+pub fn note_spend(store: &dyn Store, sync: &mut SyncClients, provider: &str, now: u64) {
+    if let Err(e) = warn(store, sync, provider, now) {
+        error!("failed to check {provider}'s budget: {e:?}");
+    }
+}
+
+fn warn(store: &dyn Store, sync: &mut SyncClients, provider: &str, now: u64) -> anyhow::Result<()> {
+    let Some(budget) = load(store)?.value.budgets.remove(provider) else {
+        return Ok(());
+    };
+    let mut warnings: Warnings = store::get_json(store, WARNINGS_KEY)?.unwrap_or_default();
+    let before = warnings.warned.len();
+    let mut changed = false;
+    for spent in spent(store, provider, &budget, now)? {
+        let key = format!("{provider}:{}:{}", spent.period.name(), spent.since);
+        let warned = warnings.warned.get(&key).copied().unwrap_or(0);
+        let Some(&crossed) = budget
+            .warn_at
+            .iter()
+            .filter(|&&at| at > warned && f64::from(at) <= spent.percent)
+            .max()
+        else {
+            continue;
+        };
+        info!("{}", spent.describe(provider));
+        warnings.warned.insert(key, crossed);
+        changed = true;
+        let event = ChangeEvent::BudgetWarning {
+            provider: provider.to_string(),
+            period: spent.period.name().to_string(),
+            percent: crossed,
+        };
+        sync.publish(store, event, now);
+    }
+    // Forget periods that are over
+    let current: Vec<String> = [Period::Daily, Period::Monthly]
+        .into_iter()
+        .map(|period| usage::format_day(period.days(now).0))
+        .collect();
+    warnings
+        .warned
+        .retain(|key, _| current.iter().any(|since| key.ends_with(since.as_str())));
+    if changed || warnings.warned.len() != before {
+        store::set_json(store, WARNINGS_KEY, &warnings)?;
+    }
+    Ok(())
+}
+
+/// A provider's budget and how it stands, as `GET /api/budgets` lists it.
+#[derive(Debug, Serialize)]
+pub struct Status {
+    #[serde(flatten)]
+    pub budget: Budget,
+    pub spent: Vec<Spent>,
+}
+
+/// Serve the budget endpoints.
+pub fn handle_http(
+    store: &dyn Store,
+    sync: &mut SyncClients,
+    http_request: &IncomingHttpRequest,
+    bound_path: &str,
+    now: u64,
+) -> anyhow::Result<()> {
+    let method = http_request.method()?;
+    match (method.as_str(), bound_path) {
+        ("GET", HTTP_BUDGETS_PATH) => {
+            let budgets = load(store)?;
+            let mut statuses = BTreeMap::new();
+            for (provider, budget) in budgets.value.budgets {
+                let spent = spent(store, &provider, &budget, now)?;
+                statuses.insert(provider, Status { budget, spent });
+            }
+            send_http_json_tagged(HTTP_OK, budgets.revision, Some(&statuses))
+        }
+        (method @ ("PUT" | "DELETE"), HTTP_BUDGET_PATH) => {
+            let Some(provider) = url_param(http_request, "provider") else {
+                return send_http_error(HTTP_BAD_REQUEST, "missing provider");
+            };
+            let Some(IfMatch(expected)) = read_if_match(http_request)? else {
+                return Ok(());
+            };
+            let budget = if method == "PUT" {
+                let Some(budget) = read_json_body::<Budget>()? else {
+                    return Ok(());
+                };
+                if let Err(message) = validate(provider, &budget) {
+                    return send_http_error(HTTP_BAD_REQUEST, &message);
+                }
+                Some(budget)
+            } else {
+                None
+            };
+            match set(store, provider, budget, expected) {
+                Ok(None) => send_http_error(HTTP_NOT_FOUND, "no budget for that provider"),
+                Ok(Some(revision)) => {
+                    sync.publish(store, ChangeEvent::BudgetsChanged, now);
+                    send_write_result(HTTP_BUDGET_PATH, Ok(revision))
+                }
+                Err(e) => send_write_result(HTTP_BUDGET_PATH, Err(e)),
+            }
+        }
+        (_, HTTP_BUDGETS_PATH | HTTP_BUDGET_PATH) => {
+            send_http_response(HTTP_METHOD_NOT_ALLOWED, None)
+        }
+        _ => send_http_response(HTTP_NOT_FOUND, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::usage::Usage;
+
+    /// 2024-03-15 12:00 UTC.
+    const NOW: u64 = 19797 * SECS_PER_DAY + 12 * 3600;
+
+    fn budget(daily: Limit, monthly: Limit) -> Budget {
+        Budget {
+            daily: Some(daily),
+            monthly: Some(monthly),
+            warn_at: vec![50, 80],
+        }
+    }
+
+    fn spend(store: &MemoryStore, tokens: u64, at: u64) {
+        let usage = Usage {
+            input_tokens: tokens,
+            ..Usage::default()
+        };
+        usage::record(store, "anthropic", "m", None, &usage, at).unwrap();
+    }
+
+    #[test]
+    fn spent_budgets_refuse_calls_until_the_period_is_over() {
+        let store = MemoryStore::default();
+        let tokens = |tokens| Limit {
+            usd: None,
+            tokens: Some(tokens),
+        };
+        let budget = budget(tokens(1000), tokens(5000));
+        set(&store, "anthropic", Some(budget), None).unwrap();
+        // Budgets are revisioned on their own, leaving the keys' ETag be
+        assert!(store.get(crate::keys::API_KEYS_KEY).unwrap().is_none());
+        assert_eq!(load(&store).unwrap().revision, 1);
+
+        spend(&store, 4500, NOW - 3 * SECS_PER_DAY);
+        assert_eq!(check(&store, "anthropic", NOW).unwrap(), None);
+        spend(&store, 600, NOW);
+        let refusal = check(&store, "anthropic", NOW).unwrap().unwrap();
+        assert!(refusal.contains("monthly"), "{refusal}");
+        assert!(refusal.contains("5100 of 5000 tokens"), "{refusal}");
+        // Other providers are unaffected; a new month starts afresh
+        assert_eq!(check(&store, "openai", NOW).unwrap(), None);
+        assert_eq!(
+            check(&store, "anthropic", NOW + 17 * SECS_PER_DAY).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn each_threshold_is_warned_about_once_per_period() {
+        let store = MemoryStore::default();
+        let mut sync = SyncClients::default();
+        let daily = Limit {
+            usd: None,
+            tokens: Some(1000),
+        };
+        set(
+            &store,
+            "anthropic",
+            Some(budget(daily, Limit::default())),
+            None,
+        )
+        .unwrap();
+        let warned = |sync: &mut SyncClients, at| {
+            note_spend(&store, sync, "anthropic", at);
+            let warnings: Warnings = store::get_json(&store, WARNINGS_KEY).unwrap().unwrap();
+            warnings.warned.into_values().collect::<Vec<_>>()
+        };
+
+        spend(&store, 600, NOW);
+        assert_eq!(warned(&mut sync, NOW), vec![50]);
+        spend(&store, 100, NOW);
+        assert_eq!(warned(&mut sync, NOW), vec![50]);
+        spend(&store, 200, NOW);
+        assert_eq!(warned(&mut sync, NOW), vec![80]);
+        // The next day starts afresh
+        assert_eq!(warned(&mut sync, NOW + SECS_PER_DAY), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn months_start_on_the_first() {
+        assert_eq!(Period::Monthly.days(NOW), (19797 - 14, 19797));
+        assert_eq!(Period::Daily.days(NOW), (19797, 19797));
+        let bad = Budget {
+            daily: None,
+            monthly: None,
+            warn_at: vec![0],
+        };
+        assert!(validate("anthropic", &bad).is_err());
+        assert!(validate("nobody", &budget(Limit::default(), Limit::default())).is_err());
+    }
+}
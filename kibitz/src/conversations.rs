@@ -0,0 +1,741 @@
+//! Conversations, their messages and projects, kept on the node so every
+//! device sees the same history.
+//!
+//! Layout in the db:
+//! * `CONVERSATIONS_KEY`: the index of every conversation, most recently
+//!   updated first, so listing never has to read each one.
+//! * `conversation:{id}`: a `Revisioned<Conversation>`; appending a message
+//!   bumps its revision.
+//! * `conversation:{id}:message:{seq}`: one `ChatMessage` each, `seq`
+//!   counting up from 0.
+//! * `PROJECTS_KEY`: a `Revisioned<Projects>` of per-project settings.
+//!
+//! Messages are written before the conversation that counts them, so an
+//! interrupted append leaves at worst an unreferenced message that the next
+//! append overwrites.
+
+use std::collections::{BTreeMap, HashMap};
+
+use hyperware_process_lib::http::server::IncomingHttpRequest;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::http::{
+    read_if_match, read_json_body, send_http_error, send_http_json, send_http_json_tagged,
+    send_http_response, send_write_result, url_param, IfMatch, HTTP_BAD_REQUEST, HTTP_CONFLICT,
+    HTTP_CREATED, HTTP_METHOD_NOT_ALLOWED, HTTP_NOT_FOUND, HTTP_OK,
+};
+use crate::prompts::{self, PromptRef};
+use crate::search;
+use crate::store::{self, Revisioned, Store};
+use crate::sync::{ChangeEvent, SyncClients};
+
+pub const CONVERSATIONS_KEY: &str = "conversations";
+pub const PROJECTS_KEY: &str = "projects";
+
+pub const HTTP_CONVERSATIONS_PATH: &str = "/api/conversations";
+pub const HTTP_CONVERSATION_PATH: &str = "/api/conversations/:id";
+pub const HTTP_MESSAGES_PATH: &str = "/api/conversations/:id/messages";
+pub const HTTP_PROJECTS_PATH: &str = "/api/projects";
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 200;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Conversation {
+    pub id: String,
+    pub title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub project_id: Option<String>,
+    /// Per-conversation settings (model, system prompt, ...), opaque here.
+    #[serde(default)]
+    pub settings: serde_json::Value,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub message_count: u64,
+    /// Set on a branch: where it was forked from. See `branches`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forked_from: Option<ForkPoint>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub checkpoints: Vec<Checkpoint>,
+}
+
+impl Conversation {
+    /// The id of the conversation at the root of its tree of branches.
+    pub fn root_id(&self) -> &str {
+        match self.forked_from {
+            Some(ref fork) => &fork.root_id,
+            None => &self.id,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ForkPoint {
+    pub conversation_id: String,
+    /// The last message the branch shares with it.
+    pub seq: u64,
+    pub root_id: String,
+}
+
+/// A named message to come back to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub name: String,
+    pub seq: u64,
+    pub created_at: u64,
+}
+
+/// The provider and model that wrote a reply.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServedBy {
+    pub provider: String,
+    pub model: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub seq: u64,
+    pub role: String,
+    /// The message as the provider API shapes it: text or content blocks.
+    pub content: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub served_by: Option<ServedBy>,
+    pub created_at: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConversationSummary {
+    pub id: String,
+    pub title: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub project_id: Option<String>,
+    pub updated_at: u64,
+    pub message_count: u64,
+    /// Set on branches.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root_id: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ConversationIndex {
+    conversations: Vec<ConversationSummary>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Project {
+    pub name: String,
+    #[serde(default)]
+    pub settings: serde_json::Value,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Projects {
+    pub projects: BTreeMap<String, Project>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewConversation {
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub project_id: Option<String>,
+    #[serde(default)]
+    pub settings: serde_json::Value,
+}
+
+/// The body of `POST /api/conversations`: a new conversation, optionally
+/// with its system prompt from the prompt library.
+#[derive(Debug, Deserialize)]
+struct CreateRequest {
+    #[serde(flatten)]
+    new: NewConversation,
+    #[serde(default)]
+    system_prompt: Option<PromptRef>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewMessage {
+    pub role: String,
+    pub content: serde_json::Value,
+    /// For replies: who wrote it, e.g. as a routed call's `x-kibitz-provider`
+    /// and `x-kibitz-model` say.
+    #[serde(default)]
+    pub served_by: Option<ServedBy>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Paged<T> {
+    pub items: Vec<T>,
+    pub total: usize,
+    /// Pass as `offset` to get the next page; absent on the last one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_offset: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Page {
+    pub offset: usize,
+    pub limit: usize,
+}
+
+impl Page {
+    /// `?offset=&limit=`, defaulting to the first `DEFAULT_PAGE_SIZE`.
+    pub fn from_query(query: &HashMap<String, String>) -> Result<Self, String> {
+        let param = |name: &str, default: usize| match query.get(name) {
+            Some(value) => value
+                .parse::<usize>()
+                .map_err(|_| format!("{name} must be a non-negative integer")),
+            None => Ok(default),
+        };
+        Self::new(param("offset", 0)?, param("limit", DEFAULT_PAGE_SIZE)?)
+    }
+
+    /// A page of `limit` items from `offset`, if `limit` is allowed.
+    pub fn new(offset: usize, limit: usize) -> Result<Self, String> {
+        if limit == 0 || limit > MAX_PAGE_SIZE {
+            return Err(format!("limit must be between 1 and {MAX_PAGE_SIZE}"));
+        }
+        Ok(Self { offset, limit })
+    }
+
+    pub fn range(&self, total: usize) -> std::ops::Range<usize> {
+        let start = self.offset.min(total);
+        start..start.saturating_add(self.limit).min(total)
+    }
+
+    pub fn paged<T>(&self, items: Vec<T>, total: usize) -> Paged<T> {
+        let end = self.range(total).end;
+        Paged {
+            items,
+            total,
+            next_offset: (end < total).then_some(end),
+        }
+    }
+}
+
+fn conversation_key(id: &str) -> String {
+    format!("conversation:{id}")
+}
+
+fn message_key(id: &str, seq: u64) -> String {
+    format!("conversation:{id}:message:{seq}")
+}
+
+/// Whether `id` could have come from `new_id`. Ids from URLs are checked
+/// before they go into a key, so that one can't reach into another
+/// conversation's messages or any other part of the store.
+fn valid_id(id: &str) -> bool {
+    id.len() == 32
+        && id
+            .bytes()
+            .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+pub fn new_id() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn load_index(store: &dyn Store) -> anyhow::Result<ConversationIndex> {
+    Ok(store::get_json(store, CONVERSATIONS_KEY)?.unwrap_or_default())
+}
+
+fn summary(conversation: &Conversation) -> ConversationSummary {
+    ConversationSummary {
+        id: conversation.id.clone(),
+        title: conversation.title.clone(),
+        project_id: conversation.project_id.clone(),
+        updated_at: conversation.updated_at,
+        message_count: conversation.message_count,
+        root_id: conversation
+            .forked_from
+            .as_ref()
+            .map(|fork| fork.root_id.clone()),
+    }
+}
+
+/// Put `conversation`'s summary at the head of the index.
+fn touch_index(store: &dyn Store, conversation: &Conversation) -> anyhow::Result<()> {
+    let mut index = load_index(store)?;
+    index
+        .conversations
+        .retain(|summary| summary.id != conversation.id);
+    index.conversations.insert(0, summary(conversation));
+    store::set_json(store, CONVERSATIONS_KEY, &index)
+}
+
+/// List conversations, most recently updated first, optionally only those
+/// in `project_id`.
+pub fn list(
+    store: &dyn Store,
+    project_id: Option<&str>,
+    page: Page,
+) -> anyhow::Result<Paged<ConversationSummary>> {
+    let conversations: Vec<_> = load_index(store)?
+        .conversations
+        .into_iter()
+        .filter(|summary| project_id.is_none() || summary.project_id.as_deref() == project_id)
+        .collect();
+    let total = conversations.len();
+    let items = conversations[page.range(total)].to_vec();
+    Ok(page.paged(items, total))
+}
+
+pub fn get(store: &dyn Store, id: &str) -> anyhow::Result<Option<Revisioned<Conversation>>> {
+    if !valid_id(id) {
+        return Ok(None);
+    }
+    store::get_json(store, &conversation_key(id))
+}
+
+pub fn create(
+    store: &dyn Store,
+    new: NewConversation,
+    now: u64,
+) -> anyhow::Result<Revisioned<Conversation>> {
+    let mut conversation = Revisioned {
+        revision: 0,
+        value: Conversation {
+            id: new_id(),
+            title: new.title,
+            project_id: new.project_id,
+            settings: new.settings,
+            created_at: now,
+            updated_at: now,
+            message_count: 0,
+            ..Default::default()
+        },
+    };
+    let key = conversation_key(&conversation.value.id);
+    store::put_revisioned(store, &key, &mut conversation, None)?;
+    touch_index(store, &conversation.value)?;
+    Ok(conversation)
+}
+
+/// Store a conversation brought in from elsewhere under a fresh id, keeping
+/// its timestamps. Messages are renumbered in order.
+pub fn restore(
+    store: &dyn Store,
+    mut conversation: Conversation,
+    messages: Vec<ChatMessage>,
+) -> anyhow::Result<Revisioned<Conversation>> {
+    conversation.id = new_id();
+    conversation.message_count = messages.len() as u64;
+    for (seq, mut message) in messages.into_iter().enumerate() {
+        message.seq = seq as u64;
+        store::set_json(store, &message_key(&conversation.id, message.seq), &message)?;
+        search::index_message(store, &message, &conversation.id)?;
+    }
+    let key = conversation_key(&conversation.id);
+    let mut conversation = Revisioned {
+        revision: 0,
+        value: conversation,
+    };
+    store::put_revisioned(store, &key, &mut conversation, None)?;
+
+    // Slot it in by when it was last updated rather than at the head
+    let mut index = load_index(store)?;
+    let at = index
+        .conversations
+        .iter()
+        .position(|summary| summary.updated_at <= conversation.value.updated_at)
+        .unwrap_or(index.conversations.len());
+    index.conversations.insert(at, summary(&conversation.value));
+    store::set_json(store, CONVERSATIONS_KEY, &index)?;
+    Ok(conversation)
+}
+
+/// Append to a conversation, returning the new message and conversation
+/// revision, or `None` if there is no such conversation.
+pub fn append(
+    store: &dyn Store,
+    id: &str,
+    new: NewMessage,
+    now: u64,
+) -> anyhow::Result<Option<(ChatMessage, u64)>> {
+    let Some(mut conversation) = get(store, id)? else {
+        return Ok(None);
+    };
+    let message = ChatMessage {
+        seq: conversation.value.message_count,
+        role: new.role,
+        content: new.content,
+        served_by: new.served_by,
+        created_at: now,
+    };
+    store::set_json(store, &message_key(id, message.seq), &message)?;
+    search::index_message(store, &message, id)?;
+    conversation.value.message_count += 1;
+    conversation.value.updated_at = now;
+    let revision = store::put_revisioned(store, &conversation_key(id), &mut conversation, None)?;
+    touch_index(store, &conversation.value)?;
+    Ok(Some((message, revision)))
+}
+
+/// Save changes to a conversation's own record, such as its checkpoints,
+/// returning its new revision.
+pub fn save(
+    store: &dyn Store,
+    conversation: &mut Revisioned<Conversation>,
+    expected: Option<u64>,
+) -> anyhow::Result<u64> {
+    let key = conversation_key(&conversation.value.id);
+    store::put_revisioned(store, &key, conversation, expected)
+}
+
+/// Every conversation in the tree of branches rooted at `root_id`, most
+/// recently updated first.
+pub fn tree(store: &dyn Store, root_id: &str) -> anyhow::Result<Vec<ConversationSummary>> {
+    Ok(load_index(store)?
+        .conversations
+        .into_iter()
+        .filter(|summary| summary.id == root_id || summary.root_id.as_deref() == Some(root_id))
+        .collect())
+}
+
+pub fn message(store: &dyn Store, id: &str, seq: u64) -> anyhow::Result<Option<ChatMessage>> {
+    if !valid_id(id) {
+        return Ok(None);
+    }
+    store::get_json(store, &message_key(id, seq))
+}
+
+/// A page of a conversation's messages, oldest first, or `None` if there is
+/// no such conversation.
+pub fn messages(
+    store: &dyn Store,
+    id: &str,
+    page: Page,
+) -> anyhow::Result<Option<Paged<ChatMessage>>> {
+    let Some(conversation) = get(store, id)? else {
+        return Ok(None);
+    };
+    let total = conversation.value.message_count as usize;
+    let items = page
+        .range(total)
+        .filter_map(|seq| message(store, id, seq as u64).transpose())
+        .collect::<anyhow::Result<_>>()?;
+    Ok(Some(page.paged(items, total)))
+}
+
+/// Delete a conversation and its messages. Returns whether it existed.
+pub fn delete(store: &dyn Store, id: &str, expected: Option<u64>) -> anyhow::Result<bool> {
+    let Some(conversation) = get(store, id)? else {
+        return Ok(false);
+    };
+    store::check_revision(&conversation, expected)?;
+    if conversation.value.forked_from.is_none() {
+        crate::branches::forget(store, id)?;
+    }
+    search::unindex_conversation(store, id)?;
+    let mut index = load_index(store)?;
+    index.conversations.retain(|summary| summary.id != id);
+    store::set_json(store, CONVERSATIONS_KEY, &index)?;
+    store.delete(&conversation_key(id))?;
+    for seq in 0..conversation.value.message_count {
+        store.delete(&message_key(id, seq))?;
+    }
+    Ok(true)
+}
+
+pub fn get_projects(store: &dyn Store) -> anyhow::Result<Revisioned<Projects>> {
+    store::get_revisioned(store, PROJECTS_KEY)
+}
+
+pub fn set_projects(
+    store: &dyn Store,
+    projects: Projects,
+    expected: Option<u64>,
+) -> anyhow::Result<u64> {
+    let mut doc = get_projects(store)?;
+    doc.value = projects;
+    store::put_revisioned(store, PROJECTS_KEY, &mut doc, expected)
+}
+
+fn read_page(http_request: &IncomingHttpRequest) -> anyhow::Result<Option<Page>> {
+    match Page::from_query(http_request.query_params()) {
+        Ok(page) => Ok(Some(page)),
+        Err(message) => {
+            send_http_error(HTTP_BAD_REQUEST, &message)?;
+            Ok(None)
+        }
+    }
+}
+
+/// Serve the conversation and project endpoints.
+pub fn handle_http(
+    store: &dyn Store,
+    sync: &mut SyncClients,
+    http_request: &IncomingHttpRequest,
+    method: &str,
+    bound_path: &str,
+    now: u64,
+) -> anyhow::Result<()> {
+    match (method, bound_path) {
+        ("GET", HTTP_CONVERSATIONS_PATH) => {
+            let Some(page) = read_page(http_request)? else {
+                return Ok(());
+            };
+            let project_id = http_request.query_params().get("project");
+            send_http_json(HTTP_OK, &list(store, project_id.map(String::as_str), page)?)
+        }
+        ("POST", HTTP_CONVERSATIONS_PATH) => {
+            let Some(CreateRequest {
+                mut new,
+                system_prompt,
+            }) = read_json_body()?
+            else {
+                return Ok(());
+            };
+            if let Some(prompt) = system_prompt {
+                if let Err(message) = prompts::apply(store, &mut new, &prompt)? {
+                    return send_http_error(HTTP_BAD_REQUEST, &message);
+                }
+            }
+            let conversation = create(store, new, now)?;
+            let id = conversation.value.id.clone();
+            sync.publish(store, ChangeEvent::ConversationCreated { id }, now);
+            send_http_json_tagged(
+                HTTP_CREATED,
+                conversation.revision,
+                Some(&conversation.value),
+            )
+        }
+        (method, HTTP_CONVERSATION_PATH) => {
+            let Some(id) = url_param(http_request, "id") else {
+                return send_http_error(HTTP_BAD_REQUEST, "missing conversation id");
+            };
+            match method {
+                "GET" => match get(store, id)? {
+                    Some(conversation) => send_http_json_tagged(
+                        HTTP_OK,
+                        conversation.revision,
+                        Some(&conversation.value),
+                    ),
+                    None => send_http_error(HTTP_NOT_FOUND, "no such conversation"),
+                },
+                "DELETE" => {
+                    let Some(IfMatch(expected)) = read_if_match(http_request)? else {
+                        return Ok(());
+                    };
+                    // Its branches would be left with a root that is gone
+                    if tree(store, id)?.iter().any(|summary| summary.id != id) {
+                        let message = "delete the conversation's branches first";
+                        return send_http_error(HTTP_CONFLICT, message);
+                    }
+                    match delete(store, id, expected) {
+                        Ok(true) => {
+                            let id = id.to_string();
+                            sync.publish(store, ChangeEvent::ConversationDeleted { id }, now);
+                            send_http_response(HTTP_OK, None)
+                        }
+                        Ok(false) => send_http_error(HTTP_NOT_FOUND, "no such conversation"),
+                        Err(e) => send_write_result("DELETE /api/conversations/:id", Err(e)),
+                    }
+                }
+                _ => send_http_response(HTTP_METHOD_NOT_ALLOWED, None),
+            }
+        }
+        (method, HTTP_MESSAGES_PATH) => {
+            let Some(id) = url_param(http_request, "id") else {
+                return send_http_error(HTTP_BAD_REQUEST, "missing conversation id");
+            };
+            match method {
+                "GET" => {
+                    let Some(page) = read_page(http_request)? else {
+                        return Ok(());
+                    };
+                    match messages(store, id, page)? {
+                        Some(messages) => send_http_json(HTTP_OK, &messages),
+                        None => send_http_error(HTTP_NOT_FOUND, "no such conversation"),
+                    }
+                }
+                // Appends from several devices interleave rather than
+                // conflict, so they don't take `If-Match`
+                "POST" => {
+                    let Some(new) = read_json_body::<NewMessage>()? else {
+                        return Ok(());
+                    };
+                    match append(store, id, new, now)? {
+                        Some((message, revision)) => {
+                            let event = ChangeEvent::MessageAppended {
+                                conversation_id: id.to_string(),
+                                seq: message.seq,
+                            };
+                            sync.publish(store, event, now);
+                            send_http_json_tagged(HTTP_CREATED, revision, Some(&message))
+                        }
+                        None => send_http_error(HTTP_NOT_FOUND, "no such conversation"),
+                    }
+                }
+                _ => send_http_response(HTTP_METHOD_NOT_ALLOWED, None),
+            }
+        }
+        ("GET", HTTP_PROJECTS_PATH) => {
+            let projects = get_projects(store)?;
+            send_http_json_tagged(HTTP_OK, projects.revision, Some(&projects.value))
+        }
+        ("PUT", HTTP_PROJECTS_PATH) => {
+            let Some(IfMatch(expected)) = read_if_match(http_request)? else {
+                return Ok(());
+            };
+            let Some(projects) = read_json_body::<Projects>()? else {
+                return Ok(());
+            };
+            let result = set_projects(store, projects, expected);
+            if result.is_ok() {
+                sync.publish(store, ChangeEvent::ProjectsChanged, now);
+            }
+            send_write_result("PUT /api/projects", result)
+        }
+        _ => send_http_response(HTTP_NOT_FOUND, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    fn new_conversation(title: &str, project_id: Option<&str>) -> NewConversation {
+        NewConversation {
+            title: title.to_string(),
+            project_id: project_id.map(str::to_string),
+            settings: serde_json::Value::Null,
+        }
+    }
+
+    fn text(role: &str, text: &str) -> NewMessage {
+        NewMessage {
+            role: role.to_string(),
+            content: serde_json::json!(text),
+            served_by: None,
+        }
+    }
+
+    #[test]
+    fn append_and_page_messages() {
+        let store = MemoryStore::default();
+        let id = create(&store, new_conversation("hi", None), 1)
+            .unwrap()
+            .value
+            .id;
+        for n in 0..5 {
+            let (message, revision) = append(&store, &id, text("user", &format!("m{n}")), 2 + n)
+                .unwrap()
+                .unwrap();
+            assert_eq!(message.seq, n);
+            assert_eq!(revision, n + 2);
+        }
+
+        let page = Page {
+            offset: 3,
+            limit: 10,
+        };
+        let messages = messages(&store, &id, page).unwrap().unwrap();
+        assert_eq!(messages.total, 5);
+        assert_eq!(messages.next_offset, None);
+        let contents: Vec<_> = messages.items.iter().map(|m| m.content.clone()).collect();
+        assert_eq!(
+            contents,
+            vec![serde_json::json!("m3"), serde_json::json!("m4")]
+        );
+
+        let page = Page {
+            offset: 0,
+            limit: 2,
+        };
+        assert_eq!(
+            super::messages(&store, &id, page)
+                .unwrap()
+                .unwrap()
+                .next_offset,
+            Some(2)
+        );
+        assert!(append(&store, "nope", text("user", "x"), 9)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn ids_that_new_id_cannot_make_are_not_found() {
+        let store = MemoryStore::default();
+        let id = create(&store, new_conversation("hi", None), 1)
+            .unwrap()
+            .value
+            .id;
+        append(&store, &id, text("user", "hello"), 2).unwrap();
+
+        let aliased = format!("{id}:message:0");
+        assert!(get(&store, &aliased).unwrap().is_none());
+        assert!(message(&store, &aliased, 0).unwrap().is_none());
+        assert!(!delete(&store, &aliased, None).unwrap());
+        assert!(get(&store, &id.to_uppercase()).unwrap().is_none());
+        assert!(get(&store, &id).unwrap().is_some());
+    }
+
+    #[test]
+    fn list_is_most_recent_first_and_filters_by_project() {
+        let store = MemoryStore::default();
+        let a = create(&store, new_conversation("a", Some("p1")), 1)
+            .unwrap()
+            .value
+            .id;
+        create(&store, new_conversation("b", None), 2).unwrap();
+        append(&store, &a, text("user", "bump"), 3).unwrap();
+
+        let all = list(&store, None, Page::from_query(&HashMap::new()).unwrap()).unwrap();
+        let titles: Vec<_> = all.items.iter().map(|s| s.title.as_str()).collect();
+        assert_eq!(titles, vec!["a", "b"]);
+        assert_eq!(all.items[0].message_count, 1);
+
+        let page = Page {
+            offset: 0,
+            limit: 10,
+        };
+        let p1 = list(&store, Some("p1"), page).unwrap();
+        assert_eq!(p1.total, 1);
+        assert_eq!(p1.items[0].id, a);
+    }
+
+    #[test]
+    fn delete_removes_messages_and_respects_revision() {
+        let store = MemoryStore::default();
+        let id = create(&store, new_conversation("a", None), 1)
+            .unwrap()
+            .value
+            .id;
+        append(&store, &id, text("user", "x"), 2).unwrap();
+
+        assert!(delete(&store, &id, Some(1)).is_err());
+        assert!(delete(&store, &id, Some(2)).unwrap());
+        assert!(!delete(&store, &id, None).unwrap());
+        // Only the (now empty) index is left
+        assert_eq!(
+            store.entries.borrow().keys().collect::<Vec<_>>(),
+            vec![CONVERSATIONS_KEY]
+        );
+    }
+
+    #[test]
+    fn page_params_are_checked() {
+        let query = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        assert_eq!(
+            Page::from_query(&query(&[("offset", "10"), ("limit", "5")])),
+            Ok(Page {
+                offset: 10,
+                limit: 5
+            })
+        );
+        assert!(Page::from_query(&query(&[("limit", "0")])).is_err());
+        assert!(Page::from_query(&query(&[("limit", "1000")])).is_err());
+        assert!(Page::from_query(&query(&[("offset", "-1")])).is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! At-rest encryption for secrets kibitz keeps in KV.
+//!
+//! The key is generated on first use and kept in a VFS drive of its own,
+//! apart from the KV db, so a copy of the db (or a backup of it) alone does
+//! not reveal any secrets.
+//!
+//! Backups that must be readable on another node are sealed instead with a
+//! key derived from a passphrase (`passphrase_key`).
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::{Hmac, Mac};
+use hyperware_process_lib::vfs::{create_drive, open_file};
+use hyperware_process_lib::PackageId;
+use rand::RngCore;
+use sha2::Sha256;
+
+const KEY_DRIVE: &str = "secrets";
+const KEY_FILE: &str = "kv-key";
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+pub const SALT_LEN: usize = 16;
+
+/// Marks a value produced by `Cipher::seal`.
+pub const SEALED_PREFIX: &str = "sealed:";
+
+pub struct Cipher(ChaCha20Poly1305);
+
+impl Cipher {
+    pub fn new(key: &[u8; KEY_LEN]) -> Self {
+        Self(ChaCha20Poly1305::new(Key::from_slice(key)))
+    }
+
+    /// Load the node-local key from VFS, generating it on first run.
+    pub fn load_or_create(package_id: PackageId) -> anyhow::Result<Self> {
+        let drive = create_drive(package_id, KEY_DRIVE, None)?;
+        let file = open_file(&format!("{drive}/{KEY_FILE}"), true, None)?;
+        let key = file.read()?;
+        if key.is_empty() {
+            let mut key = [0u8; KEY_LEN];
+            rand::thread_rng().fill_bytes(&mut key);
+            file.write(&key)?;
+            return Ok(Self::new(&key));
+        }
+        let key: [u8; KEY_LEN] = key
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("encryption key in {drive}/{KEY_FILE} is corrupt"))?;
+        Ok(Self::new(&key))
+    }
+
+    /// Encrypt `plaintext`, binding it to `context` (e.g. the provider name)
+    /// so sealed values can't be swapped between slots.
+    pub fn seal(&self, plaintext: &[u8], context: &[u8]) -> anyhow::Result<String> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = self
+            .0
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: context,
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("encryption failed"))?;
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        Ok(format!("{SEALED_PREFIX}{}", BASE64.encode(sealed)))
+    }
+
+    pub fn open(&self, sealed: &str, context: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let encoded = sealed
+            .strip_prefix(SEALED_PREFIX)
+            .ok_or_else(|| anyhow::anyhow!("value is not sealed"))?;
+        let sealed = BASE64.decode(encoded)?;
+        anyhow::ensure!(sealed.len() > NONCE_LEN, "sealed value is truncated");
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.0
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: context,
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("decryption failed: wrong key or corrupt value"))
+    }
+}
+
+pub fn is_sealed(value: &str) -> bool {
+    value.starts_with(SEALED_PREFIX)
+}
+
+/// A fresh random salt for `passphrase_key`.
+pub fn new_salt() -> [u8; SALT_LEN] {
+    let mut salt = [0u8; SALT_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    salt
+}
+
+/// Derive a key from `passphrase` with PBKDF2-HMAC-SHA256. A key is one
+/// SHA-256 block, so only the first PBKDF2 block is needed.
+pub fn passphrase_key(passphrase: &str, salt: &[u8], iterations: u32) -> [u8; KEY_LEN] {
+    let prf = <Hmac<Sha256> as Mac>::new_from_slice(passphrase.as_bytes())
+        .expect("HMAC takes keys of any length");
+    let mut block = prf.clone();
+    block.update(salt);
+    block.update(&1u32.to_be_bytes());
+    let mut u: [u8; KEY_LEN] = block.finalize().into_bytes().into();
+    let mut key = u;
+    for _ in 1..iterations {
+        let mut next = prf.clone();
+        next.update(&u);
+        u = next.finalize().into_bytes().into();
+        for (k, u) in key.iter_mut().zip(u) {
+            *k ^= u;
+        }
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_round_trip() {
+        let cipher = Cipher::new(&[7; KEY_LEN]);
+        let sealed = cipher.seal(b"sk-secret", b"anthropic").unwrap();
+        assert!(is_sealed(&sealed));
+        assert!(!sealed.contains("sk-secret"));
+        assert_eq!(cipher.open(&sealed, b"anthropic").unwrap(), b"sk-secret");
+
+        // Fresh nonce every time
+        assert_ne!(sealed, cipher.seal(b"sk-secret", b"anthropic").unwrap());
+    }
+
+    #[test]
+    fn open_rejects_wrong_context_or_key() {
+        let cipher = Cipher::new(&[7; KEY_LEN]);
+        let sealed = cipher.seal(b"sk-secret", b"anthropic").unwrap();
+        assert!(cipher.open(&sealed, b"openai").is_err());
+        assert!(Cipher::new(&[8; KEY_LEN])
+            .open(&sealed, b"anthropic")
+            .is_err());
+        assert!(cipher.open("sk-secret", b"anthropic").is_err());
+    }
+
+    #[test]
+    fn passphrase_key_matches_pbkdf2() {
+        // RFC 7914, section 11
+        assert_eq!(
+            passphrase_key("passwd", b"salt", 1),
+            [
+                0x55, 0xac, 0x04, 0x6e, 0x56, 0xe3, 0x08, 0x9f, 0xec, 0x16, 0x91, 0xc2, 0x25, 0x44,
+                0xb6, 0x05, 0xf9, 0x41, 0x85, 0x21, 0x6d, 0xde, 0x04, 0x65, 0xe6, 0x8b, 0x9d, 0x57,
+                0xc2, 0x0d, 0xac, 0xbc,
+            ]
+        );
+        assert_eq!(
+            passphrase_key("Password", b"NaCl", 80000)[..4],
+            [0x4d, 0xdc, 0xd8, 0xf6]
+        );
+    }
+}
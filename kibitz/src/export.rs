@@ -0,0 +1,660 @@
+//! Exporting conversations, singly or a whole project, as a versioned JSON
+//! bundle or readable Markdown, and importing bundles back.
+//!
+//! Imports never overwrite: every conversation gets a fresh id, and bundle
+//! projects whose id is taken locally by a different project get one too.
+//! A conversation whose title and messages match one already here (one
+//! imported before, or the original it was exported from) is skipped.
+//! What was imported is remembered under `import:{fingerprint}`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use hyperware_process_lib::http::server::{HttpResponse, IncomingHttpRequest};
+use serde::{Deserialize, Serialize};
+
+use crate::conversations::{self, ChatMessage, Conversation, Page, Project};
+use crate::http::{
+    read_json_body, send_http, send_http_error, send_http_json, send_http_response, url_param,
+    HTTP_BAD_REQUEST, HTTP_NOT_FOUND, HTTP_OK,
+};
+use crate::store::{self, Store};
+use crate::sync::{ChangeEvent, SyncClients};
+
+pub const HTTP_CONVERSATION_EXPORT_PATH: &str = "/api/conversations/:id/export";
+pub const HTTP_PROJECT_EXPORT_PATH: &str = "/api/projects/:id/export";
+pub const HTTP_IMPORT_PATH: &str = "/api/import";
+
+pub const BUNDLE_FORMAT: &str = "kibitz-bundle";
+/// Bump when the bundle layout changes; imports refuse newer bundles.
+pub const BUNDLE_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Bundle {
+    pub format: String,
+    pub version: u32,
+    pub exported_at: u64,
+    /// The projects the conversations belong to, by id.
+    #[serde(default)]
+    pub projects: BTreeMap<String, Project>,
+    pub conversations: Vec<BundledConversation>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BundledConversation {
+    #[serde(flatten)]
+    pub conversation: Conversation,
+    pub messages: Vec<ChatMessage>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Imported {
+    /// The conversation's id in the bundle.
+    pub from: String,
+    /// Its id here.
+    pub id: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub imported: Vec<Imported>,
+    /// Conversations already here, with the id of the copy kept.
+    pub duplicates: Vec<Imported>,
+    /// Projects from the bundle that weren't here, by their id here.
+    pub added_projects: Vec<String>,
+    /// Bundle project ids that were taken and so changed, old to new.
+    pub remapped_projects: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Markdown,
+}
+
+impl Format {
+    /// `?format=json` (the default) or `?format=markdown`.
+    pub fn from_query(query: &std::collections::HashMap<String, String>) -> Result<Self, String> {
+        match query.get("format").map(String::as_str) {
+            None | Some("json") => Ok(Self::Json),
+            Some("markdown" | "md") => Ok(Self::Markdown),
+            Some(other) => Err(format!("unknown format {other}: use json or markdown")),
+        }
+    }
+}
+
+fn import_key(fingerprint: &str) -> String {
+    format!("import:{fingerprint}")
+}
+
+/// Identifies a conversation by its title and messages, whatever its id.
+pub fn fingerprint(title: &str, messages: &[ChatMessage]) -> anyhow::Result<String> {
+    let content: Vec<_> = messages
+        .iter()
+        .map(|message| (&message.role, &message.content, message.created_at))
+        .collect();
+    // FNV-1a: stable across builds, unlike `DefaultHasher`
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in serde_json::to_vec(&(title, content))? {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    Ok(format!("{hash:016x}"))
+}
+
+fn all_messages(
+    store: &dyn Store,
+    conversation: &Conversation,
+) -> anyhow::Result<Vec<ChatMessage>> {
+    let page = Page {
+        offset: 0,
+        limit: conversation.message_count as usize,
+    };
+    Ok(conversations::messages(store, &conversation.id, page)?
+        .map(|messages| messages.items)
+        .unwrap_or_default())
+}
+
+fn bundle(store: &dyn Store, conversations: Vec<Conversation>, now: u64) -> anyhow::Result<Bundle> {
+    let all_projects = conversations::get_projects(store)?.value.projects;
+    let mut bundle = Bundle {
+        format: BUNDLE_FORMAT.to_string(),
+        version: BUNDLE_VERSION,
+        exported_at: now,
+        projects: BTreeMap::new(),
+        conversations: vec![],
+    };
+    for conversation in conversations {
+        if let Some(project_id) = &conversation.project_id {
+            if let Some(project) = all_projects.get(project_id) {
+                bundle.projects.insert(project_id.clone(), project.clone());
+            }
+        }
+        let messages = all_messages(store, &conversation)?;
+        bundle.conversations.push(BundledConversation {
+            conversation,
+            messages,
+        });
+    }
+    Ok(bundle)
+}
+
+/// Every conversation (in `project_id`, if given), oldest first.
+fn oldest_first(store: &dyn Store, project_id: Option<&str>) -> anyhow::Result<Vec<Conversation>> {
+    let everything = Page {
+        offset: 0,
+        limit: usize::MAX,
+    };
+    let mut summaries = conversations::list(store, project_id, everything)?.items;
+    summaries.reverse();
+    summaries
+        .into_iter()
+        .filter_map(|summary| conversations::get(store, &summary.id).transpose())
+        .map(|conversation| conversation.map(|doc| doc.value))
+        .collect()
+}
+
+/// A bundle of one conversation, or `None` if there is no such conversation.
+pub fn export_conversation(
+    store: &dyn Store,
+    id: &str,
+    now: u64,
+) -> anyhow::Result<Option<Bundle>> {
+    let Some(conversation) = conversations::get(store, id)? else {
+        return Ok(None);
+    };
+    bundle(store, vec![conversation.value], now).map(Some)
+}
+
+/// A bundle of every conversation in a project, oldest first, or `None` if
+/// there is no such project.
+pub fn export_project(
+    store: &dyn Store,
+    project_id: &str,
+    now: u64,
+) -> anyhow::Result<Option<Bundle>> {
+    let projects = conversations::get_projects(store)?.value.projects;
+    let Some(project) = projects.get(project_id) else {
+        return Ok(None);
+    };
+    let conversations = oldest_first(store, Some(project_id))?;
+    let mut bundle = bundle(store, conversations, now)?;
+    // Include the project even when it has no conversations yet
+    bundle
+        .projects
+        .insert(project_id.to_string(), project.clone());
+    Ok(Some(bundle))
+}
+
+/// A bundle of every conversation, oldest first, and every project.
+pub fn export_all(store: &dyn Store, now: u64) -> anyhow::Result<Bundle> {
+    let mut bundle = bundle(store, oldest_first(store, None)?, now)?;
+    bundle.projects = conversations::get_projects(store)?.value.projects;
+    Ok(bundle)
+}
+
+/// Why `bundle` can't be imported here, if it can't.
+pub fn check_bundle(bundle: &Bundle) -> Result<(), String> {
+    if bundle.format != BUNDLE_FORMAT {
+        return Err(format!("not a kibitz bundle: format is {}", bundle.format));
+    }
+    if bundle.version > BUNDLE_VERSION {
+        return Err(format!(
+            "bundle version {} is newer than supported version {BUNDLE_VERSION}",
+            bundle.version
+        ));
+    }
+    Ok(())
+}
+
+/// The local copy of a bundled conversation, if there is one.
+fn find_duplicate(
+    store: &dyn Store,
+    bundled: &BundledConversation,
+    fingerprint: &str,
+) -> anyhow::Result<Option<String>> {
+    if let Some(id) = store::get_json::<String>(store, &import_key(fingerprint))? {
+        if conversations::get(store, &id)?.is_some() {
+            return Ok(Some(id));
+        }
+    }
+    // Exported from here and the original is still around
+    let Some(original) = conversations::get(store, &bundled.conversation.id)? else {
+        return Ok(None);
+    };
+    let messages = all_messages(store, &original.value)?;
+    Ok(
+        (self::fingerprint(&original.value.title, &messages)? == fingerprint)
+            .then_some(original.value.id),
+    )
+}
+
+/// Import a bundle. Errors (other than from the store) are the bundle's
+/// fault, so `Err(message)` is for the client.
+pub fn import(store: &dyn Store, bundle: Bundle) -> anyhow::Result<Result<ImportReport, String>> {
+    if let Err(message) = check_bundle(&bundle) {
+        return Ok(Err(message));
+    }
+    let mut report = ImportReport::default();
+
+    let mut projects = conversations::get_projects(store)?;
+    let mut project_ids = BTreeMap::new();
+    for (id, project) in bundle.projects {
+        let local_id = match projects.value.projects.get(&id) {
+            Some(local) if local.name == project.name => {
+                project_ids.insert(id.clone(), id);
+                continue;
+            }
+            Some(_) => {
+                let local_id = conversations::new_id();
+                report
+                    .remapped_projects
+                    .insert(id.clone(), local_id.clone());
+                local_id
+            }
+            None => id.clone(),
+        };
+        projects.value.projects.insert(local_id.clone(), project);
+        report.added_projects.push(local_id.clone());
+        project_ids.insert(id, local_id);
+    }
+    if !report.added_projects.is_empty() {
+        store::put_revisioned(store, conversations::PROJECTS_KEY, &mut projects, None)?;
+    }
+
+    for bundled in bundle.conversations {
+        let fingerprint = fingerprint(&bundled.conversation.title, &bundled.messages)?;
+        let from = bundled.conversation.id.clone();
+        if let Some(id) = find_duplicate(store, &bundled, &fingerprint)? {
+            report.duplicates.push(Imported { from, id });
+            continue;
+        }
+        let mut conversation = bundled.conversation;
+        conversation.project_id = conversation
+            .project_id
+            .map(|id| project_ids.get(&id).cloned().unwrap_or(id));
+        // Comes in on its own, not as part of a tree of branches
+        conversation.forked_from = None;
+        let conversation = conversations::restore(store, conversation, bundled.messages)?;
+        let id = conversation.value.id;
+        store::set_json(store, &import_key(&fingerprint), &id)?;
+        report.imported.push(Imported { from, id });
+    }
+    Ok(Ok(report))
+}
+
+/// Tell sync clients what an import added.
+pub fn publish_import(store: &dyn Store, sync: &mut SyncClients, report: &ImportReport, now: u64) {
+    for Imported { id, .. } in &report.imported {
+        let id = id.clone();
+        sync.publish(store, ChangeEvent::ConversationCreated { id }, now);
+    }
+    if !report.added_projects.is_empty() {
+        sync.publish(store, ChangeEvent::ProjectsChanged, now);
+    }
+}
+
+/// The `(year, month, day)` of `days` since the epoch, after Howard
+/// Hinnant's `civil_from_days`.
+pub fn civil_from_days(days: u64) -> (i64, u32, u32) {
+    let days = days as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month as u32, day as u32)
+}
+
+/// `secs` since the epoch as `YYYY-MM-DD HH:MM UTC`.
+pub fn format_time(secs: u64) -> String {
+    let (year, month, day) = civil_from_days(secs / 86400);
+    let minutes = secs % 86400 / 60;
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02} UTC",
+        minutes / 60,
+        minutes % 60
+    )
+}
+
+/// `text` in a code fence that none of its own backticks can close.
+fn fenced(out: &mut String, language: &str, text: &str) {
+    let mut longest = 0;
+    let mut run = 0;
+    for c in text.chars() {
+        run = if c == '`' { run + 1 } else { 0 };
+        longest = longest.max(run);
+    }
+    let fence = "`".repeat((longest + 1).max(3));
+    let _ = writeln!(out, "{fence}{language}\n{}\n{fence}\n", text.trim_end());
+}
+
+fn pretty(value: &serde_json::Value) -> String {
+    serde_json::to_string_pretty(value).unwrap_or_default()
+}
+
+/// One content block (or plain string content) as Markdown.
+fn render_block(out: &mut String, block: &serde_json::Value) {
+    use serde_json::Value;
+    let field = |name: &str| block.get(name).and_then(Value::as_str).unwrap_or_default();
+    match block {
+        Value::String(text) => {
+            let _ = writeln!(out, "{}\n", text.trim_end());
+        }
+        Value::Array(blocks) => {
+            for block in blocks {
+                render_block(out, block);
+            }
+        }
+        _ => match field("type") {
+            "text" => {
+                let _ = writeln!(out, "{}\n", field("text").trim_end());
+            }
+            "thinking" => {
+                let _ = writeln!(
+                    out,
+                    "<details><summary>Thinking</summary>\n\n{}\n\n</details>\n",
+                    field("thinking").trim_end()
+                );
+            }
+            "tool_use" => {
+                let _ = writeln!(out, "**Tool call** `{}` ({})\n", field("name"), field("id"));
+                fenced(out, "json", &pretty(&block["input"]));
+            }
+            "tool_result" => {
+                let error = if block["is_error"] == true {
+                    ", error"
+                } else {
+                    ""
+                };
+                let _ = writeln!(out, "**Tool result** ({}{error})\n", field("tool_use_id"));
+                match &block["content"] {
+                    Value::String(text) => fenced(out, "", text),
+                    Value::Array(blocks) => {
+                        for block in blocks {
+                            match block.get("text").and_then(Value::as_str) {
+                                Some(text) => fenced(out, "", text),
+                                None => fenced(out, "json", &pretty(block)),
+                            }
+                        }
+                    }
+                    Value::Null => {}
+                    other => fenced(out, "json", &pretty(other)),
+                }
+            }
+            "image" => out.push_str("_[image]_\n\n"),
+            _ => fenced(out, "json", &pretty(block)),
+        },
+    }
+}
+
+fn render_conversation(
+    out: &mut String,
+    conversation: &BundledConversation,
+    project: Option<&Project>,
+    level: usize,
+) {
+    let heading = "#".repeat(level);
+    let BundledConversation {
+        conversation,
+        messages,
+    } = conversation;
+    let title = match conversation.title.as_str() {
+        "" => "Untitled conversation",
+        title => title,
+    };
+    let _ = writeln!(out, "{heading} {title}\n");
+    let _ = writeln!(out, "- Created: {}", format_time(conversation.created_at));
+    if let Some(project) = project {
+        let _ = writeln!(out, "- Project: {}", project.name);
+    }
+    if let Some(model) = conversation.settings.get("model").and_then(|m| m.as_str()) {
+        let _ = writeln!(out, "- Model: {model}");
+    }
+    out.push('\n');
+    for message in messages {
+        let mut role = message.role.clone();
+        if let Some(first) = role.get_mut(..1) {
+            first.make_ascii_uppercase();
+        }
+        let _ = writeln!(
+            out,
+            "{heading}# {role} · {}\n",
+            format_time(message.created_at)
+        );
+        render_block(out, &message.content);
+    }
+}
+
+/// A bundle as Markdown: each conversation under its title, or, for a
+/// project, under the project's name.
+pub fn to_markdown(bundle: &Bundle, project_id: Option<&str>) -> String {
+    let mut out = String::new();
+    let project = |id: Option<&String>| id.and_then(|id| bundle.projects.get(id));
+    let level = match project_id.and_then(|id| bundle.projects.get(id)) {
+        Some(project) => {
+            let _ = writeln!(out, "# {}\n", project.name);
+            2
+        }
+        None => 1,
+    };
+    for (n, conversation) in bundle.conversations.iter().enumerate() {
+        if n > 0 {
+            out.push_str("---\n\n");
+        }
+        let project = project(conversation.conversation.project_id.as_ref());
+        render_conversation(&mut out, conversation, project, level);
+    }
+    out
+}
+
+fn send_export(
+    bundle: &Bundle,
+    format: Format,
+    name: &str,
+    project_id: Option<&str>,
+) -> anyhow::Result<()> {
+    let (content_type, extension, body) = match format {
+        Format::Json => (
+            "application/json",
+            "json",
+            serde_json::to_vec_pretty(bundle)?,
+        ),
+        Format::Markdown => (
+            "text/markdown; charset=utf-8",
+            "md",
+            to_markdown(bundle, project_id).into_bytes(),
+        ),
+    };
+    send_http(
+        HttpResponse::new(HTTP_OK)
+            .header("Content-Type", content_type)
+            .header(
+                "Content-Disposition",
+                format!("attachment; filename=\"kibitz-{name}.{extension}\""),
+            ),
+        Some(body),
+    )
+}
+
+/// Serve the export and import endpoints.
+pub fn handle_http(
+    store: &dyn Store,
+    sync: &mut SyncClients,
+    http_request: &IncomingHttpRequest,
+    method: &str,
+    bound_path: &str,
+    now: u64,
+) -> anyhow::Result<()> {
+    match (method, bound_path) {
+        ("GET", HTTP_CONVERSATION_EXPORT_PATH | HTTP_PROJECT_EXPORT_PATH) => {
+            let Some(id) = url_param(http_request, "id") else {
+                return send_http_error(HTTP_BAD_REQUEST, "missing id");
+            };
+            let format = match Format::from_query(http_request.query_params()) {
+                Ok(format) => format,
+                Err(message) => return send_http_error(HTTP_BAD_REQUEST, &message),
+            };
+            if bound_path == HTTP_PROJECT_EXPORT_PATH {
+                match export_project(store, id, now)? {
+                    Some(bundle) => send_export(&bundle, format, id, Some(id)),
+                    None => send_http_error(HTTP_NOT_FOUND, "no such project"),
+                }
+            } else {
+                match export_conversation(store, id, now)? {
+                    Some(bundle) => send_export(&bundle, format, id, None),
+                    None => send_http_error(HTTP_NOT_FOUND, "no such conversation"),
+                }
+            }
+        }
+        ("POST", HTTP_IMPORT_PATH) => {
+            let Some(bundle) = read_json_body::<Bundle>()? else {
+                return Ok(());
+            };
+            let report = match import(store, bundle)? {
+                Ok(report) => report,
+                Err(message) => return send_http_error(HTTP_BAD_REQUEST, &message),
+            };
+            publish_import(store, sync, &report, now);
+            send_http_json(HTTP_OK, &report)
+        }
+        _ => send_http_response(HTTP_NOT_FOUND, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversations::{append, create, NewConversation, NewMessage};
+    use crate::store::MemoryStore;
+
+    fn sample(store: &MemoryStore) -> String {
+        let mut projects = conversations::get_projects(store).unwrap().value;
+        projects.projects.insert(
+            "p1".to_string(),
+            Project {
+                name: "Migrations".to_string(),
+                settings: serde_json::Value::Null,
+            },
+        );
+        conversations::set_projects(store, projects, None).unwrap();
+        let new = NewConversation {
+            title: "Fix v2".to_string(),
+            project_id: Some("p1".to_string()),
+            settings: serde_json::json!({ "model": "claude" }),
+        };
+        let id = create(store, new, 86400).unwrap().value.id;
+        let messages = [
+            ("user", serde_json::json!("Why does the migration fail?")),
+            (
+                "assistant",
+                serde_json::json!([
+                    {"type": "text", "text": "Let me look."},
+                    {"type": "tool_use", "id": "t1", "name": "read_file", "input": {"path": "store.rs"}},
+                ]),
+            ),
+            (
+                "user",
+                serde_json::json!([
+                    {"type": "tool_result", "tool_use_id": "t1", "content": "fn v1_to_v2() ```"},
+                ]),
+            ),
+        ];
+        for (n, (role, content)) in messages.into_iter().enumerate() {
+            let new = NewMessage {
+                role: role.to_string(),
+                content,
+                served_by: None,
+            };
+            append(store, &id, new, 86400 + n as u64 * 60).unwrap();
+        }
+        id
+    }
+
+    #[test]
+    fn import_remaps_ids_and_skips_duplicates() {
+        let here = MemoryStore::default();
+        let id = sample(&here);
+        let exported =
+            serde_json::to_vec(&export_project(&here, "p1", 9).unwrap().unwrap()).unwrap();
+        let parse = || serde_json::from_slice::<Bundle>(&exported).unwrap();
+
+        // Back into the store it came from: the original is still here
+        let report = import(&here, parse()).unwrap().unwrap();
+        assert_eq!(report.imported, vec![]);
+        assert_eq!(report.duplicates[0].id, id);
+
+        let there = MemoryStore::default();
+        let mut projects = conversations::get_projects(&there).unwrap().value;
+        projects.projects.insert(
+            "p1".to_string(),
+            Project {
+                name: "Something else".to_string(),
+                settings: serde_json::Value::Null,
+            },
+        );
+        conversations::set_projects(&there, projects, None).unwrap();
+        let report = import(&there, parse()).unwrap().unwrap();
+        assert_eq!(report.imported.len(), 1);
+        assert_ne!(report.imported[0].id, id);
+        let new_project = &report.remapped_projects["p1"];
+        let copy = conversations::get(&there, &report.imported[0].id)
+            .unwrap()
+            .unwrap()
+            .value;
+        assert_eq!(copy.project_id.as_ref(), Some(new_project));
+        assert_eq!(copy.message_count, 3);
+        assert_eq!(copy.created_at, 86400);
+        assert_eq!(
+            conversations::get_projects(&there).unwrap().value.projects[new_project].name,
+            "Migrations"
+        );
+
+        // And again: now it is a duplicate of the first import
+        let report = import(&there, parse()).unwrap().unwrap();
+        assert_eq!(report.imported, vec![]);
+        assert_eq!(report.duplicates.len(), 1);
+    }
+
+    #[test]
+    fn newer_bundles_are_refused() {
+        let store = MemoryStore::default();
+        let bundle = Bundle {
+            format: BUNDLE_FORMAT.to_string(),
+            version: BUNDLE_VERSION + 1,
+            exported_at: 0,
+            projects: BTreeMap::new(),
+            conversations: vec![],
+        };
+        assert!(import(&store, bundle).unwrap().is_err());
+    }
+
+    #[test]
+    fn markdown_shows_tool_calls_and_results() {
+        let store = MemoryStore::default();
+        let id = sample(&store);
+        let bundle = export_conversation(&store, &id, 9).unwrap().unwrap();
+        let markdown = to_markdown(&bundle, None);
+        assert!(markdown.starts_with(
+            "# Fix v2\n\n- Created: 1970-01-02 00:00 UTC\n- Project: Migrations\n- Model: claude\n"
+        ));
+        assert!(
+            markdown.contains("## User · 1970-01-02 00:00 UTC\n\nWhy does the migration fail?\n")
+        );
+        assert!(markdown.contains(
+            "**Tool call** `read_file` (t1)\n\n```json\n{\n  \"path\": \"store.rs\"\n}\n```\n"
+        ));
+        // The fence outgrows the backticks inside
+        assert!(markdown.contains("**Tool result** (t1)\n\n````\nfn v1_to_v2() ```\n````\n"));
+    }
+
+    #[test]
+    fn times_are_formatted_as_utc() {
+        assert_eq!(format_time(0), "1970-01-01 00:00 UTC");
+        assert_eq!(format_time(1_709_210_096), "2024-02-29 12:34 UTC");
+    }
+}
@@ -0,0 +1,155 @@
+//! Helpers for answering `http-server` requests.
+
+use hyperware_process_lib::http::server::{HttpResponse, IncomingHttpRequest};
+use hyperware_process_lib::logging::{error, info};
+use hyperware_process_lib::{last_blob, Response};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::store;
+
+// HTTP status codes as u16
+pub const HTTP_OK: u16 = 200;
+pub const HTTP_CREATED: u16 = 201;
+pub const HTTP_NO_CONTENT: u16 = 204;
+pub const HTTP_BAD_REQUEST: u16 = 400;
+pub const HTTP_UNAUTHORIZED: u16 = 401;
+pub const HTTP_PAYMENT_REQUIRED: u16 = 402;
+pub const HTTP_NOT_FOUND: u16 = 404;
+pub const HTTP_METHOD_NOT_ALLOWED: u16 = 405;
+pub const HTTP_CONFLICT: u16 = 409;
+pub const HTTP_PRECONDITION_FAILED: u16 = 412;
+pub const HTTP_PRECONDITION_REQUIRED: u16 = 428;
+pub const HTTP_SERVER_ERROR: u16 = 500;
+pub const HTTP_BAD_GATEWAY: u16 = 502;
+pub const HTTP_SERVICE_UNAVAILABLE: u16 = 503;
+
+pub fn send_http_response(status: u16, body: Option<Vec<u8>>) -> anyhow::Result<()> {
+    send_http(HttpResponse::new(status), body)
+}
+
+pub fn send_http(http_response: HttpResponse, body: Option<Vec<u8>>) -> anyhow::Result<()> {
+    let mut response = Response::new().body(serde_json::to_vec(&http_response)?);
+    if let Some(body) = body {
+        response = response.blob_bytes(body);
+    }
+    response.send()?;
+    Ok(())
+}
+
+pub fn send_http_json<T: Serialize>(status: u16, body: &T) -> anyhow::Result<()> {
+    send_http_response(status, Some(serde_json::to_vec(body)?))
+}
+
+/// Error responses carry `{"error": message}` so the UI can show why.
+pub fn send_http_error(status: u16, message: &str) -> anyhow::Result<()> {
+    send_http_json(status, &serde_json::json!({ "error": message }))
+}
+
+/// The `ETag` for a document at `revision`.
+pub fn etag(revision: u64) -> String {
+    format!("\"{revision}\"")
+}
+
+/// Answer with the document's revision as its `ETag`, so the client can
+/// send it back in `If-Match`.
+pub fn send_http_json_tagged<T: Serialize>(
+    status: u16,
+    revision: u64,
+    body: Option<&T>,
+) -> anyhow::Result<()> {
+    let body = body.map(serde_json::to_vec).transpose()?;
+    send_http(
+        HttpResponse::new(status).header("ETag", etag(revision)),
+        body,
+    )
+}
+
+/// The revision a write's `If-Match` names; `None` for `*`.
+pub struct IfMatch(pub Option<u64>);
+
+/// Read `If-Match`, which every write to a revisioned document must carry.
+/// Answers 428 if it is missing or 400 if it isn't an ETag we issued (in
+/// which case `None` is returned and the caller is done).
+pub fn read_if_match(http_request: &IncomingHttpRequest) -> anyhow::Result<Option<IfMatch>> {
+    let headers = http_request.headers();
+    let Some(value) = headers.get("if-match") else {
+        send_http_error(
+            HTTP_PRECONDITION_REQUIRED,
+            "If-Match is required: send the ETag from your last GET",
+        )?;
+        return Ok(None);
+    };
+    let value = value.to_str().unwrap_or_default().trim();
+    if value == "*" {
+        return Ok(Some(IfMatch(None)));
+    }
+    let revision = value
+        .strip_prefix("W/")
+        .unwrap_or(value)
+        .trim_matches('"')
+        .parse();
+    match revision {
+        Ok(revision) => Ok(Some(IfMatch(Some(revision)))),
+        Err(_) => {
+            send_http_error(HTTP_BAD_REQUEST, &format!("malformed If-Match: {value}"))?;
+            Ok(None)
+        }
+    }
+}
+
+/// Answer a failed store operation with a 500 carrying the reason.
+pub fn send_store_error(what: &str, e: anyhow::Error) -> anyhow::Result<()> {
+    error!("{what}: {e:?}");
+    send_http_error(HTTP_SERVER_ERROR, &format!("{e:#}"))
+}
+
+/// Answer a write to a revisioned document: its new `ETag`, or 412 with the
+/// current one if the client's `If-Match` was stale.
+pub fn send_write_result(what: &str, result: anyhow::Result<u64>) -> anyhow::Result<()> {
+    match result {
+        Ok(revision) => send_http_json_tagged::<()>(HTTP_OK, revision, None),
+        Err(e) => match e.downcast_ref::<store::Conflict>() {
+            Some(conflict) => {
+                info!("{what}: {conflict}");
+                let body = serde_json::json!({ "error": conflict.to_string() });
+                send_http(
+                    HttpResponse::new(HTTP_PRECONDITION_FAILED)
+                        .header("ETag", etag(conflict.current)),
+                    Some(serde_json::to_vec(&body)?),
+                )
+            }
+            None => send_store_error(what, e),
+        },
+    }
+}
+
+/// Parse the request body as JSON, answering 400 if it is missing or
+/// malformed (in which case `None` is returned and the caller is done).
+pub fn read_json_body<T: DeserializeOwned>() -> anyhow::Result<Option<T>> {
+    parse_json_body(last_blob().map(|blob| blob.bytes).as_deref())
+}
+
+/// As `read_json_body`, for a body read earlier, e.g. of a request held
+/// while its login cookie was checked.
+pub fn parse_json_body<T: DeserializeOwned>(body: Option<&[u8]>) -> anyhow::Result<Option<T>> {
+    let Some(body) = body else {
+        send_http_error(HTTP_BAD_REQUEST, "missing request body")?;
+        return Ok(None);
+    };
+    match serde_json::from_slice(body) {
+        Ok(body) => Ok(Some(body)),
+        Err(e) => {
+            send_http_error(HTTP_BAD_REQUEST, &format!("improper format: {e}"))?;
+            Ok(None)
+        }
+    }
+}
+
+/// A path parameter from a bound path like `/api/keys/:provider`.
+pub fn url_param<'a>(http_request: &'a IncomingHttpRequest, name: &str) -> Option<&'a str> {
+    http_request
+        .url_params()
+        .get(name)
+        .map(String::as_str)
+        .filter(|value| !value.is_empty())
+}
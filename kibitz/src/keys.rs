@@ -0,0 +1,263 @@
+//! Provider API keys, sealed at rest.
+//!
+//! Stored under `API_KEYS_KEY` as `{"revision": n, "keys": {provider:
+//! StoredKey}}`; writes take the revision the caller last saw. Each
+//! key is sealed with the node-local `Cipher`; listing only needs the
+//! plaintext metadata next to it, so keys are decrypted only when one is
+//! actually used.
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::crypto::Cipher;
+use crate::store::{self, Revisioned, Store};
+
+pub const API_KEYS_KEY: &str = "api_keys";
+
+const VISIBLE_SUFFIX_LEN: usize = 4;
+
+/// Plaintext keys, as accepted by the bulk `PUT /api/keys`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ApiKeys {
+    pub keys: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredKey {
+    pub sealed: String,
+    pub last4: String,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct StoredKeys {
+    pub keys: BTreeMap<String, StoredKey>,
+}
+
+/// What `GET /api/keys` reveals about a key.
+#[derive(Debug, Serialize)]
+pub struct KeyInfo {
+    pub masked: String,
+    pub last4: String,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct KeyListing {
+    pub keys: BTreeMap<String, KeyInfo>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetKey {
+    pub key: String,
+}
+
+/// The last few characters, or nothing if the key is too short for that
+/// to be safe to show.
+pub fn last4(key: &str) -> String {
+    let chars: Vec<char> = key.chars().collect();
+    if chars.len() < 2 * VISIBLE_SUFFIX_LEN {
+        return String::new();
+    }
+    chars[chars.len().saturating_sub(VISIBLE_SUFFIX_LEN)..]
+        .iter()
+        .collect()
+}
+
+fn load_stored(store: &dyn Store) -> anyhow::Result<Revisioned<StoredKeys>> {
+    store::get_revisioned(store, API_KEYS_KEY)
+}
+
+fn seal(
+    cipher: &Cipher,
+    provider: &str,
+    key: &str,
+    created_at: u64,
+    now: u64,
+) -> anyhow::Result<StoredKey> {
+    Ok(StoredKey {
+        sealed: cipher.seal(key.as_bytes(), provider.as_bytes())?,
+        last4: last4(key),
+        created_at,
+        updated_at: now,
+    })
+}
+
+pub fn list(store: &dyn Store) -> anyhow::Result<Revisioned<KeyListing>> {
+    let stored = load_stored(store)?;
+    let keys = stored
+        .value
+        .keys
+        .into_iter()
+        .map(|(provider, stored)| {
+            let info = KeyInfo {
+                masked: format!("****{}", stored.last4),
+                last4: stored.last4,
+                created_at: stored.created_at,
+                updated_at: stored.updated_at,
+            };
+            (provider, info)
+        })
+        .collect();
+    Ok(Revisioned {
+        revision: stored.revision,
+        value: KeyListing { keys },
+    })
+}
+
+/// Decrypt the key for one provider.
+pub fn get(store: &dyn Store, cipher: &Cipher, provider: &str) -> anyhow::Result<Option<String>> {
+    let Some(stored) = load_stored(store)?.value.keys.remove(provider) else {
+        return Ok(None);
+    };
+    let key = cipher.open(&stored.sealed, provider.as_bytes())?;
+    Ok(Some(String::from_utf8(key)?))
+}
+
+/// Returns the new revision.
+pub fn set(
+    store: &dyn Store,
+    cipher: &Cipher,
+    provider: &str,
+    key: &str,
+    now: u64,
+    expected: Option<u64>,
+) -> anyhow::Result<u64> {
+    let mut stored = load_stored(store)?;
+    store::check_revision(&stored, expected)?;
+    let created_at = stored
+        .value
+        .keys
+        .get(provider)
+        .map_or(now, |existing| existing.created_at);
+    stored.value.keys.insert(
+        provider.to_string(),
+        seal(cipher, provider, key, created_at, now)?,
+    );
+    store::put_revisioned(store, API_KEYS_KEY, &mut stored, expected)
+}
+
+/// Returns the new revision, or `None` if there was no key to delete.
+pub fn delete(
+    store: &dyn Store,
+    provider: &str,
+    expected: Option<u64>,
+) -> anyhow::Result<Option<u64>> {
+    let mut stored = load_stored(store)?;
+    store::check_revision(&stored, expected)?;
+    if stored.value.keys.remove(provider).is_none() {
+        return Ok(None);
+    }
+    store::put_revisioned(store, API_KEYS_KEY, &mut stored, expected).map(Some)
+}
+
+/// Replace every key; providers that already had a key keep `created_at`.
+/// Returns the new revision.
+pub fn replace_all(
+    store: &dyn Store,
+    cipher: &Cipher,
+    api_keys: &ApiKeys,
+    now: u64,
+    expected: Option<u64>,
+) -> anyhow::Result<u64> {
+    let mut stored = load_stored(store)?;
+    store::check_revision(&stored, expected)?;
+    let keys = api_keys
+        .keys
+        .iter()
+        .map(|(provider, key)| {
+            let created_at = stored
+                .value
+                .keys
+                .get(provider)
+                .map_or(now, |existing| existing.created_at);
+            Ok((
+                provider.clone(),
+                seal(cipher, provider, key, created_at, now)?,
+            ))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    stored.value.keys = keys;
+    store::put_revisioned(store, API_KEYS_KEY, &mut stored, expected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    fn cipher() -> Cipher {
+        Cipher::new(&[1; 32])
+    }
+
+    #[test]
+    fn listing_is_masked() {
+        let store = MemoryStore::default();
+        set(
+            &store,
+            &cipher(),
+            "anthropic",
+            "sk-ant-secret-wxyz",
+            10,
+            None,
+        )
+        .unwrap();
+
+        let raw = store.get(API_KEYS_KEY).unwrap().unwrap();
+        assert!(!String::from_utf8(raw).unwrap().contains("secret"));
+
+        let listing = serde_json::to_string(&list(&store).unwrap()).unwrap();
+        assert!(!listing.contains("secret"));
+        let info = &list(&store).unwrap().value.keys["anthropic"];
+        assert_eq!(info.last4, "wxyz");
+        assert_eq!(info.masked, "****wxyz");
+        assert_eq!(
+            get(&store, &cipher(), "anthropic").unwrap().as_deref(),
+            Some("sk-ant-secret-wxyz")
+        );
+    }
+
+    #[test]
+    fn per_provider_writes_leave_others_alone() {
+        let store = MemoryStore::default();
+        set(&store, &cipher(), "anthropic", "sk-ant", 10, Some(0)).unwrap();
+        set(&store, &cipher(), "openai", "sk-oai", 11, Some(1)).unwrap();
+        set(&store, &cipher(), "anthropic", "sk-ant-2", 12, Some(2)).unwrap();
+
+        let listing = list(&store).unwrap();
+        assert_eq!(listing.revision, 3);
+        let listing = listing.value;
+        assert_eq!(listing.keys["anthropic"].created_at, 10);
+        assert_eq!(listing.keys["anthropic"].updated_at, 12);
+        assert_eq!(listing.keys["openai"].updated_at, 11);
+
+        assert_eq!(delete(&store, "openai", Some(3)).unwrap(), Some(4));
+        assert_eq!(delete(&store, "openai", Some(4)).unwrap(), None);
+        let listing = list(&store).unwrap().value;
+        assert_eq!(listing.keys.keys().collect::<Vec<_>>(), vec!["anthropic"]);
+    }
+
+    #[test]
+    fn stale_writes_are_refused() {
+        let store = MemoryStore::default();
+        set(&store, &cipher(), "anthropic", "sk-ant", 10, Some(0)).unwrap();
+
+        // A tab that loaded the empty set of keys tries to overwrite them
+        let stale = ApiKeys {
+            keys: HashMap::from([("openai".to_string(), "sk-oai".to_string())]),
+        };
+        let e = replace_all(&store, &cipher(), &stale, 11, Some(0)).unwrap_err();
+        assert!(e.downcast_ref::<store::Conflict>().is_some());
+        assert!(delete(&store, "anthropic", Some(0)).is_err());
+        assert!(list(&store).unwrap().value.keys.contains_key("anthropic"));
+    }
+
+    #[test]
+    fn short_keys_are_not_revealed() {
+        assert_eq!(last4("abcdefgh"), "efgh");
+        assert_eq!(last4("abcdefg"), "");
+        assert_eq!(last4(""), "");
+    }
+}
@@ -1,45 +1,550 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hyperware_process_lib::http::client::HttpClientRequest;
 use hyperware_process_lib::http::server::{
-    HttpBindingConfig, HttpResponse, HttpServer, HttpServerRequest,
+    HttpBindingConfig, HttpServer, HttpServerRequest, IncomingHttpRequest, WsBindingConfig,
 };
-use hyperware_process_lib::kv;
-use hyperware_process_lib::logging::{info, init_logging, Level};
+use hyperware_process_lib::logging::{error, info, init_logging, Level};
 use hyperware_process_lib::{
-    await_message, call_init, homepage::add_to_homepage, last_blob, Address, Response,
+    await_message, call_init, homepage::add_to_homepage, last_blob, Address, LazyLoadBlob, Message,
+    Response,
 };
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde::Serialize;
 
-// HTTP status codes as u16
-const HTTP_OK: u16 = 200;
-const HTTP_BAD_REQUEST: u16 = 400;
-const HTTP_SERVER_ERROR: u16 = 500;
+mod agent;
+mod backup;
+mod branches;
+mod budget;
+mod conversations;
+mod crypto;
+mod export;
+mod http;
+mod keys;
+mod llm;
+mod profiles;
+mod prompts;
+mod requests;
+mod routing;
+mod schedule;
+mod search;
+mod store;
+mod sync;
+mod usage;
+mod webhook;
+use crypto::Cipher;
+use http::{
+    read_if_match, read_json_body, send_http_error, send_http_json, send_http_json_tagged,
+    send_http_response, send_store_error, send_write_result, url_param, IfMatch, HTTP_BAD_REQUEST,
+    HTTP_METHOD_NOT_ALLOWED, HTTP_NOT_FOUND, HTTP_OK, HTTP_SERVICE_UNAVAILABLE,
+};
+use keys::{ApiKeys, SetKey};
+use store::KvStore;
+use sync::ChangeEvent;
 
 const HTTP_API_PATH: &str = "/api/keys";
-
-const DB_NAME: &str = "kibitz_api_keys";
+const HTTP_API_KEY_PATH: &str = "/api/keys/:provider";
+const HTTP_STATUS_PATH: &str = "/api/status";
+const HTTP_LLM_PREFIX: &str = "/api/llm";
+const HTTP_LLM_PATH: &str = "/api/llm/:provider/*rest";
+const HTTP_PROVIDERS_PATH: &str = "/api/providers";
+const WS_SYNC_PATH: &str = "/api/sync";
 
 const ICON: &str = include_str!("icon");
 
-#[derive(Debug, Serialize, Deserialize)]
-struct ApiKeys {
-    keys: HashMap<String, String>,
+struct State {
+    our: Address,
+    server: HttpServer,
+    store: KvStore,
+    cipher: Cipher,
+    /// Set when the db could not be migrated: reads are still served, but
+    /// writes are refused so the old data isn't clobbered.
+    read_only: Option<String>,
+    calls: llm::Calls,
+    sync: sync::SyncClients,
+    runs: agent::Runs,
+    scheduler: schedule::Scheduler,
+    sessions: webhook::Sessions,
 }
 
-impl Default for ApiKeys {
-    fn default() -> Self {
-        Self {
-            keys: HashMap::new(),
-        }
-    }
+#[derive(Serialize)]
+struct Status<'a> {
+    schema_version: u32,
+    read_only: bool,
+    error: Option<&'a str>,
 }
 
 wit_bindgen::generate!({
     path: "target/wit",
-    world: "process-v1",
+    world: "kibitz-nick-dot-hypr-v0",
     generate_unused_types: true,
     additional_derives: [serde::Deserialize, serde::Serialize, process_macros::SerdeJsonInto],
 });
 
+/// Seconds since the Unix epoch.
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+fn handle_http_request(
+    state: &mut State,
+    http_request: &IncomingHttpRequest,
+) -> anyhow::Result<()> {
+    let method = http_request.method()?;
+    let process = state.our.process.to_string();
+    let bound_path = http_request.bound_path(Some(&process));
+    info!(
+        "got IncomingHttpRequest with method & path: {:?} & {:?}",
+        method.as_str(),
+        http_request.path()?
+    );
+    let token_path = matches!(
+        bound_path,
+        agent::HTTP_RUNS_PATH | agent::HTTP_RUN_PATH | agent::HTTP_RUN_CANCEL_PATH
+    );
+    if !token_path {
+        // http-server let it through, so it came with a good login cookie
+        state.sessions.note_origin(http_request);
+    }
+    let is_write = !matches!(method.as_str(), "GET" | "HEAD");
+    // Proxied LLM calls only add to the usage tallies, and backups only read
+    //  the db
+    if is_write && bound_path != HTTP_LLM_PATH && bound_path != backup::HTTP_BACKUP_PATH {
+        if let Some(ref read_only) = state.read_only {
+            info!("{} {bound_path}: read-only", method.as_str());
+            return send_http_error(HTTP_SERVICE_UNAVAILABLE, read_only);
+        }
+    }
+    match (method.as_str(), bound_path) {
+        ("GET", HTTP_STATUS_PATH) => {
+            let status = Status {
+                schema_version: store::get_json(&state.store, store::SCHEMA_VERSION_KEY)?
+                    .unwrap_or(0),
+                read_only: state.read_only.is_some(),
+                error: state.read_only.as_deref(),
+            };
+            send_http_json(HTTP_OK, &status)
+        }
+        ("GET", HTTP_API_PATH) => match keys::list(&state.store) {
+            Ok(listing) => send_http_json_tagged(HTTP_OK, listing.revision, Some(&listing.value)),
+            Err(e) => match state.read_only {
+                // An unmigrated db may not be listable at all
+                Some(ref read_only) => {
+                    error!("GET /api/keys: {e:?}");
+                    send_http_error(HTTP_SERVICE_UNAVAILABLE, read_only)
+                }
+                None => send_store_error("GET /api/keys", e),
+            },
+        },
+        ("PUT", HTTP_API_PATH) => {
+            let Some(IfMatch(expected)) = read_if_match(http_request)? else {
+                return Ok(());
+            };
+            let Some(new_keys) = read_json_body::<ApiKeys>()? else {
+                return Ok(());
+            };
+            let result = keys::replace_all(&state.store, &state.cipher, &new_keys, now(), expected);
+            if result.is_ok() {
+                state
+                    .sync
+                    .publish(&state.store, ChangeEvent::KeysChanged, now());
+            }
+            send_write_result("PUT /api/keys", result)
+        }
+        (method, HTTP_API_KEY_PATH) => {
+            let Some(provider) = url_param(http_request, "provider") else {
+                return send_http_error(HTTP_BAD_REQUEST, "missing provider");
+            };
+            if !matches!(method, "PUT" | "DELETE") {
+                return send_http_response(HTTP_METHOD_NOT_ALLOWED, None);
+            }
+            let Some(IfMatch(expected)) = read_if_match(http_request)? else {
+                return Ok(());
+            };
+            if method == "PUT" {
+                let Some(SetKey { key }) = read_json_body()? else {
+                    return Ok(());
+                };
+                let result =
+                    keys::set(&state.store, &state.cipher, provider, &key, now(), expected);
+                if result.is_ok() {
+                    state
+                        .sync
+                        .publish(&state.store, ChangeEvent::KeysChanged, now());
+                }
+                return send_write_result("PUT /api/keys/:provider", result);
+            }
+            match keys::delete(&state.store, provider, expected) {
+                Ok(None) => send_http_error(HTTP_NOT_FOUND, "no key for that provider"),
+                Ok(Some(revision)) => {
+                    state
+                        .sync
+                        .publish(&state.store, ChangeEvent::KeysChanged, now());
+                    send_write_result("DELETE /api/keys/:provider", Ok(revision))
+                }
+                Err(e) => send_write_result("DELETE /api/keys/:provider", Err(e)),
+            }
+        }
+        ("GET", HTTP_PROVIDERS_PATH) => {
+            let settings = llm::get_settings(&state.store)?;
+            send_http_json_tagged(HTTP_OK, settings.revision, Some(&settings.value))
+        }
+        ("PUT", HTTP_PROVIDERS_PATH) => {
+            let Some(IfMatch(expected)) = read_if_match(http_request)? else {
+                return Ok(());
+            };
+            let Some(settings) = read_json_body::<llm::ProviderSettings>()? else {
+                return Ok(());
+            };
+            if let Err(e) = llm::validate_settings(&settings) {
+                return send_http_error(HTTP_BAD_REQUEST, &format!("{e:#}"));
+            }
+            let result = llm::set_settings(&state.store, settings, expected);
+            if result.is_ok() {
+                state
+                    .sync
+                    .publish(&state.store, ChangeEvent::ProvidersChanged, now());
+            }
+            send_write_result("PUT /api/providers", result)
+        }
+        (_, HTTP_LLM_PATH) => {
+            if !llm::is_proxied_method(&method) {
+                return send_http_response(HTTP_METHOD_NOT_ALLOWED, None);
+            }
+            let Some(provider) = url_param(http_request, "provider") else {
+                return send_http_error(HTTP_BAD_REQUEST, "missing provider");
+            };
+            let path = http_request.path()?;
+            let rest = path
+                .strip_prefix(&format!("{HTTP_LLM_PREFIX}/{provider}"))
+                .unwrap_or_default();
+            if llm::provider(provider).is_none() {
+                return routing::handle_proxy(
+                    &state.store,
+                    &state.cipher,
+                    &mut state.calls,
+                    http_request,
+                    provider,
+                    rest,
+                );
+            }
+            llm::handle_proxy(
+                &state.store,
+                &state.cipher,
+                &mut state.calls,
+                http_request,
+                provider,
+                rest,
+            )
+        }
+        (
+            method,
+            conversations::HTTP_CONVERSATIONS_PATH
+            | conversations::HTTP_CONVERSATION_PATH
+            | conversations::HTTP_MESSAGES_PATH
+            | conversations::HTTP_PROJECTS_PATH,
+        ) => conversations::handle_http(
+            &state.store,
+            &mut state.sync,
+            http_request,
+            method,
+            bound_path,
+            now(),
+        ),
+        (
+            _,
+            branches::HTTP_FORK_PATH
+            | branches::HTTP_BRANCHES_PATH
+            | branches::HTTP_CHECKPOINTS_PATH
+            | branches::HTTP_DIFF_PATH,
+        ) => branches::handle_http(
+            &state.store,
+            &mut state.sync,
+            http_request,
+            bound_path,
+            now(),
+        ),
+        (
+            method,
+            export::HTTP_CONVERSATION_EXPORT_PATH
+            | export::HTTP_PROJECT_EXPORT_PATH
+            | export::HTTP_IMPORT_PATH,
+        ) => export::handle_http(
+            &state.store,
+            &mut state.sync,
+            http_request,
+            method,
+            bound_path,
+            now(),
+        ),
+        (
+            method,
+            backup::HTTP_BACKUP_PATH | backup::HTTP_BACKUPS_PATH | backup::HTTP_RESTORE_PATH,
+        ) => {
+            backup::handle_http(
+                &state.our,
+                &state.store,
+                &state.cipher,
+                &mut state.sync,
+                method,
+                bound_path,
+                now(),
+            )?;
+            state.scheduler.rearm(&state.store, now())
+        }
+        ("GET", search::HTTP_SEARCH_PATH) => search::handle_http(&state.store, http_request),
+        ("GET", webhook::HTTP_SESSION_PATH) => state.sessions.handle_http(http_request),
+        (_, agent::HTTP_RUNS_PATH | agent::HTTP_RUN_PATH | agent::HTTP_RUN_CANCEL_PATH) => {
+            // Bound without the login cookie, for scripts
+            match state
+                .sessions
+                .authorize(&state.store, http_request, bound_path, now())?
+            {
+                webhook::Authorization::Granted => {
+                    let body = last_blob().map(|blob| blob.bytes);
+                    serve_runs(state, http_request, bound_path, body.as_deref())
+                }
+                webhook::Authorization::Denied | webhook::Authorization::Held => Ok(()),
+            }
+        }
+        (
+            _,
+            schedule::HTTP_TASKS_PATH | schedule::HTTP_TASK_PATH | schedule::HTTP_TASK_RUN_PATH,
+        ) => state.scheduler.handle_http(
+            &state.store,
+            &state.cipher,
+            &mut state.sync,
+            &mut state.runs,
+            http_request,
+            bound_path,
+        ),
+        (_, budget::HTTP_BUDGETS_PATH | budget::HTTP_BUDGET_PATH) => budget::handle_http(
+            &state.store,
+            &mut state.sync,
+            http_request,
+            bound_path,
+            now(),
+        ),
+        (_, usage::HTTP_USAGE_PATH | usage::HTTP_PRICES_PATH) => usage::handle_http(
+            &state.store,
+            &mut state.sync,
+            http_request,
+            bound_path,
+            now(),
+        ),
+        (_, profiles::HTTP_PROFILES_PATH | profiles::HTTP_PROFILE_PATH) => profiles::handle_http(
+            &state.store,
+            &mut state.sync,
+            http_request,
+            bound_path,
+            now(),
+        ),
+        (
+            _,
+            prompts::HTTP_PROMPTS_PATH
+            | prompts::HTTP_PROMPT_PATH
+            | prompts::HTTP_PROMPT_RENDER_PATH,
+        ) => prompts::handle_http(
+            &state.store,
+            &mut state.sync,
+            http_request,
+            bound_path,
+            now(),
+        ),
+        (_, webhook::HTTP_TOKENS_PATH | webhook::HTTP_TOKEN_PATH) => webhook::handle_http(
+            &state.store,
+            &mut state.sync,
+            http_request,
+            bound_path,
+            now(),
+        ),
+        _ => send_http_response(HTTP_NOT_FOUND, None),
+    }
+}
+
+/// Serve an authorized request to the run endpoints, with its body.
+fn serve_runs(
+    state: &mut State,
+    http_request: &IncomingHttpRequest,
+    bound_path: &str,
+    body: Option<&[u8]>,
+) -> anyhow::Result<()> {
+    if bound_path == agent::HTTP_RUNS_PATH && http_request.method()?.as_str() == "POST" {
+        return state
+            .runs
+            .handle_create(&state.store, &state.cipher, &mut state.sync, body, now());
+    }
+    state.runs.handle_http(
+        &state.store,
+        &mut state.sync,
+        http_request,
+        bound_path,
+        now(),
+    )
+}
+
+/// `http-client`'s answer to a call sent with `context`.
+fn handle_upstream(
+    state: &mut State,
+    context: &[u8],
+    response: Result<(&[u8], Option<LazyLoadBlob>), String>,
+) -> anyhow::Result<()> {
+    if agent::run_context(context).is_some() {
+        state.runs.handle_llm_reply(
+            &state.store,
+            &state.cipher,
+            &mut state.sync,
+            context,
+            response,
+            now(),
+        );
+        return Ok(());
+    }
+    if webhook::session_context(context).is_some() {
+        // A login cookie check: serve the request it held if it passed
+        let Some(held) = state.sessions.handle_reply(context, now())? else {
+            return Ok(());
+        };
+        let process = state.our.process.to_string();
+        let bound_path = held.request.bound_path(Some(&process));
+        return serve_runs(state, &held.request, bound_path, held.body.as_deref());
+    }
+    if llm::call_context(context).is_some() {
+        return state.calls.handle_upstream(
+            &state.store,
+            &state.cipher,
+            &mut state.sync,
+            context,
+            response,
+            now(),
+        );
+    }
+    info!("reply to no call we know of");
+    Ok(())
+}
+
+fn handle_message(state: &mut State, message: &Message) -> anyhow::Result<()> {
+    info!("got message from {:?}", message.source());
+    if !message.is_request() {
+        // Timers pop as responses to our `set_timer` requests
+        if message.source() == &Address::new(state.our.node(), ("timer", "distro", "sys")) {
+            if message.context() == Some(schedule::TIMER_CONTEXT) {
+                state.scheduler.tick(
+                    &state.store,
+                    &state.cipher,
+                    &mut state.sync,
+                    &mut state.runs,
+                    now(),
+                )?;
+            } else if let Some(context) = message.context() {
+                // Routed LLM calls waiting to retry a target
+                state
+                    .calls
+                    .handle_timer(&state.store, &state.cipher, context)?;
+            }
+            return Ok(());
+        }
+        // LLM calls are sent off without awaiting the reply
+        if let Some(context) = message.context() {
+            handle_upstream(state, context, Ok((message.body(), last_blob())))?;
+        }
+        return Ok(());
+    }
+    if message.source() == &Address::new(state.our.node(), ("http-client", "distro", "sys")) {
+        // Runs' connections to their tool servers
+        match serde_json::from_slice::<HttpClientRequest>(message.body())? {
+            HttpClientRequest::WebSocketPush { channel_id, .. } => {
+                let Some(blob) = last_blob() else {
+                    return Ok(());
+                };
+                state.runs.handle_tool_message(
+                    &state.store,
+                    &state.cipher,
+                    &mut state.sync,
+                    channel_id,
+                    &blob.bytes,
+                    now(),
+                );
+            }
+            HttpClientRequest::WebSocketClose { channel_id } => {
+                state
+                    .runs
+                    .handle_tool_close(&state.store, &mut state.sync, channel_id, now());
+            }
+        }
+        return Ok(());
+    }
+    // Only http-server vouches for the login cookie: anything else sending
+    //  an HttpServerRequest would get past it
+    if message.source() == &Address::new(state.our.node(), ("http-server", "distro", "sys")) {
+        let http_request = serde_json::from_slice::<HttpServerRequest>(message.body())?;
+        return handle_http_server_request(state, http_request);
+    }
+    let Ok(request) = hyperware::process::kibitz::Request::try_from(message.body()) else {
+        info!("wasn't a kibitz Request");
+        return Ok(());
+    };
+    // Keys and prompts are only for processes on this node
+    if message.source().node != state.our.node {
+        info!("ignoring kibitz Request from {}", message.source());
+        return Ok(());
+    }
+    let response = match state.read_only {
+        Some(ref read_only) if requests::is_write(&request) => {
+            Some(hyperware::process::kibitz::Response::Err(read_only.clone()))
+        }
+        _ => requests::handle(
+            &state.store,
+            &state.cipher,
+            &mut state.sync,
+            &mut state.calls,
+            request,
+            now(),
+        )?,
+    };
+    if let Some(response) = response {
+        Response::new().body(response).send()?;
+    }
+    Ok(())
+}
+
+fn handle_http_server_request(
+    state: &mut State,
+    http_request: HttpServerRequest,
+) -> anyhow::Result<()> {
+    match http_request {
+        HttpServerRequest::Http(http_request) => handle_http_request(state, &http_request),
+        HttpServerRequest::WebSocketOpen { path, channel_id } => {
+            let process = format!("/{}", state.our.process);
+            let path = path.strip_prefix(&process).unwrap_or(&path);
+            state.server.handle_websocket_open(path, channel_id);
+            Ok(())
+        }
+        HttpServerRequest::WebSocketPush { channel_id, .. } => {
+            let Some(blob) = last_blob() else {
+                return Ok(());
+            };
+            let channels = state.server.get_ws_channels();
+            let on = |path: &str| channels.get(path).is_some_and(|c| c.contains(&channel_id));
+            if on(WS_SYNC_PATH) {
+                state
+                    .sync
+                    .handle_client_message(&state.store, channel_id, &blob.bytes)
+            } else {
+                info!("message on unknown WebSocket channel {channel_id}");
+                Ok(())
+            }
+        }
+        HttpServerRequest::WebSocketClose(channel_id) => {
+            state.server.handle_websocket_close(channel_id);
+            state.sync.handle_close(channel_id);
+            Ok(())
+        }
+    }
+}
+
 call_init!(init);
 fn init(our: Address) {
     init_logging(Level::DEBUG, Level::INFO, None, None, None).unwrap();
@@ -53,188 +558,139 @@ fn init(our: Address) {
     server
         .bind_http_path(HTTP_API_PATH, HttpBindingConfig::default())
         .expect("failed to bind API");
+    server
+        .bind_http_path(HTTP_API_KEY_PATH, HttpBindingConfig::default())
+        .expect("failed to bind API");
+    server
+        .bind_http_path(HTTP_STATUS_PATH, HttpBindingConfig::default())
+        .expect("failed to bind API");
+    server
+        .bind_http_path(HTTP_LLM_PATH, HttpBindingConfig::default())
+        .expect("failed to bind API");
+    server
+        .bind_http_path(HTTP_PROVIDERS_PATH, HttpBindingConfig::default())
+        .expect("failed to bind API");
+    for path in [
+        conversations::HTTP_CONVERSATIONS_PATH,
+        conversations::HTTP_CONVERSATION_PATH,
+        conversations::HTTP_MESSAGES_PATH,
+        branches::HTTP_FORK_PATH,
+        branches::HTTP_BRANCHES_PATH,
+        branches::HTTP_CHECKPOINTS_PATH,
+        branches::HTTP_DIFF_PATH,
+        conversations::HTTP_PROJECTS_PATH,
+        export::HTTP_CONVERSATION_EXPORT_PATH,
+        export::HTTP_PROJECT_EXPORT_PATH,
+        export::HTTP_IMPORT_PATH,
+        search::HTTP_SEARCH_PATH,
+        backup::HTTP_BACKUP_PATH,
+        backup::HTTP_BACKUPS_PATH,
+        backup::HTTP_RESTORE_PATH,
+        schedule::HTTP_TASKS_PATH,
+        schedule::HTTP_TASK_PATH,
+        schedule::HTTP_TASK_RUN_PATH,
+        webhook::HTTP_TOKENS_PATH,
+        webhook::HTTP_TOKEN_PATH,
+        webhook::HTTP_SESSION_PATH,
+        usage::HTTP_USAGE_PATH,
+        usage::HTTP_PRICES_PATH,
+        budget::HTTP_BUDGETS_PATH,
+        budget::HTTP_BUDGET_PATH,
+        prompts::HTTP_PROMPTS_PATH,
+        prompts::HTTP_PROMPT_PATH,
+        prompts::HTTP_PROMPT_RENDER_PATH,
+        profiles::HTTP_PROFILES_PATH,
+        profiles::HTTP_PROFILE_PATH,
+    ] {
+        server
+            .bind_http_path(path, HttpBindingConfig::default())
+            .expect("failed to bind API");
+    }
+    // Scripts have no login cookie: these take an API token instead, or
+    //  check the cookie themselves
+    for path in [
+        agent::HTTP_RUNS_PATH,
+        agent::HTTP_RUN_PATH,
+        agent::HTTP_RUN_CANCEL_PATH,
+    ] {
+        server
+            .bind_http_path(path, HttpBindingConfig::default().authenticated(false))
+            .expect("failed to bind API");
+    }
+    server
+        .bind_ws_path(WS_SYNC_PATH, WsBindingConfig::default())
+        .expect("failed to bind API");
 
     add_to_homepage("Kibitz", Some(ICON), Some(""), None);
 
     // Setup KV store
-    let kv = kv::open(our.package_id(), DB_NAME, None).expect("failed to open kv db");
-    let key = b"api_keys".to_vec();
-
-    //Request::new()
-    //    .target(("our", "kv", "distro", "sys"))
-    //    .body(
-    //        serde_json::to_vec(&KvRequest {
-    //            package_id: our.package_id(),
-    //            db: "kibitz_api_keys".to_string(),
-    //            action: KvAction::Open,
-    //        })
-    //        .unwrap(),
-    //    )
-    //    .send()
-    //    .unwrap();
+    let store = KvStore::open(our.package_id()).expect("failed to open kv db");
+    let cipher = Cipher::load_or_create(our.package_id()).expect("failed to load encryption key");
+    let read_only = match store::migrate(&store, &cipher) {
+        Ok(from) if from != store::SCHEMA_VERSION => {
+            info!(
+                "migrated db from schema version {from} to {}",
+                store::SCHEMA_VERSION
+            );
+            None
+        }
+        Ok(_) => None,
+        Err(e) => {
+            let message =
+                format!("kibitz could not upgrade its stored data and is running read-only: {e:#}");
+            error!("{message}");
+            Some(message)
+        }
+    };
+    let runs = agent::Runs::new(&our);
+    let mut state = State {
+        our,
+        server,
+        store,
+        cipher,
+        read_only,
+        calls: llm::Calls::default(),
+        sync: sync::SyncClients::default(),
+        runs,
+        scheduler: schedule::Scheduler::default(),
+        sessions: webhook::Sessions::default(),
+    };
+    if state.read_only.is_none() {
+        if let Err(e) = state
+            .runs
+            .resume_all(&state.store, &state.cipher, &mut state.sync, now())
+        {
+            error!("failed to resume runs: {e:?}");
+        }
+        // Run what came due while we were stopped and set the timer
+        if let Err(e) = state.scheduler.tick(
+            &state.store,
+            &state.cipher,
+            &mut state.sync,
+            &mut state.runs,
+            now(),
+        ) {
+            error!("failed to run scheduled tasks: {e:?}");
+        }
+    }
 
     loop {
         match await_message() {
             Err(e) => {
                 info!("Error receiving message: {:?}", e);
-            }
-            Ok(message) => {
-                info!("got message from {:?}", message.source());
-                let Ok(http_request) = serde_json::from_slice::<HttpServerRequest>(message.body())
-                else {
-                    info!("wasn't an HttpServerRequest");
-                    continue;
-                };
-                let HttpServerRequest::Http(http_request) = http_request else {
-                    info!("wasn't an HttpServerRequest::Http");
-                    continue;
-                };
-                info!(
-                    "got IncomingHttpRequest with method & path: {:?} & {:?}",
-                    http_request.method().unwrap().as_str(),
-                    http_request.path().unwrap().as_str()
-                );
-                match (
-                    http_request.method().unwrap().as_str(),
-                    http_request.path().unwrap().as_str(),
-                ) {
-                    ("GET", "/api/keys") => {
-                        let api_keys: ApiKeys = kv.get(&key).unwrap_or_default();
-                        // Get API keys from KV store
-                        //let api_keys = match Request::new()
-                        //    .target(("our", "kv", "distro", "sys"))
-                        //    .body(
-                        //        serde_json::to_vec(&KvRequest {
-                        //            package_id: our.package_id(),
-                        //            db: "kibitz_api_keys".to_string(),
-                        //            action: KvAction::Get(b"api_keys".to_vec()),
-                        //        })
-                        //        .unwrap(),
-                        //    )
-                        //    .send_and_await_response(5)
-                        //    .unwrap()
-                        //{
-                        //    Ok(resp) => {
-                        //        if let Ok(KvResponse::Get(value)) =
-                        //            serde_json::from_slice(resp.body())
-                        //        {
-                        //            serde_json::from_slice(&value).unwrap_or(ApiKeys {
-                        //                keys: HashMap::new(),
-                        //            })
-                        //        } else {
-                        //            ApiKeys {
-                        //                keys: HashMap::new(),
-                        //            }
-                        //        }
-                        //    }
-                        //    Err(_) => ApiKeys {
-                        //        keys: HashMap::new(),
-                        //    },
-                        //};
-
-                        info!("GET /api/keys: {api_keys:?}");
-                        Response::new()
-                            .body(serde_json::to_vec(&HttpResponse::new(HTTP_OK)).unwrap())
-                            .blob_bytes(serde_json::to_vec(&api_keys).unwrap())
-                            .send()
-                            .unwrap();
-                    }
-                    ("PUT", "/api/keys") => {
-                        let Some(blob) = last_blob() else {
-                            info!("PUT /api/keys: no blob");
-                            Response::new()
-                                .body(
-                                    serde_json::to_vec(&HttpResponse::new(HTTP_BAD_REQUEST))
-                                        .unwrap(),
-                                )
-                                .send()
-                                .unwrap();
-                            continue;
-                        };
-
-                        let Ok(new_keys) = serde_json::from_slice::<ApiKeys>(&blob.bytes) else {
-                            info!("PUT /api/keys: improper format");
-                            Response::new()
-                                .body(
-                                    serde_json::to_vec(&HttpResponse::new(HTTP_BAD_REQUEST))
-                                        .unwrap(),
-                                )
-                                .send()
-                                .unwrap();
-                            continue;
-                        };
-                        info!("PUT /api/keys: {new_keys:?}");
-                        match kv.set(&key, &new_keys, None) {
-                            Ok(_) => {
-                                info!("PUT /api/keys: succeeded");
-                                Response::new()
-                                    .body(serde_json::to_vec(&HttpResponse::new(HTTP_OK)).unwrap())
-                                    .send()
-                                    .unwrap();
-                            }
-                            Err(_) => {
-                                info!("PUT /api/keys: failed");
-                                Response::new()
-                                    .body(
-                                        serde_json::to_vec(&HttpResponse::new(HTTP_SERVER_ERROR))
-                                            .unwrap(),
-                                    )
-                                    .send()
-                                    .unwrap();
-                            }
-                        }
-                        //// Store API keys in KV store
-                        //let result = Request::new()
-                        //    .target(("our", "kv", "distro", "sys"))
-                        //    .body(
-                        //        serde_json::to_vec(&KvRequest {
-                        //            package_id: our.package_id(),
-                        //            db: "kibitz_api_keys".to_string(),
-                        //            action: KvAction::Set {
-                        //                key: b"api_keys".to_vec(),
-                        //                tx_id: None,
-                        //            },
-                        //        })
-                        //        .unwrap(),
-                        //    )
-                        //    .blob(LazyLoadBlob {
-                        //        mime: None,
-                        //        bytes: serde_json::to_vec(&new_keys).unwrap(),
-                        //    })
-                        //    .send_and_await_response(5)
-                        //    .unwrap();
-
-                        //match result {
-                        //    Ok(_) => {
-                        //        Response::new()
-                        //            .body(
-                        //                serde_json::to_vec(&HttpResponse::new(HTTP_OK))
-                        //                    .unwrap(),
-                        //            )
-                        //            .send()
-                        //            .unwrap();
-                        //    }
-                        //    Err(_) => {
-                        //        Response::new()
-                        //            .body(
-                        //                serde_json::to_vec(&HttpResponse::new(
-                        //                    HTTP_SERVER_ERROR,
-                        //                ))
-                        //                .unwrap(),
-                        //            )
-                        //            .send()
-                        //            .unwrap();
-                        //    }
-                        //}
-                    }
-                    _ => {
-                        // Serve UI requests through the HttpServer
-                        server
-                            .serve_ui("kibitz-ui", vec!["/"], HttpBindingConfig::default())
-                            .unwrap();
+                // An LLM call that timed out
+                if let Some(context) = e.context() {
+                    let failure = Err(format!("upstream {:?}", e.kind()));
+                    if let Err(e) = handle_upstream(&mut state, context, failure) {
+                        error!("got error while handling upstream failure: {e:?}");
                     }
                 }
             }
+            Ok(ref message) => {
+                if let Err(e) = handle_message(&mut state, message) {
+                    error!("got error while handling message: {e:?}");
+                }
+            }
         }
     }
 }
@@ -0,0 +1,580 @@
+//! Proxy for provider LLM APIs, so API keys never leave the node.
+//!
+//! `/api/llm/{provider}/{rest}` is forwarded through `http-client` to
+//! `{base_url}/{rest}` with the stored key attached: the browser only ever
+//! talks to kibitz. Base URLs default to each provider's public API and can
+//! be overridden under `PROVIDERS_KEY`, e.g. to point at a local stand-in.
+//! The same settings hold the routing policy: see `routing`.
+//!
+//! Calls are sent off without awaiting the reply, so kibitz keeps serving
+//! other requests while a completion runs. The kernel hands the reply back
+//! along with the request that prompted the call, so it is answered then,
+//! like any other.
+
+use std::collections::{BTreeMap, HashMap};
+
+use hyperware_process_lib::http::client::{
+    HttpClientAction, HttpClientError, HttpClientResponse, OutgoingHttpRequest,
+};
+use hyperware_process_lib::http::server::{HttpResponse, IncomingHttpRequest};
+use hyperware_process_lib::http::Method;
+use hyperware_process_lib::logging::info;
+use hyperware_process_lib::timer::set_timer;
+use hyperware_process_lib::{last_blob, LazyLoadBlob, Request};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::budget;
+use crate::crypto::Cipher;
+use crate::http::{
+    send_http, send_http_error, HTTP_BAD_GATEWAY, HTTP_NOT_FOUND, HTTP_PAYMENT_REQUIRED,
+};
+use crate::keys;
+use crate::requests;
+use crate::routing;
+use crate::store::{self, Revisioned, Store};
+use crate::sync::SyncClients;
+use crate::usage;
+
+pub const PROVIDERS_KEY: &str = "llm_providers";
+
+/// Context prefix of proxied calls, telling their replies apart from run
+/// calls.
+const CONTEXT_PREFIX: &[u8] = b"call:";
+
+/// Generous, since a long completion is only returned once it is done.
+pub const LLM_TIMEOUT_S: u64 = 300;
+
+enum Auth {
+    /// `x-api-key: {key}`
+    XApiKey,
+    /// `Authorization: Bearer {key}`
+    Bearer,
+}
+
+pub struct Provider {
+    pub name: &'static str,
+    default_base_url: &'static str,
+    auth: Auth,
+    /// Sent unless the client sets them itself.
+    default_headers: &'static [(&'static str, &'static str)],
+}
+
+pub const PROVIDERS: &[Provider] = &[
+    Provider {
+        name: "anthropic",
+        default_base_url: "https://api.anthropic.com",
+        auth: Auth::XApiKey,
+        default_headers: &[("anthropic-version", "2023-06-01")],
+    },
+    Provider {
+        name: "openai",
+        default_base_url: "https://api.openai.com",
+        auth: Auth::Bearer,
+        default_headers: &[],
+    },
+];
+
+/// Request headers passed through from the client. Everything else,
+/// notably any credentials the browser sends, is dropped.
+const FORWARDED_HEADERS: &[&str] = &[
+    "accept",
+    "content-type",
+    "anthropic-beta",
+    "anthropic-version",
+    "openai-organization",
+];
+
+/// Response headers passed back to the client.
+const RETURNED_HEADERS: &[&str] = &["content-type", "request-id", "x-request-id", "retry-after"];
+
+pub fn provider(name: &str) -> Option<&'static Provider> {
+    PROVIDERS.iter().find(|provider| provider.name == name)
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ProviderSettings {
+    /// Per-provider base URL overrides.
+    #[serde(default)]
+    pub base_urls: BTreeMap<String, String>,
+    #[serde(default)]
+    pub routing: routing::Policy,
+}
+
+pub fn get_settings(store: &dyn Store) -> anyhow::Result<Revisioned<ProviderSettings>> {
+    store::get_revisioned(store, PROVIDERS_KEY)
+}
+
+pub fn validate_settings(settings: &ProviderSettings) -> anyhow::Result<()> {
+    for (name, base_url) in &settings.base_urls {
+        anyhow::ensure!(provider(name).is_some(), "unknown provider {name}");
+        let url = Url::parse(base_url).map_err(|e| anyhow::anyhow!("{name}: {e}"))?;
+        anyhow::ensure!(
+            matches!(url.scheme(), "http" | "https"),
+            "{name}: base URL must be http or https"
+        );
+    }
+    routing::validate(&settings.routing)
+}
+
+/// Validate and save new settings, returning the new revision.
+pub fn set_settings(
+    store: &dyn Store,
+    settings: ProviderSettings,
+    expected: Option<u64>,
+) -> anyhow::Result<u64> {
+    validate_settings(&settings)?;
+    let mut doc = get_settings(store)?;
+    doc.value = settings;
+    store::put_revisioned(store, PROVIDERS_KEY, &mut doc, expected)
+}
+
+pub struct Outgoing {
+    pub url: Url,
+    pub headers: HashMap<String, String>,
+}
+
+/// The upstream request for a proxied call to `rest` (the path after
+/// `/api/llm/{provider}`), given the client's headers.
+pub fn build_request<'a>(
+    provider: &Provider,
+    base_url: Option<&str>,
+    rest: &str,
+    query: Option<&str>,
+    client_headers: impl IntoIterator<Item = (&'a str, &'a str)>,
+    key: &str,
+) -> anyhow::Result<Outgoing> {
+    let base_url = base_url.unwrap_or(provider.default_base_url);
+    let mut url = Url::parse(&format!(
+        "{}/{}",
+        base_url.trim_end_matches('/'),
+        rest.trim_start_matches('/')
+    ))?;
+    url.set_query(query);
+
+    let mut headers: HashMap<String, String> = provider
+        .default_headers
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    for (name, value) in client_headers {
+        let name = name.to_ascii_lowercase();
+        if FORWARDED_HEADERS.contains(&name.as_str()) {
+            headers.insert(name, value.to_string());
+        }
+    }
+    match provider.auth {
+        Auth::XApiKey => headers.insert("x-api-key".to_string(), key.to_string()),
+        Auth::Bearer => headers.insert("authorization".to_string(), format!("Bearer {key}")),
+    };
+    Ok(Outgoing { url, headers })
+}
+
+/// Why a call can't be made, and the status to answer it with.
+#[derive(Debug)]
+pub struct Refusal {
+    pub status: u16,
+    pub message: String,
+}
+
+impl Refusal {
+    fn new(status: u16, message: String) -> Self {
+        Self { status, message }
+    }
+}
+
+/// The upstream request for a call to `rest` on `provider_name`, or why
+/// there can't be one (unknown provider, no key stored for it, or its
+/// budget is spent).
+pub fn prepare<'a>(
+    store: &dyn Store,
+    cipher: &Cipher,
+    provider_name: &str,
+    rest: &str,
+    query: Option<&str>,
+    client_headers: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> anyhow::Result<Result<Outgoing, Refusal>> {
+    let Some(provider) = provider(provider_name) else {
+        let message = format!("unknown provider {provider_name}");
+        return Ok(Err(Refusal::new(HTTP_NOT_FOUND, message)));
+    };
+    let Some(key) = keys::get(store, cipher, provider.name)? else {
+        let message = format!("no API key stored for {provider_name}");
+        return Ok(Err(Refusal::new(HTTP_NOT_FOUND, message)));
+    };
+    if let Some(message) = budget::check(store, provider.name, crate::now())? {
+        info!("refusing a call to {provider_name}: {message}");
+        return Ok(Err(Refusal::new(HTTP_PAYMENT_REQUIRED, message)));
+    }
+    let settings = get_settings(store)?.value;
+    build_request(
+        provider,
+        settings.base_urls.get(provider.name).map(String::as_str),
+        rest,
+        query,
+        client_headers,
+        &key,
+    )
+    .map(Ok)
+}
+
+/// Send `body` to `outgoing` through `http-client` without awaiting the
+/// reply, which comes back with `context`.
+pub fn send(
+    method: &Method,
+    outgoing: Outgoing,
+    body: Vec<u8>,
+    context: Vec<u8>,
+) -> anyhow::Result<()> {
+    Request::to(("our", "http-client", "distro", "sys"))
+        .body(serde_json::to_vec(&HttpClientAction::Http(
+            OutgoingHttpRequest {
+                method: method.to_string(),
+                version: None,
+                url: outgoing.url.to_string(),
+                headers: outgoing.headers,
+            },
+        ))?)
+        .blob_bytes(body)
+        .context(context)
+        .expects_response(LLM_TIMEOUT_S)
+        .send()?;
+    Ok(())
+}
+
+/// Unpack what `http-client` answered to a call sent off without awaiting
+/// it: the response and its body, or why there is none.
+pub fn upstream_response(
+    response: Result<(&[u8], Option<LazyLoadBlob>), String>,
+) -> Result<(HttpResponse, Vec<u8>), String> {
+    let (body, blob) = response?;
+    match serde_json::from_slice::<Result<HttpClientResponse, HttpClientError>>(body) {
+        Ok(Ok(HttpClientResponse::Http(response))) => {
+            Ok((response, blob.map(|blob| blob.bytes).unwrap_or_default()))
+        }
+        Ok(Ok(other)) => Err(format!("unexpected http-client response {other:?}")),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(e) => Err(format!("malformed http-client response: {e}")),
+    }
+}
+
+/// The response relaying `upstream`, with the headers the client gets.
+pub fn response_for(upstream: &HttpResponse) -> HttpResponse {
+    let mut response = HttpResponse::new(upstream.status);
+    for (name, value) in &upstream.headers {
+        let name = name.to_ascii_lowercase();
+        if RETURNED_HEADERS.contains(&name.as_str()) {
+            response = response.header(name, value);
+        }
+    }
+    response
+}
+
+/// A JSON call body with the calling project's default model filled in.
+pub fn with_default_model(
+    store: &dyn Store,
+    project_id: Option<&str>,
+    body: Vec<u8>,
+) -> anyhow::Result<Vec<u8>> {
+    let Some(project_id) = project_id else {
+        return Ok(body);
+    };
+    let Ok(mut json) = serde_json::from_slice(&body) else {
+        return Ok(body);
+    };
+    let policy = get_settings(store)?.value.routing;
+    routing::apply_default_model(&policy, Some(project_id), &mut json);
+    Ok(serde_json::to_vec(&json)?)
+}
+
+/// Forward a request to `/api/llm/{provider}/{rest}`; the answer is
+/// relayed once it comes.
+pub fn handle_proxy(
+    store: &dyn Store,
+    cipher: &Cipher,
+    calls: &mut Calls,
+    http_request: &IncomingHttpRequest,
+    provider_name: &str,
+    rest: &str,
+) -> anyhow::Result<()> {
+    let headers = http_request.headers();
+    let project_id = headers
+        .get(usage::PROJECT_HEADER)
+        .and_then(|value| value.to_str().ok());
+    let client_headers = headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)));
+    let query = http_request.url()?.query().map(str::to_string);
+    let outgoing = match prepare(
+        store,
+        cipher,
+        provider_name,
+        rest,
+        query.as_deref(),
+        client_headers,
+    )? {
+        Ok(outgoing) => outgoing,
+        Err(refusal) => return send_http_error(refusal.status, &refusal.message),
+    };
+    let method = http_request.method()?;
+    let mut body = last_blob().map(|blob| blob.bytes).unwrap_or_default();
+    if method == Method::POST {
+        body = with_default_model(store, project_id, body)?;
+    }
+    info!("proxying {method} to {}", outgoing.url);
+    let pending = Pending::Direct(Direct {
+        caller: Caller::Http,
+        provider: provider_name.to_string(),
+        model: usage::body_model(&body),
+        project_id: project_id.map(str::to_string),
+    });
+    calls.send(pending, &method, outgoing, body)
+}
+
+/// Who is waiting on a proxied call.
+pub enum Caller {
+    /// An `/api/llm/{provider}/{rest}` request.
+    Http,
+    /// A `run-prompt` request from another process.
+    Process,
+}
+
+struct Direct {
+    caller: Caller,
+    /// For accounting for the reply.
+    provider: String,
+    model: Option<String>,
+    project_id: Option<String>,
+}
+
+enum Pending {
+    /// A call straight to a provider.
+    Direct(Direct),
+    /// A call to a route, awaiting its current target's reply or the timer
+    /// to retry it.
+    Routed(Box<routing::Routed>),
+}
+
+/// Proxied calls awaiting their upstream response (or a routed call's
+/// retry timer), keyed by the context sent with the request.
+#[derive(Default)]
+pub struct Calls {
+    next_key: u64,
+    pending: HashMap<u64, Pending>,
+}
+
+/// The key of a proxied call sent with `context`.
+pub fn call_context(context: &[u8]) -> Option<u64> {
+    std::str::from_utf8(context.strip_prefix(CONTEXT_PREFIX)?)
+        .ok()?
+        .parse()
+        .ok()
+}
+
+impl Calls {
+    fn send(
+        &mut self,
+        pending: Pending,
+        method: &Method,
+        outgoing: Outgoing,
+        body: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let key = self.next_key;
+        self.next_key += 1;
+        let context = [CONTEXT_PREFIX, key.to_string().as_bytes()].concat();
+        send(method, outgoing, body, context)?;
+        self.pending.insert(key, pending);
+        Ok(())
+    }
+
+    /// Call a routed call's current target.
+    pub fn send_routed(
+        &mut self,
+        routed: Box<routing::Routed>,
+        outgoing: Outgoing,
+        body: Vec<u8>,
+    ) -> anyhow::Result<()> {
+        self.send(Pending::Routed(routed), &Method::POST, outgoing, body)
+    }
+
+    /// Call a routed call's current target again once `delay_ms` is up.
+    pub fn retry_later(
+        &mut self,
+        routed: Box<routing::Routed>,
+        delay_ms: u64,
+    ) -> anyhow::Result<()> {
+        let key = self.next_key;
+        self.next_key += 1;
+        set_timer(
+            delay_ms,
+            Some([CONTEXT_PREFIX, key.to_string().as_bytes()].concat()),
+        );
+        self.pending.insert(key, Pending::Routed(routed));
+        Ok(())
+    }
+
+    /// A routed call's retry timer popped.
+    pub fn handle_timer(
+        &mut self,
+        store: &dyn Store,
+        cipher: &Cipher,
+        context: &[u8],
+    ) -> anyhow::Result<()> {
+        let Some(Pending::Routed(routed)) =
+            call_context(context).and_then(|key| self.pending.remove(&key))
+        else {
+            return Ok(());
+        };
+        routing::attempt(store, cipher, self, routed)
+    }
+
+    /// `POST` a JSON `body` to `rest` on `provider_name` with its stored key
+    /// for a `run-prompt` request, or say why it can't be.
+    pub fn run_prompt(
+        &mut self,
+        store: &dyn Store,
+        cipher: &Cipher,
+        provider_name: &str,
+        rest: &str,
+        body: Vec<u8>,
+    ) -> anyhow::Result<Result<(), String>> {
+        let client_headers = [("content-type", "application/json")];
+        let outgoing = match prepare(store, cipher, provider_name, rest, None, client_headers)? {
+            Ok(outgoing) => outgoing,
+            Err(refusal) => return Ok(Err(refusal.message)),
+        };
+        info!("calling {}", outgoing.url);
+        let pending = Pending::Direct(Direct {
+            caller: Caller::Process,
+            provider: provider_name.to_string(),
+            model: usage::body_model(&body),
+            project_id: None,
+        });
+        self.send(pending, &Method::POST, outgoing, body).map(Ok)
+    }
+
+    /// An `http-client` response (or failure to get one) for the call sent
+    /// with `context`: relay it to whoever made the call.
+    pub fn handle_upstream(
+        &mut self,
+        store: &dyn Store,
+        cipher: &Cipher,
+        sync: &mut SyncClients,
+        context: &[u8],
+        response: Result<(&[u8], Option<LazyLoadBlob>), String>,
+        now: u64,
+    ) -> anyhow::Result<()> {
+        let Some(pending) = call_context(context).and_then(|key| self.pending.remove(&key)) else {
+            return Ok(());
+        };
+        let upstream = upstream_response(response);
+        let Direct {
+            caller,
+            provider,
+            model,
+            project_id,
+        } = match pending {
+            Pending::Direct(direct) => direct,
+            Pending::Routed(routed) => {
+                return routing::handle_reply(store, cipher, sync, self, routed, upstream, now)
+            }
+        };
+        if let Ok((ref response, ref body)) = upstream {
+            if (200..300).contains(&response.status) {
+                let (model, project_id) = (model.as_deref(), project_id.as_deref());
+                usage::note_reply(store, sync, &provider, model, project_id, body, now);
+            }
+        }
+        match (caller, upstream) {
+            (Caller::Http, Ok((response, body))) => send_http(response_for(&response), Some(body)),
+            (Caller::Http, Err(e)) => {
+                info!("proxy to {provider} failed: {e}");
+                send_http_error(HTTP_BAD_GATEWAY, &format!("{provider}: {e}"))
+            }
+            (Caller::Process, Ok((response, body))) => {
+                requests::answer_run_prompt(Ok((response.status, body)))
+            }
+            (Caller::Process, Err(e)) => {
+                requests::answer_run_prompt(Err(format!("{provider}: {e}")))
+            }
+        }
+    }
+}
+
+/// Whether a proxied call may be made with `method`.
+pub fn is_proxied_method(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::POST)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_is_injected_and_client_credentials_dropped() {
+        let anthropic = provider("anthropic").unwrap();
+        let client_headers = [
+            ("Content-Type", "application/json"),
+            ("x-api-key", "from-the-browser"),
+            ("cookie", "hyperware-auth=secret"),
+        ];
+        let outgoing = build_request(
+            anthropic,
+            None,
+            "/v1/messages",
+            Some("beta=true"),
+            client_headers,
+            "sk-ant",
+        )
+        .unwrap();
+        assert_eq!(
+            outgoing.url.as_str(),
+            "https://api.anthropic.com/v1/messages?beta=true"
+        );
+        assert_eq!(outgoing.headers["x-api-key"], "sk-ant");
+        assert_eq!(outgoing.headers["content-type"], "application/json");
+        assert_eq!(outgoing.headers["anthropic-version"], "2023-06-01");
+        assert!(!outgoing.headers.contains_key("cookie"));
+    }
+
+    #[test]
+    fn call_contexts_are_told_apart() {
+        assert_eq!(call_context(b"call:7"), Some(7));
+        assert_eq!(call_context(b"run:7"), None);
+        assert_eq!(call_context(b"7"), None);
+    }
+
+    #[test]
+    fn base_url_override() {
+        let openai = provider("openai").unwrap();
+        let outgoing = build_request(
+            openai,
+            Some("http://localhost:8080/stand-in/"),
+            "v1/chat/completions",
+            None,
+            [],
+            "sk-oai",
+        )
+        .unwrap();
+        assert_eq!(
+            outgoing.url.as_str(),
+            "http://localhost:8080/stand-in/v1/chat/completions"
+        );
+        assert_eq!(outgoing.headers["authorization"], "Bearer sk-oai");
+    }
+
+    #[test]
+    fn settings_are_validated() {
+        let store = store::MemoryStore::default();
+        let bad = |provider: &str, url: &str| ProviderSettings {
+            base_urls: BTreeMap::from([(provider.to_string(), url.to_string())]),
+            ..Default::default()
+        };
+        assert!(set_settings(&store, bad("nope", "http://localhost"), None).is_err());
+        assert!(set_settings(&store, bad("openai", "ftp://localhost"), None).is_err());
+        assert_eq!(
+            set_settings(&store, bad("openai", "http://localhost"), Some(0)).unwrap(),
+            1
+        );
+    }
+}
@@ -0,0 +1,293 @@
+//! Agent profiles: named kinds of agent (a reviewer, a refactorer, ...),
+//! each with its own system prompt, model and the tools it may use.
+//!
+//! A run started with `profile` takes what it doesn't set itself from the
+//! profile, and may narrow the profile's tools but never widen them.
+//!
+//! Allow-lists are checked against the profile as it is at the time of
+//! each call. A run's (see `agent`) tool the profile doesn't allow is
+//! neither offered to the model nor called if the model asks for it anyway.
+//! A browser session's tool calls are held to the profile fwd-ws was given
+//! with `set-profile`: fwd-ws asks with a `check-tools` request before
+//! relaying each `tools/call`, and refuses those it's told are denied.
+//!
+//! Tools are named exactly, or by prefix with a trailing `*`: `read_*`
+//! allows `read_file` and `read_dir`, and `*` allows everything.
+
+use std::collections::BTreeMap;
+
+use hyperware_process_lib::http::server::IncomingHttpRequest;
+use serde::{Deserialize, Serialize};
+
+use crate::agent::NewRun;
+use crate::conversations;
+use crate::http::{
+    read_json_body, send_http_error, send_http_json, send_http_response, url_param,
+    HTTP_BAD_REQUEST, HTTP_CREATED, HTTP_METHOD_NOT_ALLOWED, HTTP_NOT_FOUND, HTTP_OK,
+};
+use crate::prompts::PromptRef;
+use crate::store::{self, Store};
+use crate::sync::{ChangeEvent, SyncClients};
+
+pub const HTTP_PROFILES_PATH: &str = "/api/profiles";
+pub const HTTP_PROFILE_PATH: &str = "/api/profiles/:id";
+
+pub const PROFILES_KEY: &str = "agent_profiles";
+
+/// What a client says a profile is: the body of `POST /api/profiles` and
+/// `PUT /api/profiles/{id}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProfileSpec {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    /// In place of `system`, from the prompt library.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<PromptRef>,
+    /// The tools its runs may use; none if empty.
+    #[serde(default)]
+    pub tools: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_steps: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    pub id: String,
+    #[serde(flatten)]
+    pub spec: ProfileSpec,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Profiles {
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+pub fn load(store: &dyn Store) -> anyhow::Result<Profiles> {
+    Ok(store::get_json(store, PROFILES_KEY)?.unwrap_or_default())
+}
+
+pub fn save(store: &dyn Store, profiles: &Profiles) -> anyhow::Result<()> {
+    store::set_json(store, PROFILES_KEY, profiles)
+}
+
+pub fn get(store: &dyn Store, id: &str) -> anyhow::Result<Option<Profile>> {
+    Ok(load(store)?.profiles.remove(id))
+}
+
+/// Whether `pattern` allows everything `requested` (a name or pattern)
+/// does.
+fn covers(pattern: &str, requested: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => requested.starts_with(prefix),
+        None => pattern == requested,
+    }
+}
+
+/// Whether any of `patterns` allows the tool `name`.
+pub fn tool_allowed(patterns: &[String], name: &str) -> bool {
+    patterns.iter().any(|pattern| covers(pattern, name))
+}
+
+/// Whether `profile_id`'s profile, as it is now, allows the tool `name`.
+/// A deleted profile allows nothing.
+pub fn profile_allows(store: &dyn Store, profile_id: &str, name: &str) -> anyhow::Result<bool> {
+    Ok(get(store, profile_id)?.is_some_and(|profile| tool_allowed(&profile.spec.tools, name)))
+}
+
+pub fn validate(spec: &mut ProfileSpec) -> Result<(), String> {
+    spec.name = spec.name.trim().to_string();
+    if spec.name.is_empty() {
+        return Err("a profile needs a name".to_string());
+    }
+    if spec.system.is_some() && spec.system_prompt.is_some() {
+        return Err("give system or system_prompt, not both".to_string());
+    }
+    for tool in &spec.tools {
+        let name = tool.strip_suffix('*').unwrap_or(tool);
+        if tool.is_empty() || name.contains('*') {
+            return Err(format!("bad tool pattern {tool:?}"));
+        }
+    }
+    Ok(())
+}
+
+/// Fill in what `new` leaves unset from `profile`, and check it asks for no
+/// tools the profile doesn't allow.
+pub fn apply(profile: &Profile, new: &mut NewRun) -> Result<(), String> {
+    let spec = &profile.spec;
+    if new.model.is_empty() {
+        new.model = spec.model.clone().unwrap_or_default();
+    }
+    if new.system.is_none() && new.system_prompt.is_none() {
+        new.system = spec.system.clone();
+        new.system_prompt = spec.system_prompt.clone();
+    }
+    new.max_steps = new.max_steps.or(spec.max_steps);
+    match new.tools {
+        Some(ref tools) => {
+            if let Some(tool) = tools
+                .iter()
+                .find(|tool| !spec.tools.iter().any(|pattern| covers(pattern, tool)))
+            {
+                return Err(format!("profile {} doesn't allow {tool}", spec.name));
+            }
+        }
+        None => new.tools = Some(spec.tools.clone()),
+    }
+    Ok(())
+}
+
+/// Serve the profile endpoints.
+pub fn handle_http(
+    store: &dyn Store,
+    sync: &mut SyncClients,
+    http_request: &IncomingHttpRequest,
+    bound_path: &str,
+    now: u64,
+) -> anyhow::Result<()> {
+    let method = http_request.method()?;
+    let mut profiles = load(store)?;
+    match (method.as_str(), bound_path) {
+        ("GET", HTTP_PROFILES_PATH) => {
+            let listed: Vec<&Profile> = profiles.profiles.values().collect();
+            send_http_json(HTTP_OK, &listed)
+        }
+        ("POST", HTTP_PROFILES_PATH) => {
+            let Some(mut spec) = read_json_body::<ProfileSpec>()? else {
+                return Ok(());
+            };
+            if let Err(message) = validate(&mut spec) {
+                return send_http_error(HTTP_BAD_REQUEST, &message);
+            }
+            let profile = Profile {
+                id: conversations::new_id(),
+                spec,
+                created_at: now,
+                updated_at: now,
+            };
+            profiles
+                .profiles
+                .insert(profile.id.clone(), profile.clone());
+            save(store, &profiles)?;
+            sync.publish(store, ChangeEvent::ProfilesChanged, now);
+            send_http_json(HTTP_CREATED, &profile)
+        }
+        (method, HTTP_PROFILE_PATH) => {
+            let Some(id) = url_param(http_request, "id") else {
+                return send_http_error(HTTP_BAD_REQUEST, "missing profile id");
+            };
+            let Some(profile) = profiles.profiles.get_mut(id) else {
+                return send_http_error(HTTP_NOT_FOUND, "no such profile");
+            };
+            match method {
+                "GET" => send_http_json(HTTP_OK, profile),
+                "PUT" => {
+                    let Some(mut spec) = read_json_body::<ProfileSpec>()? else {
+                        return Ok(());
+                    };
+                    if let Err(message) = validate(&mut spec) {
+                        return send_http_error(HTTP_BAD_REQUEST, &message);
+                    }
+                    profile.spec = spec;
+                    profile.updated_at = now;
+                    let profile = profile.clone();
+                    save(store, &profiles)?;
+                    sync.publish(store, ChangeEvent::ProfilesChanged, now);
+                    send_http_json(HTTP_OK, &profile)
+                }
+                "DELETE" => {
+                    profiles.profiles.remove(id);
+                    save(store, &profiles)?;
+                    sync.publish(store, ChangeEvent::ProfilesChanged, now);
+                    send_http_response(HTTP_OK, None)
+                }
+                _ => send_http_response(HTTP_METHOD_NOT_ALLOWED, None),
+            }
+        }
+        _ => send_http_response(HTTP_NOT_FOUND, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    fn reviewer() -> Profile {
+        Profile {
+            id: "p".to_string(),
+            spec: ProfileSpec {
+                name: "reviewer".to_string(),
+                description: String::new(),
+                model: Some("m".to_string()),
+                system: Some("review".to_string()),
+                system_prompt: None,
+                tools: vec!["read_*".to_string(), "search".to_string()],
+                max_steps: Some(10),
+            },
+            created_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    fn new_run(run: serde_json::Value) -> NewRun {
+        serde_json::from_value(run).unwrap()
+    }
+
+    #[test]
+    fn tools_are_allowed_by_name_or_prefix() {
+        let tools = reviewer().spec.tools;
+        assert!(tool_allowed(&tools, "read_file"));
+        assert!(tool_allowed(&tools, "search"));
+        assert!(!tool_allowed(&tools, "search_replace"));
+        assert!(!tool_allowed(&tools, "write_file"));
+        assert!(tool_allowed(&["*".to_string()], "run_command"));
+        assert!(!tool_allowed(&[], "read_file"));
+
+        let mut spec = reviewer().spec;
+        spec.tools = vec!["re*ad".to_string()];
+        assert!(validate(&mut spec).is_err());
+    }
+
+    #[test]
+    fn runs_take_the_profile_and_cannot_widen_its_tools() {
+        let profile = reviewer();
+        let mut new = new_run(serde_json::json!({ "prompt": "look" }));
+        apply(&profile, &mut new).unwrap();
+        assert_eq!(new.model, "m");
+        assert_eq!(new.system.as_deref(), Some("review"));
+        assert_eq!(new.max_steps, Some(10));
+        assert_eq!(new.tools, Some(profile.spec.tools.clone()));
+
+        let mut narrower = new_run(serde_json::json!({
+            "prompt": "look", "model": "n", "tools": ["read_file", "read_d*"],
+        }));
+        apply(&profile, &mut narrower).unwrap();
+        assert_eq!(narrower.model, "n");
+
+        for tools in [
+            serde_json::json!(["write_file"]),
+            serde_json::json!(["*"]),
+            serde_json::json!(["search*"]),
+        ] {
+            let mut wider = new_run(serde_json::json!({ "prompt": "look", "tools": tools }));
+            assert!(apply(&profile, &mut wider).is_err(), "{tools}");
+        }
+
+        let store = MemoryStore::default();
+        let profiles = Profiles {
+            profiles: BTreeMap::from([("p".to_string(), profile)]),
+        };
+        save(&store, &profiles).unwrap();
+        assert!(profile_allows(&store, "p", "read_file").unwrap());
+        assert!(!profile_allows(&store, "p", "run_command").unwrap());
+        assert!(!profile_allows(&store, "gone", "read_file").unwrap());
+    }
+}
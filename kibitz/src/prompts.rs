@@ -0,0 +1,445 @@
+//! The shared prompt library: named, tagged prompt templates, such as
+//! system prompts, kept on the node for everyone using it.
+//!
+//! A template's text may hold variables, `{{name}}`, filled in when it is
+//! used. Changing the text adds a version rather than overwriting the last,
+//! so conversations and runs started from a template can say which text
+//! they got. A conversation or run takes a `PromptRef` in place of a system
+//! prompt: the template, a pinned version if wanted (else the latest), and
+//! the variables' values.
+
+use std::collections::BTreeMap;
+
+use hyperware_process_lib::http::server::IncomingHttpRequest;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::conversations::{self, NewConversation};
+use crate::http::{
+    read_json_body, send_http_error, send_http_json, send_http_response, url_param,
+    HTTP_BAD_REQUEST, HTTP_CREATED, HTTP_METHOD_NOT_ALLOWED, HTTP_NOT_FOUND, HTTP_OK,
+};
+use crate::store::{self, Store};
+use crate::sync::{ChangeEvent, SyncClients};
+
+pub const HTTP_PROMPTS_PATH: &str = "/api/prompts";
+pub const HTTP_PROMPT_PATH: &str = "/api/prompts/:id";
+pub const HTTP_PROMPT_RENDER_PATH: &str = "/api/prompts/:id/render";
+
+pub const PROMPTS_KEY: &str = "prompts";
+
+/// What a client says a template is: the body of `POST /api/prompts` and
+/// `PUT /api/prompts/{id}`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PromptSpec {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PromptVersion {
+    /// 1 for the first.
+    pub version: u32,
+    pub text: String,
+    pub created_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Prompt {
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Oldest first; never empty.
+    pub versions: Vec<PromptVersion>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl Prompt {
+    pub fn latest(&self) -> &PromptVersion {
+        self.versions.last().expect("a prompt has a version")
+    }
+
+    pub fn version(&self, version: u32) -> Option<&PromptVersion> {
+        self.versions.iter().find(|v| v.version == version)
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Prompts {
+    pub prompts: BTreeMap<String, Prompt>,
+}
+
+/// A template as listed: its latest version only.
+#[derive(Debug, Serialize)]
+pub struct PromptSummary<'a> {
+    pub id: &'a str,
+    pub name: &'a str,
+    pub description: &'a str,
+    pub tags: &'a [String],
+    pub version: u32,
+    pub text: &'a str,
+    pub variables: Vec<&'a str>,
+    pub updated_at: u64,
+}
+
+impl<'a> From<&'a Prompt> for PromptSummary<'a> {
+    fn from(prompt: &'a Prompt) -> Self {
+        let latest = prompt.latest();
+        Self {
+            id: &prompt.id,
+            name: &prompt.name,
+            description: &prompt.description,
+            tags: &prompt.tags,
+            version: latest.version,
+            text: &latest.text,
+            variables: variables(&latest.text),
+            updated_at: prompt.updated_at,
+        }
+    }
+}
+
+/// A template to use, in place of a system prompt.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PromptRef {
+    pub id: String,
+    /// The latest if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub variables: BTreeMap<String, String>,
+}
+
+/// The body of `POST /api/prompts/{id}/render`.
+#[derive(Debug, Deserialize)]
+struct RenderRequest {
+    #[serde(default)]
+    version: Option<u32>,
+    #[serde(default)]
+    variables: BTreeMap<String, String>,
+}
+
+pub fn load(store: &dyn Store) -> anyhow::Result<Prompts> {
+    Ok(store::get_json(store, PROMPTS_KEY)?.unwrap_or_default())
+}
+
+pub fn save(store: &dyn Store, prompts: &Prompts) -> anyhow::Result<()> {
+    store::set_json(store, PROMPTS_KEY, prompts)
+}
+
+/// The `{{name}}` placeholders in `text` and where each lies, in order.
+fn placeholders(text: &str) -> Vec<(std::ops::Range<usize>, &str)> {
+    let mut found = vec![];
+    let mut from = 0;
+    while let Some(start) = text[from..].find("{{").map(|at| from + at) {
+        let Some(end) = text[start + 2..].find("}}").map(|at| start + 2 + at) else {
+            break;
+        };
+        let name = text[start + 2..end].trim();
+        if !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            found.push((start..end + 2, name));
+            from = end + 2;
+        } else {
+            from = start + 2;
+        }
+    }
+    found
+}
+
+/// The names of the variables in `text`, each once, in order of first use.
+pub fn variables(text: &str) -> Vec<&str> {
+    let mut names: Vec<&str> = vec![];
+    for (_, name) in placeholders(text) {
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+/// `text` with its variables filled in, or the first one `values` lacks.
+pub fn render(text: &str, values: &BTreeMap<String, String>) -> Result<String, String> {
+    let mut rendered = String::with_capacity(text.len());
+    let mut last = 0;
+    for (range, name) in placeholders(text) {
+        let value = values
+            .get(name)
+            .ok_or_else(|| format!("no value for variable {name}"))?;
+        rendered.push_str(&text[last..range.start]);
+        rendered.push_str(value);
+        last = range.end;
+    }
+    rendered.push_str(&text[last..]);
+    Ok(rendered)
+}
+
+/// A referenced template's text, filled in, and the version it came from;
+/// or why there is none.
+pub fn resolve(
+    store: &dyn Store,
+    prompt: &PromptRef,
+) -> anyhow::Result<Result<(String, u32), String>> {
+    let prompts = load(store)?;
+    let Some(template) = prompts.prompts.get(&prompt.id) else {
+        return Ok(Err(format!("no prompt {}", prompt.id)));
+    };
+    let version = match prompt.version {
+        Some(version) => match template.version(version) {
+            Some(version) => version,
+            None => {
+                return Ok(Err(format!(
+                    "prompt {} has no version {version}",
+                    prompt.id
+                )))
+            }
+        },
+        None => template.latest(),
+    };
+    Ok(render(&version.text, &prompt.variables).map(|text| (text, version.version)))
+}
+
+/// Apply a template to a new conversation: its text becomes the
+/// conversation's `system` setting, and `system_prompt` says where that
+/// came from.
+pub fn apply(
+    store: &dyn Store,
+    new: &mut NewConversation,
+    prompt: &PromptRef,
+) -> anyhow::Result<Result<(), String>> {
+    let (text, version) = match resolve(store, prompt)? {
+        Ok(resolved) => resolved,
+        Err(message) => return Ok(Err(message)),
+    };
+    if new.settings.is_null() {
+        new.settings = json!({});
+    }
+    let Some(settings) = new.settings.as_object_mut() else {
+        return Ok(Err("settings must be an object".to_string()));
+    };
+    settings.insert("system".to_string(), json!(text));
+    settings.insert(
+        "system_prompt".to_string(),
+        json!({ "id": prompt.id, "version": version }),
+    );
+    Ok(Ok(()))
+}
+
+fn validate(spec: &mut PromptSpec) -> Result<(), String> {
+    spec.name = spec.name.trim().to_string();
+    if spec.name.is_empty() {
+        return Err("a prompt needs a name".to_string());
+    }
+    if spec.text.trim().is_empty() {
+        return Err("a prompt needs text".to_string());
+    }
+    let mut tags: Vec<String> = vec![];
+    for tag in &spec.tags {
+        let tag = tag.trim();
+        if !tag.is_empty() && !tags.iter().any(|t| t == tag) {
+            tags.push(tag.to_string());
+        }
+    }
+    spec.tags = tags;
+    Ok(())
+}
+
+/// Make a template from `spec`, at version 1.
+pub fn create(mut spec: PromptSpec, now: u64) -> Result<Prompt, String> {
+    validate(&mut spec)?;
+    Ok(Prompt {
+        id: conversations::new_id(),
+        name: spec.name,
+        description: spec.description,
+        tags: spec.tags,
+        versions: vec![PromptVersion {
+            version: 1,
+            text: spec.text,
+            created_at: now,
+        }],
+        created_at: now,
+        updated_at: now,
+    })
+}
+
+/// Update a template to `spec`, adding a version if its text changed.
+pub fn update(prompt: &mut Prompt, mut spec: PromptSpec, now: u64) -> Result<(), String> {
+    validate(&mut spec)?;
+    if spec.text != prompt.latest().text {
+        let version = PromptVersion {
+            version: prompt.latest().version + 1,
+            text: spec.text,
+            created_at: now,
+        };
+        prompt.versions.push(version);
+    }
+    prompt.name = spec.name;
+    prompt.description = spec.description;
+    prompt.tags = spec.tags;
+    prompt.updated_at = now;
+    Ok(())
+}
+
+/// Serve the prompt library endpoints.
+pub fn handle_http(
+    store: &dyn Store,
+    sync: &mut SyncClients,
+    http_request: &IncomingHttpRequest,
+    bound_path: &str,
+    now: u64,
+) -> anyhow::Result<()> {
+    let method = http_request.method()?;
+    let mut prompts = load(store)?;
+    match (method.as_str(), bound_path) {
+        ("GET", HTTP_PROMPTS_PATH) => {
+            let tag = http_request.query_params().get("tag");
+            let listed: Vec<PromptSummary> = prompts
+                .prompts
+                .values()
+                .filter(|prompt| tag.is_none_or(|tag| prompt.tags.contains(tag)))
+                .map(Into::into)
+                .collect();
+            send_http_json(HTTP_OK, &listed)
+        }
+        ("POST", HTTP_PROMPTS_PATH) => {
+            let Some(spec) = read_json_body::<PromptSpec>()? else {
+                return Ok(());
+            };
+            let prompt = match create(spec, now) {
+                Ok(prompt) => prompt,
+                Err(message) => return send_http_error(HTTP_BAD_REQUEST, &message),
+            };
+            prompts.prompts.insert(prompt.id.clone(), prompt.clone());
+            save(store, &prompts)?;
+            sync.publish(store, ChangeEvent::PromptsChanged, now);
+            send_http_json(HTTP_CREATED, &prompt)
+        }
+        (method, HTTP_PROMPT_PATH | HTTP_PROMPT_RENDER_PATH) => {
+            let Some(id) = url_param(http_request, "id") else {
+                return send_http_error(HTTP_BAD_REQUEST, "missing prompt id");
+            };
+            let Some(prompt) = prompts.prompts.get_mut(id) else {
+                return send_http_error(HTTP_NOT_FOUND, "no such prompt");
+            };
+            match (method, bound_path) {
+                ("GET", HTTP_PROMPT_PATH) => send_http_json(HTTP_OK, prompt),
+                ("PUT", HTTP_PROMPT_PATH) => {
+                    let Some(spec) = read_json_body::<PromptSpec>()? else {
+                        return Ok(());
+                    };
+                    if let Err(message) = update(prompt, spec, now) {
+                        return send_http_error(HTTP_BAD_REQUEST, &message);
+                    }
+                    let prompt = prompt.clone();
+                    save(store, &prompts)?;
+                    sync.publish(store, ChangeEvent::PromptsChanged, now);
+                    send_http_json(HTTP_OK, &prompt)
+                }
+                ("DELETE", HTTP_PROMPT_PATH) => {
+                    prompts.prompts.remove(id);
+                    save(store, &prompts)?;
+                    sync.publish(store, ChangeEvent::PromptsChanged, now);
+                    send_http_response(HTTP_OK, None)
+                }
+                ("POST", HTTP_PROMPT_RENDER_PATH) => {
+                    let Some(request) = read_json_body::<RenderRequest>()? else {
+                        return Ok(());
+                    };
+                    let prompt = PromptRef {
+                        id: id.to_string(),
+                        version: request.version,
+                        variables: request.variables,
+                    };
+                    match resolve(store, &prompt)? {
+                        Ok((text, version)) => {
+                            send_http_json(HTTP_OK, &json!({ "version": version, "text": text }))
+                        }
+                        Err(message) => send_http_error(HTTP_BAD_REQUEST, &message),
+                    }
+                }
+                _ => send_http_response(HTTP_METHOD_NOT_ALLOWED, None),
+            }
+        }
+        _ => send_http_response(HTTP_NOT_FOUND, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    fn spec(text: &str) -> PromptSpec {
+        PromptSpec {
+            name: " reviewer ".to_string(),
+            description: String::new(),
+            tags: vec!["review".to_string(), " ".to_string(), "review".to_string()],
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn variables_are_found_and_filled_in() {
+        let text = "Review {{ language }} code for {{team}}. {{language}} only. {{not a var}} {{";
+        assert_eq!(variables(text), vec!["language", "team"]);
+        let values = BTreeMap::from([
+            ("language".to_string(), "Rust".to_string()),
+            ("team".to_string(), "core".to_string()),
+        ]);
+        assert_eq!(
+            render(text, &values).unwrap(),
+            "Review Rust code for core. Rust only. {{not a var}} {{"
+        );
+        let partial = BTreeMap::from([("language".to_string(), "Rust".to_string())]);
+        assert_eq!(
+            render(text, &partial),
+            Err("no value for variable team".to_string())
+        );
+    }
+
+    #[test]
+    fn changing_the_text_adds_a_version() {
+        let mut prompt = create(spec("v1 {{x}}"), 5).unwrap();
+        assert_eq!(prompt.name, "reviewer");
+        assert_eq!(prompt.tags, vec!["review"]);
+        update(&mut prompt, spec("v1 {{x}}"), 6).unwrap();
+        assert_eq!(prompt.versions.len(), 1);
+        update(&mut prompt, spec("v2 {{x}}"), 7).unwrap();
+        assert_eq!(prompt.latest().version, 2);
+        assert!(update(&mut prompt, spec(" "), 8).is_err());
+
+        let store = MemoryStore::default();
+        let prompts = Prompts {
+            prompts: BTreeMap::from([(prompt.id.clone(), prompt.clone())]),
+        };
+        save(&store, &prompts).unwrap();
+        let mut by_ref = PromptRef {
+            id: prompt.id.clone(),
+            version: None,
+            variables: BTreeMap::from([("x".to_string(), "go".to_string())]),
+        };
+        assert_eq!(
+            resolve(&store, &by_ref).unwrap(),
+            Ok(("v2 go".to_string(), 2))
+        );
+        by_ref.version = Some(1);
+        let mut new = NewConversation {
+            title: String::new(),
+            project_id: None,
+            settings: serde_json::Value::Null,
+        };
+        apply(&store, &mut new, &by_ref).unwrap().unwrap();
+        assert_eq!(
+            new.settings,
+            json!({ "system": "v1 go", "system_prompt": { "id": prompt.id, "version": 1 } })
+        );
+        by_ref.version = Some(3);
+        assert!(resolve(&store, &by_ref).unwrap().is_err());
+    }
+}
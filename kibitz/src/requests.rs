@@ -0,0 +1,406 @@
+//! Typed requests from other processes on this node, as declared by the
+//! `kibitz` interface in `api/`.
+//!
+//! These reach the same data as the HTTP API, and writes are published to
+//! sync clients the same way. JSON-valued fields (settings, message
+//! content, prompt bodies) travel as JSON strings.
+
+use crate::conversations::{self, NewConversation, NewMessage, Page, Paged, ServedBy};
+use crate::crypto::Cipher;
+use crate::hyperware::process::kibitz::{self as wit, Request, Response};
+use crate::keys;
+use crate::llm::Calls;
+use crate::profiles;
+use crate::store::Store;
+use crate::sync::{ChangeEvent, SyncClients};
+
+impl From<conversations::ConversationSummary> for wit::ConversationSummary {
+    fn from(summary: conversations::ConversationSummary) -> Self {
+        Self {
+            id: summary.id,
+            title: summary.title,
+            project_id: summary.project_id,
+            updated_at: summary.updated_at,
+            message_count: summary.message_count,
+        }
+    }
+}
+
+impl From<conversations::Conversation> for wit::Conversation {
+    fn from(conversation: conversations::Conversation) -> Self {
+        Self {
+            id: conversation.id,
+            title: conversation.title,
+            project_id: conversation.project_id,
+            settings: conversation.settings.to_string(),
+            created_at: conversation.created_at,
+            updated_at: conversation.updated_at,
+            message_count: conversation.message_count,
+        }
+    }
+}
+
+impl From<conversations::ChatMessage> for wit::ChatMessage {
+    fn from(message: conversations::ChatMessage) -> Self {
+        Self {
+            seq: message.seq,
+            role: message.role,
+            content: message.content.to_string(),
+            served_by: message.served_by.map(|served_by| wit::ServedBy {
+                provider: served_by.provider,
+                model: served_by.model,
+            }),
+            created_at: message.created_at,
+        }
+    }
+}
+
+fn page(offset: u64, limit: u64) -> Result<Page, String> {
+    let offset = usize::try_from(offset).map_err(|_| "offset is too large".to_string())?;
+    Page::new(offset, usize::try_from(limit).unwrap_or(usize::MAX))
+}
+
+fn json(what: &str, value: &str) -> Result<serde_json::Value, String> {
+    serde_json::from_str(value).map_err(|e| format!("{what} is not JSON: {e}"))
+}
+
+/// Whether `request` changes anything, so must be refused while read-only.
+pub fn is_write(request: &Request) -> bool {
+    matches!(
+        request,
+        Request::SetKey(_)
+            | Request::DeleteKey(_)
+            | Request::CreateConversation(_)
+            | Request::AppendMessage(_)
+    )
+}
+
+/// Answer a request; failures the caller caused come back as `Err`
+/// responses, store failures as errors. `None` once a `run-prompt` call is
+/// sent off: `answer_run_prompt` answers it when the reply comes.
+pub fn handle(
+    store: &dyn Store,
+    cipher: &Cipher,
+    sync: &mut SyncClients,
+    calls: &mut Calls,
+    request: Request,
+    now: u64,
+) -> anyhow::Result<Option<Response>> {
+    Ok(
+        match handle_request(store, cipher, sync, calls, request, now)? {
+            Ok(response) => response,
+            Err(message) => Some(Response::Err(message)),
+        },
+    )
+}
+
+/// Answer a `run-prompt` request with the provider's reply.
+pub fn answer_run_prompt(reply: Result<(u16, Vec<u8>), String>) -> anyhow::Result<()> {
+    let response = match reply {
+        Ok((status, body)) => Response::RunPrompt(wit::RunPromptResponse {
+            status,
+            body: String::from_utf8_lossy(&body).into_owned(),
+        }),
+        Err(message) => Response::Err(message),
+    };
+    hyperware_process_lib::Response::new()
+        .body(response)
+        .send()?;
+    Ok(())
+}
+
+fn handle_request(
+    store: &dyn Store,
+    cipher: &Cipher,
+    sync: &mut SyncClients,
+    calls: &mut Calls,
+    request: Request,
+    now: u64,
+) -> anyhow::Result<Result<Option<Response>, String>> {
+    Ok(Ok(Some(match request {
+        Request::ListKeys => {
+            let keys = keys::list(store)?.value.keys;
+            Response::ListKeys(
+                keys.into_iter()
+                    .map(|(provider, info)| wit::KeyInfo {
+                        provider,
+                        last4: info.last4,
+                        created_at: info.created_at,
+                        updated_at: info.updated_at,
+                    })
+                    .collect(),
+            )
+        }
+        Request::SetKey(wit::SetKeyRequest { provider, key }) => {
+            keys::set(store, cipher, &provider, &key, now, None)?;
+            sync.publish(store, ChangeEvent::KeysChanged, now);
+            Response::SetKey
+        }
+        Request::DeleteKey(provider) => {
+            let deleted = keys::delete(store, &provider, None)?.is_some();
+            if deleted {
+                sync.publish(store, ChangeEvent::KeysChanged, now);
+            }
+            Response::DeleteKey(deleted)
+        }
+        Request::ListConversations(wit::ListConversationsRequest {
+            project_id,
+            offset,
+            limit,
+        }) => {
+            let page = match page(offset, limit) {
+                Ok(page) => page,
+                Err(message) => return Ok(Err(message)),
+            };
+            let Paged {
+                items,
+                total,
+                next_offset,
+            } = conversations::list(store, project_id.as_deref(), page)?;
+            Response::ListConversations(wit::ConversationPage {
+                items: items.into_iter().map(Into::into).collect(),
+                total: total as u64,
+                next_offset: next_offset.map(|offset| offset as u64),
+            })
+        }
+        Request::GetConversation(id) => Response::GetConversation(
+            conversations::get(store, &id)?.map(|conversation| conversation.value.into()),
+        ),
+        Request::CreateConversation(wit::CreateConversationRequest {
+            title,
+            project_id,
+            settings,
+        }) => {
+            let settings = match settings.map(|settings| json("settings", &settings)) {
+                Some(Ok(settings)) => settings,
+                Some(Err(message)) => return Ok(Err(message)),
+                None => serde_json::Value::Null,
+            };
+            let new = NewConversation {
+                title,
+                project_id,
+                settings,
+            };
+            let conversation = conversations::create(store, new, now)?.value;
+            let id = conversation.id.clone();
+            sync.publish(store, ChangeEvent::ConversationCreated { id }, now);
+            Response::CreateConversation(conversation.into())
+        }
+        Request::AppendMessage(wit::AppendMessageRequest {
+            conversation_id,
+            role,
+            content,
+            served_by,
+        }) => {
+            let content = match json("content", &content) {
+                Ok(content) => content,
+                Err(message) => return Ok(Err(message)),
+            };
+            let new = NewMessage {
+                role,
+                content,
+                served_by: served_by.map(|served_by| ServedBy {
+                    provider: served_by.provider,
+                    model: served_by.model,
+                }),
+            };
+            let appended = conversations::append(store, &conversation_id, new, now)?;
+            if let Some((message, _)) = &appended {
+                let event = ChangeEvent::MessageAppended {
+                    conversation_id,
+                    seq: message.seq,
+                };
+                sync.publish(store, event, now);
+            }
+            Response::AppendMessage(appended.map(|(message, _)| message.into()))
+        }
+        Request::GetMessages(wit::GetMessagesRequest {
+            conversation_id,
+            offset,
+            limit,
+        }) => {
+            let page = match page(offset, limit) {
+                Ok(page) => page,
+                Err(message) => return Ok(Err(message)),
+            };
+            let messages = conversations::messages(store, &conversation_id, page)?;
+            Response::GetMessages(messages.map(|messages| wit::MessagePage {
+                items: messages.items.into_iter().map(Into::into).collect(),
+                total: messages.total as u64,
+                next_offset: messages.next_offset.map(|offset| offset as u64),
+            }))
+        }
+        Request::RunPrompt(wit::RunPromptRequest {
+            provider,
+            path,
+            body,
+        }) => {
+            if let Err(message) = json("body", &body) {
+                return Ok(Err(message));
+            }
+            if let Err(message) =
+                calls.run_prompt(store, cipher, &provider, &path, body.into_bytes())?
+            {
+                return Ok(Err(message));
+            }
+            return Ok(Ok(None));
+        }
+        Request::CheckTools(wit::CheckToolsRequest { profile_id, tools }) => {
+            Response::CheckTools(profiles::get(store, &profile_id)?.map(|profile| {
+                tools
+                    .into_iter()
+                    .filter(|tool| !profiles::tool_allowed(&profile.spec.tools, tool))
+                    .collect()
+            }))
+        }
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn conversations_round_trip_as_json_strings() {
+        let store = MemoryStore::default();
+        let cipher = Cipher::new(&[1; 32]);
+        let mut sync = SyncClients::default();
+        let mut calls = Calls::default();
+        let mut call = |request| {
+            handle(&store, &cipher, &mut sync, &mut calls, request, 5)
+                .unwrap()
+                .unwrap()
+        };
+
+        let created = call(Request::CreateConversation(
+            wit::CreateConversationRequest {
+                title: "t".to_string(),
+                project_id: None,
+                settings: Some(r#"{"model":"m"}"#.to_string()),
+            },
+        ));
+        let Response::CreateConversation(conversation) = created else {
+            panic!("unexpected {created:?}");
+        };
+        assert_eq!(conversation.settings, r#"{"model":"m"}"#);
+
+        let append = |content: &str| {
+            Request::AppendMessage(wit::AppendMessageRequest {
+                conversation_id: conversation.id.clone(),
+                role: "user".to_string(),
+                content: content.to_string(),
+                served_by: None,
+            })
+        };
+        assert!(matches!(call(append("not json")), Response::Err(_)));
+        let Response::AppendMessage(Some(message)) = call(append(r#""hi""#)) else {
+            panic!("append failed");
+        };
+        assert_eq!((message.seq, message.content.as_str()), (0, r#""hi""#));
+        assert!(message.served_by.is_none());
+        let reply = Request::AppendMessage(wit::AppendMessageRequest {
+            conversation_id: conversation.id.clone(),
+            role: "assistant".to_string(),
+            content: r#""hello""#.to_string(),
+            served_by: Some(wit::ServedBy {
+                provider: "openai".to_string(),
+                model: "mini".to_string(),
+            }),
+        });
+        assert!(matches!(call(reply), Response::AppendMessage(Some(_))));
+
+        let get = Request::GetMessages(wit::GetMessagesRequest {
+            conversation_id: conversation.id.clone(),
+            offset: 0,
+            limit: 0,
+        });
+        assert!(matches!(call(get), Response::Err(_)));
+        let get = Request::GetMessages(wit::GetMessagesRequest {
+            conversation_id: conversation.id.clone(),
+            offset: 0,
+            limit: 10,
+        });
+        let Response::GetMessages(Some(page)) = call(get) else {
+            panic!("get failed");
+        };
+        assert_eq!((page.total, page.next_offset), (2, None));
+        let served_by = page.items[1].served_by.as_ref().expect("served-by");
+        assert_eq!(
+            (served_by.provider.as_str(), served_by.model.as_str()),
+            ("openai", "mini")
+        );
+    }
+
+    #[test]
+    fn keys_are_listed_without_their_value() {
+        let store = MemoryStore::default();
+        let cipher = Cipher::new(&[1; 32]);
+        let mut sync = SyncClients::default();
+        let mut calls = Calls::default();
+        let mut call = |request| {
+            handle(&store, &cipher, &mut sync, &mut calls, request, 5)
+                .unwrap()
+                .unwrap()
+        };
+
+        let set = Request::SetKey(wit::SetKeyRequest {
+            provider: "openai".to_string(),
+            key: "sk-0123456789".to_string(),
+        });
+        assert!(matches!(call(set), Response::SetKey));
+        let Response::ListKeys(keys) = call(Request::ListKeys) else {
+            panic!("list failed");
+        };
+        assert_eq!(keys.len(), 1);
+        assert_eq!(
+            (keys[0].provider.as_str(), keys[0].last4.as_str()),
+            ("openai", "6789")
+        );
+        assert!(matches!(
+            call(Request::DeleteKey("openai".to_string())),
+            Response::DeleteKey(true)
+        ));
+        assert!(matches!(
+            call(Request::DeleteKey("openai".to_string())),
+            Response::DeleteKey(false)
+        ));
+    }
+
+    #[test]
+    fn tools_are_checked_against_the_profile_as_it_is() {
+        let store = MemoryStore::default();
+        let cipher = Cipher::new(&[1; 32]);
+        let mut sync = SyncClients::default();
+        let mut calls = Calls::default();
+        let profile = profiles::Profile {
+            id: "p".to_string(),
+            spec: profiles::ProfileSpec {
+                name: "reader".to_string(),
+                description: String::new(),
+                model: None,
+                system: None,
+                system_prompt: None,
+                tools: vec!["read_*".to_string()],
+                max_steps: None,
+            },
+            created_at: 0,
+            updated_at: 0,
+        };
+        let mut stored = profiles::Profiles::default();
+        stored.profiles.insert(profile.id.clone(), profile);
+        profiles::save(&store, &stored).unwrap();
+        let mut check = |profile_id: &str| {
+            let request = Request::CheckTools(wit::CheckToolsRequest {
+                profile_id: profile_id.to_string(),
+                tools: vec!["read_file".to_string(), "write_file".to_string()],
+            });
+            match handle(&store, &cipher, &mut sync, &mut calls, request, 5).unwrap() {
+                Some(Response::CheckTools(denied)) => denied,
+                other => panic!("unexpected {other:?}"),
+            }
+        };
+        assert_eq!(check("p"), Some(vec!["write_file".to_string()]));
+        assert_eq!(check("gone"), None);
+    }
+}
@@ -0,0 +1,909 @@
+//! Failover and model routing for proxied LLM calls.
+//!
+//! A route, set in the provider settings' `routing`, is an ordered list of
+//! targets: a provider and, optionally, the model to ask it for.
+//! `/api/llm/{route}/{rest}` tries them in turn. A reply with one of
+//! `retry_on`'s statuses (or none at all) is retried `retries` more times,
+//! after a delay (`retry_delay_ms`), then the next target is tried; targets
+//! with no key or a spent budget are skipped. The route `auto` is the
+//! calling project's route, or else `default_route`; a project may also have
+//! a default model, used by its calls that name none.
+//!
+//! Targets needn't speak the client's API: plain-text calls that aren't
+//! streamed are translated between Anthropic Messages (`v1/messages`) and
+//! OpenAI Chat Completions (`v1/chat/completions`), replies included.
+//! Anything else only goes to targets speaking the client's API. A model
+//! name means nothing to a provider with another API, so in a route whose
+//! targets speak both, each must name its model.
+//!
+//! Replies name the target that answered in `x-kibitz-provider` and
+//! `x-kibitz-model`, for the client to record on the message.
+//!
+//! Only calls through `/api/llm/{route}/{rest}` fail over. Agent runs may
+//! name a route too, but each of their calls goes to the first of its
+//! targets that speaks Anthropic Messages and can be called; `run-prompt`
+//! requests name a provider and go straight to it.
+
+use std::collections::BTreeMap;
+
+use hyperware_process_lib::http::server::{HttpResponse, IncomingHttpRequest};
+use hyperware_process_lib::http::Method;
+use hyperware_process_lib::last_blob;
+use hyperware_process_lib::logging::info;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::crypto::Cipher;
+use crate::http::{
+    send_http, send_http_error, send_http_response, HTTP_BAD_GATEWAY, HTTP_BAD_REQUEST,
+    HTTP_METHOD_NOT_ALLOWED, HTTP_NOT_FOUND,
+};
+use crate::llm::{self, Calls};
+use crate::store::Store;
+use crate::sync::SyncClients;
+use crate::usage;
+
+/// The calling project's route, or the default one.
+pub const AUTO_ROUTE: &str = "auto";
+
+pub const PROVIDER_HEADER: &str = "x-kibitz-provider";
+pub const MODEL_HEADER: &str = "x-kibitz-model";
+
+/// Anthropic requires `max_tokens`; OpenAI calls translated to it that
+/// don't set one get this.
+const DEFAULT_MAX_TOKENS: u64 = 4096;
+const MAX_RETRIES: u32 = 5;
+
+fn default_retry_on() -> Vec<u16> {
+    vec![429, 500, 502, 503, 504, 529]
+}
+
+fn default_retries() -> u32 {
+    1
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Target {
+    pub provider: String,
+    /// The model to ask for instead of the one the call names.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProjectDefaults {
+    /// What `auto` means for the project's calls.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    /// For the project's calls that name no model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Policy {
+    #[serde(default)]
+    pub routes: BTreeMap<String, Vec<Target>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_route: Option<String>,
+    /// By project id.
+    #[serde(default)]
+    pub projects: BTreeMap<String, ProjectDefaults>,
+    #[serde(default = "default_retry_on")]
+    pub retry_on: Vec<u16>,
+    /// Retries of a target before moving on to the next.
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            routes: BTreeMap::new(),
+            default_route: None,
+            projects: BTreeMap::new(),
+            retry_on: default_retry_on(),
+            retries: default_retries(),
+        }
+    }
+}
+
+pub fn validate(policy: &Policy) -> anyhow::Result<()> {
+    for (name, targets) in &policy.routes {
+        anyhow::ensure!(
+            !name.is_empty() && !name.contains('/'),
+            "bad route name {name:?}"
+        );
+        anyhow::ensure!(
+            name != AUTO_ROUTE && llm::provider(name).is_none(),
+            "route {name} has a reserved name"
+        );
+        anyhow::ensure!(!targets.is_empty(), "route {name} has no targets");
+        for target in targets {
+            anyhow::ensure!(
+                llm::provider(&target.provider).is_some(),
+                "route {name}: unknown provider {}",
+                target.provider
+            );
+        }
+        let mixed = targets.iter().any(|target| {
+            Format::of_provider(&target.provider) != Format::of_provider(&targets[0].provider)
+        });
+        if mixed {
+            for target in targets {
+                anyhow::ensure!(
+                    target.model.is_some(),
+                    "route {name}: its targets speak different APIs, so {} needs a model",
+                    target.provider
+                );
+            }
+        }
+    }
+    let named = policy
+        .projects
+        .values()
+        .filter_map(|defaults| defaults.route.as_ref())
+        .chain(&policy.default_route);
+    for route in named {
+        anyhow::ensure!(policy.routes.contains_key(route), "no route named {route}");
+    }
+    anyhow::ensure!(
+        policy
+            .retry_on
+            .iter()
+            .all(|status| (400..600).contains(status)),
+        "retry_on takes error statuses"
+    );
+    anyhow::ensure!(
+        policy.retries <= MAX_RETRIES,
+        "at most {MAX_RETRIES} retries"
+    );
+    Ok(())
+}
+
+/// The targets of `route` for a call from `project_id`.
+pub fn targets<'a>(
+    policy: &'a Policy,
+    route: &str,
+    project_id: Option<&str>,
+) -> Option<&'a [Target]> {
+    let route = if route == AUTO_ROUTE {
+        project_id
+            .and_then(|id| policy.projects.get(id)?.route.as_ref())
+            .or(policy.default_route.as_ref())?
+    } else {
+        route
+    };
+    policy.routes.get(route).map(Vec::as_slice)
+}
+
+/// The targets a call naming `name`, a provider or a route, may go to.
+fn candidates(policy: &Policy, name: &str, project_id: Option<&str>) -> Option<Vec<Target>> {
+    if llm::provider(name).is_some() {
+        let target = Target {
+            provider: name.to_string(),
+            model: None,
+        };
+        return Some(vec![target]);
+    }
+    targets(policy, name, project_id).map(<[Target]>::to_vec)
+}
+
+/// Whether calls in `format` naming `name`, a provider or a route, have a
+/// target speaking it; if not, why not.
+pub fn check_format(
+    store: &dyn Store,
+    name: &str,
+    project_id: Option<&str>,
+    format: Format,
+) -> anyhow::Result<Result<(), String>> {
+    let policy = llm::get_settings(store)?.value.routing;
+    let Some(candidates) = candidates(&policy, name, project_id) else {
+        return Ok(Err(format!("no provider or route named {name}")));
+    };
+    Ok(
+        match candidates
+            .iter()
+            .any(|target| Format::of_provider(&target.provider) == format)
+        {
+            true => Ok(()),
+            false => Err(format!("{name} has no provider speaking {format:?}")),
+        },
+    )
+}
+
+/// For calls that are neither translated nor failed over, such as agent
+/// runs': the first target `name`, a provider or a route, offers that
+/// speaks `format` and has a key and budget to call now, and the upstream
+/// request for it; or why there is none.
+pub fn pick<'a>(
+    store: &dyn Store,
+    cipher: &Cipher,
+    name: &str,
+    project_id: Option<&str>,
+    format: Format,
+    client_headers: &[(&'a str, &'a str)],
+) -> anyhow::Result<Result<(Target, llm::Outgoing), llm::Refusal>> {
+    let policy = llm::get_settings(store)?.value.routing;
+    let mut refusal = llm::Refusal {
+        status: HTTP_NOT_FOUND,
+        message: format!("no provider speaking {format:?} in {name}"),
+    };
+    let candidates = candidates(&policy, name, project_id).unwrap_or_default();
+    for target in candidates {
+        if Format::of_provider(&target.provider) != format {
+            continue;
+        }
+        let headers = client_headers.iter().copied();
+        match llm::prepare(
+            store,
+            cipher,
+            &target.provider,
+            format.path(),
+            None,
+            headers,
+        )? {
+            Ok(outgoing) => return Ok(Ok((target, outgoing))),
+            Err(skipped) => refusal = skipped,
+        }
+    }
+    Ok(Err(refusal))
+}
+
+/// Fill in the project's default model if `body` names none.
+pub fn apply_default_model(policy: &Policy, project_id: Option<&str>, body: &mut Value) {
+    let Some(model) = project_id.and_then(|id| policy.projects.get(id)?.model.as_ref()) else {
+        return;
+    };
+    if let Some(body) = body.as_object_mut() {
+        body.entry("model").or_insert_with(|| json!(model));
+    }
+}
+
+/// The provider APIs calls can be translated between.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Anthropic Messages.
+    Anthropic,
+    /// OpenAI Chat Completions.
+    OpenAi,
+}
+
+impl Format {
+    /// The API a call to `rest` is made in.
+    pub fn of_path(rest: &str) -> Option<Self> {
+        match rest.trim_matches('/') {
+            "v1/messages" => Some(Self::Anthropic),
+            "v1/chat/completions" => Some(Self::OpenAi),
+            _ => None,
+        }
+    }
+
+    pub fn of_provider(provider: &str) -> Self {
+        match provider {
+            "openai" => Self::OpenAi,
+            _ => Self::Anthropic,
+        }
+    }
+
+    pub fn path(self) -> &'static str {
+        match self {
+            Self::Anthropic => "v1/messages",
+            Self::OpenAi => "v1/chat/completions",
+        }
+    }
+}
+
+/// Text content, as a string or text blocks, as one string; `None` if it
+/// holds anything else.
+fn plain_text(content: &Value) -> Option<String> {
+    match content {
+        Value::String(text) => Some(text.clone()),
+        Value::Array(blocks) => blocks
+            .iter()
+            .map(|block| match block["type"].as_str() {
+                Some("text") => block["text"].as_str(),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(|texts| texts.join("\n")),
+        _ => None,
+    }
+}
+
+fn copy(from: &Value, from_field: &str, to: &mut Map<String, Value>, to_field: &str) {
+    if let Some(value) = from.get(from_field).filter(|value| !value.is_null()) {
+        to.insert(to_field.to_string(), value.clone());
+    }
+}
+
+/// A call's body in the API `to` speaks, or why it can't be put in it.
+pub fn translate_request(body: &Value, from: Format, to: Format) -> Result<Value, String> {
+    if from == to {
+        return Ok(body.clone());
+    }
+    if body["stream"] == json!(true) {
+        return Err("streamed calls can't be translated".to_string());
+    }
+    if ["tools", "tool_choice", "functions"]
+        .iter()
+        .any(|field| !body[field].is_null())
+    {
+        return Err("calls with tools can't be translated".to_string());
+    }
+    let text = |message: &Value| {
+        plain_text(&message["content"]).ok_or("only text messages can be translated".to_string())
+    };
+    let mut out = Map::new();
+    copy(body, "model", &mut out, "model");
+    copy(body, "temperature", &mut out, "temperature");
+    copy(body, "top_p", &mut out, "top_p");
+    let mut messages = vec![];
+    match to {
+        Format::OpenAi => {
+            if !body["system"].is_null() {
+                let system = plain_text(&body["system"])
+                    .ok_or("only a text system prompt can be translated")?;
+                messages.push(json!({ "role": "system", "content": system }));
+            }
+            for message in body["messages"].as_array().into_iter().flatten() {
+                messages.push(json!({ "role": message["role"], "content": text(message)? }));
+            }
+            copy(body, "max_tokens", &mut out, "max_tokens");
+            copy(body, "stop_sequences", &mut out, "stop");
+        }
+        Format::Anthropic => {
+            let mut system = vec![];
+            for message in body["messages"].as_array().into_iter().flatten() {
+                match message["role"].as_str() {
+                    Some("system" | "developer") => system.push(text(message)?),
+                    Some(role @ ("user" | "assistant")) => {
+                        messages.push(json!({ "role": role, "content": text(message)? }));
+                    }
+                    _ => return Err("only text messages can be translated".to_string()),
+                }
+            }
+            if !system.is_empty() {
+                out.insert("system".to_string(), json!(system.join("\n\n")));
+            }
+            let max_tokens = body["max_tokens"]
+                .as_u64()
+                .or(body["max_completion_tokens"].as_u64())
+                .unwrap_or(DEFAULT_MAX_TOKENS);
+            out.insert("max_tokens".to_string(), json!(max_tokens));
+            match &body["stop"] {
+                Value::String(stop) => {
+                    out.insert("stop_sequences".to_string(), json!([stop]));
+                }
+                stop @ Value::Array(_) => {
+                    out.insert("stop_sequences".to_string(), stop.clone());
+                }
+                _ => {}
+            }
+        }
+    }
+    out.insert("messages".to_string(), Value::Array(messages));
+    Ok(Value::Object(out))
+}
+
+/// A successful reply from a target speaking `from`, in the API `to` the
+/// client speaks.
+pub fn translate_reply(reply: &Value, from: Format, to: Format) -> Value {
+    if from == to {
+        return reply.clone();
+    }
+    let count = |field: &str| reply["usage"][field].as_u64().unwrap_or(0);
+    match to {
+        Format::Anthropic => {
+            let choice = &reply["choices"][0];
+            let stop_reason = match choice["finish_reason"].as_str() {
+                Some("length") => "max_tokens",
+                _ => "end_turn",
+            };
+            json!({
+                "id": reply["id"],
+                "type": "message",
+                "role": "assistant",
+                "model": reply["model"],
+                "content": [{
+                    "type": "text",
+                    "text": choice["message"]["content"].as_str().unwrap_or_default(),
+                }],
+                "stop_reason": stop_reason,
+                "stop_sequence": null,
+                "usage": {
+                    "input_tokens": count("prompt_tokens"),
+                    "output_tokens": count("completion_tokens"),
+                },
+            })
+        }
+        Format::OpenAi => {
+            let text: Vec<&str> = reply["content"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|block| block["text"].as_str())
+                .collect();
+            let finish_reason = match reply["stop_reason"].as_str() {
+                Some("max_tokens") => "length",
+                _ => "stop",
+            };
+            let prompt_tokens = count("input_tokens")
+                + count("cache_read_input_tokens")
+                + count("cache_creation_input_tokens");
+            let completion_tokens = count("output_tokens");
+            json!({
+                "id": reply["id"],
+                "object": "chat.completion",
+                "model": reply["model"],
+                "choices": [{
+                    "index": 0,
+                    "message": { "role": "assistant", "content": text.join("") },
+                    "finish_reason": finish_reason,
+                }],
+                "usage": {
+                    "prompt_tokens": prompt_tokens,
+                    "completion_tokens": completion_tokens,
+                    "total_tokens": prompt_tokens + completion_tokens,
+                },
+            })
+        }
+    }
+}
+
+/// Wait before the first retry of a target, doubled for each one after.
+const RETRY_DELAY_MS: u64 = 1_000;
+/// Longest wait before retrying a target, whatever its `retry-after` asks.
+const MAX_RETRY_DELAY_MS: u64 = 30_000;
+
+/// How long to wait before retry `attempt` (from 1) of a target that
+/// answered with `retry_after`: as many seconds as it asks, or else backing
+/// off from `RETRY_DELAY_MS`.
+pub fn retry_delay_ms(attempt: u32, retry_after: Option<&str>) -> u64 {
+    retry_after
+        .and_then(|seconds| seconds.trim().parse::<u64>().ok())
+        .map(|seconds| seconds.saturating_mul(1000))
+        .unwrap_or_else(|| RETRY_DELAY_MS << attempt.saturating_sub(1).min(16))
+        .min(MAX_RETRY_DELAY_MS)
+}
+
+/// Why the last target tried didn't answer.
+enum Failure {
+    Refused(u16, String),
+    Upstream {
+        response: HttpResponse,
+        body: Vec<u8>,
+        target: Target,
+        model: String,
+    },
+}
+
+/// A call to a route, working through its targets.
+pub struct Routed {
+    route: String,
+    targets: Vec<Target>,
+    /// The target being tried, and how often it has been.
+    at: usize,
+    attempts: u32,
+    retries: u32,
+    retry_on: Vec<u16>,
+    /// The client's API.
+    format: Format,
+    body: Value,
+    client_headers: Vec<(String, String)>,
+    project_id: Option<String>,
+    /// The model asked of the target being tried.
+    model: String,
+    failure: Option<Failure>,
+}
+
+impl Routed {
+    /// The upstream request for the target being tried, or why it can't
+    /// be made.
+    fn prepare(
+        &mut self,
+        store: &dyn Store,
+        cipher: &Cipher,
+        target: &Target,
+    ) -> anyhow::Result<Result<(llm::Outgoing, Vec<u8>), Failure>> {
+        let target_format = Format::of_provider(&target.provider);
+        if target_format != self.format && target.model.is_none() {
+            return Ok(Err(Failure::Refused(
+                HTTP_BAD_REQUEST,
+                format!(
+                    "route {}: {} speaks another API and names no model",
+                    self.route, target.provider
+                ),
+            )));
+        }
+        let mut body = self.body.clone();
+        if let (Some(model), Some(body)) = (&target.model, body.as_object_mut()) {
+            body.insert("model".to_string(), json!(model));
+        }
+        self.model = usage::body_model(&serde_json::to_vec(&body)?).unwrap_or_default();
+        let body = match translate_request(&body, self.format, target_format) {
+            Ok(body) => serde_json::to_vec(&body)?,
+            Err(message) => return Ok(Err(Failure::Refused(HTTP_BAD_REQUEST, message))),
+        };
+        let client_headers = self
+            .client_headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()));
+        Ok(
+            match llm::prepare(
+                store,
+                cipher,
+                &target.provider,
+                target_format.path(),
+                None,
+                client_headers,
+            )? {
+                Ok(outgoing) => Ok((outgoing, body)),
+                Err(refusal) => Err(Failure::Refused(refusal.status, refusal.message)),
+            },
+        )
+    }
+
+    /// Answer the client with why the last target tried didn't answer.
+    fn give_up(self) -> anyhow::Result<()> {
+        match self.failure {
+            Some(Failure::Upstream {
+                response,
+                body,
+                target,
+                model,
+            }) => relay(response, body, &target, self.format, &model),
+            Some(Failure::Refused(status, message)) => send_http_error(status, &message),
+            None => send_http_error(
+                HTTP_NOT_FOUND,
+                &format!("route {} has no targets", self.route),
+            ),
+        }
+    }
+}
+
+fn header<'a>(response: &'a HttpResponse, name: &str) -> Option<&'a str> {
+    response
+        .headers
+        .iter()
+        .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// Relay an upstream reply from `target`, translated to `format` if it
+/// succeeded.
+fn relay(
+    upstream: HttpResponse,
+    mut body: Vec<u8>,
+    target: &Target,
+    format: Format,
+    model: &str,
+) -> anyhow::Result<()> {
+    let target_format = Format::of_provider(&target.provider);
+    if (200..300).contains(&upstream.status) && target_format != format {
+        if let Ok(reply) = serde_json::from_slice::<Value>(&body) {
+            body = serde_json::to_vec(&translate_reply(&reply, target_format, format))?;
+        }
+    }
+    let response = llm::response_for(&upstream)
+        .header(PROVIDER_HEADER, target.provider.as_str())
+        .header(MODEL_HEADER, model);
+    send_http(response, Some(body))
+}
+
+/// Forward a call to `/api/llm/{route}/{rest}` to the route's targets in
+/// turn; the first answer that isn't a retryable failure is relayed once
+/// it comes.
+pub fn handle_proxy(
+    store: &dyn Store,
+    cipher: &Cipher,
+    calls: &mut Calls,
+    http_request: &IncomingHttpRequest,
+    route: &str,
+    rest: &str,
+) -> anyhow::Result<()> {
+    let policy = llm::get_settings(store)?.value.routing;
+    let headers = http_request.headers();
+    let project_id = headers
+        .get(usage::PROJECT_HEADER)
+        .and_then(|value| value.to_str().ok());
+    let Some(targets) = targets(&policy, route, project_id) else {
+        return send_http_error(
+            HTTP_NOT_FOUND,
+            &format!("no provider or route named {route}"),
+        );
+    };
+    if http_request.method()? != Method::POST {
+        return send_http_response(HTTP_METHOD_NOT_ALLOWED, None);
+    }
+    let Some(format) = Format::of_path(rest) else {
+        return send_http_error(
+            HTTP_BAD_REQUEST,
+            "routes take v1/messages and v1/chat/completions calls",
+        );
+    };
+    let body = last_blob().map(|blob| blob.bytes).unwrap_or_default();
+    let mut body: Value = match serde_json::from_slice(&body) {
+        Ok(body) => body,
+        Err(e) => return send_http_error(HTTP_BAD_REQUEST, &format!("body is not JSON: {e}")),
+    };
+    apply_default_model(&policy, project_id, &mut body);
+    let client_headers = headers
+        .iter()
+        .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+        .collect();
+    let routed = Routed {
+        route: route.to_string(),
+        targets: targets.to_vec(),
+        at: 0,
+        attempts: 0,
+        retries: policy.retries,
+        retry_on: policy.retry_on,
+        format,
+        body,
+        client_headers,
+        project_id: project_id.map(str::to_string),
+        model: String::new(),
+        failure: None,
+    };
+    attempt(store, cipher, calls, Box::new(routed))
+}
+
+/// Call the target being tried, or else the next one that can be; answer
+/// the client once none is left.
+pub fn attempt(
+    store: &dyn Store,
+    cipher: &Cipher,
+    calls: &mut Calls,
+    mut routed: Box<Routed>,
+) -> anyhow::Result<()> {
+    while let Some(target) = routed.targets.get(routed.at).cloned() {
+        match routed.prepare(store, cipher, &target)? {
+            Ok((outgoing, body)) => {
+                info!(
+                    "route {}: calling {} (attempt {})",
+                    routed.route,
+                    outgoing.url,
+                    routed.attempts + 1
+                );
+                return calls.send_routed(routed, outgoing, body);
+            }
+            Err(failure) => {
+                if let Failure::Refused(_, ref message) = failure {
+                    info!(
+                        "route {}: skipping {}: {message}",
+                        routed.route, target.provider
+                    );
+                }
+                routed.failure = Some(failure);
+                routed.at += 1;
+                routed.attempts = 0;
+            }
+        }
+    }
+    routed.give_up()
+}
+
+/// The current target's reply: relay it, or retry the target after a
+/// while, or move on to the next.
+pub fn handle_reply(
+    store: &dyn Store,
+    cipher: &Cipher,
+    sync: &mut SyncClients,
+    calls: &mut Calls,
+    mut routed: Box<Routed>,
+    upstream: Result<(HttpResponse, Vec<u8>), String>,
+    now: u64,
+) -> anyhow::Result<()> {
+    let target = routed.targets[routed.at].clone();
+    routed.attempts += 1;
+    let retry_after = match upstream {
+        Ok((response, body)) if !routed.retry_on.contains(&response.status) => {
+            if (200..300).contains(&response.status) {
+                let (model, project_id) =
+                    (Some(routed.model.as_str()), routed.project_id.as_deref());
+                usage::note_reply(store, sync, &target.provider, model, project_id, &body, now);
+            }
+            return relay(response, body, &target, routed.format, &routed.model);
+        }
+        Ok((response, body)) => {
+            info!(
+                "route {}: {} answered {}",
+                routed.route, target.provider, response.status
+            );
+            let retry_after = header(&response, "retry-after").map(str::to_string);
+            let model = routed.model.clone();
+            routed.failure = Some(Failure::Upstream {
+                response,
+                body,
+                target: target.clone(),
+                model,
+            });
+            retry_after
+        }
+        Err(e) => {
+            let message = format!("{}: {e}", target.provider);
+            info!("route {}: {message}", routed.route);
+            routed.failure = Some(Failure::Refused(HTTP_BAD_GATEWAY, message));
+            None
+        }
+    };
+    if routed.attempts <= routed.retries {
+        let delay_ms = retry_delay_ms(routed.attempts, retry_after.as_deref());
+        info!(
+            "route {}: retrying {} in {delay_ms}ms",
+            routed.route, target.provider
+        );
+        return calls.retry_later(routed, delay_ms);
+    }
+    routed.at += 1;
+    routed.attempts = 0;
+    attempt(store, cipher, calls, routed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> Policy {
+        let target = |provider: &str, model: Option<&str>| Target {
+            provider: provider.to_string(),
+            model: model.map(str::to_string),
+        };
+        Policy {
+            routes: BTreeMap::from([
+                (
+                    "fast".to_string(),
+                    vec![
+                        target("anthropic", Some("haiku")),
+                        target("openai", Some("mini")),
+                    ],
+                ),
+                ("cheap".to_string(), vec![target("openai", Some("mini"))]),
+            ]),
+            default_route: Some("fast".to_string()),
+            projects: BTreeMap::from([(
+                "p".to_string(),
+                ProjectDefaults {
+                    route: Some("cheap".to_string()),
+                    model: Some("mini".to_string()),
+                },
+            )]),
+            ..Policy::default()
+        }
+    }
+
+    #[test]
+    fn retries_back_off_unless_asked_to_wait() {
+        assert_eq!(retry_delay_ms(1, None), 1_000);
+        assert_eq!(retry_delay_ms(3, None), 4_000);
+        assert_eq!(retry_delay_ms(5, Some("bad")), 16_000);
+        assert_eq!(retry_delay_ms(1, Some(" 7 ")), 7_000);
+        assert_eq!(retry_delay_ms(1, Some("3600")), MAX_RETRY_DELAY_MS);
+        assert_eq!(retry_delay_ms(40, None), MAX_RETRY_DELAY_MS);
+    }
+
+    #[test]
+    fn auto_routes_by_project_and_policies_are_validated() {
+        let policy = policy();
+        assert!(validate(&policy).is_ok());
+        assert_eq!(targets(&policy, "auto", None).unwrap().len(), 2);
+        assert_eq!(targets(&policy, "auto", Some("p")).unwrap().len(), 1);
+        assert_eq!(targets(&policy, "auto", Some("other")).unwrap().len(), 2);
+        assert!(targets(&policy, "slow", None).is_none());
+
+        let mut body = json!({ "messages": [] });
+        apply_default_model(&policy, Some("p"), &mut body);
+        assert_eq!(body["model"], "mini");
+        let mut body = json!({ "model": "mine" });
+        apply_default_model(&policy, Some("p"), &mut body);
+        assert_eq!(body["model"], "mine");
+
+        let mut bad = policy.clone();
+        bad.routes.insert("openai".to_string(), vec![]);
+        assert!(validate(&bad).is_err());
+        let mut bad = policy.clone();
+        bad.default_route = Some("slow".to_string());
+        assert!(validate(&bad).is_err());
+        let mut bad = policy.clone();
+        bad.routes.get_mut("fast").unwrap()[1].model = None;
+        assert!(validate(&bad).is_err());
+        let mut bad = policy;
+        bad.retry_on = vec![200];
+        assert!(validate(&bad).is_err());
+    }
+
+    #[test]
+    fn untranslated_calls_pick_the_first_target_speaking_their_api() {
+        use crate::store::MemoryStore;
+        use crate::{keys, llm::ProviderSettings};
+
+        let store = MemoryStore::default();
+        let cipher = Cipher::new(&[1; 32]);
+        let settings = ProviderSettings {
+            routing: policy(),
+            ..ProviderSettings::default()
+        };
+        llm::set_settings(&store, settings, None).unwrap();
+        let check = |name: &str| check_format(&store, name, None, Format::Anthropic).unwrap();
+        assert!(check("fast").is_ok());
+        assert!(check("auto").is_ok());
+        assert!(check("anthropic").is_ok());
+        assert!(check("cheap").is_err());
+        assert!(check("openai").is_err());
+        assert!(check("nope").is_err());
+
+        let pick = |name: &str| {
+            pick(&store, &cipher, name, None, Format::Anthropic, &[])
+                .unwrap()
+                .map(|(target, _)| target)
+        };
+        keys::set(&store, &cipher, "openai", "sk-openai", 1, None).unwrap();
+        assert_eq!(pick("fast").unwrap_err().status, HTTP_NOT_FOUND);
+        keys::set(&store, &cipher, "anthropic", "sk-ant", 1, None).unwrap();
+        let target = pick("fast").unwrap();
+        assert_eq!(
+            (target.provider.as_str(), target.model.as_deref()),
+            ("anthropic", Some("haiku"))
+        );
+        assert_eq!(pick("anthropic").unwrap().model, None);
+        assert!(pick("cheap").is_err());
+    }
+
+    #[test]
+    fn text_calls_translate_both_ways() {
+        let anthropic = json!({
+            "model": "m",
+            "system": [{ "type": "text", "text": "be brief" }],
+            "messages": [{ "role": "user", "content": [{ "type": "text", "text": "hi" }] }],
+            "max_tokens": 100,
+            "stop_sequences": ["END"],
+        });
+        let openai = translate_request(&anthropic, Format::Anthropic, Format::OpenAi).unwrap();
+        assert_eq!(
+            openai,
+            json!({
+                "model": "m",
+                "messages": [
+                    { "role": "system", "content": "be brief" },
+                    { "role": "user", "content": "hi" },
+                ],
+                "max_tokens": 100,
+                "stop": ["END"],
+            })
+        );
+        let back = translate_request(&openai, Format::OpenAi, Format::Anthropic).unwrap();
+        assert_eq!(back["system"], "be brief");
+        assert_eq!(
+            back["messages"],
+            json!([{ "role": "user", "content": "hi" }])
+        );
+        assert_eq!(back["stop_sequences"], json!(["END"]));
+
+        let mut streamed = anthropic.clone();
+        streamed["stream"] = json!(true);
+        assert!(translate_request(&streamed, Format::Anthropic, Format::OpenAi).is_err());
+        let image = json!({ "messages": [{ "role": "user", "content": [{ "type": "image" }] }] });
+        assert!(translate_request(&image, Format::Anthropic, Format::OpenAi).is_err());
+        // The same API needs no translating
+        assert_eq!(
+            translate_request(&streamed, Format::Anthropic, Format::Anthropic).unwrap(),
+            streamed
+        );
+
+        let reply = json!({
+            "id": "c1",
+            "model": "gpt",
+            "choices": [{ "message": { "content": "hello" }, "finish_reason": "length" }],
+            "usage": { "prompt_tokens": 3, "completion_tokens": 4 },
+        });
+        let translated = translate_reply(&reply, Format::OpenAi, Format::Anthropic);
+        assert_eq!(translated["content"][0]["text"], "hello");
+        assert_eq!(translated["stop_reason"], "max_tokens");
+        assert_eq!(translated["usage"]["output_tokens"], 4);
+        let round_trip = translate_reply(&translated, Format::Anthropic, Format::OpenAi);
+        assert_eq!(round_trip["choices"][0]["message"]["content"], "hello");
+        assert_eq!(round_trip["choices"][0]["finish_reason"], "length");
+        assert_eq!(round_trip["usage"]["total_tokens"], 7);
+    }
+}
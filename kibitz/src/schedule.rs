@@ -0,0 +1,624 @@
+//! Scheduled and recurring agent runs.
+//!
+//! A task is an agent run (as `POST /api/runs` takes it) and a cron
+//! schedule, stored with the rest under `TASKS_KEY`. When one falls due
+//! kibitz starts the run, so its result is saved as a conversation like any
+//! other, and records the attempt in the task's history. A failed attempt
+//! is retried after `retry_delay_s`, up to `max_retries` times. Occurrences
+//! missed while kibitz was stopped are run once, not once each.
+//!
+//! `timer:distro:sys` wakes kibitz when the next task is due, and every
+//! `POLL_S` while a task's run is going, to see how it ended.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use hyperware_process_lib::http::server::IncomingHttpRequest;
+use hyperware_process_lib::logging::info;
+use hyperware_process_lib::timer::set_timer;
+use serde::{Deserialize, Serialize};
+
+use crate::agent::{self, NewRun, RunStatus, Runs};
+use crate::conversations;
+use crate::crypto::Cipher;
+use crate::export::civil_from_days;
+use crate::http::{
+    read_json_body, send_http_error, send_http_json, send_http_response, url_param,
+    HTTP_BAD_REQUEST, HTTP_CREATED, HTTP_METHOD_NOT_ALLOWED, HTTP_NOT_FOUND, HTTP_OK,
+};
+use crate::store::{self, Store};
+use crate::sync::{ChangeEvent, SyncClients};
+
+pub const HTTP_TASKS_PATH: &str = "/api/tasks";
+pub const HTTP_TASK_PATH: &str = "/api/tasks/:id";
+pub const HTTP_TASK_RUN_PATH: &str = "/api/tasks/:id/run";
+
+pub const TASKS_KEY: &str = "tasks";
+
+/// Sent with our timers, to tell their pops apart.
+pub const TIMER_CONTEXT: &[u8] = b"schedule";
+
+/// How often to look in on a task's running attempt.
+const POLL_S: u64 = 60;
+/// Attempts kept in each task's history.
+const HISTORY_LEN: usize = 50;
+/// How far ahead to look for a schedule's next time.
+const HORIZON_DAYS: u64 = 366 * 5;
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_max_retries() -> u32 {
+    2
+}
+
+fn default_retry_delay_s() -> u64 {
+    300
+}
+
+/// What a client says a task is: the body of `POST /api/tasks` and `PUT
+/// /api/tasks/{id}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskSpec {
+    pub name: String,
+    /// `minute hour day-of-month month day-of-week`, in UTC.
+    pub schedule: String,
+    pub run: NewRun,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    #[serde(default = "default_retry_delay_s")]
+    pub retry_delay_s: u64,
+}
+
+/// One attempt at running a task.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TaskRun {
+    /// When the occurrence was due; retries share it.
+    pub scheduled_for: u64,
+    /// 1 for the first try.
+    pub attempt: u32,
+    pub started_at: u64,
+    /// Unset if the run could not even be started.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conversation_id: Option<String>,
+    pub status: RunStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Task {
+    pub id: String,
+    #[serde(flatten)]
+    pub spec: TaskSpec,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_run_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_at: Option<u64>,
+    /// Oldest first.
+    #[serde(default)]
+    pub history: VecDeque<TaskRun>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Tasks {
+    pub tasks: BTreeMap<String, Task>,
+}
+
+pub fn load(store: &dyn Store) -> anyhow::Result<Tasks> {
+    Ok(store::get_json(store, TASKS_KEY)?.unwrap_or_default())
+}
+
+pub fn save(store: &dyn Store, tasks: &Tasks) -> anyhow::Result<()> {
+    store::set_json(store, TASKS_KEY, tasks)
+}
+
+/// A parsed cron schedule. Each field is a bit set of the values it
+/// matches.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether day-of-month and day-of-week were `*`: if both are
+    /// restricted, a day matching either will do, as in cron.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step = step
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|step| *step > 0)
+                    .ok_or_else(|| format!("bad step in {part}"))?;
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let value = |value: &str| {
+            value
+                .parse::<u32>()
+                .ok()
+                .filter(|value| (min..=max).contains(value))
+                .ok_or_else(|| format!("{value} is not in {min}-{max}"))
+        };
+        let (from, to) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((from, to)) => (value(from)?, value(to)?),
+                // `5/10` means from 5 on
+                None if step > 1 => (value(range)?, max),
+                None => {
+                    let value = value(range)?;
+                    (value, value)
+                }
+            },
+        };
+        if from > to {
+            return Err(format!("{range} is backwards"));
+        }
+        for value in (from..=to).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+impl Cron {
+    pub fn parse(schedule: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = schedule.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(
+                "a schedule has five fields: minute hour day-of-month month day-of-week"
+                    .to_string(),
+            );
+        };
+        let field = |name: &str, field: &str, min: u32, max: u32| {
+            parse_field(field, min, max).map_err(|e| format!("{name}: {e}"))
+        };
+        // Sunday is 0 or 7
+        let mut weekday_bits = field("day-of-week", weekdays, 0, 7)?;
+        if weekday_bits & 1 << 7 != 0 {
+            weekday_bits = (weekday_bits | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            minutes: field("minute", minutes, 0, 59)?,
+            hours: field("hour", hours, 0, 23)?,
+            days: field("day-of-month", days, 1, 31)?,
+            months: field("month", months, 1, 12)?,
+            weekdays: weekday_bits,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        })
+    }
+
+    fn matches_day(&self, days_since_epoch: u64) -> bool {
+        let (_, month, day) = civil_from_days(days_since_epoch);
+        // 1970-01-01 was a Thursday
+        let weekday = (days_since_epoch + 4) % 7;
+        let day_matches = self.days & 1 << day != 0;
+        let weekday_matches = self.weekdays & 1 << weekday != 0;
+        let day_ok = match (self.any_day, self.any_weekday) {
+            (false, false) => day_matches || weekday_matches,
+            _ => day_matches && weekday_matches,
+        };
+        self.months & 1 << month != 0 && day_ok
+    }
+
+    /// The first time after `after` that the schedule matches, if there is
+    /// one within `HORIZON_DAYS` (e.g. not for `0 0 30 2 *`).
+    pub fn next_after(&self, after: u64) -> Option<u64> {
+        let start = (after / 60 + 1) * 60;
+        let first_day = start / 86400;
+        (first_day..first_day + HORIZON_DAYS)
+            .filter(|day| self.matches_day(*day))
+            .find_map(|day| {
+                (0..24u64)
+                    .filter(|hour| self.hours & 1 << hour != 0)
+                    .flat_map(|hour| {
+                        (0..60u64)
+                            .filter(|minute| self.minutes & 1 << minute != 0)
+                            .map(move |minute| day * 86400 + hour * 3600 + minute * 60)
+                    })
+                    .find(|time| *time >= start)
+            })
+    }
+}
+
+/// Record how the latest attempt ended, and when to retry it if it failed
+/// and has retries left.
+fn settle(task: &mut Task, status: RunStatus, error: Option<String>, now: u64) {
+    let Some(last) = task.history.back_mut() else {
+        return;
+    };
+    last.status = status;
+    last.error = error;
+    if status == RunStatus::Failed && last.attempt <= task.spec.max_retries {
+        task.retry_at = Some(now + task.spec.retry_delay_s);
+    }
+}
+
+/// The occurrence and attempt due at `now`, if any, with the task moved on
+/// past it.
+pub fn due(task: &mut Task, cron: &Cron, now: u64) -> Option<(u64, u32)> {
+    if !task.spec.enabled {
+        return None;
+    }
+    let running = task
+        .history
+        .back()
+        .is_some_and(|last| last.status == RunStatus::Running);
+    if let Some(retry_at) = task.retry_at.filter(|retry_at| *retry_at <= now) {
+        task.retry_at = None;
+        if let Some(last) = task.history.back() {
+            return Some((last.scheduled_for, last.attempt + 1));
+        }
+        return Some((retry_at, 1));
+    }
+    let next_run_at = task.next_run_at.filter(|next_run_at| *next_run_at <= now)?;
+    task.next_run_at = cron.next_after(now);
+    if running {
+        info!("task {}: still running, skipping this time", task.id);
+        return None;
+    }
+    // A retry still pending is superseded by the new occurrence
+    task.retry_at = None;
+    Some((next_run_at, 1))
+}
+
+/// When the scheduler next needs waking for `tasks`.
+pub fn next_wake(tasks: &Tasks, now: u64) -> Option<u64> {
+    tasks
+        .tasks
+        .values()
+        .filter(|task| task.spec.enabled)
+        .flat_map(|task| {
+            let running = task
+                .history
+                .back()
+                .is_some_and(|last| last.status == RunStatus::Running);
+            [
+                task.next_run_at,
+                task.retry_at,
+                running.then_some(now + POLL_S),
+            ]
+        })
+        .flatten()
+        .min()
+}
+
+/// Keeps a timer set for when the next task is due.
+#[derive(Default)]
+pub struct Scheduler {
+    armed_for: Option<u64>,
+}
+
+impl Scheduler {
+    fn arm(&mut self, tasks: &Tasks, now: u64) {
+        let Some(wake) = next_wake(tasks, now) else {
+            return;
+        };
+        if self
+            .armed_for
+            .is_some_and(|armed_for| armed_for > now && armed_for <= wake)
+        {
+            return;
+        }
+        set_timer(
+            wake.saturating_sub(now).max(1) * 1000,
+            Some(TIMER_CONTEXT.to_vec()),
+        );
+        self.armed_for = Some(wake);
+    }
+
+    /// Set the timer for tasks written other than through `handle_http`,
+    /// such as by a restore.
+    pub fn rearm(&mut self, store: &dyn Store, now: u64) -> anyhow::Result<()> {
+        self.arm(&load(store)?, now);
+        Ok(())
+    }
+
+    /// Start an attempt at `task` and record it.
+    fn start(
+        task: &mut Task,
+        store: &dyn Store,
+        cipher: &Cipher,
+        sync: &mut SyncClients,
+        runs: &mut Runs,
+        (scheduled_for, attempt): (u64, u32),
+        now: u64,
+    ) -> anyhow::Result<()> {
+        info!("task {}: starting attempt {attempt}", task.id);
+        let started = runs.start(store, cipher, sync, task.spec.run.clone(), now)?;
+        let (run, status, error) = match started {
+            Ok(run) => {
+                let status = run.status;
+                let error = run.error.clone();
+                (Some(run), status, error)
+            }
+            Err(message) => (None, RunStatus::Failed, Some(message)),
+        };
+        task.history.push_back(TaskRun {
+            scheduled_for,
+            attempt,
+            started_at: now,
+            run_id: run.as_ref().map(|run| run.id.clone()),
+            conversation_id: run.map(|run| run.conversation_id),
+            status: RunStatus::Running,
+            error: None,
+        });
+        while task.history.len() > HISTORY_LEN {
+            task.history.pop_front();
+        }
+        if status != RunStatus::Running {
+            settle(task, status, error, now);
+        }
+        task.updated_at = now;
+        Ok(())
+    }
+
+    /// Run whatever is due, note how running attempts ended, and set the
+    /// timer for next time.
+    pub fn tick(
+        &mut self,
+        store: &dyn Store,
+        cipher: &Cipher,
+        sync: &mut SyncClients,
+        runs: &mut Runs,
+        now: u64,
+    ) -> anyhow::Result<()> {
+        self.armed_for = self.armed_for.filter(|armed_for| *armed_for > now);
+        let mut tasks = load(store)?;
+        let mut changed = false;
+        for task in tasks.tasks.values_mut() {
+            let running = task
+                .history
+                .back()
+                .filter(|last| last.status == RunStatus::Running)
+                .map(|last| last.run_id.clone());
+            if let Some(run_id) = running {
+                let run = match run_id {
+                    Some(run_id) => agent::get(store, &run_id)?,
+                    None => None,
+                };
+                match run {
+                    Some(run) if run.status == RunStatus::Running => {}
+                    Some(run) => {
+                        settle(task, run.status, run.error, now);
+                        changed = true;
+                    }
+                    None => {
+                        settle(
+                            task,
+                            RunStatus::Failed,
+                            Some("run was lost".to_string()),
+                            now,
+                        );
+                        changed = true;
+                    }
+                }
+            }
+            let Ok(cron) = Cron::parse(&task.spec.schedule) else {
+                continue;
+            };
+            let before = (task.next_run_at, task.retry_at);
+            if let Some(occurrence) = due(task, &cron, now) {
+                Self::start(task, store, cipher, sync, runs, occurrence, now)?;
+                changed = true;
+            }
+            changed |= before != (task.next_run_at, task.retry_at);
+        }
+        if changed {
+            save(store, &tasks)?;
+            sync.publish(store, ChangeEvent::TasksChanged, now);
+        }
+        self.arm(&tasks, now);
+        Ok(())
+    }
+
+    /// Serve the task endpoints.
+    pub fn handle_http(
+        &mut self,
+        store: &dyn Store,
+        cipher: &Cipher,
+        sync: &mut SyncClients,
+        runs: &mut Runs,
+        http_request: &IncomingHttpRequest,
+        bound_path: &str,
+    ) -> anyhow::Result<()> {
+        let now = crate::now();
+        let method = http_request.method()?;
+        let mut tasks = load(store)?;
+        let task = match (method.as_str(), bound_path) {
+            ("GET", HTTP_TASKS_PATH) => {
+                let tasks: Vec<&Task> = tasks.tasks.values().collect();
+                return send_http_json(HTTP_OK, &tasks);
+            }
+            ("POST", HTTP_TASKS_PATH) => {
+                let Some(spec) = read_json_body::<TaskSpec>()? else {
+                    return Ok(());
+                };
+                let cron = match Cron::parse(&spec.schedule) {
+                    Ok(cron) => cron,
+                    Err(message) => return send_http_error(HTTP_BAD_REQUEST, &message),
+                };
+                let task = Task {
+                    id: conversations::new_id(),
+                    spec,
+                    next_run_at: cron.next_after(now),
+                    retry_at: None,
+                    history: VecDeque::new(),
+                    created_at: now,
+                    updated_at: now,
+                };
+                tasks.tasks.insert(task.id.clone(), task.clone());
+                save(store, &tasks)?;
+                sync.publish(store, ChangeEvent::TasksChanged, now);
+                self.arm(&tasks, now);
+                return send_http_json(HTTP_CREATED, &task);
+            }
+            (method, HTTP_TASK_PATH | HTTP_TASK_RUN_PATH) => {
+                let Some(id) = url_param(http_request, "id") else {
+                    return send_http_error(HTTP_BAD_REQUEST, "missing task id");
+                };
+                let Some(task) = tasks.tasks.get_mut(id) else {
+                    return send_http_error(HTTP_NOT_FOUND, "no such task");
+                };
+                match (method, bound_path) {
+                    ("GET", HTTP_TASK_PATH) => return send_http_json(HTTP_OK, task),
+                    ("PUT", HTTP_TASK_PATH) => {
+                        let Some(spec) = read_json_body::<TaskSpec>()? else {
+                            return Ok(());
+                        };
+                        let cron = match Cron::parse(&spec.schedule) {
+                            Ok(cron) => cron,
+                            Err(message) => return send_http_error(HTTP_BAD_REQUEST, &message),
+                        };
+                        task.spec = spec;
+                        task.next_run_at = cron.next_after(now);
+                        task.retry_at = None;
+                        task.updated_at = now;
+                    }
+                    ("DELETE", HTTP_TASK_PATH) => {
+                        tasks.tasks.remove(id);
+                        save(store, &tasks)?;
+                        sync.publish(store, ChangeEvent::TasksChanged, now);
+                        return send_http_response(HTTP_OK, None);
+                    }
+                    // Run it now, whatever its schedule
+                    ("POST", HTTP_TASK_RUN_PATH) => {
+                        Self::start(task, store, cipher, sync, runs, (now, 1), now)?;
+                    }
+                    _ => return send_http_response(HTTP_METHOD_NOT_ALLOWED, None),
+                }
+                task.clone()
+            }
+            _ => return send_http_response(HTTP_NOT_FOUND, None),
+        };
+        save(store, &tasks)?;
+        sync.publish(store, ChangeEvent::TasksChanged, now);
+        self.arm(&tasks, now);
+        send_http_json(HTTP_OK, &task)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-01-01 00:00 UTC, a Monday.
+    const NEW_YEAR_2024: u64 = 1704067200;
+    const HOUR: u64 = 3600;
+    const DAY: u64 = 86400;
+
+    fn next(schedule: &str, after: u64) -> Option<u64> {
+        Cron::parse(schedule).unwrap().next_after(after)
+    }
+
+    #[test]
+    fn cron_schedules_find_their_next_time() {
+        let at = NEW_YEAR_2024 + 10 * HOUR;
+        assert_eq!(next("0 9 * * *", at), Some(NEW_YEAR_2024 + DAY + 9 * HOUR));
+        assert_eq!(next("*/15 * * * *", at + 60), Some(at + 15 * 60));
+        // Strictly after: not the minute we are in
+        assert_eq!(next("0 10 * * *", at), Some(at + DAY));
+        // Weekdays only: Saturday the 6th is skipped for Monday the 8th
+        assert_eq!(
+            next("30 8 * * 1-5", NEW_YEAR_2024 + 4 * DAY + 9 * HOUR),
+            Some(NEW_YEAR_2024 + 7 * DAY + 8 * HOUR + 30 * 60)
+        );
+        // Sunday as 7; day-of-month or day-of-week once both are restricted
+        assert_eq!(next("0 0 * * 7", at), Some(NEW_YEAR_2024 + 6 * DAY));
+        assert_eq!(next("0 0 3 * 0", at), Some(NEW_YEAR_2024 + 2 * DAY));
+        assert_eq!(next("0 0 1 3 *", at), Some(NEW_YEAR_2024 + 60 * DAY));
+        assert_eq!(next("0 0 30 2 *", at), None);
+
+        for bad in [
+            "* * * *",
+            "60 * * * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "a * * * *",
+        ] {
+            assert!(Cron::parse(bad).is_err(), "{bad}");
+        }
+    }
+
+    fn task(now: u64) -> Task {
+        Task {
+            id: "t".to_string(),
+            spec: TaskSpec {
+                name: "commits".to_string(),
+                schedule: "0 9 * * *".to_string(),
+                run: serde_json::from_value(
+                    serde_json::json!({ "prompt": "summarise", "model": "m" }),
+                )
+                .unwrap(),
+                enabled: true,
+                max_retries: 1,
+                retry_delay_s: 60,
+            },
+            next_run_at: Cron::parse("0 9 * * *").unwrap().next_after(now),
+            retry_at: None,
+            history: VecDeque::new(),
+            created_at: now,
+            updated_at: now,
+        }
+    }
+
+    fn attempt(task: &mut Task, (scheduled_for, attempt): (u64, u32), now: u64) {
+        task.history.push_back(TaskRun {
+            scheduled_for,
+            attempt,
+            started_at: now,
+            run_id: Some(format!("r{attempt}")),
+            conversation_id: None,
+            status: RunStatus::Running,
+            error: None,
+        });
+    }
+
+    #[test]
+    fn failures_are_retried_until_out_of_retries() {
+        let cron = Cron::parse("0 9 * * *").unwrap();
+        let mut task = task(NEW_YEAR_2024);
+        let nine = NEW_YEAR_2024 + 9 * HOUR;
+        assert_eq!(due(&mut task, &cron, nine - 60), None);
+        // Missed by a while: run once, and next due tomorrow
+        let late = nine + 3 * HOUR;
+        assert_eq!(due(&mut task, &cron, late), Some((nine, 1)));
+        assert_eq!(task.next_run_at, Some(nine + DAY));
+        attempt(&mut task, (nine, 1), late);
+
+        let tasks = Tasks {
+            tasks: BTreeMap::from([("t".to_string(), task.clone())]),
+        };
+        assert_eq!(next_wake(&tasks, late), Some(late + POLL_S));
+
+        settle(&mut task, RunStatus::Failed, Some("429".to_string()), late);
+        assert_eq!(task.retry_at, Some(late + 60));
+        assert_eq!(due(&mut task, &cron, late + 30), None);
+        assert_eq!(due(&mut task, &cron, late + 60), Some((nine, 2)));
+        attempt(&mut task, (nine, 2), late + 60);
+        settle(&mut task, RunStatus::Failed, None, late + 120);
+        assert_eq!(task.retry_at, None);
+
+        task.spec.enabled = false;
+        assert_eq!(due(&mut task, &cron, nine + DAY), None);
+        task.spec.enabled = true;
+        assert_eq!(due(&mut task, &cron, nine + DAY), Some((nine + DAY, 1)));
+    }
+}
@@ -0,0 +1,550 @@
+//! Full-text search over stored conversation messages.
+//!
+//! An inverted index kept in the db alongside the messages:
+//! * `search:term:{term}`: the `Postings` of every message containing
+//!   `term`, with how often it occurs there.
+//! * `search:conversation:{id}`: what conversation `id` put in the index,
+//!   so deleting it knows which postings to drop.
+//! * `SEARCH_STATS_KEY`: message count and total length, for ranking.
+//!
+//! Messages are indexed as they are appended. Results are messages holding
+//! every query term, ranked by BM25 with a boost for terms in the
+//! conversation title.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use hyperware_process_lib::http::server::IncomingHttpRequest;
+use serde::{Deserialize, Serialize};
+
+use crate::conversations::{self, ChatMessage, Conversation, Page, Paged};
+use crate::http::{send_http_error, send_http_json, HTTP_BAD_REQUEST, HTTP_OK};
+use crate::store::{self, Store};
+
+pub const HTTP_SEARCH_PATH: &str = "/api/search";
+
+pub const SEARCH_STATS_KEY: &str = "search:stats";
+
+/// Longer "words" are mostly encoded data, not worth indexing.
+const MAX_TERM_LEN: usize = 64;
+
+/// Characters of context kept either side of the first match in a snippet.
+const SNIPPET_BEFORE: usize = 60;
+const SNIPPET_AFTER: usize = 140;
+
+// BM25 parameters
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+/// Added to the score for each query term in the conversation title.
+const TITLE_BOOST: f64 = 1.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Posting {
+    conversation_id: String,
+    seq: u64,
+    /// Occurrences of the term in the message.
+    count: u32,
+    /// Terms in the whole message.
+    length: u32,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Postings {
+    postings: Vec<Posting>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct IndexedConversation {
+    terms: BTreeSet<String>,
+    messages: u64,
+    length: u64,
+    /// Terms in each indexed message, by seq.
+    #[serde(default)]
+    lengths: BTreeMap<u64, u32>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Stats {
+    messages: u64,
+    length: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchHit {
+    pub conversation_id: String,
+    pub title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub project_id: Option<String>,
+    pub seq: u64,
+    pub role: String,
+    pub created_at: u64,
+    pub score: f64,
+    pub snippet: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Filters {
+    pub project_id: Option<String>,
+    /// Matched against the conversation's `settings.model`.
+    pub model: Option<String>,
+    /// Only messages created at or after this time.
+    pub since: Option<u64>,
+    /// Only messages created before this time.
+    pub until: Option<u64>,
+}
+
+impl Filters {
+    /// `?project=&model=&since=&until=`.
+    pub fn from_query(query: &HashMap<String, String>) -> Result<Self, String> {
+        let time = |name: &str| {
+            query
+                .get(name)
+                .map(|value| {
+                    value
+                        .parse::<u64>()
+                        .map_err(|_| format!("{name} must be seconds since the epoch"))
+                })
+                .transpose()
+        };
+        Ok(Self {
+            project_id: query.get("project").cloned(),
+            model: query.get("model").cloned(),
+            since: time("since")?,
+            until: time("until")?,
+        })
+    }
+
+    fn admits(&self, conversation: &Conversation, message_created_at: u64) -> bool {
+        let model = conversation.settings.get("model").and_then(|m| m.as_str());
+        (self.project_id.is_none() || conversation.project_id == self.project_id)
+            && (self.model.is_none() || model == self.model.as_deref())
+            && self.since.is_none_or(|since| message_created_at >= since)
+            && self.until.is_none_or(|until| message_created_at < until)
+    }
+}
+
+fn term_key(term: &str) -> String {
+    format!("search:term:{term}")
+}
+
+fn conversation_key(id: &str) -> String {
+    format!("search:conversation:{id}")
+}
+
+/// The words of `text` with their byte offsets, as written.
+fn words(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(move |word| (word.as_ptr() as usize - text.as_ptr() as usize, word))
+}
+
+fn normalize(word: &str) -> Option<String> {
+    let count = word.chars().count();
+    (count > 1 && count <= MAX_TERM_LEN).then(|| word.to_lowercase())
+}
+
+/// Index terms of `text`, in order, repeats included.
+pub fn tokenize(text: &str) -> Vec<String> {
+    words(text)
+        .filter_map(|(_, word)| normalize(word))
+        .collect()
+}
+
+/// The searchable text of a message: plain string content, or the `text`
+/// of its content blocks (including those nested in tool results).
+pub fn message_text(content: &serde_json::Value) -> String {
+    fn collect(value: &serde_json::Value, out: &mut Vec<String>) {
+        match value {
+            serde_json::Value::String(text) => out.push(text.clone()),
+            serde_json::Value::Array(blocks) => {
+                for block in blocks {
+                    collect(block, out);
+                }
+            }
+            serde_json::Value::Object(block) => {
+                if let Some(serde_json::Value::String(text)) = block.get("text") {
+                    out.push(text.clone());
+                }
+                if let Some(content) = block.get("content") {
+                    collect(content, out);
+                }
+            }
+            _ => {}
+        }
+    }
+    let mut out = vec![];
+    collect(content, &mut out);
+    out.join("\n")
+}
+
+/// Add a newly appended message to the index.
+pub fn index_message(store: &dyn Store, message: &ChatMessage, id: &str) -> anyhow::Result<()> {
+    let terms = tokenize(&message_text(&message.content));
+    let length = terms.len() as u32;
+    let mut counts: BTreeMap<String, u32> = BTreeMap::new();
+    for term in terms {
+        *counts.entry(term).or_default() += 1;
+    }
+
+    let key = conversation_key(id);
+    let mut indexed: IndexedConversation = store::get_json(store, &key)?.unwrap_or_default();
+    // Re-indexing the same message (e.g. after an interrupted append)
+    // replaces rather than duplicates it
+    let previous = indexed.lengths.insert(message.seq, length);
+    if previous.is_some() {
+        for term in indexed
+            .terms
+            .iter()
+            .filter(|term| !counts.contains_key(*term))
+        {
+            let key = term_key(term);
+            let Some(mut postings) = store::get_json::<Postings>(store, &key)? else {
+                continue;
+            };
+            let before = postings.postings.len();
+            postings
+                .postings
+                .retain(|p| !(p.conversation_id == id && p.seq == message.seq));
+            if postings.postings.is_empty() {
+                store.delete(&key)?;
+            } else if postings.postings.len() != before {
+                store::set_json(store, &key, &postings)?;
+            }
+        }
+    }
+    for (term, count) in &counts {
+        let key = term_key(term);
+        let mut postings: Postings = store::get_json(store, &key)?.unwrap_or_default();
+        postings
+            .postings
+            .retain(|p| !(p.conversation_id == id && p.seq == message.seq));
+        postings.postings.push(Posting {
+            conversation_id: id.to_string(),
+            seq: message.seq,
+            count: *count,
+            length,
+        });
+        store::set_json(store, &key, &postings)?;
+    }
+
+    let previous_length = u64::from(previous.unwrap_or(0));
+    let added = u64::from(previous.is_none());
+    indexed.terms.extend(counts.into_keys());
+    indexed.messages += added;
+    indexed.length = indexed.length.saturating_sub(previous_length) + u64::from(length);
+    store::set_json(store, &key, &indexed)?;
+
+    let mut stats: Stats = store::get_json(store, SEARCH_STATS_KEY)?.unwrap_or_default();
+    stats.messages += added;
+    stats.length = stats.length.saturating_sub(previous_length) + u64::from(length);
+    store::set_json(store, SEARCH_STATS_KEY, &stats)
+}
+
+/// Drop everything conversation `id` put in the index.
+pub fn unindex_conversation(store: &dyn Store, id: &str) -> anyhow::Result<()> {
+    let key = conversation_key(id);
+    let Some(indexed) = store::get_json::<IndexedConversation>(store, &key)? else {
+        return Ok(());
+    };
+    for term in &indexed.terms {
+        let key = term_key(term);
+        let Some(mut postings) = store::get_json::<Postings>(store, &key)? else {
+            continue;
+        };
+        postings.postings.retain(|p| p.conversation_id != id);
+        if postings.postings.is_empty() {
+            store.delete(&key)?;
+        } else {
+            store::set_json(store, &key, &postings)?;
+        }
+    }
+    let mut stats: Stats = store::get_json(store, SEARCH_STATS_KEY)?.unwrap_or_default();
+    stats.messages = stats.messages.saturating_sub(indexed.messages);
+    stats.length = stats.length.saturating_sub(indexed.length);
+    if stats.messages == 0 {
+        store.delete(SEARCH_STATS_KEY)?;
+    } else {
+        store::set_json(store, SEARCH_STATS_KEY, &stats)?;
+    }
+    store.delete(&key)
+}
+
+/// Up to `SNIPPET_BEFORE` and `SNIPPET_AFTER` characters around the first
+/// of `terms` in `text`, on one line.
+pub fn snippet(text: &str, terms: &BTreeSet<String>) -> String {
+    let at = words(text)
+        .find(|(_, word)| normalize(word).is_some_and(|term| terms.contains(&term)))
+        .map_or(0, |(at, _)| at);
+    let start = text[..at]
+        .char_indices()
+        .rev()
+        .nth(SNIPPET_BEFORE - 1)
+        .map_or(0, |(i, _)| i);
+    let end = text[at..]
+        .char_indices()
+        .nth(SNIPPET_AFTER)
+        .map_or(text.len(), |(i, _)| at + i);
+    let mut snippet = text[start..end]
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    if start > 0 {
+        snippet.insert(0, '…');
+    }
+    if end < text.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+/// Messages containing every term of `query` that pass `filters`, best
+/// first (newest first among equals).
+pub fn search(
+    store: &dyn Store,
+    query: &str,
+    filters: &Filters,
+    page: Page,
+) -> anyhow::Result<Paged<SearchHit>> {
+    let terms: BTreeSet<String> = tokenize(query).into_iter().collect();
+    let stats: Stats = store::get_json(store, SEARCH_STATS_KEY)?.unwrap_or_default();
+    let average_length = (stats.length as f64 / stats.messages.max(1) as f64).max(1.0);
+
+    // (conversation, seq) -> (score, terms matched)
+    let mut matches: HashMap<(String, u64), (f64, usize)> = HashMap::new();
+    for term in &terms {
+        let postings: Postings = store::get_json(store, &term_key(term))?.unwrap_or_default();
+        let n = postings.postings.len() as f64;
+        let idf = ((stats.messages as f64 - n + 0.5) / (n + 0.5) + 1.0).ln();
+        for posting in postings.postings {
+            let count = f64::from(posting.count);
+            let norm = 1.0 - B + B * f64::from(posting.length) / average_length;
+            let score = idf * count * (K1 + 1.0) / (count + K1 * norm);
+            let entry = matches
+                .entry((posting.conversation_id, posting.seq))
+                .or_default();
+            entry.0 += score;
+            entry.1 += 1;
+        }
+    }
+
+    let mut conversations: HashMap<String, Option<Conversation>> = HashMap::new();
+    let mut hits = vec![];
+    for ((id, seq), (score, matched)) in matches {
+        if matched < terms.len() {
+            continue;
+        }
+        if !conversations.contains_key(&id) {
+            let conversation = conversations::get(store, &id)?.map(|doc| doc.value);
+            conversations.insert(id.clone(), conversation);
+        }
+        let Some(conversation) = &conversations[&id] else {
+            continue;
+        };
+        let Some(message) = conversations::message(store, &id, seq)? else {
+            continue;
+        };
+        if !filters.admits(conversation, message.created_at) {
+            continue;
+        }
+        let title_terms: BTreeSet<String> = tokenize(&conversation.title).into_iter().collect();
+        let boost = TITLE_BOOST * terms.intersection(&title_terms).count() as f64;
+        hits.push((score + boost, message, conversation.clone()));
+    }
+    hits.sort_by(|(a, a_message, _), (b, b_message, _)| {
+        b.total_cmp(a)
+            .then(b_message.created_at.cmp(&a_message.created_at))
+    });
+
+    let total = hits.len();
+    let items = hits
+        .drain(page.range(total))
+        .map(|(score, message, conversation)| SearchHit {
+            conversation_id: conversation.id,
+            title: conversation.title,
+            project_id: conversation.project_id,
+            seq: message.seq,
+            role: message.role,
+            created_at: message.created_at,
+            score,
+            snippet: snippet(&message_text(&message.content), &terms),
+        })
+        .collect();
+    Ok(page.paged(items, total))
+}
+
+/// Index every stored conversation, e.g. ones stored before search existed.
+pub fn reindex_all(store: &dyn Store) -> anyhow::Result<()> {
+    let everything = Page {
+        offset: 0,
+        limit: usize::MAX,
+    };
+    for summary in conversations::list(store, None, everything)?.items {
+        unindex_conversation(store, &summary.id)?;
+        let Some(conversation) = conversations::get(store, &summary.id)? else {
+            continue;
+        };
+        for seq in 0..conversation.value.message_count {
+            if let Some(message) = conversations::message(store, &summary.id, seq)? {
+                index_message(store, &message, &summary.id)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Serve `GET /api/search?q=&project=&model=&since=&until=&offset=&limit=`.
+pub fn handle_http(store: &dyn Store, http_request: &IncomingHttpRequest) -> anyhow::Result<()> {
+    let query = http_request.query_params();
+    let Some(q) = query.get("q").filter(|q| !tokenize(q).is_empty()) else {
+        return send_http_error(HTTP_BAD_REQUEST, "q must contain a word to search for");
+    };
+    let (filters, page) = match Filters::from_query(query)
+        .and_then(|filters| Ok((filters, Page::from_query(query)?)))
+    {
+        Ok(parsed) => parsed,
+        Err(message) => return send_http_error(HTTP_BAD_REQUEST, &message),
+    };
+    send_http_json(HTTP_OK, &search(store, q, &filters, page)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversations::{append, create, delete, NewConversation, NewMessage};
+    use crate::store::MemoryStore;
+
+    fn page() -> Page {
+        Page {
+            offset: 0,
+            limit: 10,
+        }
+    }
+
+    fn conversation(store: &MemoryStore, title: &str, model: &str) -> String {
+        let new = NewConversation {
+            title: title.to_string(),
+            project_id: None,
+            settings: serde_json::json!({ "model": model }),
+        };
+        create(store, new, 1).unwrap().value.id
+    }
+
+    fn say(store: &MemoryStore, id: &str, content: serde_json::Value, now: u64) {
+        let new = NewMessage {
+            role: "user".to_string(),
+            content,
+            served_by: None,
+        };
+        append(store, id, new, now).unwrap().unwrap();
+    }
+
+    #[test]
+    fn finds_messages_with_every_term_best_first() {
+        let store = MemoryStore::default();
+        let a = conversation(&store, "Migration bug", "claude");
+        let b = conversation(&store, "Lunch", "gpt");
+        say(
+            &store,
+            &a,
+            serde_json::json!("The migration fails on v2."),
+            2,
+        );
+        say(
+            &store,
+            &a,
+            serde_json::json!([{"type": "text", "text": "Fixed the MIGRATION bug by resealing keys"}]),
+            3,
+        );
+        say(&store, &b, serde_json::json!("No bug here, just lunch"), 4);
+
+        let hits = search(&store, "migration bug", &Filters::default(), page()).unwrap();
+        assert_eq!(hits.total, 1);
+        assert_eq!(hits.items[0].conversation_id, a);
+        assert_eq!(hits.items[0].seq, 1);
+        assert_eq!(
+            hits.items[0].snippet,
+            "Fixed the MIGRATION bug by resealing keys"
+        );
+
+        let hits = search(&store, "bug", &Filters::default(), page()).unwrap();
+        assert_eq!(hits.total, 2);
+        // The title match outranks the shorter message
+        assert_eq!(hits.items[0].conversation_id, a);
+
+        let filters = Filters {
+            model: Some("gpt".to_string()),
+            ..Filters::default()
+        };
+        let hits = search(&store, "bug", &filters, page()).unwrap();
+        assert_eq!(hits.items[0].conversation_id, b);
+        let filters = Filters {
+            since: Some(4),
+            ..Filters::default()
+        };
+        assert_eq!(search(&store, "bug", &filters, page()).unwrap().total, 1);
+    }
+
+    #[test]
+    fn deleted_conversations_leave_the_index() {
+        let store = MemoryStore::default();
+        let id = conversation(&store, "x", "claude");
+        say(&store, &id, serde_json::json!("ephemeral words"), 2);
+        assert_eq!(
+            search(&store, "ephemeral", &Filters::default(), page())
+                .unwrap()
+                .total,
+            1
+        );
+        delete(&store, &id, None).unwrap();
+        assert_eq!(
+            store.entries.borrow().keys().collect::<Vec<_>>(),
+            vec![conversations::CONVERSATIONS_KEY]
+        );
+        reindex_all(&store).unwrap();
+        assert_eq!(
+            search(&store, "ephemeral", &Filters::default(), page())
+                .unwrap()
+                .total,
+            0
+        );
+    }
+
+    #[test]
+    fn reindexing_a_message_replaces_it_in_the_stats() {
+        let store = MemoryStore::default();
+        let id = conversation(&store, "x", "claude");
+        say(&store, &id, serde_json::json!("first draft words"), 2);
+        say(&store, &id, serde_json::json!("another message"), 3);
+        let stats = || {
+            store::get_json::<Stats>(&store, SEARCH_STATS_KEY)
+                .unwrap()
+                .unwrap()
+        };
+        assert_eq!((stats().messages, stats().length), (2, 5));
+
+        let mut message = conversations::message(&store, &id, 0).unwrap().unwrap();
+        index_message(&store, &message, &id).unwrap();
+        assert_eq!((stats().messages, stats().length), (2, 5));
+
+        message.content = serde_json::json!("final words");
+        index_message(&store, &message, &id).unwrap();
+        assert_eq!((stats().messages, stats().length), (2, 4));
+        let indexed: IndexedConversation = store::get_json(&store, &conversation_key(&id))
+            .unwrap()
+            .unwrap();
+        assert_eq!((indexed.messages, indexed.length), (2, 4));
+        for (q, total) in [("draft", 0), ("final", 1), ("words", 1)] {
+            let hits = search(&store, q, &Filters::default(), page()).unwrap();
+            assert_eq!(hits.total, total, "{q}");
+        }
+    }
+
+    #[test]
+    fn snippets_are_trimmed_around_the_match() {
+        let text = format!("{} needle {}", "a ".repeat(100), "b ".repeat(100));
+        let terms = BTreeSet::from(["needle".to_string()]);
+        let snippet = snippet(&text, &terms);
+        assert!(snippet.starts_with('…') && snippet.ends_with('…'));
+        assert!(snippet.contains("needle"));
+        assert!(snippet.chars().count() < SNIPPET_BEFORE + SNIPPET_AFTER + 2);
+    }
+}
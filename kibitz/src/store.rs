@@ -0,0 +1,340 @@
+//! Storage for everything kibitz keeps in its KV db.
+//!
+//! All reads and writes go through the `Store` trait so the logic on top can
+//! be exercised off-node against `MemoryStore`. The db carries a schema
+//! version record; `migrate` brings older layouts up to date at startup.
+
+use anyhow::Context;
+use hyperware_process_lib::kv::{self, Kv, KvError};
+use hyperware_process_lib::PackageId;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::crypto::{self, Cipher};
+use crate::keys;
+
+pub const DB_NAME: &str = "kibitz_api_keys";
+
+pub const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+/// Current schema; bump it and append to `MIGRATIONS` whenever the layout
+/// of anything in the db changes.
+pub const SCHEMA_VERSION: u32 = 4;
+
+type Migration = fn(&dyn Store, &Cipher) -> anyhow::Result<()>;
+
+/// `MIGRATIONS[n]` rewrites a version `n` db into version `n + 1`.
+const MIGRATIONS: &[Migration] = &[v0_to_v1, v1_to_v2, v2_to_v3, v3_to_v4];
+
+pub trait Store {
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>>;
+    fn set(&self, key: &str, value: &[u8]) -> anyhow::Result<()>;
+    fn delete(&self, key: &str) -> anyhow::Result<()>;
+}
+
+pub fn get_json<T: DeserializeOwned>(store: &dyn Store, key: &str) -> anyhow::Result<Option<T>> {
+    store
+        .get(key)?
+        .map(|bytes| serde_json::from_slice(&bytes))
+        .transpose()
+        .with_context(|| format!("malformed value at {key}"))
+}
+
+pub fn set_json<T: Serialize>(store: &dyn Store, key: &str, value: &T) -> anyhow::Result<()> {
+    store.set(key, &serde_json::to_vec(value)?)
+}
+
+/// A document carrying a revision counter that every write bumps, so a
+/// writer can tell whether someone else wrote since it read.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Revisioned<T> {
+    /// Documents written before revisions existed read as revision 0.
+    #[serde(default)]
+    pub revision: u64,
+    #[serde(flatten)]
+    pub value: T,
+}
+
+/// A write named a revision other than the current one.
+#[derive(Debug)]
+pub struct Conflict {
+    pub current: u64,
+}
+
+impl std::fmt::Display for Conflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "document has changed (now at revision {})", self.current)
+    }
+}
+
+impl std::error::Error for Conflict {}
+
+pub fn get_revisioned<T: DeserializeOwned + Default>(
+    store: &dyn Store,
+    key: &str,
+) -> anyhow::Result<Revisioned<T>> {
+    Ok(get_json(store, key)?.unwrap_or_default())
+}
+
+/// Fail with `Conflict` unless `expected` is `None` (any revision) or `doc`'s.
+pub fn check_revision<T>(doc: &Revisioned<T>, expected: Option<u64>) -> anyhow::Result<()> {
+    match expected {
+        Some(expected) if expected != doc.revision => Err(Conflict {
+            current: doc.revision,
+        }
+        .into()),
+        _ => Ok(()),
+    }
+}
+
+/// Write back a document read with `get_revisioned`, returning its new
+/// revision.
+pub fn put_revisioned<T: Serialize>(
+    store: &dyn Store,
+    key: &str,
+    doc: &mut Revisioned<T>,
+    expected: Option<u64>,
+) -> anyhow::Result<u64> {
+    check_revision(doc, expected)?;
+    doc.revision += 1;
+    set_json(store, key, doc)?;
+    Ok(doc.revision)
+}
+
+/// The node's KV db, keyed by the raw bytes of `key`.
+pub struct KvStore {
+    kv: Kv<Vec<u8>, Vec<u8>>,
+}
+
+impl KvStore {
+    pub fn open(package_id: PackageId) -> anyhow::Result<Self> {
+        Ok(Self {
+            kv: kv::open_raw(package_id, DB_NAME, None)?,
+        })
+    }
+}
+
+fn is_not_found(error: &anyhow::Error) -> bool {
+    matches!(error.downcast_ref::<KvError>(), Some(KvError::KeyNotFound))
+}
+
+impl Store for KvStore {
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        match self.kv.get_raw(key.as_bytes()) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if is_not_found(&e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn set(&self, key: &str, value: &[u8]) -> anyhow::Result<()> {
+        self.kv.set_raw(key.as_bytes(), value, None)
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        match self.kv.delete_raw(key.as_bytes(), None) {
+            Err(e) if !is_not_found(&e) => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Bring the db up to `SCHEMA_VERSION`, returning the version it started at.
+pub fn migrate(store: &dyn Store, cipher: &Cipher) -> anyhow::Result<u32> {
+    let from = get_json::<u32>(store, SCHEMA_VERSION_KEY)?.unwrap_or(0);
+    if from > SCHEMA_VERSION {
+        anyhow::bail!("db schema version {from} is newer than supported version {SCHEMA_VERSION}");
+    }
+    for version in from..SCHEMA_VERSION {
+        MIGRATIONS[version as usize](store, cipher)
+            .with_context(|| format!("failed to migrate db from schema version {version}"))?;
+        // Record progress so a failure later in the chain resumes from here
+        set_json(store, SCHEMA_VERSION_KEY, &(version + 1))?;
+    }
+    Ok(from)
+}
+
+/// v0 stored `ApiKeys` under the JSON encoding of `b"api_keys".to_vec()`
+/// (the key type of the old `Kv<Vec<u8>, ApiKeys>` handle); v1 keys are
+/// plain strings.
+pub const V0_API_KEYS_KEY: &str = "[97,112,105,95,107,101,121,115]";
+
+fn v0_to_v1(store: &dyn Store, _cipher: &Cipher) -> anyhow::Result<()> {
+    let Some(api_keys) = store.get(V0_API_KEYS_KEY)? else {
+        return Ok(());
+    };
+    let api_keys: serde_json::Value =
+        serde_json::from_slice(&api_keys).context("malformed v0 api keys")?;
+    anyhow::ensure!(
+        api_keys.get("keys").is_some_and(|keys| keys.is_object()),
+        "v0 api keys have no `keys` map"
+    );
+    set_json(store, keys::API_KEYS_KEY, &api_keys)?;
+    store.delete(V0_API_KEYS_KEY)
+}
+
+/// v1 stored API keys in plaintext; v2 seals each one (in place, so a
+/// partially sealed map from an interrupted run is fine).
+fn v1_to_v2(store: &dyn Store, cipher: &Cipher) -> anyhow::Result<()> {
+    let Some(mut api_keys) = get_json::<ApiKeysV1>(store, keys::API_KEYS_KEY)? else {
+        return Ok(());
+    };
+    for (provider, key) in api_keys.keys.iter_mut() {
+        if !crypto::is_sealed(key) {
+            *key = cipher.seal(key.as_bytes(), provider.as_bytes())?;
+        }
+    }
+    set_json(store, keys::API_KEYS_KEY, &api_keys)
+}
+
+/// v2 stored bare sealed strings; v3 adds metadata so keys can be listed
+/// without decrypting them.
+fn v2_to_v3(store: &dyn Store, cipher: &Cipher) -> anyhow::Result<()> {
+    let Some(api_keys) = get_json::<ApiKeysV1>(store, keys::API_KEYS_KEY)? else {
+        return Ok(());
+    };
+    let now = crate::now();
+    let keys = api_keys
+        .keys
+        .into_iter()
+        .map(|(provider, sealed)| {
+            let key = String::from_utf8(cipher.open(&sealed, provider.as_bytes())?)?;
+            let stored = keys::StoredKey {
+                sealed,
+                last4: keys::last4(&key),
+                created_at: now,
+                updated_at: now,
+            };
+            Ok((provider, stored))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    set_json(store, keys::API_KEYS_KEY, &keys::StoredKeys { keys })
+}
+
+/// v4 adds the search index; build it for conversations stored before.
+fn v3_to_v4(store: &dyn Store, _cipher: &Cipher) -> anyhow::Result<()> {
+    crate::search::reindex_all(store)
+}
+
+/// The v1 and v2 layout of `keys::API_KEYS_KEY`.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct ApiKeysV1 {
+    keys: std::collections::HashMap<String, String>,
+}
+
+/// In-memory `Store` for host-side tests.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryStore {
+    pub entries: std::cell::RefCell<std::collections::BTreeMap<String, Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl Store for MemoryStore {
+    fn get(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.entries.borrow().get(key).cloned())
+    }
+
+    fn set(&self, key: &str, value: &[u8]) -> anyhow::Result<()> {
+        self.entries
+            .borrow_mut()
+            .insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> anyhow::Result<()> {
+        self.entries.borrow_mut().remove(key);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cipher() -> Cipher {
+        Cipher::new(&[3; 32])
+    }
+
+    #[test]
+    fn v0_key_matches_old_encoding() {
+        let old_key = serde_json::to_vec(&b"api_keys".to_vec()).unwrap();
+        assert_eq!(V0_API_KEYS_KEY.as_bytes(), old_key.as_slice());
+    }
+
+    #[test]
+    fn migrates_v0_api_keys() {
+        let store = MemoryStore::default();
+        store
+            .set(V0_API_KEYS_KEY, br#"{"keys":{"anthropic":"sk-1"}}"#)
+            .unwrap();
+
+        assert_eq!(migrate(&store, &cipher()).unwrap(), 0);
+        assert_eq!(
+            keys::get(&store, &cipher(), "anthropic")
+                .unwrap()
+                .as_deref(),
+            Some("sk-1")
+        );
+        assert!(store.get(V0_API_KEYS_KEY).unwrap().is_none());
+        let raw = store.get(keys::API_KEYS_KEY).unwrap().unwrap();
+        assert!(!String::from_utf8(raw).unwrap().contains("sk-1"));
+        assert_eq!(
+            get_json::<u32>(&store, SCHEMA_VERSION_KEY).unwrap(),
+            Some(SCHEMA_VERSION)
+        );
+
+        // Already current: nothing to do
+        assert_eq!(migrate(&store, &cipher()).unwrap(), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn fresh_db_is_stamped() {
+        let store = MemoryStore::default();
+        assert_eq!(migrate(&store, &cipher()).unwrap(), 0);
+        assert_eq!(
+            get_json::<u32>(&store, SCHEMA_VERSION_KEY).unwrap(),
+            Some(SCHEMA_VERSION)
+        );
+    }
+
+    #[derive(Default, Serialize, Deserialize)]
+    struct Doc {
+        items: Vec<u32>,
+    }
+
+    #[test]
+    fn stale_revision_conflicts() {
+        let store = MemoryStore::default();
+        let mut doc = get_revisioned::<Doc>(&store, "doc").unwrap();
+        assert_eq!(doc.revision, 0);
+        doc.value.items.push(1);
+        assert_eq!(put_revisioned(&store, "doc", &mut doc, Some(0)).unwrap(), 1);
+
+        // A writer still holding revision 0
+        let mut doc = get_revisioned::<Doc>(&store, "doc").unwrap();
+        doc.value.items.push(2);
+        let e = put_revisioned(&store, "doc", &mut doc, Some(0)).unwrap_err();
+        assert_eq!(e.downcast_ref::<Conflict>().unwrap().current, 1);
+        assert_eq!(
+            get_revisioned::<Doc>(&store, "doc").unwrap().value.items,
+            vec![1]
+        );
+
+        // No precondition: last write wins
+        let mut doc = get_revisioned::<Doc>(&store, "doc").unwrap();
+        assert_eq!(put_revisioned(&store, "doc", &mut doc, None).unwrap(), 2);
+    }
+
+    #[test]
+    fn failed_migration_leaves_data_in_place() {
+        let store = MemoryStore::default();
+        store.set(V0_API_KEYS_KEY, b"[1,2,3]").unwrap();
+        assert!(migrate(&store, &cipher()).is_err());
+        assert!(store.get(V0_API_KEYS_KEY).unwrap().is_some());
+        assert!(store.get(SCHEMA_VERSION_KEY).unwrap().is_none());
+
+        let store = MemoryStore::default();
+        set_json(&store, SCHEMA_VERSION_KEY, &(SCHEMA_VERSION + 1)).unwrap();
+        assert!(migrate(&store, &cipher()).is_err());
+    }
+}
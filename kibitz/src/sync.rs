@@ -0,0 +1,266 @@
+//! Change notifications for every connected client, over the `/api/sync`
+//! WebSocket.
+//!
+//! Each change to kibitz data is appended to a log under `CHANGES_KEY` with
+//! the next sync revision, and pushed to synced clients. A client opens with
+//! `{"type": "resume", "since": revision}` (or `null` if it has nothing
+//! yet): it is sent every change after `since` it missed, then `synced`, and
+//! only then live changes. Only the last `RETAINED_CHANGES` are kept; a
+//! client further behind than that is sent `reset` and should reload.
+//!
+//! Changes name what changed; clients fetch the data itself over REST.
+
+use std::collections::{HashSet, VecDeque};
+
+use hyperware_process_lib::http::server::{send_ws_push, WsMessageType};
+use hyperware_process_lib::logging::{error, info};
+use hyperware_process_lib::LazyLoadBlob;
+use serde::{Deserialize, Serialize};
+
+use crate::store::{self, Store};
+
+pub const CHANGES_KEY: &str = "changes";
+
+const RETAINED_CHANGES: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChangeEvent {
+    KeysChanged,
+    ProvidersChanged,
+    ProjectsChanged,
+    ConversationCreated {
+        id: String,
+    },
+    MessageAppended {
+        conversation_id: String,
+        seq: u64,
+    },
+    ConversationDeleted {
+        id: String,
+    },
+    /// Its own record changed, e.g. its checkpoints or active branch.
+    ConversationUpdated {
+        id: String,
+    },
+    RunUpdated {
+        id: String,
+    },
+    TasksChanged,
+    TokensChanged,
+    PricesChanged,
+    BudgetsChanged,
+    PromptsChanged,
+    ProfilesChanged,
+    /// Spending crossed one of a provider's budget warning thresholds.
+    BudgetWarning {
+        provider: String,
+        period: String,
+        percent: u32,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    pub revision: u64,
+    pub at: u64,
+    pub event: ChangeEvent,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ChangeLog {
+    /// The revision of the latest change, even once it is trimmed.
+    revision: u64,
+    changes: VecDeque<Change>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Catchup {
+    /// Everything after the client's revision, oldest first.
+    Changes(Vec<Change>),
+    /// The client is too far behind: it should reload everything.
+    Reset,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Resume { since: Option<u64> },
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage<'a> {
+    Change(&'a Change),
+    Synced { revision: u64 },
+    Reset { revision: u64 },
+    Error { message: String },
+}
+
+fn load_log(store: &dyn Store) -> anyhow::Result<ChangeLog> {
+    Ok(store::get_json(store, CHANGES_KEY)?.unwrap_or_default())
+}
+
+pub fn current_revision(store: &dyn Store) -> anyhow::Result<u64> {
+    Ok(load_log(store)?.revision)
+}
+
+/// Append `event` to the log.
+pub fn record(store: &dyn Store, event: ChangeEvent, now: u64) -> anyhow::Result<Change> {
+    let mut log = load_log(store)?;
+    log.revision += 1;
+    let change = Change {
+        revision: log.revision,
+        at: now,
+        event,
+    };
+    log.changes.push_back(change.clone());
+    while log.changes.len() > RETAINED_CHANGES {
+        log.changes.pop_front();
+    }
+    store::set_json(store, CHANGES_KEY, &log)?;
+    Ok(change)
+}
+
+/// What a client last synced at `since` has missed.
+pub fn since(store: &dyn Store, since: u64) -> anyhow::Result<Catchup> {
+    let log = load_log(store)?;
+    if since > log.revision {
+        // From before the log was lost or from another node
+        return Ok(Catchup::Reset);
+    }
+    let oldest = log
+        .changes
+        .front()
+        .map_or(log.revision + 1, |change| change.revision);
+    if since + 1 < oldest && since < log.revision {
+        return Ok(Catchup::Reset);
+    }
+    Ok(Catchup::Changes(
+        log.changes
+            .into_iter()
+            .filter(|change| change.revision > since)
+            .collect(),
+    ))
+}
+
+fn push(channel_id: u32, message: &ServerMessage) -> anyhow::Result<()> {
+    send_ws_push(
+        channel_id,
+        WsMessageType::Text,
+        LazyLoadBlob::new(Some("application/json"), serde_json::to_vec(message)?),
+    );
+    Ok(())
+}
+
+/// Clients that have resumed and now get live changes.
+#[derive(Default)]
+pub struct SyncClients {
+    synced: HashSet<u32>,
+}
+
+impl SyncClients {
+    /// Record a change and push it to synced clients. A failure here must
+    /// not fail the write that caused it, so it is only logged.
+    pub fn publish(&mut self, store: &dyn Store, event: ChangeEvent, now: u64) {
+        let change = match record(store, event, now) {
+            Ok(change) => change,
+            Err(e) => {
+                error!("failed to record change: {e:?}");
+                return;
+            }
+        };
+        for channel_id in &self.synced {
+            if let Err(e) = push(*channel_id, &ServerMessage::Change(&change)) {
+                error!("failed to push change to {channel_id}: {e:?}");
+            }
+        }
+    }
+
+    pub fn handle_client_message(
+        &mut self,
+        store: &dyn Store,
+        channel_id: u32,
+        bytes: &[u8],
+    ) -> anyhow::Result<()> {
+        let ClientMessage::Resume { since: from } = match serde_json::from_slice(bytes) {
+            Ok(message) => message,
+            Err(e) => {
+                let message = format!("improper format: {e}");
+                return push(channel_id, &ServerMessage::Error { message });
+            }
+        };
+        let revision = current_revision(store)?;
+        match from.map(|from| since(store, from)).transpose()? {
+            None => {}
+            Some(Catchup::Reset) => {
+                info!("sync: channel {channel_id} is too far behind, resetting");
+                push(channel_id, &ServerMessage::Reset { revision })?;
+                self.synced.insert(channel_id);
+                return Ok(());
+            }
+            Some(Catchup::Changes(changes)) => {
+                for change in &changes {
+                    push(channel_id, &ServerMessage::Change(change))?;
+                }
+            }
+        }
+        push(channel_id, &ServerMessage::Synced { revision })?;
+        self.synced.insert(channel_id);
+        Ok(())
+    }
+
+    pub fn handle_close(&mut self, channel_id: u32) {
+        self.synced.remove(&channel_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    fn revisions(catchup: Catchup) -> Vec<u64> {
+        match catchup {
+            Catchup::Changes(changes) => changes.iter().map(|change| change.revision).collect(),
+            Catchup::Reset => panic!("unexpected reset"),
+        }
+    }
+
+    #[test]
+    fn resume_sends_only_what_was_missed() {
+        let store = MemoryStore::default();
+        assert_eq!(revisions(since(&store, 0).unwrap()), Vec::<u64>::new());
+        for n in 0..3 {
+            let change = record(
+                &store,
+                ChangeEvent::MessageAppended {
+                    conversation_id: "c".to_string(),
+                    seq: n,
+                },
+                n,
+            )
+            .unwrap();
+            assert_eq!(change.revision, n + 1);
+        }
+        assert_eq!(revisions(since(&store, 1).unwrap()), vec![2, 3]);
+        assert_eq!(revisions(since(&store, 3).unwrap()), Vec::<u64>::new());
+        assert_eq!(since(&store, 4).unwrap(), Catchup::Reset);
+    }
+
+    #[test]
+    fn trimmed_log_resets_stale_clients() {
+        let store = MemoryStore::default();
+        for n in 0..RETAINED_CHANGES as u64 + 5 {
+            record(&store, ChangeEvent::KeysChanged, n).unwrap();
+        }
+        assert_eq!(since(&store, 2).unwrap(), Catchup::Reset);
+        // The oldest retained change is 6, so a client at 5 missed nothing
+        //  that was dropped
+        assert_eq!(revisions(since(&store, 5).unwrap()).len(), RETAINED_CHANGES);
+        assert_eq!(
+            current_revision(&store).unwrap(),
+            RETAINED_CHANGES as u64 + 5
+        );
+    }
+}
@@ -0,0 +1,751 @@
+//! Token usage and cost accounting.
+//!
+//! Every successful LLM reply passing through kibitz (proxied, from another
+//! process or an agent run) has its token counts added to a per-day tally
+//! under `usage:{day}`, one row per provider, model and project. Calls
+//! through the proxy name their project with the `x-kibitz-project` header;
+//! runs use their conversation's.
+//!
+//! Costs come from a price table (USD per million tokens, by model name
+//! prefix) under `PRICES_KEY`, applied when usage is summarised, so setting
+//! a price also prices what came before it.
+
+use std::collections::{BTreeMap, HashMap};
+
+use hyperware_process_lib::http::server::IncomingHttpRequest;
+use hyperware_process_lib::logging::error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::budget;
+use crate::export::civil_from_days;
+use crate::http::{
+    read_if_match, read_json_body, send_http_error, send_http_json, send_http_json_tagged,
+    send_http_response, send_write_result, IfMatch, HTTP_BAD_REQUEST, HTTP_METHOD_NOT_ALLOWED,
+    HTTP_NOT_FOUND, HTTP_OK,
+};
+use crate::store::{self, Revisioned, Store};
+use crate::sync::{ChangeEvent, SyncClients};
+
+pub const HTTP_USAGE_PATH: &str = "/api/usage";
+pub const HTTP_PRICES_PATH: &str = "/api/usage/prices";
+
+pub const PRICES_KEY: &str = "usage_prices";
+
+/// Names the project a proxied or streamed call is for. Not forwarded.
+pub const PROJECT_HEADER: &str = "x-kibitz-project";
+
+/// Days summarised when the client doesn't say.
+const DEFAULT_DAYS: u64 = 30;
+/// The longest range one summary may cover.
+const MAX_DAYS: u64 = 366;
+
+const SECS_PER_DAY: u64 = 86400;
+
+/// Token counts. `input_tokens` excludes cached input, which is counted
+/// apart since it is priced apart.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    #[serde(default)]
+    pub input_tokens: u64,
+    #[serde(default)]
+    pub output_tokens: u64,
+    #[serde(default)]
+    pub cache_read_input_tokens: u64,
+    #[serde(default)]
+    pub cache_creation_input_tokens: u64,
+}
+
+impl Usage {
+    pub fn add(&mut self, other: &Usage) {
+        self.input_tokens += other.input_tokens;
+        self.output_tokens += other.output_tokens;
+        self.cache_read_input_tokens += other.cache_read_input_tokens;
+        self.cache_creation_input_tokens += other.cache_creation_input_tokens;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.input_tokens
+            + self.output_tokens
+            + self.cache_read_input_tokens
+            + self.cache_creation_input_tokens
+    }
+}
+
+fn count(value: &Value, field: &str) -> Option<u64> {
+    value.get(field).and_then(Value::as_u64)
+}
+
+/// Fold one `usage` object, in any of the providers' shapes, into `into`.
+/// Counts it has replace those already there: streams repeat them as they
+/// grow.
+fn read_usage(usage: &Value, into: &mut Usage) {
+    if let Some(prompt) = count(usage, "prompt_tokens") {
+        // OpenAI chat completions: cached input is part of the prompt
+        let cached = usage
+            .pointer("/prompt_tokens_details/cached_tokens")
+            .and_then(Value::as_u64)
+            .unwrap_or(0);
+        into.input_tokens = prompt.saturating_sub(cached);
+        into.cache_read_input_tokens = cached;
+        if let Some(completion) = count(usage, "completion_tokens") {
+            into.output_tokens = completion;
+        }
+        return;
+    }
+    if let Some(details) = usage.get("input_tokens_details") {
+        // OpenAI responses: likewise
+        let cached = count(details, "cached_tokens").unwrap_or(0);
+        if let Some(input) = count(usage, "input_tokens") {
+            into.input_tokens = input.saturating_sub(cached);
+        }
+        into.cache_read_input_tokens = cached;
+        if let Some(output) = count(usage, "output_tokens") {
+            into.output_tokens = output;
+        }
+        return;
+    }
+    // Anthropic counts cached input apart already
+    let fields = [
+        ("input_tokens", &mut into.input_tokens),
+        ("output_tokens", &mut into.output_tokens),
+        ("cache_read_input_tokens", &mut into.cache_read_input_tokens),
+        (
+            "cache_creation_input_tokens",
+            &mut into.cache_creation_input_tokens,
+        ),
+    ];
+    for (field, slot) in fields {
+        if let Some(value) = count(usage, field) {
+            *slot = value;
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct SseEvent {
+    event: Option<String>,
+    data: String,
+}
+
+/// Split an SSE body into its events. `data` lines are joined with `\n`;
+/// comments and fields other than `event` and `data` are dropped.
+fn parse_sse(body: &str) -> Vec<SseEvent> {
+    let mut events = vec![];
+    let mut event = None;
+    let mut data: Option<String> = None;
+    for line in body.lines() {
+        if line.is_empty() {
+            if let Some(data) = data.take() {
+                events.push(SseEvent {
+                    event: event.take(),
+                    data,
+                });
+            }
+            event = None;
+            continue;
+        }
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        let value = value.strip_prefix(' ').unwrap_or(value);
+        match field {
+            "event" => event = Some(value.to_string()),
+            "data" => match data {
+                Some(ref mut data) => {
+                    data.push('\n');
+                    data.push_str(value);
+                }
+                None => data = Some(value.to_string()),
+            },
+            _ => {}
+        }
+    }
+    // Tolerate a final event with no blank line after it
+    if let Some(data) = data {
+        events.push(SseEvent { event, data });
+    }
+    events
+}
+
+/// The model and token counts a reply reports, whether a JSON body or an
+/// SSE stream of them; `None` if it reports no usage.
+pub fn from_reply(body: &[u8]) -> Option<(Option<String>, Usage)> {
+    let values = match serde_json::from_slice::<Value>(body) {
+        Ok(value) => vec![value],
+        Err(_) => parse_sse(&String::from_utf8_lossy(body))
+            .into_iter()
+            .filter_map(|event| serde_json::from_str(&event.data).ok())
+            .collect(),
+    };
+    let mut model = None;
+    let mut usage = Usage::default();
+    let mut seen = false;
+    for value in &values {
+        // Anthropic's `message_start` and OpenAI's `response.completed`
+        //  nest the message
+        for value in [value, &value["message"], &value["response"]] {
+            if let Some(name) = value.get("model").and_then(Value::as_str) {
+                model = Some(name.to_string());
+            }
+            if let Some(reported) = value.get("usage").filter(|usage| usage.is_object()) {
+                read_usage(reported, &mut usage);
+                seen = true;
+            }
+        }
+    }
+    seen.then_some((model, usage))
+}
+
+/// One tally: a provider, model and project's usage on a day.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Row {
+    pub provider: String,
+    pub model: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub project_id: Option<String>,
+    pub requests: u64,
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Day {
+    rows: Vec<Row>,
+}
+
+fn day_key(day: u64) -> String {
+    format!("usage:{day}")
+}
+
+/// `days` since the epoch as `YYYY-MM-DD`.
+pub fn format_day(days: u64) -> String {
+    let (year, month, day) = civil_from_days(days);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// Days since the epoch of a `YYYY-MM-DD` date, after Howard Hinnant's
+/// `days_from_civil`.
+pub fn parse_day(date: &str) -> Option<u64> {
+    let mut parts = date.splitn(3, '-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: u32 = parts.next()?.parse().ok()?;
+    let day: u32 = parts.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let year_of_march = if month <= 2 { year - 1 } else { year };
+    let era = year_of_march.div_euclid(400);
+    let year_of_era = year_of_march.rem_euclid(400);
+    let month_from_march = i64::from((month + 9) % 12);
+    let day_of_year = (153 * month_from_march + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = u64::try_from(era * 146097 + day_of_era - 719468).ok()?;
+    // Refuse e.g. February 30th rather than roll it over
+    (civil_from_days(days) == (year, month, day)).then_some(days)
+}
+
+/// Add a reply's usage to today's tally.
+pub fn record(
+    store: &dyn Store,
+    provider: &str,
+    model: &str,
+    project_id: Option<&str>,
+    usage: &Usage,
+    now: u64,
+) -> anyhow::Result<()> {
+    let key = day_key(now / SECS_PER_DAY);
+    let mut day: Day = store::get_json(store, &key)?.unwrap_or_default();
+    let row = day.rows.iter_mut().find(|row| {
+        row.provider == provider && row.model == model && row.project_id.as_deref() == project_id
+    });
+    match row {
+        Some(row) => {
+            row.requests += 1;
+            row.usage.add(usage);
+        }
+        None => day.rows.push(Row {
+            provider: provider.to_string(),
+            model: model.to_string(),
+            project_id: project_id.map(str::to_string),
+            requests: 1,
+            usage: *usage,
+        }),
+    }
+    store::set_json(store, &key, &day)
+}
+
+/// The model a JSON request or reply body names.
+pub fn body_model(body: &[u8]) -> Option<String> {
+    let body: Value = serde_json::from_slice(body).ok()?;
+    body.get("model")?.as_str().map(str::to_string)
+}
+
+/// Account for a successful reply to a call for `requested` (the model the
+/// request named): what it used, or `None` if it didn't say. Failing to
+/// record is only logged, so as not to fail the call itself.
+pub fn note_reply(
+    store: &dyn Store,
+    sync: &mut SyncClients,
+    provider: &str,
+    requested: Option<&str>,
+    project_id: Option<&str>,
+    reply: &[u8],
+    now: u64,
+) -> Option<Usage> {
+    let (model, usage) = from_reply(reply)?;
+    let model = model
+        .or_else(|| requested.map(str::to_string))
+        .unwrap_or_else(|| "unknown".to_string());
+    if let Err(e) = record(store, provider, &model, project_id, &usage, now) {
+        error!("failed to record usage for {provider} {model}: {e:?}");
+    }
+    budget::note_spend(store, sync, provider, now);
+    Some(usage)
+}
+
+/// USD per million tokens. Cached input is priced as input unless set.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Price {
+    pub input: f64,
+    pub output: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_read: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_write: Option<f64>,
+}
+
+impl Price {
+    pub fn cost(&self, usage: &Usage) -> f64 {
+        let tokens = |count: u64, per_million: f64| count as f64 * per_million / 1_000_000.0;
+        tokens(usage.input_tokens, self.input)
+            + tokens(usage.output_tokens, self.output)
+            + tokens(
+                usage.cache_read_input_tokens,
+                self.cache_read.unwrap_or(self.input),
+            )
+            + tokens(
+                usage.cache_creation_input_tokens,
+                self.cache_write.unwrap_or(self.input),
+            )
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Prices {
+    /// By model name, or a prefix of it such as `claude-sonnet-4`: the
+    /// longest match wins.
+    #[serde(default)]
+    pub models: BTreeMap<String, Price>,
+}
+
+impl Prices {
+    pub fn for_model(&self, model: &str) -> Option<&Price> {
+        self.models
+            .iter()
+            .filter(|(prefix, _)| model.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, price)| price)
+    }
+}
+
+pub fn validate_prices(prices: &Prices) -> Result<(), String> {
+    for (model, price) in &prices.models {
+        if model.is_empty() {
+            return Err("a price needs a model".to_string());
+        }
+        let rates = [Some(price.input), Some(price.output)]
+            .into_iter()
+            .chain([price.cache_read, price.cache_write])
+            .flatten();
+        for rate in rates {
+            if !rate.is_finite() || rate < 0.0 {
+                return Err(format!("{model}: prices must be zero or more"));
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn get_prices(store: &dyn Store) -> anyhow::Result<Revisioned<Prices>> {
+    store::get_revisioned(store, PRICES_KEY)
+}
+
+/// Usage and what it cost.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Totals {
+    pub requests: u64,
+    #[serde(flatten)]
+    pub usage: Usage,
+    /// Of the priced usage only.
+    pub cost_usd: f64,
+    /// Requests to models with no price, so not in `cost_usd`.
+    pub unpriced_requests: u64,
+}
+
+impl Totals {
+    fn add(&mut self, row: &Row, prices: &Prices) {
+        self.requests += row.requests;
+        self.usage.add(&row.usage);
+        match prices.for_model(&row.model) {
+            Some(price) => self.cost_usd += price.cost(&row.usage),
+            None => self.unpriced_requests += row.requests,
+        }
+    }
+}
+
+/// What usage is broken down by.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct GroupBy {
+    pub day: bool,
+    pub provider: bool,
+    pub model: bool,
+    pub project: bool,
+}
+
+impl GroupBy {
+    /// From a comma-separated list of `day`, `provider`, `model` and
+    /// `project`.
+    pub fn parse(list: &str) -> Result<Self, String> {
+        let mut group_by = Self::default();
+        for name in list
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            match name {
+                "day" => group_by.day = true,
+                "provider" => group_by.provider = true,
+                "model" => group_by.model = true,
+                "project" => group_by.project = true,
+                _ => return Err(format!("can't group usage by {name}")),
+            }
+        }
+        Ok(group_by)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct GroupKey {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub day: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// `Some(None)` for usage outside any project.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub project_id: Option<Option<String>>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Group {
+    #[serde(flatten)]
+    pub key: GroupKey,
+    #[serde(flatten)]
+    pub totals: Totals,
+}
+
+/// Only usage matching every filter set is summarised.
+#[derive(Debug, Default)]
+pub struct Filter {
+    pub provider: Option<String>,
+    pub model: Option<String>,
+    pub project_id: Option<String>,
+}
+
+impl Filter {
+    fn matches(&self, row: &Row) -> bool {
+        self.provider.as_ref().is_none_or(|p| *p == row.provider)
+            && self.model.as_ref().is_none_or(|m| *m == row.model)
+            && self
+                .project_id
+                .as_ref()
+                .is_none_or(|p| row.project_id.as_ref() == Some(p))
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Summary {
+    pub from: String,
+    pub to: String,
+    pub total: Totals,
+    pub groups: Vec<Group>,
+}
+
+/// Usage from day `from` to day `to` (days since the epoch, inclusive).
+pub fn summarise(
+    store: &dyn Store,
+    (from, to): (u64, u64),
+    filter: &Filter,
+    group_by: GroupBy,
+) -> anyhow::Result<Summary> {
+    let prices = get_prices(store)?.value;
+    let mut total = Totals::default();
+    let mut groups: BTreeMap<GroupKey, Totals> = BTreeMap::new();
+    for day in from..=to {
+        let Some(tally) = store::get_json::<Day>(store, &day_key(day))? else {
+            continue;
+        };
+        for row in tally.rows.iter().filter(|row| filter.matches(row)) {
+            total.add(row, &prices);
+            let key = GroupKey {
+                day: group_by.day.then(|| format_day(day)),
+                provider: group_by.provider.then(|| row.provider.clone()),
+                model: group_by.model.then(|| row.model.clone()),
+                project_id: group_by.project.then(|| row.project_id.clone()),
+            };
+            groups.entry(key).or_default().add(row, &prices);
+        }
+    }
+    Ok(Summary {
+        from: format_day(from),
+        to: format_day(to),
+        total,
+        groups: groups
+            .into_iter()
+            .map(|(key, totals)| Group { key, totals })
+            .collect(),
+    })
+}
+
+/// The days a summary covers, from `from` and `to` query params.
+fn range(query: &HashMap<String, String>, now: u64) -> Result<(u64, u64), String> {
+    let day = |name: &str| {
+        query
+            .get(name)
+            .map(|date| parse_day(date).ok_or(format!("{name} must be a YYYY-MM-DD date")))
+            .transpose()
+    };
+    let to = day("to")?.unwrap_or(now / SECS_PER_DAY);
+    let from = day("from")?.unwrap_or(to.saturating_sub(DEFAULT_DAYS - 1));
+    if from > to {
+        return Err("from is after to".to_string());
+    }
+    if to - from >= MAX_DAYS {
+        return Err(format!("usage can be summarised {MAX_DAYS} days at a time"));
+    }
+    Ok((from, to))
+}
+
+/// Serve the usage summary and the price table.
+pub fn handle_http(
+    store: &dyn Store,
+    sync: &mut SyncClients,
+    http_request: &IncomingHttpRequest,
+    bound_path: &str,
+    now: u64,
+) -> anyhow::Result<()> {
+    let method = http_request.method()?;
+    match (method.as_str(), bound_path) {
+        ("GET", HTTP_USAGE_PATH) => {
+            let query = http_request.query_params();
+            let range = match range(query, now) {
+                Ok(range) => range,
+                Err(message) => return send_http_error(HTTP_BAD_REQUEST, &message),
+            };
+            let group_by = query.get("group_by").map(String::as_str).unwrap_or("");
+            let group_by = match GroupBy::parse(group_by) {
+                Ok(group_by) => group_by,
+                Err(message) => return send_http_error(HTTP_BAD_REQUEST, &message),
+            };
+            let filter = Filter {
+                provider: query.get("provider").cloned(),
+                model: query.get("model").cloned(),
+                project_id: query.get("project_id").cloned(),
+            };
+            send_http_json(HTTP_OK, &summarise(store, range, &filter, group_by)?)
+        }
+        ("GET", HTTP_PRICES_PATH) => {
+            let prices = get_prices(store)?;
+            send_http_json_tagged(HTTP_OK, prices.revision, Some(&prices.value))
+        }
+        ("PUT", HTTP_PRICES_PATH) => {
+            let Some(IfMatch(expected)) = read_if_match(http_request)? else {
+                return Ok(());
+            };
+            let Some(prices) = read_json_body::<Prices>()? else {
+                return Ok(());
+            };
+            if let Err(message) = validate_prices(&prices) {
+                return send_http_error(HTTP_BAD_REQUEST, &message);
+            }
+            let mut doc = get_prices(store)?;
+            doc.value = prices;
+            let result = store::put_revisioned(store, PRICES_KEY, &mut doc, expected);
+            if result.is_ok() {
+                sync.publish(store, ChangeEvent::PricesChanged, now);
+            }
+            send_write_result("PUT /api/usage/prices", result)
+        }
+        (_, HTTP_USAGE_PATH | HTTP_PRICES_PATH) => {
+            send_http_response(HTTP_METHOD_NOT_ALLOWED, None)
+        }
+        _ => send_http_response(HTTP_NOT_FOUND, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn sse_events_keep_their_boundaries() {
+        let body = "event: message_start\r\ndata: {\"a\":1}\r\n\r\n\
+                    : keep-alive\n\n\
+                    data: line one\ndata: line two\n\n\
+                    event: message_stop\ndata: {}";
+        assert_eq!(
+            parse_sse(body),
+            vec![
+                SseEvent {
+                    event: Some("message_start".to_string()),
+                    data: "{\"a\":1}".to_string(),
+                },
+                SseEvent {
+                    event: None,
+                    data: "line one\nline two".to_string(),
+                },
+                SseEvent {
+                    event: Some("message_stop".to_string()),
+                    data: "{}".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn usage_is_read_from_each_providers_replies() {
+        let anthropic = br#"{"model": "claude-x", "usage": {"input_tokens": 10,
+            "output_tokens": 5, "cache_read_input_tokens": 100}}"#;
+        assert_eq!(
+            from_reply(anthropic),
+            Some((
+                Some("claude-x".to_string()),
+                Usage {
+                    input_tokens: 10,
+                    output_tokens: 5,
+                    cache_read_input_tokens: 100,
+                    cache_creation_input_tokens: 0,
+                }
+            ))
+        );
+        let stream = "event: message_start\n\
+            data: {\"type\":\"message_start\",\"message\":{\"model\":\"claude-x\",\"usage\":{\"input_tokens\":10,\"output_tokens\":1}}}\n\n\
+            event: message_delta\n\
+            data: {\"type\":\"message_delta\",\"usage\":{\"output_tokens\":42}}\n\n";
+        let (model, usage) = from_reply(stream.as_bytes()).unwrap();
+        assert_eq!(model.as_deref(), Some("claude-x"));
+        assert_eq!((usage.input_tokens, usage.output_tokens), (10, 42));
+
+        let openai = br#"{"model": "gpt-x", "usage": {"prompt_tokens": 30,
+            "completion_tokens": 7, "prompt_tokens_details": {"cached_tokens": 20}}}"#;
+        let (_, usage) = from_reply(openai).unwrap();
+        assert_eq!(
+            (
+                usage.input_tokens,
+                usage.cache_read_input_tokens,
+                usage.output_tokens
+            ),
+            (10, 20, 7)
+        );
+        let chunks = "data: {\"model\":\"gpt-x\",\"usage\":null}\n\n\
+            data: {\"model\":\"gpt-x\",\"usage\":{\"prompt_tokens\":3,\"completion_tokens\":4}}\n\n\
+            data: [DONE]\n\n";
+        let (_, usage) = from_reply(chunks.as_bytes()).unwrap();
+        assert_eq!((usage.input_tokens, usage.output_tokens), (3, 4));
+
+        assert_eq!(from_reply(br#"{"error": "overloaded"}"#), None);
+    }
+
+    #[test]
+    fn days_round_trip() {
+        assert_eq!(parse_day("1970-01-01"), Some(0));
+        assert_eq!(parse_day("2024-03-01"), Some(19783));
+        assert_eq!(format_day(19783), "2024-03-01");
+        for bad in [
+            "2023-02-29",
+            "2024-13-01",
+            "2024-1",
+            "yesterday",
+            "1969-12-31",
+        ] {
+            assert_eq!(parse_day(bad), None, "{bad}");
+        }
+    }
+
+    #[test]
+    fn usage_is_tallied_and_priced() {
+        let store = MemoryStore::default();
+        let day = 19783;
+        let now = day * SECS_PER_DAY + 60;
+        let usage = |input_tokens, output_tokens| Usage {
+            input_tokens,
+            output_tokens,
+            ..Usage::default()
+        };
+        record(
+            &store,
+            "anthropic",
+            "claude-x-1",
+            Some("p"),
+            &usage(1000, 100),
+            now,
+        )
+        .unwrap();
+        record(
+            &store,
+            "anthropic",
+            "claude-x-1",
+            Some("p"),
+            &usage(1000, 100),
+            now,
+        )
+        .unwrap();
+        record(
+            &store,
+            "openai",
+            "gpt-x",
+            None,
+            &usage(500, 0),
+            now + SECS_PER_DAY,
+        )
+        .unwrap();
+        let mut prices = get_prices(&store).unwrap();
+        prices.value.models.insert(
+            "claude-x".to_string(),
+            Price {
+                input: 3.0,
+                output: 15.0,
+                cache_read: None,
+                cache_write: None,
+            },
+        );
+        store::put_revisioned(&store, PRICES_KEY, &mut prices, None).unwrap();
+
+        let group_by = GroupBy::parse("project").unwrap();
+        let summary = summarise(&store, (day, day + 1), &Filter::default(), group_by).unwrap();
+        assert_eq!(summary.total.requests, 3);
+        assert_eq!(summary.total.usage.input_tokens, 2500);
+        assert_eq!(summary.total.unpriced_requests, 1);
+        assert!((summary.total.cost_usd - 0.009).abs() < 1e-9);
+        let projects: Vec<_> = summary
+            .groups
+            .iter()
+            .map(|group| (group.key.project_id.clone(), group.totals.requests))
+            .collect();
+        assert_eq!(
+            projects,
+            vec![(Some(None), 1), (Some(Some("p".to_string())), 2)]
+        );
+
+        let filter = Filter {
+            provider: Some("openai".to_string()),
+            ..Filter::default()
+        };
+        let group_by = GroupBy::parse("day,model").unwrap();
+        let summary = summarise(&store, (day, day + 1), &filter, group_by).unwrap();
+        assert_eq!(summary.groups.len(), 1);
+        assert_eq!(summary.groups[0].key.day.as_deref(), Some("2024-03-02"));
+        assert!(GroupBy::parse("week").is_err());
+    }
+}
@@ -0,0 +1,446 @@
+//! Starting agent runs from scripts, such as CI.
+//!
+//! A script doesn't have the node's login cookie, so the `/api/runs`
+//! endpoints are bound without it and take an API token instead, sent as
+//! `Authorization: Bearer <token>`. Tokens are made and revoked over
+//! `/api/tokens`, which is behind the login like everything else. A token
+//! is only shown when it is made: kibitz keeps its SHA-256 hash.
+//!
+//! The browser may call them with its login cookie instead. Only
+//! `http-server` can tell a good cookie, so kibitz asks it: it sends the
+//! cookie back to its own `/api/session`, which is behind the login, with a
+//! one-off nonce, and holds the request until that comes back. If the
+//! nonce reached `/api/session`, the cookie is good, and is taken without
+//! asking again for a few minutes. "Its own" is wherever requests behind
+//! the login last came in, never the host a held request names.
+
+use std::collections::{BTreeMap, HashMap};
+
+use hyperware_process_lib::http::client::{HttpClientAction, OutgoingHttpRequest};
+use hyperware_process_lib::http::server::{HttpResponse, IncomingHttpRequest};
+use hyperware_process_lib::logging::info;
+use hyperware_process_lib::{last_blob, Request};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::conversations;
+use crate::http::{
+    read_json_body, send_http, send_http_error, send_http_json, send_http_response, url_param,
+    HTTP_BAD_REQUEST, HTTP_CREATED, HTTP_METHOD_NOT_ALLOWED, HTTP_NOT_FOUND, HTTP_NO_CONTENT,
+    HTTP_OK, HTTP_UNAUTHORIZED,
+};
+use crate::store::{self, Store};
+use crate::sync::{ChangeEvent, SyncClients};
+
+pub const HTTP_TOKENS_PATH: &str = "/api/tokens";
+pub const HTTP_TOKEN_PATH: &str = "/api/tokens/:id";
+/// Behind the login: answers 204, noting the `check` nonce it is sent.
+pub const HTTP_SESSION_PATH: &str = "/api/session";
+
+pub const TOKENS_KEY: &str = "tokens";
+
+/// Marks a string as a kibitz token, e.g. for secret scanners.
+const TOKEN_PREFIX: &str = "kbz_";
+
+/// Prefixes the context of login cookie checks, with their nonce.
+const SESSION_CONTEXT_PREFIX: &[u8] = b"session:";
+const SESSION_CHECK_TIMEOUT_S: u64 = 10;
+/// How long a login cookie is taken once checked.
+const SESSION_TTL_S: u64 = 300;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Token {
+    pub id: String,
+    pub name: String,
+    /// Hex SHA-256 of the token.
+    pub hash: String,
+    pub created_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<u64>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Tokens {
+    pub tokens: BTreeMap<String, Token>,
+}
+
+/// A token as listed: everything but its hash.
+#[derive(Debug, Serialize)]
+pub struct TokenInfo<'a> {
+    pub id: &'a str,
+    pub name: &'a str,
+    pub created_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<u64>,
+}
+
+impl<'a> From<&'a Token> for TokenInfo<'a> {
+    fn from(token: &'a Token) -> Self {
+        Self {
+            id: &token.id,
+            name: &token.name,
+            created_at: token.created_at,
+            last_used_at: token.last_used_at,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewToken {
+    pub name: String,
+}
+
+/// A token just made: the only time `token` is shown.
+#[derive(Debug, Serialize)]
+pub struct CreatedToken<'a> {
+    #[serde(flatten)]
+    pub info: TokenInfo<'a>,
+    pub token: String,
+}
+
+pub fn load(store: &dyn Store) -> anyhow::Result<Tokens> {
+    Ok(store::get_json(store, TOKENS_KEY)?.unwrap_or_default())
+}
+
+fn save(store: &dyn Store, tokens: &Tokens) -> anyhow::Result<()> {
+    store::set_json(store, TOKENS_KEY, tokens)
+}
+
+fn hash(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn new_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+    format!("{TOKEN_PREFIX}{hex}")
+}
+
+/// Make a token named `name`, returning it and the token itself.
+pub fn create(store: &dyn Store, name: &str, now: u64) -> anyhow::Result<(Token, String)> {
+    let name = name.trim();
+    anyhow::ensure!(!name.is_empty(), "a token needs a name");
+    let secret = new_token();
+    let token = Token {
+        id: conversations::new_id(),
+        name: name.to_string(),
+        hash: hash(&secret),
+        created_at: now,
+        last_used_at: None,
+    };
+    let mut tokens = load(store)?;
+    tokens.tokens.insert(token.id.clone(), token.clone());
+    save(store, &tokens)?;
+    Ok((token, secret))
+}
+
+/// The stored token an `Authorization` header value names, if any.
+pub fn authenticate<'a>(tokens: &'a Tokens, authorization: Option<&str>) -> Option<&'a Token> {
+    let (scheme, secret) = authorization?.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    let secret = secret.trim();
+    if !secret.starts_with(TOKEN_PREFIX) {
+        return None;
+    }
+    // Only hashes are compared, so how long that takes says nothing of the
+    //  token
+    let hash = hash(secret);
+    tokens.tokens.values().find(|token| token.hash == hash)
+}
+
+/// Serve the token endpoints.
+pub fn handle_http(
+    store: &dyn Store,
+    sync: &mut SyncClients,
+    http_request: &IncomingHttpRequest,
+    bound_path: &str,
+    now: u64,
+) -> anyhow::Result<()> {
+    let method = http_request.method()?;
+    match (method.as_str(), bound_path) {
+        ("GET", HTTP_TOKENS_PATH) => {
+            let tokens = load(store)?;
+            let tokens: Vec<TokenInfo> = tokens.tokens.values().map(Into::into).collect();
+            send_http_json(HTTP_OK, &tokens)
+        }
+        ("POST", HTTP_TOKENS_PATH) => {
+            let Some(NewToken { name }) = read_json_body()? else {
+                return Ok(());
+            };
+            let (token, secret) = match create(store, &name, now) {
+                Ok(created) => created,
+                Err(e) => return send_http_error(HTTP_BAD_REQUEST, &format!("{e:#}")),
+            };
+            sync.publish(store, ChangeEvent::TokensChanged, now);
+            let created = CreatedToken {
+                info: (&token).into(),
+                token: secret,
+            };
+            send_http_json(HTTP_CREATED, &created)
+        }
+        ("DELETE", HTTP_TOKEN_PATH) => {
+            let Some(id) = url_param(http_request, "id") else {
+                return send_http_error(HTTP_BAD_REQUEST, "missing token id");
+            };
+            let mut tokens = load(store)?;
+            if tokens.tokens.remove(id).is_none() {
+                return send_http_error(HTTP_NOT_FOUND, "no such token");
+            }
+            save(store, &tokens)?;
+            sync.publish(store, ChangeEvent::TokensChanged, now);
+            send_http_response(HTTP_OK, None)
+        }
+        (_, HTTP_TOKENS_PATH | HTTP_TOKEN_PATH) => {
+            send_http_response(HTTP_METHOD_NOT_ALLOWED, None)
+        }
+        _ => send_http_response(HTTP_NOT_FOUND, None),
+    }
+}
+
+/// How a request to a path bound without the login cookie fared.
+pub enum Authorization {
+    Granted,
+    /// Answered with 401.
+    Denied,
+    /// Held while its login cookie is checked: `Sessions::handle_reply`
+    /// hands it back if the cookie is good.
+    Held,
+}
+
+/// A request held while its login cookie is checked.
+pub struct Held {
+    pub request: IncomingHttpRequest,
+    pub body: Option<Vec<u8>>,
+    /// The hash of its login cookie.
+    cookie: String,
+    /// Whether the check reached `/api/session`.
+    seen: bool,
+}
+
+/// Login cookies checked for requests bound without them.
+#[derive(Default)]
+pub struct Sessions {
+    /// Our own address and process, e.g.
+    /// `http://localhost:8080/kibitz:kibitz:nick.hypr`, from the last
+    /// request behind the login.
+    origin: Option<String>,
+    /// Hashes of cookies found good, with when they stop being taken.
+    verified: HashMap<String, u64>,
+    /// By nonce.
+    held: HashMap<String, Held>,
+}
+
+/// The nonce of a cookie check sent with `context`.
+pub fn session_context(context: &[u8]) -> Option<&str> {
+    std::str::from_utf8(context.strip_prefix(SESSION_CONTEXT_PREFIX)?).ok()
+}
+
+fn cookie(http_request: &IncomingHttpRequest) -> Option<String> {
+    let headers = http_request.headers();
+    let cookie = headers.get("cookie")?.to_str().ok()?;
+    (!cookie.is_empty()).then(|| cookie.to_string())
+}
+
+fn deny(bound_path: &str) -> anyhow::Result<Authorization> {
+    info!("{bound_path}: no valid token or login");
+    send_http(
+        HttpResponse::new(HTTP_UNAUTHORIZED).header("WWW-Authenticate", "Bearer"),
+        Some(serde_json::to_vec(
+            &serde_json::json!({ "error": "a valid API token or login is required" }),
+        )?),
+    )?;
+    Ok(Authorization::Denied)
+}
+
+impl Sessions {
+    /// Note where a request that came in behind the login was sent.
+    pub fn note_origin(&mut self, http_request: &IncomingHttpRequest) {
+        let Ok(url) = http_request.url() else {
+            return;
+        };
+        let Some(process) = url.path_segments().and_then(|mut segments| segments.next()) else {
+            return;
+        };
+        self.origin = Some(format!("{}/{process}", url.origin().ascii_serialization()));
+    }
+
+    /// Check the API token or login cookie a request to a path bound
+    /// without the cookie carries, noting a token's use.
+    pub fn authorize(
+        &mut self,
+        store: &dyn Store,
+        http_request: &IncomingHttpRequest,
+        bound_path: &str,
+        now: u64,
+    ) -> anyhow::Result<Authorization> {
+        let method = http_request.method()?;
+        let mut tokens = load(store)?;
+        let headers = http_request.headers();
+        let authorization = headers
+            .get("authorization")
+            .and_then(|value| value.to_str().ok());
+        if let Some(token) = authenticate(&tokens, authorization) {
+            let token_id = token.id.clone();
+            if method.as_str() != "GET" {
+                info!("token {token_id}: {} {bound_path}", method.as_str());
+                if let Some(token) = tokens.tokens.get_mut(&token_id) {
+                    token.last_used_at = Some(now);
+                }
+                save(store, &tokens)?;
+            }
+            return Ok(Authorization::Granted);
+        }
+        let Some(cookie) = cookie(http_request) else {
+            return deny(bound_path);
+        };
+        let cookie_hash = hash(&cookie);
+        self.verified.retain(|_, until| *until > now);
+        if self.verified.contains_key(&cookie_hash) {
+            return Ok(Authorization::Granted);
+        }
+        let Some(ref origin) = self.origin else {
+            return deny(bound_path);
+        };
+        let nonce = new_token();
+        let headers = HashMap::from([("cookie".to_string(), cookie)]);
+        Request::to(("our", "http-client", "distro", "sys"))
+            .body(serde_json::to_vec(&HttpClientAction::Http(
+                OutgoingHttpRequest {
+                    method: "GET".to_string(),
+                    version: None,
+                    url: format!("{origin}{HTTP_SESSION_PATH}?check={nonce}"),
+                    headers,
+                },
+            ))?)
+            .context([SESSION_CONTEXT_PREFIX, nonce.as_bytes()].concat())
+            .expects_response(SESSION_CHECK_TIMEOUT_S)
+            .send()?;
+        let held = Held {
+            request: http_request.clone(),
+            body: last_blob().map(|blob| blob.bytes),
+            cookie: cookie_hash,
+            seen: false,
+        };
+        self.held.insert(nonce, held);
+        Ok(Authorization::Held)
+    }
+
+    /// Serve `/api/session`, which only requests with a good login cookie
+    /// reach.
+    pub fn handle_http(&mut self, http_request: &IncomingHttpRequest) -> anyhow::Result<()> {
+        if let Some(held) = http_request
+            .query_params()
+            .get("check")
+            .and_then(|nonce| self.held.get_mut(nonce))
+        {
+            held.seen = true;
+        }
+        send_http_response(HTTP_NO_CONTENT, None)
+    }
+
+    /// The answer to the cookie check sent with `context`: the request it
+    /// held, if the cookie was good, or else answered with 401.
+    pub fn handle_reply(&mut self, context: &[u8], now: u64) -> anyhow::Result<Option<Held>> {
+        let Some(held) = session_context(context).and_then(|nonce| self.held.remove(nonce)) else {
+            return Ok(None);
+        };
+        if !held.seen {
+            deny(held.request.bound_path(None))?;
+            return Ok(None);
+        }
+        self.verified
+            .insert(held.cookie.clone(), now + SESSION_TTL_S);
+        Ok(Some(held))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn only_the_token_made_authenticates() {
+        let store = MemoryStore::default();
+        let (token, secret) = create(&store, " ci ", 5).unwrap();
+        assert_eq!(token.name, "ci");
+        assert!(secret.starts_with(TOKEN_PREFIX));
+        let tokens = load(&store).unwrap();
+        // Only the hash is kept
+        assert!(!serde_json::to_string(&tokens).unwrap().contains(&secret));
+
+        let auth = |header: &str| authenticate(&tokens, Some(header)).map(|t| t.id.clone());
+        assert_eq!(auth(&format!("Bearer {secret}")), Some(token.id.clone()));
+        assert_eq!(auth(&format!("bearer  {secret} ")), Some(token.id.clone()));
+        assert_eq!(auth(&format!("Basic {secret}")), None);
+        assert_eq!(auth(&format!("Bearer {secret}0")), None);
+        assert_eq!(auth(&format!("Bearer {}", token.hash)), None);
+        assert_eq!(auth("Bearer"), None);
+        assert_eq!(authenticate(&tokens, None).map(|t| t.id.clone()), None);
+    }
+
+    fn incoming(url: &str, headers: serde_json::Value) -> IncomingHttpRequest {
+        serde_json::from_value(serde_json::json!({
+            "source_socket_addr": null,
+            "method": "GET",
+            "url": url,
+            "bound_path": "/kibitz:kibitz:nick.hypr/api/runs",
+            "headers": headers,
+            "url_params": {},
+            "query_params": {},
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn tokens_and_checked_cookies_are_taken() {
+        let store = MemoryStore::default();
+        let (_, secret) = create(&store, "ci", 5).unwrap();
+        let mut sessions = Sessions::default();
+        let granted = |sessions: &mut Sessions, request: &IncomingHttpRequest| {
+            matches!(
+                sessions
+                    .authorize(&store, request, "/api/runs", 10)
+                    .unwrap(),
+                Authorization::Granted
+            )
+        };
+        let url = "http://localhost:8080/kibitz:kibitz:nick.hypr/api/runs";
+        let bearer = incoming(
+            url,
+            serde_json::json!({ "authorization": format!("Bearer {secret}") }),
+        );
+        assert!(granted(&mut sessions, &bearer));
+
+        sessions.note_origin(&incoming(url, serde_json::json!({})));
+        assert_eq!(
+            sessions.origin.as_deref(),
+            Some("http://localhost:8080/kibitz:kibitz:nick.hypr")
+        );
+        let with_cookie = incoming(url, serde_json::json!({ "cookie": "hyperware-auth=x" }));
+        sessions.verified.insert(hash("hyperware-auth=x"), 11);
+        assert!(granted(&mut sessions, &with_cookie));
+
+        assert_eq!(session_context(b"session:abc"), Some("abc"));
+        assert_eq!(session_context(b"call:1"), None);
+    }
+
+    #[test]
+    fn tokens_need_a_name_and_list_without_their_hash() {
+        let store = MemoryStore::default();
+        assert!(create(&store, "  ", 5).is_err());
+        let (token, _) = create(&store, "ci", 5).unwrap();
+        let listed = serde_json::to_value(TokenInfo::from(&token)).unwrap();
+        assert_eq!(
+            listed,
+            serde_json::json!({ "id": token.id, "name": "ci", "created_at": 5 })
+        );
+    }
+}
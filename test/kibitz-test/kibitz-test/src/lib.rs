@@ -0,0 +1,1927 @@
+use std::collections::HashMap;
+
+use crate::hyperware::process::fwd_ws::{
+    ConnectionType, Request as FwdWsRequest, Response as FwdWsResponse,
+};
+use crate::hyperware::process::kibitz::{
+    AppendMessageRequest, CheckToolsRequest, CreateConversationRequest, GetMessagesRequest,
+    ListConversationsRequest, Request as KibitzRequest, Response as KibitzProcessResponse,
+    RunPromptRequest, ServedBy, SetKeyRequest,
+};
+use crate::hyperware::process::tester::{
+    FailResponse, Request as TesterRequest, Response as TesterResponse, RunRequest,
+};
+use hyperware_process_lib::http::client::{
+    HttpClientAction, HttpClientError, HttpClientResponse, OutgoingHttpRequest,
+};
+use hyperware_process_lib::http::server::{
+    send_response, send_ws_push, HttpBindingConfig, HttpResponse, HttpServer, HttpServerRequest,
+    StatusCode, WsBindingConfig, WsMessageType,
+};
+use hyperware_process_lib::{
+    await_message, call_init, get_blob, print_to_terminal, Address, LazyLoadBlob, Message, Request,
+    Response,
+};
+
+wit_bindgen::generate!({
+    path: "target/wit",
+    world: "kibitz-test-nick-dot-hypr-v0",
+    generate_unused_types: true,
+    additional_derives: [serde::Deserialize, serde::Serialize, process_macros::SerdeJsonInto],
+});
+
+const KIBITZ_KEYS_PATH: &str = "/api/keys";
+const KIBITZ_KEY_PATH: &str = "/api/keys/:provider";
+const KIBITZ_PROVIDERS_PATH: &str = "/api/providers";
+const KIBITZ_CONVERSATIONS_PATH: &str = "/api/conversations";
+const KIBITZ_CONVERSATION_PATH: &str = "/api/conversations/:id";
+const KIBITZ_MESSAGES_PATH: &str = "/api/conversations/:id/messages";
+const KIBITZ_SEARCH_PATH: &str = "/api/search";
+const KIBITZ_EXPORT_PATH: &str = "/api/conversations/:id/export";
+const KIBITZ_IMPORT_PATH: &str = "/api/import";
+const KIBITZ_BACKUP_PATH: &str = "/api/backup";
+const KIBITZ_BACKUPS_PATH: &str = "/api/backups";
+const KIBITZ_RESTORE_PATH: &str = "/api/restore";
+const KIBITZ_LLM_PATH: &str = "/api/llm/:provider/*rest";
+const KIBITZ_RUNS_PATH: &str = "/api/runs";
+const KIBITZ_RUN_PATH: &str = "/api/runs/:id";
+const KIBITZ_RUN_CANCEL_PATH: &str = "/api/runs/:id/cancel";
+const KIBITZ_TOKENS_PATH: &str = "/api/tokens";
+const KIBITZ_TASKS_PATH: &str = "/api/tasks";
+const KIBITZ_USAGE_PATH: &str = "/api/usage";
+const KIBITZ_BUDGETS_PATH: &str = "/api/budgets";
+const KIBITZ_BUDGET_PATH: &str = "/api/budgets/:provider";
+const KIBITZ_TASK_PATH: &str = "/api/tasks/:id";
+const KIBITZ_TASK_RUN_PATH: &str = "/api/tasks/:id/run";
+const KIBITZ_PROMPTS_PATH: &str = "/api/prompts";
+const KIBITZ_PROMPT_PATH: &str = "/api/prompts/:id";
+const KIBITZ_FORK_PATH: &str = "/api/conversations/:id/fork";
+const KIBITZ_BRANCHES_PATH: &str = "/api/conversations/:id/branches";
+const KIBITZ_CHECKPOINTS_PATH: &str = "/api/conversations/:id/checkpoints";
+const KIBITZ_DIFF_PATH: &str = "/api/conversations/:id/diff";
+const KIBITZ_PROFILES_PATH: &str = "/api/profiles";
+const KIBITZ_PROFILE_PATH: &str = "/api/profiles/:id";
+/// Nothing listens here, so proxied calls fail upstream.
+const UNREACHABLE_BASE_URL: &str = "http://127.0.0.1:9";
+
+/// Must match the node port in `test/tests.toml`.
+const NODE_URL: &str = "http://localhost:8080";
+const KIBITZ_URL: &str = "http://localhost:8080/kibitz:kibitz:nick.hypr";
+/// What the login page sends for the password `kit` boots fake nodes with,
+/// `secret`: its Argon2id hash (19456 KiB, 2 passes, 1 lane, 32 bytes),
+/// salted with the node name, `first.dev` in `test/tests.toml`.
+const LOGIN_PASSWORD_HASH: &str =
+    "0x507b55bb660c86785ac94a05327e7578a9ba3baf6a3e4ae1ae9f0f60d0351431";
+
+/// WebSocket path we bind to stand in for a ws-mcp server.
+const STAND_IN_WS_PATH: &str = "/ws";
+const STAND_IN_WS_URL: &str = "ws://localhost:8080/kibitz-test:kibitz-test:nick.hypr/ws";
+/// HTTP path we bind to stand in for the Anthropic Messages API.
+const STAND_IN_LLM_PATH: &str = "/llm/v1/messages";
+const STAND_IN_LLM_BASE_URL: &str = "http://localhost:8080/kibitz-test:kibitz-test:nick.hypr/llm";
+const STAND_IN_MODEL: &str = "stand-in-model";
+/// What the stand-in LLM answers when not offered the echo tool.
+const STAND_IN_REPLY: &str = "hello from the stand-in";
+
+const TIMEOUT_S: u64 = 10;
+/// Routed calls wait a second before retrying an unreachable provider.
+const HTTP_TIMEOUT_S: u64 = 30;
+
+/// Us, and the login cookie kibitz's HTTP API wants, as a browser would
+/// send it.
+struct Node {
+    our: Address,
+    cookie: String,
+}
+
+type TestFn = fn(&Node, &mut HttpServer) -> anyhow::Result<()>;
+
+const TESTS: &[(&str, TestFn)] = &[
+    ("kibitz_keys", test_kibitz_keys),
+    ("keys_http", test_keys_http),
+    ("fwd_ws_round_trip", test_fwd_ws_round_trip),
+    ("conversations", test_conversations),
+    ("conversations_http", test_conversations_http),
+    ("run_prompt", test_run_prompt),
+    ("llm_proxy", test_llm_proxy),
+    ("backup_restore", test_backup_restore),
+    ("agent_run", test_agent_run),
+    ("budgets", test_budgets),
+    ("scheduled_tasks", test_scheduled_tasks),
+    ("prompt_library", test_prompt_library),
+    ("agent_profiles", test_agent_profiles),
+    ("conversation_branches", test_conversation_branches),
+];
+
+fn log(message: &str) {
+    print_to_terminal(0, &format!("kibitz-test: {message}"));
+}
+
+fn kibitz_address(our: &Address) -> Address {
+    Address::from((our.node(), "kibitz", "kibitz", "nick.hypr"))
+}
+
+fn fwd_ws_address(our: &Address) -> Address {
+    Address::from((our.node(), "fwd-ws", "kibitz", "nick.hypr"))
+}
+
+struct KibitzResponse {
+    status: u16,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+impl KibitzResponse {
+    fn json(&self) -> anyhow::Result<serde_json::Value> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn etag(&self) -> Option<String> {
+        self.header("etag").map(str::to_string)
+    }
+}
+
+/// An HTTP request through `http-client`, not yet sent.
+fn http_request(
+    method: &str,
+    url: String,
+    headers: &[(&str, &str)],
+    body: Option<Vec<u8>>,
+) -> anyhow::Result<Request> {
+    let headers = headers
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    let request = Request::to(("our", "http-client", "distro", "sys")).body(serde_json::to_vec(
+        &HttpClientAction::Http(OutgoingHttpRequest {
+            method: method.to_string(),
+            version: None,
+            url,
+            headers,
+        }),
+    )?);
+    Ok(match body {
+        Some(body) => request.blob_bytes(body),
+        None => request,
+    })
+}
+
+/// Unpack what `http-client` answered, with the body from the blob.
+fn http_response(body: &[u8]) -> anyhow::Result<KibitzResponse> {
+    match serde_json::from_slice::<Result<HttpClientResponse, HttpClientError>>(body)? {
+        Ok(HttpClientResponse::Http(HttpResponse { status, headers })) => Ok(KibitzResponse {
+            status,
+            headers,
+            body: get_blob().map(|blob| blob.bytes).unwrap_or_default(),
+        }),
+        Ok(other) => Err(anyhow::anyhow!("unexpected http-client response {other:?}")),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Log in as the login page would, returning the cookie it sets.
+fn log_in() -> anyhow::Result<String> {
+    let login = serde_json::json!({ "password_hash": LOGIN_PASSWORD_HASH, "subdomain": "" });
+    let response = http_request(
+        "POST",
+        format!("{NODE_URL}/login"),
+        &[("content-type", "application/json")],
+        Some(serde_json::to_vec(&login)?),
+    )?
+    .send_and_await_response(HTTP_TIMEOUT_S)??;
+    let response = http_response(response.body())?;
+    anyhow::ensure!(response.status == 200, "login: status {}", response.status);
+    response
+        .header("set-cookie")
+        .and_then(|cookie| cookie.split(';').next())
+        .map(str::to_string)
+        .ok_or_else(|| anyhow::anyhow!("login set no cookie"))
+}
+
+/// The request for `method bound_path` to kibitz, through `http-server`,
+/// with `:param` (or `*param`) segments filled in from `url_params`. A
+/// `?query` on the path is passed on as it is.
+fn kibitz_request(
+    method: &str,
+    bound_path: &str,
+    url_params: &[(&str, &str)],
+    headers: &[(&str, &str)],
+    body: Option<Vec<u8>>,
+) -> anyhow::Result<Request> {
+    let path = url_params
+        .iter()
+        .fold(bound_path.to_string(), |path, (name, value)| {
+            path.replace(&format!(":{name}"), value)
+                .replace(&format!("*{name}"), value)
+        });
+    http_request(method, format!("{KIBITZ_URL}{path}"), headers, body)
+}
+
+/// Call kibitz's HTTP API with only `headers`: no login cookie unless
+/// they carry it.
+fn kibitz_http_with(
+    method: &str,
+    bound_path: &str,
+    url_params: &[(&str, &str)],
+    headers: &[(&str, &str)],
+    body: Option<Vec<u8>>,
+) -> anyhow::Result<KibitzResponse> {
+    let response = kibitz_request(method, bound_path, url_params, headers, body)?
+        .send_and_await_response(HTTP_TIMEOUT_S)??;
+    http_response(response.body())
+}
+
+/// Call kibitz's HTTP API as the logged-in browser, on a bound path with
+/// `url_params` filled in.
+fn kibitz_http_bound(
+    node: &Node,
+    method: &str,
+    bound_path: &str,
+    url_params: &[(&str, &str)],
+    if_match: Option<&str>,
+    body: Option<Vec<u8>>,
+) -> anyhow::Result<KibitzResponse> {
+    let mut headers = vec![("cookie", node.cookie.as_str())];
+    headers.extend(if_match.map(|etag| ("if-match", etag)));
+    kibitz_http_with(method, bound_path, url_params, &headers, body)
+}
+
+/// Call kibitz's HTTP API as the logged-in browser.
+fn kibitz_http(
+    node: &Node,
+    method: &str,
+    path: &str,
+    if_match: Option<&str>,
+    body: Option<Vec<u8>>,
+) -> anyhow::Result<KibitzResponse> {
+    kibitz_http_bound(node, method, path, &[], if_match, body)
+}
+
+/// Like `kibitz_http_bound`, for calls that have kibitz call the stand-in
+/// LLM before it answers: we play the LLM while waiting.
+fn kibitz_http_answering_llm(
+    node: &Node,
+    bound_path: &str,
+    url_params: &[(&str, &str)],
+    body: serde_json::Value,
+) -> anyhow::Result<KibitzResponse> {
+    let headers = [("cookie", node.cookie.as_str())];
+    kibitz_request(
+        "POST",
+        bound_path,
+        url_params,
+        &headers,
+        Some(serde_json::to_vec(&body)?),
+    )?
+    .expects_response(HTTP_TIMEOUT_S)
+    .send()?;
+    let response = await_answering_llm(&node.our)?;
+    http_response(response.body())
+}
+
+fn fwd_ws(our: &Address, request: FwdWsRequest) -> anyhow::Result<FwdWsResponse> {
+    let response = Request::to(fwd_ws_address(our))
+        .body(request)
+        .send_and_await_response(TIMEOUT_S)??;
+    Ok(response.body().try_into()?)
+}
+
+fn kibitz(our: &Address, request: KibitzRequest) -> anyhow::Result<KibitzProcessResponse> {
+    let response = Request::to(kibitz_address(our))
+        .body(request)
+        .send_and_await_response(TIMEOUT_S)??;
+    Ok(response.body().try_into()?)
+}
+
+/// An API token, as `/api/runs` takes from scripts.
+fn api_token(node: &Node) -> anyhow::Result<String> {
+    let name = serde_json::to_vec(&serde_json::json!({ "name": "kibitz-test" }))?;
+    let created = kibitz_http(node, "POST", KIBITZ_TOKENS_PATH, None, Some(name))?;
+    anyhow::ensure!(
+        created.status == 201,
+        "POST {KIBITZ_TOKENS_PATH}: status {}",
+        created.status
+    );
+    let created = created.json()?;
+    let token = created["token"].as_str().unwrap_or_default();
+    Ok(format!("Bearer {token}"))
+}
+
+/// Point kibitz's `anthropic` provider, and only it, at our stand-in LLM,
+/// with a key.
+fn use_stand_in_llm(node: &Node) -> anyhow::Result<()> {
+    let settings = serde_json::json!({ "base_urls": { "anthropic": STAND_IN_LLM_BASE_URL } });
+    let put = kibitz_http(
+        node,
+        "PUT",
+        KIBITZ_PROVIDERS_PATH,
+        Some("*"),
+        Some(serde_json::to_vec(&settings)?),
+    )?;
+    anyhow::ensure!(
+        put.status == 200,
+        "PUT {KIBITZ_PROVIDERS_PATH}: status {}",
+        put.status
+    );
+    let set = SetKeyRequest {
+        provider: "anthropic".to_string(),
+        key: "sk-kibitz-test".to_string(),
+    };
+    match kibitz(&node.our, KibitzRequest::SetKey(set))? {
+        KibitzProcessResponse::SetKey => Ok(()),
+        other => Err(anyhow::anyhow!("set-key: got {other:?}")),
+    }
+}
+
+fn expect_ok(response: FwdWsResponse) -> anyhow::Result<()> {
+    match response {
+        FwdWsResponse::Ok => Ok(()),
+        other => Err(anyhow::anyhow!("expected Ok, got {other:?}")),
+    }
+}
+
+/// Wait for the next event on our stand-in ws-mcp server, acking opens.
+fn next_ws_event(server: &mut HttpServer) -> anyhow::Result<HttpServerRequest> {
+    loop {
+        let message = await_message()?;
+        if !message.is_request() {
+            continue;
+        }
+        let Ok(request) = serde_json::from_slice::<HttpServerRequest>(message.body()) else {
+            continue;
+        };
+        match request {
+            HttpServerRequest::WebSocketOpen {
+                ref path,
+                channel_id,
+            } => server.handle_websocket_open(path, channel_id),
+            HttpServerRequest::WebSocketClose(channel_id) => {
+                server.handle_websocket_close(channel_id)
+            }
+            _ => {}
+        }
+        return Ok(request);
+    }
+}
+
+fn expect_ws_text(server: &mut HttpServer, expected_channel_id: u32) -> anyhow::Result<String> {
+    match next_ws_event(server)? {
+        HttpServerRequest::WebSocketPush { channel_id, .. }
+            if channel_id == expected_channel_id =>
+        {
+            let blob = get_blob().ok_or_else(|| anyhow::anyhow!("WebSocketPush without blob"))?;
+            Ok(String::from_utf8(blob.bytes)?)
+        }
+        other => Err(anyhow::anyhow!("expected WebSocketPush, got {other:?}")),
+    }
+}
+
+/// Answer a JSON-RPC request from kibitz as a one-tool MCP server would.
+fn answer_mcp(channel_id: u32, request: &serde_json::Value) -> anyhow::Result<()> {
+    let result = match request["method"].as_str() {
+        Some("initialize") => serde_json::json!({
+            "protocolVersion": "2024-11-05",
+            "capabilities": { "tools": {} },
+            "serverInfo": { "name": "kibitz-test", "version": "0" },
+        }),
+        Some("tools/list") => serde_json::json!({ "tools": [{
+            "name": "echo",
+            "description": "Echo the text back",
+            "inputSchema": { "type": "object", "properties": { "text": { "type": "string" } } },
+        }] }),
+        Some("tools/call") => serde_json::json!({
+            "content": [{ "type": "text", "text": request["params"]["arguments"]["text"] }],
+            "isError": false,
+        }),
+        // Notifications
+        _ => return Ok(()),
+    };
+    let response = serde_json::json!({ "jsonrpc": "2.0", "id": request["id"], "result": result });
+    send_ws_push(
+        channel_id,
+        WsMessageType::Text,
+        LazyLoadBlob::new(Some("application/json"), serde_json::to_vec(&response)?),
+    );
+    Ok(())
+}
+
+/// The body of a call to our stand-in LLM.
+fn llm_call() -> anyhow::Result<serde_json::Value> {
+    let blob = get_blob().ok_or_else(|| anyhow::anyhow!("LLM call without body"))?;
+    Ok(serde_json::from_slice(&blob.bytes)?)
+}
+
+/// Reply as the Messages API would: offered the echo tool, first call it,
+/// then, once its result is in, finish; otherwise just say hello.
+fn answer_llm(request: &serde_json::Value) -> anyhow::Result<()> {
+    let messages = request["messages"].as_array().cloned().unwrap_or_default();
+    let (content, stop_reason) = match messages.last() {
+        Some(last) if last["content"][0]["type"] == "tool_result" => {
+            let echoed = &last["content"][0]["content"][0]["text"];
+            let text = format!("tool said {}", echoed.as_str().unwrap_or_default());
+            (
+                serde_json::json!([{ "type": "text", "text": text }]),
+                "end_turn",
+            )
+        }
+        _ if request["tools"][0]["name"] == "echo" => (
+            serde_json::json!([{
+                "type": "tool_use",
+                "id": "toolu_1",
+                "name": "echo",
+                "input": { "text": "hello" },
+            }]),
+            "tool_use",
+        ),
+        _ => (
+            serde_json::json!([{ "type": "text", "text": STAND_IN_REPLY }]),
+            "end_turn",
+        ),
+    };
+    let reply = serde_json::json!({
+        "type": "message",
+        "role": "assistant",
+        "model": STAND_IN_MODEL,
+        "content": content,
+        "stop_reason": stop_reason,
+        "usage": { "input_tokens": 10, "output_tokens": 5 },
+    });
+    let headers = HashMap::from([("Content-Type".to_string(), "application/json".to_string())]);
+    send_response(StatusCode::OK, Some(headers), serde_json::to_vec(&reply)?);
+    Ok(())
+}
+
+/// Wait for the response to a request sent without awaiting it, playing
+/// the stand-in LLM for any calls kibitz makes in the meantime.
+fn await_answering_llm(our: &Address) -> anyhow::Result<Message> {
+    let process = our.process.to_string();
+    loop {
+        let message = await_message()?;
+        if !message.is_request() {
+            return Ok(message);
+        }
+        if let Ok(HttpServerRequest::Http(request)) = serde_json::from_slice(message.body()) {
+            if request.bound_path(Some(&process)) == STAND_IN_LLM_PATH {
+                answer_llm(&llm_call()?)?;
+            }
+        }
+    }
+}
+
+fn test_kibitz_keys(node: &Node, _server: &mut HttpServer) -> anyhow::Result<()> {
+    let our = &node.our;
+    let set = |key: &str| {
+        KibitzRequest::SetKey(SetKeyRequest {
+            provider: "openai".to_string(),
+            key: key.to_string(),
+        })
+    };
+    for key in ["sk-kibitz-test-first", "sk-kibitz-test-abcd"] {
+        match kibitz(our, set(key))? {
+            KibitzProcessResponse::SetKey => {}
+            other => return Err(anyhow::anyhow!("set-key: got {other:?}")),
+        }
+    }
+    match kibitz(our, KibitzRequest::ListKeys)? {
+        KibitzProcessResponse::ListKeys(keys) => {
+            anyhow::ensure!(
+                !format!("{keys:?}").contains("sk-kibitz"),
+                "list-keys leaked a key"
+            );
+            anyhow::ensure!(
+                keys.iter()
+                    .any(|key| key.provider == "openai" && key.last4 == "abcd"),
+                "list-keys: got {keys:?}"
+            );
+        }
+        other => return Err(anyhow::anyhow!("list-keys: got {other:?}")),
+    }
+
+    let delete = || KibitzRequest::DeleteKey("openai".to_string());
+    match kibitz(our, delete())? {
+        KibitzProcessResponse::DeleteKey(true) => {}
+        other => return Err(anyhow::anyhow!("delete-key: got {other:?}")),
+    }
+    match kibitz(our, delete())? {
+        KibitzProcessResponse::DeleteKey(false) => {}
+        other => return Err(anyhow::anyhow!("second delete-key: got {other:?}")),
+    }
+    match kibitz(our, KibitzRequest::ListKeys)? {
+        KibitzProcessResponse::ListKeys(keys) => anyhow::ensure!(
+            keys.iter().all(|key| key.provider != "openai"),
+            "list-keys after delete-key: got {keys:?}"
+        ),
+        other => return Err(anyhow::anyhow!("list-keys: got {other:?}")),
+    }
+    Ok(())
+}
+
+fn test_keys_http(node: &Node, _server: &mut HttpServer) -> anyhow::Result<()> {
+    let listing = kibitz_http(node, "GET", KIBITZ_KEYS_PATH, None, None)?;
+    anyhow::ensure!(
+        listing.status == 200,
+        "GET {KIBITZ_KEYS_PATH}: status {}",
+        listing.status
+    );
+    let Some(first_etag) = listing.etag() else {
+        return Err(anyhow::anyhow!("GET {KIBITZ_KEYS_PATH}: no ETag"));
+    };
+
+    let keys =
+        serde_json::to_vec(&serde_json::json!({ "keys": { "anthropic": "sk-kibitz-test" } }))?;
+    let put = kibitz_http(node, "PUT", KIBITZ_KEYS_PATH, None, Some(keys.clone()))?;
+    anyhow::ensure!(
+        put.status == 428,
+        "PUT without If-Match: status {}",
+        put.status
+    );
+    let put = kibitz_http(
+        node,
+        "PUT",
+        KIBITZ_KEYS_PATH,
+        Some(&first_etag),
+        Some(keys.clone()),
+    )?;
+    anyhow::ensure!(
+        put.status == 200,
+        "PUT {KIBITZ_KEYS_PATH}: status {}",
+        put.status
+    );
+    let Some(etag) = put.etag() else {
+        return Err(anyhow::anyhow!("PUT {KIBITZ_KEYS_PATH}: no ETag"));
+    };
+
+    // The ETag we started from is now stale
+    let stale = kibitz_http(node, "PUT", KIBITZ_KEYS_PATH, Some(&first_etag), Some(keys))?;
+    anyhow::ensure!(stale.status == 412, "stale PUT: status {}", stale.status);
+    anyhow::ensure!(
+        stale.etag().as_deref() == Some(etag.as_str()),
+        "stale PUT: ETag {:?}",
+        stale.etag()
+    );
+
+    let listing = kibitz_http(node, "GET", KIBITZ_KEYS_PATH, None, None)?;
+    anyhow::ensure!(
+        !String::from_utf8_lossy(&listing.body).contains("sk-kibitz"),
+        "GET {KIBITZ_KEYS_PATH} leaked a key"
+    );
+    let got = listing.json()?;
+    anyhow::ensure!(
+        got["keys"]["anthropic"]["masked"] == "****test",
+        "GET {KIBITZ_KEYS_PATH}: got {got}"
+    );
+
+    let malformed = kibitz_http(
+        node,
+        "PUT",
+        KIBITZ_KEYS_PATH,
+        Some("*"),
+        Some(b"not json".to_vec()),
+    )?;
+    anyhow::ensure!(
+        malformed.status == 400,
+        "malformed PUT: status {}",
+        malformed.status
+    );
+
+    let provider = [("provider", "openai")];
+    let key = serde_json::json!({ "key": "sk-openai-test-abcd" });
+    let put = kibitz_http_bound(
+        node,
+        "PUT",
+        KIBITZ_KEY_PATH,
+        &provider,
+        Some(&etag),
+        Some(serde_json::to_vec(&key)?),
+    )?;
+    anyhow::ensure!(
+        put.status == 200,
+        "PUT {KIBITZ_KEY_PATH}: status {}",
+        put.status
+    );
+    let got = kibitz_http(node, "GET", KIBITZ_KEYS_PATH, None, None)?.json()?;
+    anyhow::ensure!(
+        got["keys"]["openai"]["last4"] == "abcd" && got["keys"]["anthropic"].is_object(),
+        "GET {KIBITZ_KEYS_PATH} after PUT {KIBITZ_KEY_PATH}: got {got}"
+    );
+
+    let delete = kibitz_http_bound(
+        node,
+        "DELETE",
+        KIBITZ_KEY_PATH,
+        &provider,
+        Some(&etag),
+        None,
+    )?;
+    anyhow::ensure!(
+        delete.status == 412,
+        "stale DELETE: status {}",
+        delete.status
+    );
+    let delete = kibitz_http_bound(
+        node,
+        "DELETE",
+        KIBITZ_KEY_PATH,
+        &provider,
+        put.etag().as_deref(),
+        None,
+    )?;
+    anyhow::ensure!(
+        delete.status == 200,
+        "DELETE {KIBITZ_KEY_PATH}: status {}",
+        delete.status
+    );
+    let delete = kibitz_http_bound(node, "DELETE", KIBITZ_KEY_PATH, &provider, Some("*"), None)?;
+    anyhow::ensure!(
+        delete.status == 404,
+        "second DELETE {KIBITZ_KEY_PATH}: status {}",
+        delete.status
+    );
+    Ok(())
+}
+
+fn test_fwd_ws_round_trip(node: &Node, server: &mut HttpServer) -> anyhow::Result<()> {
+    let our = &node.our;
+    // We are our own partner: fwd-ws forwards what our stand-in server sends
+    //  back to itself, which pushes it to the server again
+    expect_ok(fwd_ws(our, FwdWsRequest::Disconnect)?)?;
+    expect_ok(fwd_ws(
+        our,
+        FwdWsRequest::SetPartner(Some(our.node().to_string())),
+    )?)?;
+    expect_ok(fwd_ws(
+        our,
+        FwdWsRequest::ConnectToServer(STAND_IN_WS_URL.to_string()),
+    )?)?;
+
+    let channel_id = match next_ws_event(server)? {
+        HttpServerRequest::WebSocketOpen { channel_id, .. } => channel_id,
+        other => return Err(anyhow::anyhow!("expected WebSocketOpen, got {other:?}")),
+    };
+
+    let FwdWsResponse::GetState(state) = fwd_ws(our, FwdWsRequest::GetState)? else {
+        return Err(anyhow::anyhow!("expected GetState response"));
+    };
+    anyhow::ensure!(
+        matches!(state.connection, ConnectionType::ToWsServer),
+        "connection: {:?}",
+        state.connection
+    );
+    anyhow::ensure!(state.ws_url.as_deref() == Some(STAND_IN_WS_URL));
+    anyhow::ensure!(state.partner.as_deref() == Some(our.node()));
+
+    expect_ok(fwd_ws(our, FwdWsRequest::Forward("ping".to_string()))?)?;
+    let got = expect_ws_text(server, channel_id)?;
+    anyhow::ensure!(got == "ping", "server got {got:?}");
+
+    send_ws_push(
+        channel_id,
+        WsMessageType::Text,
+        LazyLoadBlob {
+            mime: Some("text/plain".to_string()),
+            bytes: b"pong".to_vec(),
+        },
+    );
+    let got = expect_ws_text(server, channel_id)?;
+    anyhow::ensure!(got == "pong", "server got {got:?}");
+
+    expect_ok(fwd_ws(our, FwdWsRequest::Disconnect)?)?;
+    let FwdWsResponse::GetState(state) = fwd_ws(our, FwdWsRequest::GetState)? else {
+        return Err(anyhow::anyhow!("expected GetState response"));
+    };
+    anyhow::ensure!(matches!(state.connection, ConnectionType::None));
+    Ok(())
+}
+
+fn test_conversations(node: &Node, _server: &mut HttpServer) -> anyhow::Result<()> {
+    let our = &node.our;
+    let create = CreateConversationRequest {
+        title: "kibitz-test".to_string(),
+        project_id: None,
+        settings: Some(r#"{"model":"test"}"#.to_string()),
+    };
+    let conversation = match kibitz(our, KibitzRequest::CreateConversation(create))? {
+        KibitzProcessResponse::CreateConversation(conversation) => conversation,
+        other => return Err(anyhow::anyhow!("create-conversation: got {other:?}")),
+    };
+    anyhow::ensure!(
+        conversation.settings == r#"{"model":"test"}"#,
+        "create-conversation: got {conversation:?}"
+    );
+
+    let append = |conversation_id: &str, content: &str| {
+        KibitzRequest::AppendMessage(AppendMessageRequest {
+            conversation_id: conversation_id.to_string(),
+            role: "user".to_string(),
+            content: content.to_string(),
+            served_by: None,
+        })
+    };
+    match kibitz(our, append(&conversation.id, "not json"))? {
+        KibitzProcessResponse::Err(_) => {}
+        other => return Err(anyhow::anyhow!("append bad content: got {other:?}")),
+    }
+    match kibitz(our, append("nope", r#""hello""#))? {
+        KibitzProcessResponse::AppendMessage(None) => {}
+        other => return Err(anyhow::anyhow!("append to no conversation: got {other:?}")),
+    }
+    let served_by = ServedBy {
+        provider: "anthropic".to_string(),
+        model: "stand-in".to_string(),
+    };
+    let reply = KibitzRequest::AppendMessage(AppendMessageRequest {
+        conversation_id: conversation.id.clone(),
+        role: "assistant".to_string(),
+        content: r#""world""#.to_string(),
+        served_by: Some(served_by.clone()),
+    });
+    for (seq, request) in [append(&conversation.id, r#""hello""#), reply]
+        .into_iter()
+        .enumerate()
+    {
+        match kibitz(our, request)? {
+            KibitzProcessResponse::AppendMessage(Some(message)) => anyhow::ensure!(
+                message.seq == seq as u64 && message.served_by.is_some() == (seq == 1),
+                "append-message: got {message:?}"
+            ),
+            other => return Err(anyhow::anyhow!("append-message: got {other:?}")),
+        }
+    }
+
+    let get = GetMessagesRequest {
+        conversation_id: conversation.id.clone(),
+        offset: 1,
+        limit: 10,
+    };
+    match kibitz(our, KibitzRequest::GetMessages(get))? {
+        KibitzProcessResponse::GetMessages(Some(page)) => anyhow::ensure!(
+            page.total == 2
+                && page.next_offset.is_none()
+                && page.items.len() == 1
+                && page.items[0].content == r#""world""#
+                && page.items[0]
+                    .served_by
+                    .as_ref()
+                    .is_some_and(|got| got.model == served_by.model),
+            "get-messages: got {page:?}"
+        ),
+        other => return Err(anyhow::anyhow!("get-messages: got {other:?}")),
+    }
+    match kibitz(our, KibitzRequest::GetConversation(conversation.id.clone()))? {
+        KibitzProcessResponse::GetConversation(Some(got)) => anyhow::ensure!(
+            got.message_count == 2 && got.title == "kibitz-test",
+            "get-conversation: got {got:?}"
+        ),
+        other => return Err(anyhow::anyhow!("get-conversation: got {other:?}")),
+    }
+    match kibitz(our, KibitzRequest::GetConversation("nope".to_string()))? {
+        KibitzProcessResponse::GetConversation(None) => {}
+        other => return Err(anyhow::anyhow!("get-conversation nope: got {other:?}")),
+    }
+
+    let list = ListConversationsRequest {
+        project_id: None,
+        offset: 0,
+        limit: 100,
+    };
+    match kibitz(our, KibitzRequest::ListConversations(list))? {
+        KibitzProcessResponse::ListConversations(page) => anyhow::ensure!(
+            page.items
+                .iter()
+                .any(|summary| summary.id == conversation.id && summary.message_count == 2),
+            "list-conversations: got {page:?}"
+        ),
+        other => return Err(anyhow::anyhow!("list-conversations: got {other:?}")),
+    }
+    Ok(())
+}
+
+fn test_conversations_http(node: &Node, _server: &mut HttpServer) -> anyhow::Result<()> {
+    let new = serde_json::to_vec(&serde_json::json!({ "title": "kibitz-test http" }))?;
+    let created = kibitz_http(node, "POST", KIBITZ_CONVERSATIONS_PATH, None, Some(new))?;
+    anyhow::ensure!(
+        created.status == 201,
+        "POST {KIBITZ_CONVERSATIONS_PATH}: status {}",
+        created.status
+    );
+    let created = created.json()?;
+    let Some(id) = created["id"].as_str() else {
+        return Err(anyhow::anyhow!(
+            "POST {KIBITZ_CONVERSATIONS_PATH}: got {created}"
+        ));
+    };
+    let conversation = [("id", id)];
+
+    let mut etag = None;
+    for text in ["hello", "world"] {
+        let message = serde_json::json!({ "role": "user", "content": text });
+        let appended = kibitz_http_bound(
+            node,
+            "POST",
+            KIBITZ_MESSAGES_PATH,
+            &conversation,
+            None,
+            Some(serde_json::to_vec(&message)?),
+        )?;
+        anyhow::ensure!(
+            appended.status == 201,
+            "POST {KIBITZ_MESSAGES_PATH}: status {}",
+            appended.status
+        );
+        etag = appended.etag();
+    }
+
+    let messages =
+        kibitz_http_bound(node, "GET", KIBITZ_MESSAGES_PATH, &conversation, None, None)?.json()?;
+    anyhow::ensure!(
+        messages["total"] == 2 && messages["items"][1]["content"] == "world",
+        "GET {KIBITZ_MESSAGES_PATH}: got {messages}"
+    );
+    let listing = kibitz_http(node, "GET", KIBITZ_CONVERSATIONS_PATH, None, None)?.json()?;
+    anyhow::ensure!(
+        listing["items"][0]["id"] == id && listing["items"][0]["message_count"] == 2,
+        "GET {KIBITZ_CONVERSATIONS_PATH}: got {listing}"
+    );
+    let search = format!("{KIBITZ_SEARCH_PATH}?q=World");
+    let found = kibitz_http(node, "GET", &search, None, None)?.json()?;
+    anyhow::ensure!(
+        found["items"].as_array().is_some_and(|hits| hits
+            .iter()
+            .any(|hit| hit["conversation_id"] == id
+                && hit["seq"] == 1
+                && hit["snippet"] == "world")),
+        "GET {search}: got {found}"
+    );
+
+    let exported = kibitz_http_bound(node, "GET", KIBITZ_EXPORT_PATH, &conversation, None, None)?;
+    anyhow::ensure!(
+        exported.status == 200,
+        "GET {KIBITZ_EXPORT_PATH}: status {}",
+        exported.status
+    );
+    let report =
+        kibitz_http(node, "POST", KIBITZ_IMPORT_PATH, None, Some(exported.body))?.json()?;
+    anyhow::ensure!(
+        report["imported"] == serde_json::json!([]) && report["duplicates"][0]["id"] == id,
+        "POST {KIBITZ_IMPORT_PATH} of our own export: got {report}"
+    );
+    let markdown = format!("{KIBITZ_EXPORT_PATH}?format=markdown");
+    let markdown = kibitz_http_bound(node, "GET", &markdown, &conversation, None, None)?;
+    let markdown = String::from_utf8(markdown.body)?;
+    anyhow::ensure!(
+        markdown.starts_with("# kibitz-test http\n") && markdown.contains("\nworld\n"),
+        "GET {KIBITZ_EXPORT_PATH} as markdown: got {markdown}"
+    );
+
+    let deleted = kibitz_http_bound(
+        node,
+        "DELETE",
+        KIBITZ_CONVERSATION_PATH,
+        &conversation,
+        etag.as_deref(),
+        None,
+    )?;
+    anyhow::ensure!(
+        deleted.status == 200,
+        "DELETE {KIBITZ_CONVERSATION_PATH}: status {}",
+        deleted.status
+    );
+    let gone = kibitz_http_bound(
+        node,
+        "GET",
+        KIBITZ_CONVERSATION_PATH,
+        &conversation,
+        None,
+        None,
+    )?;
+    anyhow::ensure!(
+        gone.status == 404,
+        "GET deleted conversation: status {}",
+        gone.status
+    );
+    let found = kibitz_http(node, "GET", &search, None, None)?.json()?;
+    anyhow::ensure!(
+        found["items"]
+            .as_array()
+            .is_some_and(|hits| hits.iter().all(|hit| hit["conversation_id"] != id)),
+        "GET {search} after delete: got {found}"
+    );
+    Ok(())
+}
+
+fn test_run_prompt(node: &Node, _server: &mut HttpServer) -> anyhow::Result<()> {
+    let our = &node.our;
+    use_stand_in_llm(node)?;
+    match kibitz(our, KibitzRequest::DeleteKey("openai".to_string()))? {
+        KibitzProcessResponse::DeleteKey(_) => {}
+        other => return Err(anyhow::anyhow!("delete-key: got {other:?}")),
+    }
+
+    let prompt = |provider: &str, body: &str| {
+        KibitzRequest::RunPrompt(RunPromptRequest {
+            provider: provider.to_string(),
+            path: "v1/messages".to_string(),
+            body: body.to_string(),
+        })
+    };
+    match kibitz(our, prompt("anthropic", "not json"))? {
+        KibitzProcessResponse::Err(_) => {}
+        other => return Err(anyhow::anyhow!("run-prompt bad body: got {other:?}")),
+    }
+    match kibitz(our, prompt("nope", "{}"))? {
+        KibitzProcessResponse::Err(_) => {}
+        other => {
+            return Err(anyhow::anyhow!(
+                "run-prompt unknown provider: got {other:?}"
+            ))
+        }
+    }
+    match kibitz(our, prompt("openai", "{}"))? {
+        KibitzProcessResponse::Err(message) => anyhow::ensure!(
+            !message.contains("sk-kibitz"),
+            "run-prompt error leaked a key"
+        ),
+        other => return Err(anyhow::anyhow!("run-prompt with no key: got {other:?}")),
+    }
+
+    // kibitz calls our stand-in LLM before it answers, so play it meanwhile
+    let body = serde_json::json!({
+        "model": "stand-in",
+        "max_tokens": 64,
+        "messages": [{ "role": "user", "content": "hello?" }],
+    });
+    Request::to(kibitz_address(our))
+        .body(prompt("anthropic", &serde_json::to_string(&body)?))
+        .expects_response(TIMEOUT_S)
+        .send()?;
+    let response = await_answering_llm(our)?;
+    let reply = match response.body().try_into()? {
+        KibitzProcessResponse::RunPrompt(response) if response.status == 200 => response.body,
+        other => return Err(anyhow::anyhow!("run-prompt: got {other:?}")),
+    };
+    let reply: serde_json::Value = serde_json::from_str(&reply)?;
+    anyhow::ensure!(
+        reply["content"][0]["text"] == STAND_IN_REPLY,
+        "run-prompt: got {reply}"
+    );
+
+    // Keep the exchange in a conversation of its own, as a caller would
+    let create = CreateConversationRequest {
+        title: "kibitz-test run-prompt".to_string(),
+        project_id: None,
+        settings: None,
+    };
+    let conversation = match kibitz(our, KibitzRequest::CreateConversation(create))? {
+        KibitzProcessResponse::CreateConversation(conversation) => conversation,
+        other => return Err(anyhow::anyhow!("create-conversation: got {other:?}")),
+    };
+    let exchange = [
+        ("user", body["messages"][0]["content"].clone(), None),
+        (
+            "assistant",
+            reply["content"].clone(),
+            Some(ServedBy {
+                provider: "anthropic".to_string(),
+                model: STAND_IN_MODEL.to_string(),
+            }),
+        ),
+    ];
+    for (role, content, served_by) in exchange {
+        let append = AppendMessageRequest {
+            conversation_id: conversation.id.clone(),
+            role: role.to_string(),
+            content: content.to_string(),
+            served_by,
+        };
+        match kibitz(our, KibitzRequest::AppendMessage(append))? {
+            KibitzProcessResponse::AppendMessage(Some(_)) => {}
+            other => return Err(anyhow::anyhow!("append-message: got {other:?}")),
+        }
+    }
+    match kibitz(our, KibitzRequest::GetConversation(conversation.id))? {
+        KibitzProcessResponse::GetConversation(Some(got)) => {
+            anyhow::ensure!(got.message_count == 2, "get-conversation: got {got:?}");
+            Ok(())
+        }
+        other => Err(anyhow::anyhow!("get-conversation: got {other:?}")),
+    }
+}
+
+fn test_llm_proxy(node: &Node, _server: &mut HttpServer) -> anyhow::Result<()> {
+    let settings = |url: &str| -> anyhow::Result<Vec<u8>> {
+        Ok(serde_json::to_vec(
+            &serde_json::json!({ "base_urls": { "anthropic": url } }),
+        )?)
+    };
+    let put = kibitz_http(
+        node,
+        "PUT",
+        KIBITZ_PROVIDERS_PATH,
+        Some("*"),
+        Some(settings("not a url")?),
+    )?;
+    anyhow::ensure!(put.status == 400, "bad base URL: status {}", put.status);
+    let put = kibitz_http(
+        node,
+        "PUT",
+        KIBITZ_PROVIDERS_PATH,
+        Some("*"),
+        Some(settings(UNREACHABLE_BASE_URL)?),
+    )?;
+    anyhow::ensure!(
+        put.status == 200,
+        "PUT {KIBITZ_PROVIDERS_PATH}: status {}",
+        put.status
+    );
+    let got = kibitz_http(node, "GET", KIBITZ_PROVIDERS_PATH, None, None)?.json()?;
+    anyhow::ensure!(
+        got["base_urls"]["anthropic"] == UNREACHABLE_BASE_URL,
+        "GET {KIBITZ_PROVIDERS_PATH}: got {got}"
+    );
+
+    let key = serde_json::to_vec(&serde_json::json!({ "key": "sk-kibitz-test" }))?;
+    kibitz_http_bound(
+        node,
+        "PUT",
+        KIBITZ_KEY_PATH,
+        &[("provider", "anthropic")],
+        Some("*"),
+        Some(key),
+    )?;
+    kibitz_http_bound(
+        node,
+        "DELETE",
+        KIBITZ_KEY_PATH,
+        &[("provider", "openai")],
+        Some("*"),
+        None,
+    )?;
+
+    let call_body = serde_json::json!({
+        "model": "stand-in",
+        "max_tokens": 64,
+        "messages": [{ "role": "user", "content": "hello?" }],
+    });
+    let call = |provider: &str| {
+        kibitz_http_bound(
+            node,
+            "POST",
+            KIBITZ_LLM_PATH,
+            &[("provider", provider), ("rest", "v1/messages")],
+            None,
+            Some(serde_json::to_vec(&call_body)?),
+        )
+    };
+    let unknown = call("nope")?;
+    anyhow::ensure!(
+        unknown.status == 404,
+        "unknown provider: status {}",
+        unknown.status
+    );
+    let unreachable = call("anthropic")?;
+    anyhow::ensure!(
+        unreachable.status == 502,
+        "unreachable provider: status {}",
+        unreachable.status
+    );
+    anyhow::ensure!(
+        !String::from_utf8_lossy(&unreachable.body).contains("sk-kibitz"),
+        "proxy error leaked the key"
+    );
+
+    // A route falls over from openai, which has no key, to anthropic
+    let routed = |routing: serde_json::Value| -> anyhow::Result<Vec<u8>> {
+        Ok(serde_json::to_vec(&serde_json::json!({
+            "base_urls": { "anthropic": STAND_IN_LLM_BASE_URL },
+            "routing": routing,
+        }))?)
+    };
+    let bad = routed(serde_json::json!({ "routes": { "main": [{ "provider": "nope" }] } }))?;
+    let put = kibitz_http(node, "PUT", KIBITZ_PROVIDERS_PATH, Some("*"), Some(bad))?;
+    anyhow::ensure!(put.status == 400, "bad route: status {}", put.status);
+    let unnamed = routed(serde_json::json!({
+        "routes": { "main": [{ "provider": "openai" }, { "provider": "anthropic" }] },
+    }))?;
+    let put = kibitz_http(node, "PUT", KIBITZ_PROVIDERS_PATH, Some("*"), Some(unnamed))?;
+    anyhow::ensure!(
+        put.status == 400,
+        "route mixing APIs without models: status {}",
+        put.status
+    );
+    let routing = routed(serde_json::json!({
+        "routes": { "main": [
+            { "provider": "openai", "model": "gpt-stand-in" },
+            { "provider": "anthropic", "model": "stand-in" },
+        ] },
+        "default_route": "main",
+    }))?;
+    let put = kibitz_http(node, "PUT", KIBITZ_PROVIDERS_PATH, Some("*"), Some(routing))?;
+    anyhow::ensure!(put.status == 200, "route: status {}", put.status);
+    let failed_over = kibitz_http_answering_llm(
+        node,
+        KIBITZ_LLM_PATH,
+        &[("provider", "auto"), ("rest", "v1/messages")],
+        call_body.clone(),
+    )?;
+    anyhow::ensure!(
+        failed_over.status == 200 && failed_over.header("x-kibitz-provider") == Some("anthropic"),
+        "routed call: status {}, served by {:?}",
+        failed_over.status,
+        failed_over.header("x-kibitz-provider")
+    );
+    let reply = failed_over.json()?;
+    anyhow::ensure!(
+        reply["content"][0]["text"] == STAND_IN_REPLY,
+        "routed call: got {reply}"
+    );
+    Ok(())
+}
+
+fn test_backup_restore(node: &Node, _server: &mut HttpServer) -> anyhow::Result<()> {
+    let post = |path: &str, body: serde_json::Value| -> anyhow::Result<KibitzResponse> {
+        kibitz_http(node, "POST", path, None, Some(serde_json::to_vec(&body)?))
+    };
+    // Leave fwd-ws alone: re-applying its configuration would reconnect it
+    let backup = post(
+        KIBITZ_BACKUP_PATH,
+        serde_json::json!({ "passphrase": "kibitz-test", "fwd_ws": false }),
+    )?;
+    anyhow::ensure!(
+        backup.status == 200,
+        "POST {KIBITZ_BACKUP_PATH}: status {}",
+        backup.status
+    );
+    let archive = backup.json()?;
+    anyhow::ensure!(
+        archive["sealed"].is_string() && archive.get("snapshot").is_none(),
+        "POST {KIBITZ_BACKUP_PATH} with a passphrase: got {archive}"
+    );
+
+    let restore = |passphrase: &str| {
+        post(
+            KIBITZ_RESTORE_PATH,
+            serde_json::json!({ "archive": archive, "passphrase": passphrase }),
+        )
+    };
+    let wrong = restore("wrong")?;
+    anyhow::ensure!(
+        wrong.status == 400,
+        "restore with the wrong passphrase: status {}",
+        wrong.status
+    );
+    let restored = restore("kibitz-test")?;
+    anyhow::ensure!(
+        restored.status == 200,
+        "POST {KIBITZ_RESTORE_PATH}: status {}",
+        restored.status
+    );
+    let report = restored.json()?;
+    anyhow::ensure!(
+        report["conversations"]["imported"] == serde_json::json!([]),
+        "restoring onto the node it came from imported copies: {report}"
+    );
+
+    let saved = post(
+        KIBITZ_BACKUP_PATH,
+        serde_json::json!({ "save": true, "fwd_ws": false }),
+    )?;
+    anyhow::ensure!(
+        saved.status == 201,
+        "POST {KIBITZ_BACKUP_PATH} to VFS: status {}",
+        saved.status
+    );
+    let file = saved.json()?["file"].clone();
+    let backups = kibitz_http(node, "GET", KIBITZ_BACKUPS_PATH, None, None)?.json()?;
+    anyhow::ensure!(
+        backups["backups"]
+            .as_array()
+            .is_some_and(|backups| backups.contains(&file)),
+        "GET {KIBITZ_BACKUPS_PATH}: {file} missing from {backups}"
+    );
+    let restored = post(KIBITZ_RESTORE_PATH, serde_json::json!({ "file": file }))?;
+    anyhow::ensure!(
+        restored.status == 200,
+        "POST {KIBITZ_RESTORE_PATH} of {file}: status {}",
+        restored.status
+    );
+    Ok(())
+}
+
+fn test_agent_run(node: &Node, server: &mut HttpServer) -> anyhow::Result<()> {
+    use_stand_in_llm(node)?;
+
+    let new = serde_json::json!({
+        "prompt": "say hello with the echo tool",
+        "model": "stand-in",
+        "tool_server": STAND_IN_WS_URL,
+    });
+    let start = |headers: &[(&str, &str)], new: &serde_json::Value| {
+        kibitz_http_with(
+            "POST",
+            KIBITZ_RUNS_PATH,
+            &[],
+            headers,
+            Some(serde_json::to_vec(new)?),
+        )
+    };
+    let without_token = start(&[], &new)?;
+    anyhow::ensure!(
+        without_token.status == 401,
+        "POST {KIBITZ_RUNS_PATH} without a token: {}",
+        without_token.status
+    );
+    let token = api_token(node)?;
+    let authorization = [("authorization", token.as_str())];
+
+    // The login cookie will do instead of a token, once kibitz has checked it
+    let listed = kibitz_http(node, "GET", KIBITZ_RUNS_PATH, None, None)?;
+    anyhow::ensure!(
+        listed.status == 200,
+        "GET {KIBITZ_RUNS_PATH} with the login cookie: {}",
+        listed.status
+    );
+
+    // Cancel a run before the stand-in LLM gets to answer its first call
+    let cancelled_prompt = "wait to be cancelled";
+    let doomed = serde_json::json!({ "prompt": cancelled_prompt, "model": "stand-in" });
+    let doomed = start(&authorization, &doomed)?;
+    anyhow::ensure!(
+        doomed.status == 201,
+        "POST {KIBITZ_RUNS_PATH}: {}",
+        doomed.status
+    );
+    let doomed_id = doomed.json()?["id"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string();
+    let doomed = [("id", doomed_id.as_str())];
+    let refused = kibitz_http_with("POST", KIBITZ_RUN_CANCEL_PATH, &doomed, &[], None)?;
+    anyhow::ensure!(
+        refused.status == 401,
+        "POST {KIBITZ_RUN_CANCEL_PATH} without a token: {}",
+        refused.status
+    );
+    let cancelled = kibitz_http_with(
+        "POST",
+        KIBITZ_RUN_CANCEL_PATH,
+        &doomed,
+        &authorization,
+        None,
+    )?;
+    anyhow::ensure!(
+        cancelled.status == 200 && cancelled.json()?["status"] == "cancelled",
+        "POST {KIBITZ_RUN_CANCEL_PATH}: {}",
+        cancelled.status
+    );
+
+    let started = start(&authorization, &new)?;
+    anyhow::ensure!(
+        started.status == 201,
+        "POST {KIBITZ_RUNS_PATH}: {}",
+        started.status
+    );
+    let run = started.json()?;
+    let id = run["id"].as_str().unwrap_or_default().to_string();
+
+    // Play tool server and LLM until kibitz hangs up on the tool server,
+    //  which it does once the run is over
+    let process = node.our.process.to_string();
+    let mut channel = None;
+    let mut llm_calls = 0;
+    loop {
+        match next_ws_event(server)? {
+            HttpServerRequest::WebSocketOpen { channel_id, .. } => channel = Some(channel_id),
+            HttpServerRequest::WebSocketPush { channel_id, .. } if Some(channel_id) == channel => {
+                let blob =
+                    get_blob().ok_or_else(|| anyhow::anyhow!("WebSocketPush without blob"))?;
+                answer_mcp(channel_id, &serde_json::from_slice(&blob.bytes)?)?;
+            }
+            HttpServerRequest::WebSocketClose(channel_id) if Some(channel_id) == channel => break,
+            HttpServerRequest::Http(request)
+                if request.bound_path(Some(&process)) == STAND_IN_LLM_PATH =>
+            {
+                let body = llm_call()?;
+                // The cancelled run's call, whose answer kibitz drops
+                if body.to_string().contains(cancelled_prompt) {
+                    answer_llm(&body)?;
+                    continue;
+                }
+                llm_calls += 1;
+                anyhow::ensure!(llm_calls <= 2, "too many LLM calls");
+                anyhow::ensure!(body["tools"][0]["name"] == "echo", "LLM call: {body}");
+                answer_llm(&body)?;
+            }
+            _ => {}
+        }
+    }
+
+    let run =
+        kibitz_http_with("GET", KIBITZ_RUN_PATH, &[("id", &id)], &authorization, None)?.json()?;
+    anyhow::ensure!(
+        run["status"] == "completed" && run["steps"] == 2 && run["output"] == "tool said hello",
+        "run: {run}"
+    );
+    anyhow::ensure!(
+        run["usage"]["input_tokens"] == 20 && run["usage"]["output_tokens"] == 10,
+        "run usage: {run}"
+    );
+    let doomed = kibitz_http_bound(node, "GET", KIBITZ_RUN_PATH, &doomed, None, None)?.json()?;
+    anyhow::ensure!(doomed["status"] == "cancelled", "cancelled run: {doomed}");
+    let listed = kibitz_http_with("GET", KIBITZ_RUNS_PATH, &[], &authorization, None)?.json()?;
+    anyhow::ensure!(
+        listed["items"]
+            .as_array()
+            .is_some_and(|runs| runs.iter().any(|run| run["id"] == id.as_str())),
+        "GET {KIBITZ_RUNS_PATH}: {listed}"
+    );
+
+    let usage = kibitz_http(
+        node,
+        "GET",
+        &format!("{KIBITZ_USAGE_PATH}?group_by=model"),
+        None,
+        None,
+    )?
+    .json()?;
+    let stand_in = usage["groups"]
+        .as_array()
+        .and_then(|groups| groups.iter().find(|group| group["model"] == STAND_IN_MODEL));
+    anyhow::ensure!(
+        stand_in.is_some_and(|group| group["requests"].as_u64() >= Some(2)),
+        "usage: {usage}"
+    );
+    let conversation_id = run["conversation_id"].as_str().unwrap_or_default();
+    let messages = kibitz_http_bound(
+        node,
+        "GET",
+        KIBITZ_MESSAGES_PATH,
+        &[("id", conversation_id)],
+        None,
+        None,
+    )?
+    .json()?;
+    anyhow::ensure!(messages["total"] == 4, "messages: {messages}");
+    anyhow::ensure!(
+        messages["items"][2]["content"][0]["type"] == "tool_result",
+        "messages: {messages}"
+    );
+    anyhow::ensure!(
+        messages["items"][3]["served_by"]
+            == serde_json::json!({ "provider": "anthropic", "model": STAND_IN_MODEL }),
+        "messages: {messages}"
+    );
+    Ok(())
+}
+
+fn test_budgets(node: &Node, _server: &mut HttpServer) -> anyhow::Result<()> {
+    use_stand_in_llm(node)?;
+    let call_body = serde_json::json!({
+        "model": "stand-in",
+        "max_tokens": 64,
+        "messages": [{ "role": "user", "content": "hello?" }],
+    });
+    let call_params = [("provider", "anthropic"), ("rest", "v1/messages")];
+    // Spend some tokens today, whatever the tests before did
+    let spent = kibitz_http_answering_llm(node, KIBITZ_LLM_PATH, &call_params, call_body.clone())?;
+    anyhow::ensure!(spent.status == 200, "proxied call: {}", spent.status);
+
+    let listing = kibitz_http(node, "GET", KIBITZ_BUDGETS_PATH, None, None)?;
+    let Some(etag) = listing.etag() else {
+        return Err(anyhow::anyhow!("GET {KIBITZ_BUDGETS_PATH}: no ETag"));
+    };
+    let budget = serde_json::json!({ "daily": { "tokens": 10 } });
+    let provider = [("provider", "anthropic")];
+    let put = |if_match: Option<&str>| {
+        kibitz_http_bound(
+            node,
+            "PUT",
+            KIBITZ_BUDGET_PATH,
+            &provider,
+            if_match,
+            Some(serde_json::to_vec(&budget)?),
+        )
+    };
+    let without_if_match = put(None)?;
+    anyhow::ensure!(
+        without_if_match.status == 428,
+        "PUT {KIBITZ_BUDGET_PATH} without If-Match: {}",
+        without_if_match.status
+    );
+    let set = put(Some(&etag))?;
+    anyhow::ensure!(
+        set.status == 200,
+        "PUT {KIBITZ_BUDGET_PATH}: {}",
+        set.status
+    );
+    let stale = put(Some(&etag))?;
+    anyhow::ensure!(
+        stale.status == 412,
+        "stale PUT {KIBITZ_BUDGET_PATH}: {}",
+        stale.status
+    );
+
+    let budgets = kibitz_http(node, "GET", KIBITZ_BUDGETS_PATH, None, None)?.json()?;
+    anyhow::ensure!(
+        budgets["anthropic"]["spent"][0]["percent"].as_f64() >= Some(100.0),
+        "GET {KIBITZ_BUDGETS_PATH}: {budgets}"
+    );
+    let refused = kibitz_http_bound(
+        node,
+        "POST",
+        KIBITZ_LLM_PATH,
+        &call_params,
+        None,
+        Some(serde_json::to_vec(&call_body)?),
+    )?;
+    anyhow::ensure!(
+        refused.status == 402,
+        "call over budget: {}",
+        refused.status
+    );
+
+    let deleted = kibitz_http_bound(
+        node,
+        "DELETE",
+        KIBITZ_BUDGET_PATH,
+        &provider,
+        set.etag().as_deref(),
+        None,
+    )?;
+    anyhow::ensure!(
+        deleted.status == 200,
+        "DELETE {KIBITZ_BUDGET_PATH}: {}",
+        deleted.status
+    );
+    Ok(())
+}
+
+fn test_scheduled_tasks(node: &Node, _server: &mut HttpServer) -> anyhow::Result<()> {
+    let task = |schedule: &str| {
+        serde_json::to_vec(&serde_json::json!({
+            "name": "kibitz-test",
+            "schedule": schedule,
+            // Runs can't use openai, so every attempt fails to start
+            "run": { "prompt": "summarise", "model": "m", "provider": "openai" },
+            "retry_delay_s": 3600,
+        }))
+    };
+    let bad = kibitz_http(
+        node,
+        "POST",
+        KIBITZ_TASKS_PATH,
+        None,
+        Some(task("61 * * * *")?),
+    )?;
+    anyhow::ensure!(bad.status == 400, "bad schedule: status {}", bad.status);
+    let created = kibitz_http(
+        node,
+        "POST",
+        KIBITZ_TASKS_PATH,
+        None,
+        Some(task("0 9 * * *")?),
+    )?;
+    anyhow::ensure!(
+        created.status == 201,
+        "POST {KIBITZ_TASKS_PATH}: {}",
+        created.status
+    );
+    let created = created.json()?;
+    let id = created["id"].as_str().unwrap_or_default().to_string();
+    anyhow::ensure!(created["next_run_at"].is_u64(), "task: {created}");
+
+    let ran = kibitz_http_bound(
+        node,
+        "POST",
+        KIBITZ_TASK_RUN_PATH,
+        &[("id", &id)],
+        None,
+        None,
+    )?
+    .json()?;
+    let attempt = &ran["history"][0];
+    anyhow::ensure!(
+        attempt["status"] == "failed" && attempt["attempt"] == 1 && ran["retry_at"].is_u64(),
+        "task after running: {ran}"
+    );
+
+    let listed = kibitz_http(node, "GET", KIBITZ_TASKS_PATH, None, None)?.json()?;
+    anyhow::ensure!(
+        listed
+            .as_array()
+            .is_some_and(|tasks| tasks.iter().any(|task| task["id"] == id)),
+        "tasks: {listed}"
+    );
+    let deleted = kibitz_http_bound(node, "DELETE", KIBITZ_TASK_PATH, &[("id", &id)], None, None)?;
+    anyhow::ensure!(deleted.status == 200, "DELETE task: {}", deleted.status);
+    Ok(())
+}
+
+fn test_prompt_library(node: &Node, _server: &mut HttpServer) -> anyhow::Result<()> {
+    let spec = |text: &str| {
+        serde_json::to_vec(&serde_json::json!({
+            "name": "reviewer",
+            "tags": ["review"],
+            "text": text,
+        }))
+    };
+    let created = kibitz_http(
+        node,
+        "POST",
+        KIBITZ_PROMPTS_PATH,
+        None,
+        Some(spec("Review {{language}} code.")?),
+    )?;
+    anyhow::ensure!(
+        created.status == 201,
+        "POST {KIBITZ_PROMPTS_PATH}: {}",
+        created.status
+    );
+    let id = created.json()?["id"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string();
+    let updated = kibitz_http_bound(
+        node,
+        "PUT",
+        KIBITZ_PROMPT_PATH,
+        &[("id", &id)],
+        None,
+        Some(spec("Review {{language}} code strictly.")?),
+    )?
+    .json()?;
+    anyhow::ensure!(
+        updated["versions"].as_array().map(Vec::len) == Some(2),
+        "prompt: {updated}"
+    );
+    let listed = kibitz_http(
+        node,
+        "GET",
+        &format!("{KIBITZ_PROMPTS_PATH}?tag=review"),
+        None,
+        None,
+    )?
+    .json()?;
+    anyhow::ensure!(
+        listed.as_array().is_some_and(|prompts| prompts
+            .iter()
+            .any(|prompt| prompt["id"] == id && prompt["variables"][0] == "language")),
+        "prompts: {listed}"
+    );
+
+    let new = |variables: serde_json::Value| {
+        serde_json::to_vec(&serde_json::json!({
+            "title": "kibitz-test",
+            "system_prompt": { "id": id, "version": 1, "variables": variables },
+        }))
+    };
+    let missing = kibitz_http(
+        node,
+        "POST",
+        KIBITZ_CONVERSATIONS_PATH,
+        None,
+        Some(new(serde_json::json!({}))?),
+    )?;
+    anyhow::ensure!(
+        missing.status == 400,
+        "missing variable: status {}",
+        missing.status
+    );
+    let conversation = kibitz_http(
+        node,
+        "POST",
+        KIBITZ_CONVERSATIONS_PATH,
+        None,
+        Some(new(serde_json::json!({ "language": "Rust" }))?),
+    )?
+    .json()?;
+    anyhow::ensure!(
+        conversation["settings"]["system"] == "Review Rust code."
+            && conversation["settings"]["system_prompt"]["version"] == 1,
+        "conversation: {conversation}"
+    );
+    let conversation_id = conversation["id"].as_str().unwrap_or_default();
+    kibitz_http_bound(
+        node,
+        "DELETE",
+        KIBITZ_CONVERSATION_PATH,
+        &[("id", conversation_id)],
+        Some("*"),
+        None,
+    )?;
+    let deleted = kibitz_http_bound(
+        node,
+        "DELETE",
+        KIBITZ_PROMPT_PATH,
+        &[("id", &id)],
+        None,
+        None,
+    )?;
+    anyhow::ensure!(deleted.status == 200, "DELETE prompt: {}", deleted.status);
+    Ok(())
+}
+
+fn test_agent_profiles(node: &Node, _server: &mut HttpServer) -> anyhow::Result<()> {
+    let our = &node.our;
+    let profile = |tools: serde_json::Value| {
+        serde_json::to_vec(&serde_json::json!({
+            "name": "reviewer",
+            "model": "stand-in",
+            "system": "Review, don't change.",
+            "tools": tools,
+        }))
+    };
+    let bad = kibitz_http(
+        node,
+        "POST",
+        KIBITZ_PROFILES_PATH,
+        None,
+        Some(profile(serde_json::json!(["re*ad"]))?),
+    )?;
+    anyhow::ensure!(bad.status == 400, "bad tool pattern: status {}", bad.status);
+    let created = kibitz_http(
+        node,
+        "POST",
+        KIBITZ_PROFILES_PATH,
+        None,
+        Some(profile(serde_json::json!(["read_*"]))?),
+    )?;
+    anyhow::ensure!(
+        created.status == 201,
+        "POST {KIBITZ_PROFILES_PATH}: {}",
+        created.status
+    );
+    let id = created.json()?["id"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string();
+
+    let run = |profile: &str| {
+        serde_json::to_vec(&serde_json::json!({
+            "prompt": "clean up",
+            "profile": profile,
+            "tools": ["read_file", "run_command"],
+        }))
+    };
+    let token = api_token(node)?;
+    let authorization = [("authorization", token.as_str())];
+    let start = |body| kibitz_http_with("POST", KIBITZ_RUNS_PATH, &[], &authorization, body);
+    let wider = start(Some(run(&id)?))?;
+    anyhow::ensure!(
+        wider.status == 400,
+        "run widening its profile's tools: status {}",
+        wider.status
+    );
+    let unknown = start(Some(run("nope")?))?;
+    anyhow::ensure!(
+        unknown.status == 400,
+        "run with an unknown profile: status {}",
+        unknown.status
+    );
+
+    // What fwd-ws asks before passing a browser's tool calls on
+    let check = |profile_id: &str| {
+        kibitz(
+            our,
+            KibitzRequest::CheckTools(CheckToolsRequest {
+                profile_id: profile_id.to_string(),
+                tools: vec!["read_file".to_string(), "run_command".to_string()],
+            }),
+        )
+    };
+    match check(&id)? {
+        KibitzProcessResponse::CheckTools(Some(denied)) => {
+            anyhow::ensure!(denied == ["run_command"], "check-tools: denied {denied:?}")
+        }
+        other => return Err(anyhow::anyhow!("check-tools: got {other:?}")),
+    }
+    match check("nope")? {
+        KibitzProcessResponse::CheckTools(None) => {}
+        other => {
+            return Err(anyhow::anyhow!(
+                "check-tools with no profile: got {other:?}"
+            ))
+        }
+    }
+    expect_ok(fwd_ws(our, FwdWsRequest::SetProfile(Some(id.clone())))?)?;
+    let FwdWsResponse::GetState(state) = fwd_ws(our, FwdWsRequest::GetState)? else {
+        return Err(anyhow::anyhow!("expected GetState response"));
+    };
+    anyhow::ensure!(
+        state.profile.as_deref() == Some(id.as_str()),
+        "fwd-ws profile: {:?}",
+        state.profile
+    );
+    expect_ok(fwd_ws(our, FwdWsRequest::SetProfile(None))?)?;
+
+    let deleted = kibitz_http_bound(
+        node,
+        "DELETE",
+        KIBITZ_PROFILE_PATH,
+        &[("id", &id)],
+        None,
+        None,
+    )?;
+    anyhow::ensure!(deleted.status == 200, "DELETE profile: {}", deleted.status);
+    Ok(())
+}
+
+fn test_conversation_branches(node: &Node, _server: &mut HttpServer) -> anyhow::Result<()> {
+    let new = serde_json::to_vec(&serde_json::json!({ "title": "kibitz-test branches" }))?;
+    let created = kibitz_http(node, "POST", KIBITZ_CONVERSATIONS_PATH, None, Some(new))?.json()?;
+    let root = created["id"].as_str().unwrap_or_default().to_string();
+    let append = |id: &str, role: &str, text: &str| {
+        let message = serde_json::json!({ "role": role, "content": text });
+        kibitz_http_bound(
+            node,
+            "POST",
+            KIBITZ_MESSAGES_PATH,
+            &[("id", id)],
+            None,
+            Some(serde_json::to_vec(&message)?),
+        )
+    };
+    append(&root, "user", "how should I parse this?")?;
+    let checkpoint = serde_json::to_vec(&serde_json::json!({ "name": "asked" }))?;
+    let checkpointed = kibitz_http_bound(
+        node,
+        "POST",
+        KIBITZ_CHECKPOINTS_PATH,
+        &[("id", &root)],
+        None,
+        Some(checkpoint),
+    )?;
+    anyhow::ensure!(
+        checkpointed.status == 201,
+        "POST {KIBITZ_CHECKPOINTS_PATH}: {}",
+        checkpointed.status
+    );
+    append(&root, "assistant", "with a regex")?;
+
+    let fork = serde_json::to_vec(&serde_json::json!({ "checkpoint": "asked" }))?;
+    let forked = kibitz_http_bound(
+        node,
+        "POST",
+        KIBITZ_FORK_PATH,
+        &[("id", &root)],
+        None,
+        Some(fork),
+    )?;
+    anyhow::ensure!(
+        forked.status == 201,
+        "POST {KIBITZ_FORK_PATH}: {}",
+        forked.status
+    );
+    let branch = forked.json()?;
+    let branch_id = branch["id"].as_str().unwrap_or_default().to_string();
+    anyhow::ensure!(
+        branch["message_count"] == 1 && branch["forked_from"]["root_id"] == root,
+        "branch: {branch}"
+    );
+    append(&branch_id, "assistant", "with a parser combinator")?;
+
+    let listed = kibitz_http_bound(
+        node,
+        "GET",
+        KIBITZ_BRANCHES_PATH,
+        &[("id", &root)],
+        None,
+        None,
+    )?;
+    let tree = listed.json()?;
+    anyhow::ensure!(
+        tree["active"] == branch_id && tree["branches"].as_array().map(Vec::len) == Some(2),
+        "branches: {tree}"
+    );
+    let switch = serde_json::to_vec(&serde_json::json!({ "active": root }))?;
+    let switched = kibitz_http_bound(
+        node,
+        "PUT",
+        KIBITZ_BRANCHES_PATH,
+        &[("id", &branch_id)],
+        listed.etag().as_deref(),
+        Some(switch),
+    )?
+    .json()?;
+    anyhow::ensure!(switched["active"] == root, "switched: {switched}");
+
+    let diff = kibitz_http_bound(
+        node,
+        "GET",
+        &format!("{KIBITZ_DIFF_PATH}?with={branch_id}"),
+        &[("id", &root)],
+        None,
+        None,
+    )?
+    .json()?;
+    anyhow::ensure!(
+        diff["common"] == 1
+            && diff["base"][0]["content"] == "with a regex"
+            && diff["other"][0]["content"] == "with a parser combinator",
+        "diff: {diff}"
+    );
+
+    let refused = kibitz_http_bound(
+        node,
+        "DELETE",
+        KIBITZ_CONVERSATION_PATH,
+        &[("id", &root)],
+        Some("*"),
+        None,
+    )?;
+    anyhow::ensure!(
+        refused.status == 409,
+        "DELETE root with branches: {}",
+        refused.status
+    );
+    for id in [&branch_id, &root] {
+        kibitz_http_bound(
+            node,
+            "DELETE",
+            KIBITZ_CONVERSATION_PATH,
+            &[("id", id)],
+            Some("*"),
+            None,
+        )?;
+    }
+    Ok(())
+}
+
+fn fail(test: &str) -> anyhow::Result<()> {
+    Response::new()
+        .body(TesterResponse::Run(Err(FailResponse {
+            test: test.to_string(),
+            file: file!().to_string(),
+            line: line!(),
+            column: column!(),
+        })))
+        .send()?;
+    Ok(())
+}
+
+fn handle_message(our: &Address, server: &mut HttpServer) -> anyhow::Result<()> {
+    let message = await_message()?;
+    if !message.is_request() {
+        return Ok(());
+    }
+    let source = message.source();
+    if our.node != source.node {
+        return Err(anyhow::anyhow!(
+            "rejecting foreign Message from {:?}",
+            source
+        ));
+    }
+    let Ok(TesterRequest::Run(RunRequest { .. })) = message.body().try_into() else {
+        // Stray WebSocket or HTTP traffic between runs
+        return Ok(());
+    };
+
+    let node = match log_in() {
+        Ok(cookie) => Node {
+            our: our.clone(),
+            cookie,
+        },
+        Err(e) => {
+            log(&format!("log_in: FAIL: {e:?}"));
+            return fail("log_in");
+        }
+    };
+    for (name, test) in TESTS {
+        if let Err(e) = test(&node, server) {
+            log(&format!("{name}: FAIL: {e:?}"));
+            return fail(name);
+        }
+        log(&format!("{name}: pass"));
+    }
+
+    Response::new().body(TesterResponse::Run(Ok(()))).send()?;
+    Ok(())
+}
+
+call_init!(init);
+fn init(our: Address) {
+    log("begin");
+
+    let mut server = HttpServer::new(5);
+    server
+        .bind_ws_path(STAND_IN_WS_PATH, WsBindingConfig::new(false, false, false))
+        .expect("failed to bind stand-in WS");
+    server
+        .bind_http_path(
+            STAND_IN_LLM_PATH,
+            HttpBindingConfig::new(false, false, false, None),
+        )
+        .expect("failed to bind stand-in LLM");
+
+    loop {
+        if let Err(e) = handle_message(&our, &mut server) {
+            log(&format!("error: {e:?}"));
+        }
+    }
+}
@@ -0,0 +1,320 @@
+//! A scripted stand-in for a ws-mcp server.
+//!
+//! Speaks MCP JSON-RPC over WebSocket: answers `initialize` and
+//! `tools/list`, and replies to `tools/call` from a per-tool script of
+//! results, errors, delays and disconnects. Script progress is shared by
+//! all connections, so a client that reconnects after a scripted
+//! disconnect picks up at the next step.
+
+use std::collections::HashMap;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tungstenite::{Message, WebSocket};
+
+const PROTOCOL_VERSION: &str = "2024-11-05";
+
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Script {
+    #[serde(default)]
+    pub tools: Vec<Tool>,
+    /// Steps per tool name, consumed in order; the last step repeats.
+    #[serde(default)]
+    pub calls: HashMap<String, Vec<Step>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tool {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(rename = "inputSchema", default = "default_input_schema")]
+    pub input_schema: Value,
+}
+
+fn default_input_schema() -> Value {
+    json!({ "type": "object", "properties": {} })
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Step {
+    /// Wait this long before acting.
+    #[serde(default)]
+    pub delay_ms: u64,
+    #[serde(flatten)]
+    pub action: StepAction,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepAction {
+    /// Reply with a text tool result.
+    Text(String),
+    /// Reply with a tool result flagged `isError`.
+    ToolError(String),
+    /// Reply with a JSON-RPC error.
+    Error { code: i64, message: String },
+    /// Close the connection without replying.
+    Disconnect,
+}
+
+/// What the connection should do in response to one incoming frame.
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    None,
+    Send { delay_ms: u64, message: Value },
+    Disconnect { delay_ms: u64 },
+}
+
+pub struct Mock {
+    script: Script,
+    progress: HashMap<String, usize>,
+}
+
+impl Mock {
+    pub fn new(script: Script) -> Self {
+        Self {
+            script,
+            progress: HashMap::new(),
+        }
+    }
+
+    pub fn handle(&mut self, text: &str) -> Reply {
+        let Ok(request) = serde_json::from_str::<Value>(text) else {
+            return Reply::None;
+        };
+        // Notifications (e.g. `notifications/initialized`) get no reply
+        let Some(id) = request.get("id").cloned() else {
+            return Reply::None;
+        };
+        let method = request.get("method").and_then(Value::as_str).unwrap_or("");
+        let params = request.get("params").cloned().unwrap_or(Value::Null);
+
+        let result = match method {
+            "initialize" => Ok(json!({
+                "protocolVersion": PROTOCOL_VERSION,
+                "capabilities": { "tools": {} },
+                "serverInfo": { "name": "mock-ws-mcp", "version": env!("CARGO_PKG_VERSION") },
+            })),
+            "tools/list" => Ok(json!({ "tools": self.script.tools })),
+            "tools/call" => return self.call_tool(id, &params),
+            _ => Err((METHOD_NOT_FOUND, format!("method not found: {method}"))),
+        };
+        Reply::Send {
+            delay_ms: 0,
+            message: response(id, result),
+        }
+    }
+
+    fn call_tool(&mut self, id: Value, params: &Value) -> Reply {
+        let name = params.get("name").and_then(Value::as_str).unwrap_or("");
+        let Some(steps) = self.script.calls.get(name).filter(|s| !s.is_empty()) else {
+            return Reply::Send {
+                delay_ms: 0,
+                message: response(id, Err((INVALID_PARAMS, format!("unknown tool: {name}")))),
+            };
+        };
+        let index = self.progress.entry(name.to_string()).or_insert(0);
+        let step = steps[(*index).min(steps.len() - 1)].clone();
+        *index += 1;
+
+        let result = match step.action {
+            StepAction::Text(text) => Ok(json!({
+                "content": [{ "type": "text", "text": text }],
+                "isError": false,
+            })),
+            StepAction::ToolError(text) => Ok(json!({
+                "content": [{ "type": "text", "text": text }],
+                "isError": true,
+            })),
+            StepAction::Error { code, message } => Err((code, message)),
+            StepAction::Disconnect => {
+                return Reply::Disconnect {
+                    delay_ms: step.delay_ms,
+                }
+            }
+        };
+        Reply::Send {
+            delay_ms: step.delay_ms,
+            message: response(id, result),
+        }
+    }
+}
+
+fn response(id: Value, result: Result<Value, (i64, String)>) -> Value {
+    match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err((code, message)) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": code, "message": message },
+        }),
+    }
+}
+
+/// Accept connections forever, one thread per connection.
+pub fn serve(listener: TcpListener, script: Script) -> anyhow::Result<()> {
+    let mock = Arc::new(Mutex::new(Mock::new(script)));
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let mock = mock.clone();
+        thread::spawn(move || {
+            if let Err(e) = serve_connection(stream, &mock) {
+                eprintln!("mock-ws-mcp: connection error: {e}");
+            }
+        });
+    }
+    Ok(())
+}
+
+fn serve_connection(stream: TcpStream, mock: &Mutex<Mock>) -> anyhow::Result<()> {
+    let mut socket: WebSocket<TcpStream> = tungstenite::accept(stream)?;
+    loop {
+        let text = match socket.read() {
+            Ok(Message::Text(text)) => text,
+            Ok(Message::Close(_)) | Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+            Ok(_) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let reply = mock.lock().unwrap().handle(&text);
+        match reply {
+            Reply::None => {}
+            Reply::Send { delay_ms, message } => {
+                thread::sleep(Duration::from_millis(delay_ms));
+                socket.send(Message::Text(message.to_string()))?;
+            }
+            Reply::Disconnect { delay_ms } => {
+                thread::sleep(Duration::from_millis(delay_ms));
+                // Drop the TCP stream without a close handshake, like a crash
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script() -> Script {
+        serde_json::from_value(json!({
+            "tools": [{ "name": "echo", "description": "echo input" }],
+            "calls": {
+                "echo": [
+                    { "text": "first" },
+                    { "disconnect": null },
+                    { "delay_ms": 5, "error": { "code": -32000, "message": "boom" } },
+                    { "tool_error": "still broken" },
+                ],
+            },
+        }))
+        .unwrap()
+    }
+
+    fn call(id: u64) -> String {
+        json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": "tools/call",
+            "params": { "name": "echo", "arguments": {} },
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn initialize_and_list() {
+        let mut mock = Mock::new(script());
+        let Reply::Send { message, .. } =
+            mock.handle(r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#)
+        else {
+            panic!("no initialize reply");
+        };
+        assert_eq!(message["result"]["protocolVersion"], PROTOCOL_VERSION);
+
+        assert_eq!(
+            mock.handle(r#"{"jsonrpc":"2.0","method":"notifications/initialized"}"#),
+            Reply::None
+        );
+
+        let Reply::Send { message, .. } =
+            mock.handle(r#"{"jsonrpc":"2.0","id":2,"method":"tools/list"}"#)
+        else {
+            panic!("no tools/list reply");
+        };
+        assert_eq!(message["result"]["tools"][0]["name"], "echo");
+        assert_eq!(
+            message["result"]["tools"][0]["inputSchema"]["type"],
+            "object"
+        );
+    }
+
+    #[test]
+    fn scripted_calls_run_in_order_and_last_repeats() {
+        let mut mock = Mock::new(script());
+        let Reply::Send { message, .. } = mock.handle(&call(1)) else {
+            panic!();
+        };
+        assert_eq!(message["result"]["content"][0]["text"], "first");
+
+        assert_eq!(mock.handle(&call(2)), Reply::Disconnect { delay_ms: 0 });
+
+        let Reply::Send { delay_ms, message } = mock.handle(&call(3)) else {
+            panic!();
+        };
+        assert_eq!(delay_ms, 5);
+        assert_eq!(message["error"]["message"], "boom");
+
+        for id in 4..6 {
+            let Reply::Send { message, .. } = mock.handle(&call(id)) else {
+                panic!();
+            };
+            assert_eq!(message["id"], id);
+            assert_eq!(message["result"]["isError"], true);
+        }
+    }
+
+    #[test]
+    fn unknown_method_and_tool() {
+        let mut mock = Mock::new(script());
+        let Reply::Send { message, .. } =
+            mock.handle(r#"{"jsonrpc":"2.0","id":1,"method":"resources/list"}"#)
+        else {
+            panic!();
+        };
+        assert_eq!(message["error"]["code"], METHOD_NOT_FOUND);
+
+        let Reply::Send { message, .. } = mock
+            .handle(r#"{"jsonrpc":"2.0","id":2,"method":"tools/call","params":{"name":"nope"}}"#)
+        else {
+            panic!();
+        };
+        assert_eq!(message["error"]["code"], INVALID_PARAMS);
+    }
+
+    #[test]
+    fn disconnect_over_socket_then_resume_on_reconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        thread::spawn(move || serve(listener, script()));
+
+        let (mut socket, _) = tungstenite::connect(&url).unwrap();
+        socket.send(Message::Text(call(1))).unwrap();
+        let reply: Value = serde_json::from_str(socket.read().unwrap().to_text().unwrap()).unwrap();
+        assert_eq!(reply["result"]["content"][0]["text"], "first");
+
+        socket.send(Message::Text(call(2))).unwrap();
+        assert!(socket.read().is_err());
+
+        let (mut socket, _) = tungstenite::connect(&url).unwrap();
+        socket.send(Message::Text(call(3))).unwrap();
+        let reply: Value = serde_json::from_str(socket.read().unwrap().to_text().unwrap()).unwrap();
+        assert_eq!(reply["error"]["message"], "boom");
+    }
+}
@@ -0,0 +1,30 @@
+use std::net::TcpListener;
+
+use mock_ws_mcp::{serve, Script};
+
+const DEFAULT_PORT: u16 = 10125;
+
+const USAGE: &str = "usage: mock-ws-mcp [--port PORT] [--script SCRIPT.json]";
+
+fn main() -> anyhow::Result<()> {
+    let mut port = DEFAULT_PORT;
+    let mut script = Script::default();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--port" => {
+                port = args.next().ok_or_else(|| anyhow::anyhow!(USAGE))?.parse()?;
+            }
+            "--script" => {
+                let path = args.next().ok_or_else(|| anyhow::anyhow!(USAGE))?;
+                script = serde_json::from_slice(&std::fs::read(path)?)?;
+            }
+            _ => return Err(anyhow::anyhow!(USAGE)),
+        }
+    }
+
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("mock-ws-mcp: listening on ws://127.0.0.1:{port}");
+    serve(listener, script)
+}
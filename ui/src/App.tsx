@@ -88,6 +88,9 @@ function App() {
           <div>Partner: {state.partner || 'None'}</div>
           <div>Connection: {state.connection}</div>
           <div>WebSocket URL: {state.wsUrl || 'None'}</div>
+          {state.restoreError && (
+            <div style={{ color: 'red' }}>Saved configuration was not restored: {state.restoreError}</div>
+          )}
         </div>
 
         <div style={{ marginBottom: '2em' }}>
@@ -8,11 +8,29 @@ export interface FwdWsStore {
   connectToServer: (url: string) => Promise<void>
   acceptClients: (endpoint: string) => Promise<void>
   disconnect: () => Promise<void>
+  setProfile: (profile: string | null) => Promise<void>
   refreshState: () => Promise<void>
 }
 
 const BASE_URL = import.meta.env.BASE_URL;
 
+// fwd-ws reports its state with snake_case field names.
+interface WireState {
+  partner: string | null
+  connection: ConnectionType
+  ws_url: string | null
+  profile?: string | null
+  restore_error?: string | null
+}
+
+const fromWire = (state: WireState): ProcessState => ({
+  partner: state.partner,
+  connection: state.connection,
+  wsUrl: state.ws_url,
+  profile: state.profile,
+  restoreError: state.restore_error,
+});
+
 const useFwdWsStore = create<FwdWsStore>()((set) => ({
   state: {
     partner: null,
@@ -57,11 +75,20 @@ const useFwdWsStore = create<FwdWsStore>()((set) => ({
     if (!response.ok) throw new Error('Failed to disconnect');
     await useFwdWsStore.getState().refreshState();
   },
+
+  setProfile: async (profile: string | null) => {
+    const response = await fetch(`${BASE_URL}/api`, {
+      method: 'PUT',
+      body: JSON.stringify({ SetProfile: profile })
+    });
+    if (!response.ok) throw new Error('Failed to set profile');
+    await useFwdWsStore.getState().refreshState();
+  },
   
   refreshState: async () => {
     const response = await fetch(`${BASE_URL}/api`);
     if (!response.ok) throw new Error('Failed to fetch state');
-    const state = await response.json();
+    const state = fromWire(await response.json());
     set({ state });
   }
 }));
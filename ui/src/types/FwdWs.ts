@@ -8,6 +8,8 @@ export interface ProcessState {
   partner: string | null
   connection: ConnectionType
   wsUrl: string | null
+  profile?: string | null
+  restoreError?: string | null
 }
 
 export type SetPartnerRequest = {
@@ -34,6 +36,10 @@ export type ForwardRequest = {
   Forward: string
 }
 
+export type SetProfileRequest = {
+  SetProfile: string | null
+}
+
 export type FwdWsRequest =
   | SetPartnerRequest
   | ConnectToServerRequest
@@ -41,3 +47,4 @@ export type FwdWsRequest =
   | DisconnectRequest
   | GetStateRequest
   | ForwardRequest
+  | SetProfileRequest